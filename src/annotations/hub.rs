@@ -0,0 +1,455 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::sync::mpsc;
+
+use crate::feed::{frame::VideoTimestamp, zoom::ZoomRegion};
+
+use super::{
+    model::{AnnotationDocument, AnnotationItem, Change, ItemKind, ModelError, NewItem, Point},
+    pointer::{PointerMessage, PointerRelay, PointerUpdate},
+    protocol::{ClientMessage, Envelope, Presence, ServerMessage, PROTOCOL_VERSION},
+    recording::{AnnotationRecorder, AnnotationReplay, AnnotationTimeline, RecordEntry},
+};
+
+const MAX_NAME_LENGTH: usize = 32;
+/// Colors handed out to peers until they pick their own.
+const PRESENCE_COLORS: [&str; 6] = [
+    "#ff3b30", "#34c759", "#007aff", "#ff9500", "#af52de", "#ffcc00",
+];
+
+#[derive(thiserror::Error, Debug)]
+pub enum AnnotationError {
+    #[error("unsupported protocol version {0}, expected {}", PROTOCOL_VERSION)]
+    UnsupportedVersion(u32),
+    #[error("malformed message: {0}")]
+    Malformed(String),
+    #[error(transparent)]
+    Model(#[from] ModelError),
+    #[error("invalid presence: {0}")]
+    InvalidPresence(&'static str),
+    #[error("annotations are being replayed from a recording")]
+    Replaying,
+}
+
+struct HubState {
+    document: AnnotationDocument,
+    /// Bumped on every change, so consumers can tell when to redraw.
+    revision: u64,
+    /// Zoom region the feed is showing. Items are always stored in source
+    /// space, so clients need it to map what they draw.
+    view: ZoomRegion,
+    /// Connected peers, keyed by client id.
+    peers: HashMap<String, mpsc::UnboundedSender<ServerMessage>>,
+    presence: HashMap<String, Presence>,
+    /// Peers joined so far, for default names and colors.
+    joined: usize,
+    pointers: PointerRelay,
+    /// Source timestamp of the frame being composited.
+    timestamp: Option<VideoTimestamp>,
+    recorder: Option<AnnotationRecorder>,
+    /// Recent changes, for instant replays.
+    timeline: Option<AnnotationTimeline>,
+    replay: Option<AnnotationReplay>,
+}
+
+impl Default for HubState {
+    fn default() -> Self {
+        Self {
+            document: Default::default(),
+            revision: 0,
+            view: ZoomRegion::FULL,
+            peers: HashMap::new(),
+            presence: HashMap::new(),
+            joined: 0,
+            pointers: Default::default(),
+            timestamp: None,
+            recorder: None,
+            timeline: None,
+            replay: None,
+        }
+    }
+}
+
+impl HubState {
+    fn broadcast(&self, except: Option<&str>, message: &ServerMessage) {
+        for (client_id, peer) in &self.peers {
+            if Some(client_id.as_str()) != except {
+                peer.send(message.clone()).ok();
+            }
+        }
+    }
+
+    fn broadcast_change(&self, change: Change) {
+        let message = match change {
+            Change::Removed(ids) if ids.is_empty() => return,
+            Change::Removed(ids) => ServerMessage::Removed { ids },
+            Change::Restored(items) => ServerMessage::Added { items },
+        };
+        self.broadcast(None, &message);
+    }
+
+    fn broadcast_presence(&self) {
+        let mut peers = self.presence.values().cloned().collect::<Vec<_>>();
+        peers.sort_by(|a, b| a.name.cmp(&b.name));
+        self.broadcast(None, &ServerMessage::Presence { peers });
+    }
+
+    /// Apply a change from `author`, and forward it to the peers.
+    fn apply(&mut self, author: &str, message: ClientMessage) -> Result<(), AnnotationError> {
+        match message {
+            ClientMessage::StrokeBegin {
+                id,
+                color,
+                width,
+                point,
+            } => {
+                let item = NewItem {
+                    id,
+                    color,
+                    width,
+                    kind: ItemKind::Stroke {
+                        points: vec![point],
+                        complete: false,
+                    },
+                };
+                let item = self.document.add(author, item)?.clone();
+                self.broadcast(None, &ServerMessage::Added { items: vec![item] });
+            }
+            ClientMessage::StrokePoints { ref id, ref points } => {
+                self.document.extend_stroke(author, id, points)?;
+                self.broadcast(
+                    Some(author),
+                    &ServerMessage::Update {
+                        author: author.to_owned(),
+                        update: message,
+                    },
+                );
+            }
+            ClientMessage::StrokeEnd { ref id } => {
+                self.document.end_stroke(author, id)?;
+                self.broadcast(
+                    Some(author),
+                    &ServerMessage::Update {
+                        author: author.to_owned(),
+                        update: message,
+                    },
+                );
+            }
+            ClientMessage::Add { item } => {
+                let item = self.document.add(author, item)?.clone();
+                self.broadcast(None, &ServerMessage::Added { items: vec![item] });
+            }
+            ClientMessage::SetZ { ref id, z } => {
+                self.document.set_z(id, z)?;
+                self.broadcast(
+                    Some(author),
+                    &ServerMessage::Update {
+                        author: author.to_owned(),
+                        update: message,
+                    },
+                );
+            }
+            ClientMessage::Undo => {
+                let Some(change) = self.document.undo(author) else {
+                    return Ok(());
+                };
+                self.broadcast_change(change);
+            }
+            ClientMessage::Redo => {
+                let Some(change) = self.document.redo(author) else {
+                    return Ok(());
+                };
+                self.broadcast_change(change);
+            }
+            ClientMessage::ClearAll => {
+                let change = self.document.clear_all(author);
+                self.broadcast_change(change);
+            }
+            ClientMessage::ClearMine => {
+                let change = self.document.clear_mine(author);
+                self.broadcast_change(change);
+            }
+            ClientMessage::SetPresence { name, color } => {
+                let name = name.trim();
+                if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+                    return Err(AnnotationError::InvalidPresence("bad name length"));
+                }
+                if !color.starts_with('#') || color.len() > 7 {
+                    return Err(AnnotationError::InvalidPresence("bad color"));
+                }
+                let Some(presence) = self.presence.get_mut(author) else {
+                    return Ok(());
+                };
+                presence.name = name.to_owned();
+                presence.color = color;
+                self.broadcast_presence();
+                // Doesn't change what's drawn
+                return Ok(());
+            }
+        }
+
+        self.revision += 1;
+        Ok(())
+    }
+
+    fn clear(&mut self) {
+        let change = self.document.clear();
+        self.broadcast_change(change);
+        self.revision += 1;
+    }
+
+    /// Record an entry against the latest frame's timestamp. Nothing's
+    /// recorded before the first frame, since there's nothing to sync to.
+    fn record(&mut self, entry: impl FnOnce(u64) -> RecordEntry) {
+        let Some(timestamp) = self.timestamp else {
+            return;
+        };
+        if self.recorder.is_none() && self.timeline.is_none() {
+            return;
+        }
+        let entry = entry(timestamp.to_micros());
+        if let Some(timeline) = &mut self.timeline {
+            timeline.record(entry.clone());
+        }
+        if let Some(recorder) = &self.recorder {
+            recorder.record(entry);
+        }
+    }
+}
+
+/// Shares the feed's annotation document between peers. Changes from one
+/// peer are applied to the document, then rebroadcast to the others.
+#[derive(Clone, Default)]
+pub struct AnnotationHub {
+    state: Arc<Mutex<HubState>>,
+}
+
+impl AnnotationHub {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a peer. The returned channel starts with a snapshot of the
+    /// current annotations, followed by everyone else's changes.
+    pub fn join(&self, client_id: &str) -> mpsc::UnboundedReceiver<ServerMessage> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut state = self.state.lock().unwrap();
+        tx.send(ServerMessage::Snapshot {
+            client_id: client_id.to_owned(),
+            items: state.document.items().to_vec(),
+            view: state.view,
+        })
+        .ok();
+        state.peers.insert(client_id.to_owned(), tx);
+
+        state.joined += 1;
+        let presence = Presence {
+            client_id: client_id.to_owned(),
+            name: format!("Presenter {}", state.joined),
+            color: PRESENCE_COLORS[(state.joined - 1) % PRESENCE_COLORS.len()].to_owned(),
+        };
+        state.presence.insert(client_id.to_owned(), presence);
+        state.broadcast_presence();
+        rx
+    }
+
+    /// Unregister a peer. Its annotations stay, but its undo history and
+    /// pointer go.
+    pub fn leave(&self, client_id: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.remove(client_id);
+        state.document.forget(client_id);
+        state.pointers.leave(client_id);
+        if state.presence.remove(client_id).is_some() {
+            state.broadcast_presence();
+        }
+    }
+
+    /// Register a peer's pointer channel. The pointer channel is separate
+    /// from the annotation channel, since it's unreliable and unordered.
+    pub fn join_pointer(&self, client_id: &str) -> mpsc::UnboundedReceiver<PointerUpdate> {
+        self.state.lock().unwrap().pointers.join(client_id)
+    }
+
+    pub fn leave_pointer(&self, client_id: &str) {
+        self.state.lock().unwrap().pointers.leave(client_id);
+    }
+
+    /// Handle a raw message from a peer's pointer channel. Bad messages are
+    /// dropped, since there's no reliable way to answer them.
+    pub fn handle_pointer_text(&self, client_id: &str, text: &str) {
+        match serde_json::from_str::<Envelope<PointerMessage>>(text) {
+            Ok(envelope) if envelope.v == PROTOCOL_VERSION => {
+                let mut state = self.state.lock().unwrap();
+                state.pointers.update(client_id, envelope.message.position);
+            }
+            Ok(envelope) => eprintln!("Dropping pointer message with version {}", envelope.v),
+            Err(e) => eprintln!("Dropping malformed pointer message: {e}"),
+        }
+    }
+
+    /// Pointers that are up, and their presenter's color.
+    pub fn pointers(&self) -> Vec<(Point, String)> {
+        let mut state = self.state.lock().unwrap();
+        let positions = state.pointers.positions();
+        positions
+            .into_iter()
+            .map(|(client_id, position)| {
+                let color = state
+                    .presence
+                    .get(&client_id)
+                    .map_or(PRESENCE_COLORS[0], |presence| presence.color.as_str());
+                (position, color.to_owned())
+            })
+            .collect()
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.state.lock().unwrap().revision
+    }
+
+    /// The items, and the revision they're at.
+    pub fn snapshot(&self) -> (u64, Vec<AnnotationItem>) {
+        let state = self.state.lock().unwrap();
+        (state.revision, state.document.items().to_vec())
+    }
+
+    /// Clear the annotations on the server's behalf, e.g. when leaving
+    /// freeze-frame.
+    pub fn clear(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.clear();
+        state.record(|timestamp| RecordEntry::Clear { timestamp });
+    }
+
+    /// Record every change to `path` as JSON lines, starting with the next
+    /// frame.
+    pub fn record_to(&self, path: &Path) -> Result<()> {
+        let recorder = AnnotationRecorder::create(path)?;
+        self.state.lock().unwrap().recorder = Some(recorder);
+        println!("Recording annotations to {}", path.display());
+        Ok(())
+    }
+
+    /// Keep the last `length` of changes in memory, to cut out alongside
+    /// instant replays.
+    pub fn keep_timeline(&self, length: Duration) {
+        self.state.lock().unwrap().timeline = Some(AnnotationTimeline::new(length));
+    }
+
+    /// A recording of the changes up to `until`, lined up to start at
+    /// `start`. See `AnnotationTimeline::recording`.
+    pub fn timeline(&self, start: VideoTimestamp, until: VideoTimestamp) -> Vec<RecordEntry> {
+        let state = self.state.lock().unwrap();
+        match &state.timeline {
+            Some(timeline) => timeline.recording(start.to_micros(), until.to_micros()),
+            None => vec![RecordEntry::Start {
+                timestamp: start.to_micros(),
+            }],
+        }
+    }
+
+    /// Replay a recording instead of taking changes from peers. The first
+    /// frame after this lines up with the start of the recording.
+    pub fn replay_from(&self, path: &Path) -> Result<()> {
+        let replay = AnnotationReplay::load(path)?;
+        let mut state = self.state.lock().unwrap();
+        state.clear();
+        state.replay = Some(replay);
+        println!("Replaying annotations from {}", path.display());
+        Ok(())
+    }
+
+    /// Tell the hub which source frame is being composited, which is what
+    /// changes are recorded against and replays are synced to.
+    pub fn set_timestamp(&self, timestamp: VideoTimestamp) {
+        let mut state = self.state.lock().unwrap();
+        state.timestamp = Some(timestamp);
+        if let Some(recorder) = &mut state.recorder {
+            recorder.start(timestamp);
+        }
+
+        let Some(replayed) = state
+            .replay
+            .as_mut()
+            .map(|replay| replay.advance(timestamp))
+        else {
+            return;
+        };
+        if replayed.rewound {
+            // Histories would be out of step too, so start from scratch
+            state.document = Default::default();
+            state.clear();
+        }
+        for entry in replayed.entries {
+            match entry {
+                RecordEntry::Start { .. } => {}
+                RecordEntry::Operation {
+                    author, message, ..
+                } => {
+                    if let Err(e) = state.apply(&author, message) {
+                        eprintln!("Unable to replay annotation change: {e}");
+                    }
+                }
+                RecordEntry::Clear { .. } => state.clear(),
+            }
+        }
+    }
+
+    /// Tell peers the feed now shows `region` of the source.
+    pub fn set_view(&self, region: ZoomRegion) {
+        let mut state = self.state.lock().unwrap();
+        state.view = region;
+        state.broadcast(None, &ServerMessage::View { region });
+    }
+
+    /// Handle a raw message from a peer, answering it with an error if it
+    /// couldn't be applied.
+    pub fn handle_text(&self, client_id: &str, text: &str) {
+        let result = match serde_json::from_str::<Envelope<ClientMessage>>(text) {
+            Ok(envelope) if envelope.v != PROTOCOL_VERSION => {
+                Err(AnnotationError::UnsupportedVersion(envelope.v))
+            }
+            Ok(envelope) => self.apply(client_id, envelope.message),
+            Err(e) => Err(AnnotationError::Malformed(e.to_string())),
+        };
+
+        if let Err(e) = result {
+            let state = self.state.lock().unwrap();
+            if let Some(peer) = state.peers.get(client_id) {
+                peer.send(ServerMessage::Error {
+                    message: e.to_string(),
+                })
+                .ok();
+            }
+        }
+    }
+
+    /// Apply a change from `author`, forward it to the peers and record it.
+    pub fn apply(&self, author: &str, message: ClientMessage) -> Result<(), AnnotationError> {
+        let mut state = self.state.lock().unwrap();
+        let presence = matches!(message, ClientMessage::SetPresence { .. });
+        if state.replay.is_some() && !presence {
+            return Err(AnnotationError::Replaying);
+        }
+
+        // Applying consumes the message, so keep a copy for the recording
+        let recording = state.recorder.is_some() || state.timeline.is_some();
+        let recorded = (recording && !presence).then(|| message.clone());
+        state.apply(author, message)?;
+        if let Some(message) = recorded {
+            state.record(|timestamp| RecordEntry::Operation {
+                timestamp,
+                author: author.to_owned(),
+                message,
+            });
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,5 @@
+pub mod hub;
+pub mod model;
+pub mod pointer;
+pub mod protocol;
+pub mod recording;
@@ -0,0 +1,579 @@
+use std::{
+    collections::HashMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Undo steps kept per author.
+const MAX_HISTORY: usize = 100;
+const MAX_ITEMS: usize = 2000;
+const MAX_STROKE_POINTS: usize = 10_000;
+const MAX_ID_LENGTH: usize = 64;
+const MAX_TEXT_LENGTH: usize = 500;
+/// Widths and sizes are clamped to this fraction of the video height.
+const MAX_SIZE: f32 = 0.1;
+/// Points may be up to a frame outside the video, e.g. arrows pointing in
+/// from off-screen.
+const POINT_RANGE: std::ops::RangeInclusive<f32> = -1.0..=2.0;
+/// Layers have to stay within +/- this.
+const MAX_Z: i64 = 1 << 48;
+
+#[derive(thiserror::Error, Debug)]
+pub enum ModelError {
+    #[error("invalid id {0:?}")]
+    InvalidId(String),
+    #[error("annotation {0} already exists")]
+    DuplicateId(String),
+    #[error("no annotation {0}")]
+    UnknownItem(String),
+    #[error("no stroke {0} in progress")]
+    UnknownStroke(String),
+    #[error("too many annotations")]
+    TooLarge,
+    #[error("invalid point, width or size")]
+    InvalidGeometry,
+    #[error("layer {0} is out of range")]
+    InvalidZ(i64),
+}
+
+/// Position on the video, normalized to 0..1 so it's independent of the
+/// resolution each viewer sees.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Point {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Point {
+    fn is_valid(&self) -> bool {
+        POINT_RANGE.contains(&self.x) && POINT_RANGE.contains(&self.y)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StampKind {
+    Cross,
+    Check,
+    Circle,
+    Star,
+}
+
+/// Geometry of an annotation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ItemKind {
+    Stroke {
+        points: Vec<Point>,
+        /// False while the author is still drawing it.
+        complete: bool,
+    },
+    Line {
+        from: Point,
+        to: Point,
+    },
+    Arrow {
+        from: Point,
+        to: Point,
+    },
+    Rectangle {
+        from: Point,
+        to: Point,
+    },
+    Ellipse {
+        from: Point,
+        to: Point,
+    },
+    Text {
+        position: Point,
+        text: String,
+        /// Font size, as a fraction of the video height.
+        size: f32,
+    },
+    Stamp {
+        position: Point,
+        stamp: StampKind,
+        /// As a fraction of the video height.
+        size: f32,
+    },
+}
+
+/// An annotation, as created by a client. The server fills in the rest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewItem {
+    pub id: String,
+    /// CSS hex color, e.g. `#ff0000`.
+    pub color: String,
+    /// Line width, as a fraction of the video height.
+    pub width: f32,
+    #[serde(flatten)]
+    pub kind: ItemKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnotationItem {
+    pub id: String,
+    /// `client_id` of the peer that drew it.
+    pub author: String,
+    pub color: String,
+    pub width: f32,
+    /// Items are drawn in increasing z order.
+    pub z: i64,
+    /// Milliseconds since the UNIX epoch.
+    pub created_at: u64,
+    #[serde(flatten)]
+    pub kind: ItemKind,
+}
+
+/// Result of an undo, redo or clear, for telling peers.
+#[derive(Debug, Clone)]
+pub enum Change {
+    Removed(Vec<String>),
+    Restored(Vec<AnnotationItem>),
+}
+
+/// One undoable step.
+#[derive(Debug, Clone)]
+enum HistoryEntry {
+    Added(Vec<AnnotationItem>),
+    Removed(Vec<AnnotationItem>),
+}
+
+#[derive(Debug, Default)]
+struct AuthorHistory {
+    undo: Vec<HistoryEntry>,
+    redo: Vec<HistoryEntry>,
+}
+
+/// The annotations drawn on a feed, and every author's undo history. This is
+/// the single source of truth the data channel, compositor and recordings
+/// work from.
+#[derive(Debug, Default)]
+pub struct AnnotationDocument {
+    /// Sorted by z.
+    items: Vec<AnnotationItem>,
+    next_z: i64,
+    history: HashMap<String, AuthorHistory>,
+}
+
+impl AnnotationDocument {
+    pub fn items(&self) -> &[AnnotationItem] {
+        &self.items
+    }
+
+    pub fn get(&self, id: &str) -> Option<&AnnotationItem> {
+        self.items.iter().find(|item| item.id == id)
+    }
+
+    /// Add an item on top of the others. Widths and sizes are clamped to
+    /// `MAX_SIZE`.
+    pub fn add(&mut self, author: &str, mut item: NewItem) -> Result<&AnnotationItem, ModelError> {
+        if item.id.is_empty() || item.id.len() > MAX_ID_LENGTH {
+            return Err(ModelError::InvalidId(item.id));
+        }
+        if self.items.len() >= MAX_ITEMS {
+            return Err(ModelError::TooLarge);
+        }
+        if self.get(&item.id).is_some() {
+            return Err(ModelError::DuplicateId(item.id));
+        }
+        match &item.kind {
+            ItemKind::Stroke { points, .. } if points.len() > MAX_STROKE_POINTS => {
+                return Err(ModelError::TooLarge)
+            }
+            ItemKind::Text { text, .. } if text.len() > MAX_TEXT_LENGTH => {
+                return Err(ModelError::TooLarge)
+            }
+            _ => {}
+        }
+        item.width = clamp_size(item.width)?;
+        match &mut item.kind {
+            ItemKind::Stroke { points, .. } => {
+                if !points.iter().all(Point::is_valid) {
+                    return Err(ModelError::InvalidGeometry);
+                }
+            }
+            ItemKind::Line { from, to }
+            | ItemKind::Arrow { from, to }
+            | ItemKind::Rectangle { from, to }
+            | ItemKind::Ellipse { from, to } => {
+                if !from.is_valid() || !to.is_valid() {
+                    return Err(ModelError::InvalidGeometry);
+                }
+            }
+            ItemKind::Text { position, size, .. } | ItemKind::Stamp { position, size, .. } => {
+                if !position.is_valid() {
+                    return Err(ModelError::InvalidGeometry);
+                }
+                *size = clamp_size(*size)?;
+            }
+        }
+
+        let item = AnnotationItem {
+            id: item.id,
+            author: author.to_owned(),
+            color: item.color,
+            width: item.width,
+            z: self.next_z,
+            created_at: now_millis(),
+            kind: item.kind,
+        };
+        self.next_z = self.next_z.saturating_add(1);
+        self.record(author, HistoryEntry::Added(vec![item.clone()]));
+        self.insert(vec![item]);
+        Ok(self.items.last().unwrap())
+    }
+
+    /// Append points to a stroke `author` is still drawing.
+    pub fn extend_stroke(
+        &mut self,
+        author: &str,
+        id: &str,
+        new_points: &[Point],
+    ) -> Result<(), ModelError> {
+        let points = self
+            .items
+            .iter_mut()
+            .find_map(|item| match &mut item.kind {
+                ItemKind::Stroke {
+                    points,
+                    complete: false,
+                } if item.id == id && item.author == author => Some(points),
+                _ => None,
+            })
+            .ok_or_else(|| ModelError::UnknownStroke(id.to_owned()))?;
+        if points.len() + new_points.len() > MAX_STROKE_POINTS {
+            return Err(ModelError::TooLarge);
+        }
+        if !new_points.iter().all(Point::is_valid) {
+            return Err(ModelError::InvalidGeometry);
+        }
+        points.extend_from_slice(new_points);
+        Ok(())
+    }
+
+    pub fn end_stroke(&mut self, author: &str, id: &str) -> Result<(), ModelError> {
+        let complete = self
+            .items
+            .iter_mut()
+            .find_map(|item| match &mut item.kind {
+                ItemKind::Stroke { complete, .. } if item.id == id && item.author == author => {
+                    Some(complete)
+                }
+                _ => None,
+            })
+            .ok_or_else(|| ModelError::UnknownStroke(id.to_owned()))?;
+        *complete = true;
+        Ok(())
+    }
+
+    /// Move an item to another layer, within +/- `MAX_Z`. Not undoable.
+    pub fn set_z(&mut self, id: &str, z: i64) -> Result<(), ModelError> {
+        if !(-MAX_Z..=MAX_Z).contains(&z) {
+            return Err(ModelError::InvalidZ(z));
+        }
+        let item = self
+            .items
+            .iter_mut()
+            .find(|item| item.id == id)
+            .ok_or_else(|| ModelError::UnknownItem(id.to_owned()))?;
+        item.z = z;
+        self.next_z = self.next_z.max(z + 1);
+        self.items.sort_by_key(|item| item.z);
+        Ok(())
+    }
+
+    /// Remove every item, whoever drew it.
+    pub fn clear_all(&mut self, author: &str) -> Change {
+        let removed = std::mem::take(&mut self.items);
+        let ids = removed.iter().map(|item| item.id.clone()).collect();
+        if !removed.is_empty() {
+            self.record(author, HistoryEntry::Removed(removed));
+        }
+        Change::Removed(ids)
+    }
+
+    /// Remove every item, without recording it in anyone's history.
+    pub fn clear(&mut self) -> Change {
+        let removed = std::mem::take(&mut self.items);
+        Change::Removed(removed.into_iter().map(|item| item.id).collect())
+    }
+
+    /// Remove the items `author` drew.
+    pub fn clear_mine(&mut self, author: &str) -> Change {
+        let (removed, kept) = std::mem::take(&mut self.items)
+            .into_iter()
+            .partition::<Vec<_>, _>(|item| item.author == author);
+        self.items = kept;
+        let ids = removed.iter().map(|item| item.id.clone()).collect();
+        if !removed.is_empty() {
+            self.record(author, HistoryEntry::Removed(removed));
+        }
+        Change::Removed(ids)
+    }
+
+    /// Revert `author`'s last step. Steps whose items are all gone (e.g.
+    /// cleared by someone else) are skipped.
+    pub fn undo(&mut self, author: &str) -> Option<Change> {
+        loop {
+            let entry = self.history.get_mut(author)?.undo.pop()?;
+            let (change, redo) = self.revert(entry);
+            if let Some(change) = change {
+                self.history
+                    .entry(author.to_owned())
+                    .or_default()
+                    .redo
+                    .push(redo);
+                return Some(change);
+            }
+        }
+    }
+
+    /// Reapply `author`'s last undone step.
+    pub fn redo(&mut self, author: &str) -> Option<Change> {
+        loop {
+            let entry = self.history.get_mut(author)?.redo.pop()?;
+            let (change, undo) = self.revert(entry);
+            if let Some(change) = change {
+                push_bounded(
+                    &mut self.history.entry(author.to_owned()).or_default().undo,
+                    undo,
+                );
+                return Some(change);
+            }
+        }
+    }
+
+    /// Forget an author's history, e.g. once they've left.
+    pub fn forget(&mut self, author: &str) {
+        self.history.remove(author);
+    }
+
+    /// Apply the opposite of `entry`. Returns what changed, and the entry
+    /// that reverts it again.
+    fn revert(&mut self, entry: HistoryEntry) -> (Option<Change>, HistoryEntry) {
+        match entry {
+            HistoryEntry::Added(items) => {
+                // Take the current versions, since strokes grow after they're added
+                let removed = self.remove(items.iter().map(|item| item.id.as_str()));
+                let ids = removed
+                    .iter()
+                    .map(|item| item.id.clone())
+                    .collect::<Vec<_>>();
+                let change = (!ids.is_empty()).then_some(Change::Removed(ids));
+                (change, HistoryEntry::Removed(removed))
+            }
+            HistoryEntry::Removed(items) => {
+                let restored = items
+                    .into_iter()
+                    .filter(|item| self.get(&item.id).is_none())
+                    .collect::<Vec<_>>();
+                self.insert(restored.clone());
+                let change = (!restored.is_empty()).then(|| Change::Restored(restored.clone()));
+                (change, HistoryEntry::Added(restored))
+            }
+        }
+    }
+
+    fn record(&mut self, author: &str, entry: HistoryEntry) {
+        let history = self.history.entry(author.to_owned()).or_default();
+        push_bounded(&mut history.undo, entry);
+        history.redo.clear();
+    }
+
+    fn insert(&mut self, items: Vec<AnnotationItem>) {
+        self.items.extend(items);
+        self.items.sort_by_key(|item| item.z);
+    }
+
+    fn remove<'a>(&mut self, ids: impl Iterator<Item = &'a str>) -> Vec<AnnotationItem> {
+        let ids = ids.collect::<Vec<_>>();
+        let (removed, kept) = std::mem::take(&mut self.items)
+            .into_iter()
+            .partition(|item| ids.contains(&item.id.as_str()));
+        self.items = kept;
+        removed
+    }
+}
+
+fn push_bounded(stack: &mut Vec<HistoryEntry>, entry: HistoryEntry) {
+    if stack.len() >= MAX_HISTORY {
+        stack.remove(0);
+    }
+    stack.push(entry);
+}
+
+/// Clamp a width or size to `MAX_SIZE`, rejecting ones that can't be drawn.
+fn clamp_size(size: f32) -> Result<f32, ModelError> {
+    if !size.is_finite() || size <= 0. {
+        return Err(ModelError::InvalidGeometry);
+    }
+    Ok(size.min(MAX_SIZE))
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(id: &str) -> NewItem {
+        NewItem {
+            id: id.to_owned(),
+            color: "#ff0000".to_owned(),
+            width: 0.01,
+            kind: ItemKind::Line {
+                from: Point { x: 0.1, y: 0.1 },
+                to: Point { x: 0.9, y: 0.9 },
+            },
+        }
+    }
+
+    fn ids(document: &AnnotationDocument) -> Vec<&str> {
+        document
+            .items()
+            .iter()
+            .map(|item| item.id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn undo_and_redo_adds() {
+        let mut document = AnnotationDocument::default();
+        document.add("alice", line("a")).unwrap();
+        document.add("alice", line("b")).unwrap();
+
+        assert!(matches!(document.undo("alice"), Some(Change::Removed(ids)) if ids == ["b"]));
+        assert_eq!(ids(&document), ["a"]);
+        assert!(
+            matches!(document.redo("alice"), Some(Change::Restored(items)) if items[0].id == "b")
+        );
+        assert_eq!(ids(&document), ["a", "b"]);
+        assert!(document.redo("alice").is_none());
+    }
+
+    #[test]
+    fn undo_is_per_author() {
+        let mut document = AnnotationDocument::default();
+        document.add("alice", line("a")).unwrap();
+        document.add("bob", line("b")).unwrap();
+
+        document.undo("alice").unwrap();
+        assert_eq!(ids(&document), ["b"]);
+        assert!(document.undo("alice").is_none());
+        assert!(document.undo("carol").is_none());
+    }
+
+    #[test]
+    fn undo_clear_restores_in_order() {
+        let mut document = AnnotationDocument::default();
+        document.add("alice", line("a")).unwrap();
+        document.add("bob", line("b")).unwrap();
+        document.clear_all("alice");
+        assert!(document.items().is_empty());
+
+        document.undo("alice").unwrap();
+        assert_eq!(ids(&document), ["a", "b"]);
+    }
+
+    #[test]
+    fn undo_skips_steps_cleared_by_others() {
+        let mut document = AnnotationDocument::default();
+        document.add("alice", line("a")).unwrap();
+        document.add("alice", line("b")).unwrap();
+        document.clear_all("bob");
+
+        // Both of alice's steps are gone, so there's nothing left to undo
+        assert!(document.undo("alice").is_none());
+        assert!(document.items().is_empty());
+
+        // Bob can still bring them back
+        document.undo("bob").unwrap();
+        assert_eq!(ids(&document), ["a", "b"]);
+    }
+
+    #[test]
+    fn new_step_clears_redo() {
+        let mut document = AnnotationDocument::default();
+        document.add("alice", line("a")).unwrap();
+        document.undo("alice").unwrap();
+        document.add("alice", line("b")).unwrap();
+        assert!(document.redo("alice").is_none());
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut document = AnnotationDocument::default();
+        for i in 0..MAX_HISTORY + 10 {
+            document.add("alice", line(&i.to_string())).unwrap();
+        }
+        let mut undone = 0;
+        while document.undo("alice").is_some() {
+            undone += 1;
+        }
+        assert_eq!(undone, MAX_HISTORY);
+        assert_eq!(document.items().len(), 10);
+    }
+
+    #[test]
+    fn sizes_are_validated() {
+        let mut document = AnnotationDocument::default();
+        let mut wide = line("wide");
+        wide.width = 5.;
+        assert_eq!(document.add("alice", wide).unwrap().width, MAX_SIZE);
+
+        for width in [0., -1., f32::NAN, f32::INFINITY] {
+            let mut item = line("bad");
+            item.width = width;
+            assert!(matches!(
+                document.add("alice", item),
+                Err(ModelError::InvalidGeometry)
+            ));
+        }
+    }
+
+    #[test]
+    fn points_are_validated() {
+        let mut document = AnnotationDocument::default();
+        let mut item = line("far");
+        item.kind = ItemKind::Arrow {
+            from: Point { x: 0.5, y: 0.5 },
+            to: Point { x: 1e9, y: 0.5 },
+        };
+        assert!(document.add("alice", item).is_err());
+
+        let mut stroke = line("stroke");
+        stroke.kind = ItemKind::Stroke {
+            points: vec![Point { x: 0.5, y: 0.5 }],
+            complete: false,
+        };
+        document.add("alice", stroke).unwrap();
+        let nan = Point {
+            x: f32::NAN,
+            y: 0.5,
+        };
+        assert!(document.extend_stroke("alice", "stroke", &[nan]).is_err());
+        document
+            .extend_stroke("alice", "stroke", &[Point { x: -0.2, y: 1.1 }])
+            .unwrap();
+    }
+
+    #[test]
+    fn z_stays_in_range() {
+        let mut document = AnnotationDocument::default();
+        document.add("alice", line("a")).unwrap();
+        assert!(matches!(
+            document.set_z("a", i64::MAX),
+            Err(ModelError::InvalidZ(_))
+        ));
+        document.set_z("a", MAX_Z).unwrap();
+        document.add("alice", line("b")).unwrap();
+        assert_eq!(ids(&document), ["a", "b"]);
+    }
+}
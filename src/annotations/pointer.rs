@@ -0,0 +1,123 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+use super::model::Point;
+
+/// Positions relayed per presenter, at most. Anything in between only
+/// updates the position the compositor draws.
+const MAX_POINTER_RATE: f32 = 30.;
+/// Pointers that haven't moved for this long are dropped. Clients resend
+/// their position while pointing, since the channel is lossy and a lost
+/// "hide" would otherwise leave the pointer up forever.
+const POINTER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sent by a presenter on the pointer channel. No position hides it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PointerMessage {
+    pub position: Option<Point>,
+}
+
+/// Relayed to everyone but the presenter.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PointerUpdate {
+    pub client_id: String,
+    pub position: Option<Point>,
+}
+
+struct Pointer {
+    position: Point,
+    updated: Instant,
+    relayed: Option<Instant>,
+}
+
+/// Latest pointer position of each presenter, relayed to the other peers.
+#[derive(Default)]
+pub struct PointerRelay {
+    pointers: HashMap<String, Pointer>,
+    peers: HashMap<String, mpsc::UnboundedSender<PointerUpdate>>,
+}
+
+impl PointerRelay {
+    /// Register a peer. The returned channel starts with the pointers that
+    /// are currently up.
+    pub fn join(&mut self, client_id: &str) -> mpsc::UnboundedReceiver<PointerUpdate> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        for (pointer_client_id, pointer) in &self.pointers {
+            tx.send(PointerUpdate {
+                client_id: pointer_client_id.clone(),
+                position: Some(pointer.position),
+            })
+            .ok();
+        }
+        self.peers.insert(client_id.to_owned(), tx);
+        rx
+    }
+
+    pub fn leave(&mut self, client_id: &str) {
+        self.peers.remove(client_id);
+        self.update(client_id, None);
+    }
+
+    /// Move or hide `client_id`'s pointer.
+    pub fn update(&mut self, client_id: &str, position: Option<Point>) {
+        let Some(position) = position else {
+            if self.pointers.remove(client_id).is_some() {
+                self.broadcast(client_id, None);
+            }
+            return;
+        };
+
+        let now = Instant::now();
+        let position = Point {
+            x: position.x.clamp(0., 1.),
+            y: position.y.clamp(0., 1.),
+        };
+        let pointer = self
+            .pointers
+            .entry(client_id.to_owned())
+            .or_insert(Pointer {
+                position,
+                updated: now,
+                relayed: None,
+            });
+        pointer.position = position;
+        pointer.updated = now;
+
+        let interval = Duration::from_secs_f32(1. / MAX_POINTER_RATE);
+        let due = pointer
+            .relayed
+            .map_or(true, |relayed| now - relayed >= interval);
+        if due {
+            pointer.relayed = Some(now);
+            self.broadcast(client_id, Some(position));
+        }
+    }
+
+    /// Pointers that are up, by client id. Expired ones are dropped; peers
+    /// time them out on their own.
+    pub fn positions(&mut self) -> Vec<(String, Point)> {
+        self.pointers
+            .retain(|_, pointer| pointer.updated.elapsed() < POINTER_TIMEOUT);
+        self.pointers
+            .iter()
+            .map(|(client_id, pointer)| (client_id.clone(), pointer.position))
+            .collect()
+    }
+
+    fn broadcast(&self, client_id: &str, position: Option<Point>) {
+        for (peer_client_id, peer) in &self.peers {
+            if peer_client_id != client_id {
+                peer.send(PointerUpdate {
+                    client_id: client_id.to_owned(),
+                    position,
+                })
+                .ok();
+            }
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+
+use crate::feed::zoom::ZoomRegion;
+
+use super::model::{AnnotationItem, NewItem, Point};
+
+/// Bumped on breaking changes to the messages below. Peers speaking another
+/// version are answered with an error instead of being guessed at.
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Every message on the annotation channel is wrapped in one of these.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub v: u32,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(message: T) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            message,
+        }
+    }
+}
+
+/// Messages sent by clients.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMessage {
+    /// Freehand strokes are streamed while they're drawn.
+    StrokeBegin {
+        id: String,
+        color: String,
+        width: f32,
+        point: Point,
+    },
+    StrokePoints {
+        id: String,
+        points: Vec<Point>,
+    },
+    StrokeEnd {
+        id: String,
+    },
+    /// Anything drawn in one go.
+    Add {
+        item: NewItem,
+    },
+    /// Move an item to another layer.
+    SetZ {
+        id: String,
+        z: i64,
+    },
+    Undo,
+    Redo,
+    /// Remove every annotation.
+    ClearAll,
+    /// Remove the sender's annotations.
+    ClearMine,
+    /// How the sender is shown to the others.
+    SetPresence {
+        name: String,
+        color: String,
+    },
+}
+
+/// Who's connected, so viewers know who's drawing and pointing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Presence {
+    pub client_id: String,
+    pub name: String,
+    /// CSS hex color, also used for the laser pointer.
+    pub color: String,
+}
+
+/// Messages sent by the server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMessage {
+    /// Sent when the channel opens, with everything drawn so far.
+    Snapshot {
+        client_id: String,
+        items: Vec<AnnotationItem>,
+        view: ZoomRegion,
+    },
+    /// New or restored items, sent to everyone including the author.
+    Added {
+        items: Vec<AnnotationItem>,
+    },
+    /// Progress on a stroke, sent to everyone but the author.
+    Update {
+        author: String,
+        update: ClientMessage,
+    },
+    Removed {
+        ids: Vec<String>,
+    },
+    /// The part of the source the video shows, for mapping pointer positions
+    /// back into source space.
+    View {
+        region: ZoomRegion,
+    },
+    /// Everyone connected, sent whenever someone joins, leaves or changes.
+    Presence {
+        peers: Vec<Presence>,
+    },
+    Error {
+        message: String,
+    },
+}
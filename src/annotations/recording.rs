@@ -0,0 +1,477 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::feed::frame::VideoTimestamp;
+
+use super::{
+    model::{AnnotationDocument, ItemKind, NewItem},
+    protocol::{ClientMessage, Envelope, PROTOCOL_VERSION},
+};
+
+/// One line of an annotation recording. Timestamps are the source's
+/// `VideoTimestamp` (micros) of the latest frame when the entry was made.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum RecordEntry {
+    /// Always the first line, so replays can line the recording up with
+    /// video that doesn't share the source's clock.
+    Start { timestamp: u64 },
+    /// A change made by a peer, exactly as it was applied.
+    Operation {
+        timestamp: u64,
+        author: String,
+        message: ClientMessage,
+    },
+    /// The server cleared everything, e.g. when leaving freeze-frame.
+    Clear { timestamp: u64 },
+}
+
+impl RecordEntry {
+    pub fn timestamp(&self) -> u64 {
+        match self {
+            RecordEntry::Start { timestamp }
+            | RecordEntry::Operation { timestamp, .. }
+            | RecordEntry::Clear { timestamp } => *timestamp,
+        }
+    }
+}
+
+/// Appends entries to a JSON lines file. Writing happens on its own thread,
+/// so the hub never waits on the disk.
+pub struct AnnotationRecorder {
+    tx: mpsc::Sender<RecordEntry>,
+    started: bool,
+}
+
+impl AnnotationRecorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("unable to create {}", path.display()))?;
+        let (tx, rx) = mpsc::channel::<RecordEntry>();
+        let path = path.display().to_string();
+        thread::spawn(move || {
+            let mut writer = BufWriter::new(file);
+            for entry in rx {
+                let Ok(line) = serde_json::to_string(&Envelope::new(entry)) else {
+                    continue;
+                };
+                // Flush every line, so a crash loses as little as possible
+                let result = writeln!(writer, "{line}").and_then(|()| writer.flush());
+                if let Err(e) = result {
+                    eprintln!("Annotation recording to {path} failed: {e}");
+                    break;
+                }
+            }
+        });
+        Ok(Self { tx, started: false })
+    }
+
+    /// Mark where the recording starts. Only the first call counts.
+    pub fn start(&mut self, timestamp: VideoTimestamp) {
+        if !self.started {
+            self.started = true;
+            self.record(RecordEntry::Start {
+                timestamp: timestamp.to_micros(),
+            });
+        }
+    }
+
+    pub fn record(&self, entry: RecordEntry) {
+        self.tx.send(entry).ok();
+    }
+}
+
+/// The recent past of the recording, kept in memory so it can be cut out
+/// alongside instant replays.
+pub struct AnnotationTimeline {
+    /// Changes within `length` of the latest.
+    entries: VecDeque<RecordEntry>,
+    length: Duration,
+    /// What the entries that have aged out add up to, i.e. what was drawn
+    /// at the horizon.
+    base: AnnotationDocument,
+    /// Timestamp of the last entry folded into `base`.
+    base_timestamp: Option<u64>,
+}
+
+impl AnnotationTimeline {
+    pub fn new(length: Duration) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            length,
+            base: Default::default(),
+            base_timestamp: None,
+        }
+    }
+
+    /// Add an entry. Entries older than `length` before it are folded into
+    /// a snapshot, so the annotations can still be rebuilt at any point
+    /// within `length` of it.
+    pub fn record(&mut self, entry: RecordEntry) {
+        let horizon = entry
+            .timestamp()
+            .saturating_sub(self.length.as_micros() as u64);
+        // The source went back in time, so nothing before lines up anymore
+        if self
+            .entries
+            .back()
+            .is_some_and(|last| last.timestamp() > entry.timestamp())
+        {
+            self.entries.clear();
+            self.base = Default::default();
+            self.base_timestamp = None;
+        }
+        self.entries.push_back(entry);
+
+        while self
+            .entries
+            .front()
+            .is_some_and(|entry| entry.timestamp() <= horizon)
+        {
+            let Some(entry) = self.entries.pop_front() else {
+                break;
+            };
+            self.base_timestamp = Some(entry.timestamp());
+            apply(&mut self.base, entry);
+        }
+    }
+
+    /// A recording of everything drawn up to `until`, starting at `start`.
+    /// What was drawn before `start` is kept, with the replay applying it
+    /// all on its first frame, so it begins with whatever was up then.
+    pub fn recording(&self, start: u64, until: u64) -> Vec<RecordEntry> {
+        let mut recording = vec![RecordEntry::Start { timestamp: start }];
+        if let Some(timestamp) = self.base_timestamp {
+            // The snapshot, added back by whoever drew it
+            let items = self.base.items();
+            for item in items {
+                recording.push(RecordEntry::Operation {
+                    timestamp,
+                    author: item.author.clone(),
+                    message: ClientMessage::Add {
+                        item: NewItem {
+                            id: item.id.clone(),
+                            color: item.color.clone(),
+                            width: item.width,
+                            kind: item.kind.clone(),
+                        },
+                    },
+                });
+            }
+            // Adding numbers them from 0, so put back any that were moved
+            for (i, item) in items.iter().enumerate() {
+                if item.z != i as i64 {
+                    recording.push(RecordEntry::Operation {
+                        timestamp,
+                        author: item.author.clone(),
+                        message: ClientMessage::SetZ {
+                            id: item.id.clone(),
+                            z: item.z,
+                        },
+                    });
+                }
+            }
+        }
+        recording.extend(
+            self.entries
+                .iter()
+                .take_while(|entry| entry.timestamp() <= until)
+                .cloned(),
+        );
+        recording
+    }
+}
+
+/// Apply a recorded entry to `document`, the same as the hub did when it
+/// was made. Only changes that were applied are recorded, so none of them
+/// fail.
+fn apply(document: &mut AnnotationDocument, entry: RecordEntry) {
+    let (author, message) = match entry {
+        RecordEntry::Start { .. } => return,
+        RecordEntry::Operation {
+            author, message, ..
+        } => (author, message),
+        RecordEntry::Clear { .. } => {
+            document.clear();
+            return;
+        }
+    };
+    match message {
+        ClientMessage::StrokeBegin {
+            id,
+            color,
+            width,
+            point,
+        } => {
+            let item = NewItem {
+                id,
+                color,
+                width,
+                kind: ItemKind::Stroke {
+                    points: vec![point],
+                    complete: false,
+                },
+            };
+            document.add(&author, item).ok();
+        }
+        ClientMessage::StrokePoints { id, points } => {
+            document.extend_stroke(&author, &id, &points).ok();
+        }
+        ClientMessage::StrokeEnd { id } => {
+            document.end_stroke(&author, &id).ok();
+        }
+        ClientMessage::Add { item } => {
+            document.add(&author, item).ok();
+        }
+        ClientMessage::SetZ { id, z } => {
+            document.set_z(&id, z).ok();
+        }
+        ClientMessage::Undo => {
+            document.undo(&author);
+        }
+        ClientMessage::Redo => {
+            document.redo(&author);
+        }
+        ClientMessage::ClearAll => {
+            document.clear_all(&author);
+        }
+        ClientMessage::ClearMine => {
+            document.clear_mine(&author);
+        }
+        ClientMessage::SetPresence { .. } => {}
+    }
+}
+
+/// Entries due since the last `AnnotationReplay::advance`.
+pub struct Replayed {
+    /// The source went back in time (looped, or was seeked), so the
+    /// annotations must be cleared before applying `entries`.
+    pub rewound: bool,
+    pub entries: Vec<RecordEntry>,
+}
+
+/// Plays a recording back against the source's timestamps.
+pub struct AnnotationReplay {
+    entries: Vec<RecordEntry>,
+    /// Timestamp of the `Start` entry.
+    start: u64,
+    /// Index of the next entry due.
+    next: usize,
+    /// Source timestamp minus recording timestamp, taken from the first
+    /// frame replayed against.
+    offset: Option<i64>,
+    /// Recording timestamp last advanced to.
+    position: i64,
+}
+
+impl AnnotationReplay {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("unable to open {}", path.display()))?;
+
+        let mut entries = Vec::new();
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.context("unable to read recording")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let envelope = serde_json::from_str::<Envelope<RecordEntry>>(&line)
+                .with_context(|| format!("malformed entry on line {}", i + 1))?;
+            if envelope.v != PROTOCOL_VERSION {
+                bail!(
+                    "recording has protocol version {}, expected {}",
+                    envelope.v,
+                    PROTOCOL_VERSION
+                );
+            }
+            entries.push(envelope.message);
+        }
+
+        let Some(&RecordEntry::Start { timestamp: start }) = entries.first() else {
+            bail!("recording doesn't begin with a start entry");
+        };
+        Ok(Self {
+            entries,
+            start,
+            next: 0,
+            offset: None,
+            position: i64::MIN,
+        })
+    }
+
+    /// Move the replay along to the source frame at `timestamp`. The first
+    /// frame lines up with the start of the recording.
+    pub fn advance(&mut self, timestamp: VideoTimestamp) -> Replayed {
+        let timestamp = timestamp.to_micros() as i64;
+        let offset = *self.offset.get_or_insert(timestamp - self.start as i64);
+        let position = timestamp - offset;
+
+        let rewound = position < self.position;
+        if rewound {
+            self.next = 0;
+        }
+        self.position = position;
+
+        let due = self.entries[self.next..]
+            .iter()
+            .take_while(|entry| entry.timestamp() as i64 <= position)
+            .cloned()
+            .collect::<Vec<_>>();
+        self.next += due.len();
+        Replayed {
+            rewound,
+            entries: due,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotations::model::Point;
+
+    const SECOND: u64 = 1_000_000;
+
+    fn operation(timestamp: u64, author: &str, message: ClientMessage) -> RecordEntry {
+        RecordEntry::Operation {
+            timestamp,
+            author: author.to_owned(),
+            message,
+        }
+    }
+
+    fn add(timestamp: u64, author: &str, id: &str) -> RecordEntry {
+        let item = NewItem {
+            id: id.to_owned(),
+            color: "#ff0000".to_owned(),
+            width: 0.01,
+            kind: ItemKind::Line {
+                from: Point { x: 0.1, y: 0.1 },
+                to: Point { x: 0.9, y: 0.9 },
+            },
+        };
+        operation(timestamp, author, ClientMessage::Add { item })
+    }
+
+    /// The annotations a recording ends with.
+    fn replay(recording: Vec<RecordEntry>) -> Vec<(String, String, i64)> {
+        let mut document = AnnotationDocument::default();
+        for entry in recording {
+            apply(&mut document, entry);
+        }
+        document
+            .items()
+            .iter()
+            .map(|item| (item.id.clone(), item.author.clone(), item.z))
+            .collect()
+    }
+
+    #[test]
+    fn timeline_stays_bounded_without_clears() {
+        let mut timeline = AnnotationTimeline::new(Duration::from_secs(10));
+        for i in 0..1000 {
+            timeline.record(add(i * SECOND, "alice", &format!("line-{i}")));
+            if i % 2 == 1 {
+                timeline.record(operation(i * SECOND, "alice", ClientMessage::Undo));
+            }
+        }
+        assert!(timeline.entries.len() <= 16);
+        assert_eq!(timeline.base.items().len(), 495);
+
+        // Everything from before the horizon is still there
+        let recording = timeline.recording(995 * SECOND, 999 * SECOND);
+        assert!(
+            matches!(recording[0], RecordEntry::Start { timestamp } if timestamp == 995 * SECOND)
+        );
+        assert_eq!(replay(recording).len(), 500);
+    }
+
+    #[test]
+    fn recording_rebuilds_the_snapshot() {
+        let mut timeline = AnnotationTimeline::new(Duration::from_secs(10));
+        timeline.record(add(0, "alice", "a"));
+        timeline.record(add(SECOND, "bob", "b"));
+        timeline.record(operation(
+            2 * SECOND,
+            "alice",
+            ClientMessage::SetZ {
+                id: "a".to_owned(),
+                z: 5,
+            },
+        ));
+        timeline.record(operation(
+            3 * SECOND,
+            "bob",
+            ClientMessage::StrokeBegin {
+                id: "c".to_owned(),
+                color: "#00ff00".to_owned(),
+                width: 0.01,
+                point: Point { x: 0.5, y: 0.5 },
+            },
+        ));
+        let before = replay(timeline.recording(0, 3 * SECOND));
+
+        // Fold everything into the snapshot
+        timeline.record(add(20 * SECOND, "carol", "d"));
+        assert_eq!(timeline.entries.len(), 1);
+        assert_eq!(replay(timeline.recording(15 * SECOND, 15 * SECOND)), before);
+
+        // The stroke is still open, so can carry on
+        timeline.record(operation(
+            21 * SECOND,
+            "bob",
+            ClientMessage::StrokePoints {
+                id: "c".to_owned(),
+                points: vec![Point { x: 0.6, y: 0.6 }],
+            },
+        ));
+        let mut document = AnnotationDocument::default();
+        for entry in timeline.recording(15 * SECOND, 21 * SECOND) {
+            apply(&mut document, entry);
+        }
+        let ids = document
+            .items()
+            .iter()
+            .map(|item| item.id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["b", "a", "c", "d"]);
+        assert!(matches!(
+            &document.get("c").unwrap().kind,
+            ItemKind::Stroke { points, complete: false } if points.len() == 2
+        ));
+    }
+
+    #[test]
+    fn clears_empty_the_snapshot() {
+        let mut timeline = AnnotationTimeline::new(Duration::from_secs(10));
+        timeline.record(add(0, "alice", "a"));
+        timeline.record(RecordEntry::Clear { timestamp: SECOND });
+        timeline.record(add(20 * SECOND, "alice", "b"));
+        assert!(timeline.base.items().is_empty());
+        let ids = replay(timeline.recording(15 * SECOND, 20 * SECOND))
+            .into_iter()
+            .map(|(id, ..)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["b"]);
+    }
+
+    #[test]
+    fn going_back_in_time_starts_over() {
+        let mut timeline = AnnotationTimeline::new(Duration::from_secs(10));
+        timeline.record(add(0, "alice", "a"));
+        timeline.record(add(20 * SECOND, "alice", "b"));
+        timeline.record(add(SECOND, "alice", "c"));
+        assert!(timeline.base_timestamp.is_none());
+        assert_eq!(replay(timeline.recording(0, 5 * SECOND)).len(), 1);
+    }
+}
@@ -0,0 +1,414 @@
+pub mod mpegts;
+
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use futures::SinkExt;
+use srt_tokio::SrtSocket;
+use tokio::{
+    net::UdpSocket,
+    sync::{broadcast, mpsc, watch},
+};
+use webrtc::{
+    rtp::{header::Header, packet::Packet},
+    util::Marshal,
+};
+
+use crate::{
+    feed::manager::{FeedControlMessage, FeedResultMessage},
+    remote::PLAYOUT_MAX_DELAY,
+};
+
+use self::mpegts::{to_90khz, TsMuxer, TS_PACKET_SIZE};
+
+/// Client id the contribution output joins the feed as.
+const CONTRIBUTION_CLIENT_ID: &str = "contribution";
+/// TS packets per datagram, the most that fit in an Ethernet MTU.
+const PACKETS_PER_DATAGRAM: usize = 7;
+/// Datagrams queued for the network before frames are dropped.
+const DATAGRAM_QUEUE: usize = 1024;
+/// Static payload type of MPEG-TS over RTP. (RFC 3551)
+const MP2T_PAYLOAD_TYPE: u8 = 33;
+const SRT_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub enum ContributionTransport {
+    /// Connect to an SRT listener, e.g. `203.0.113.10:9000`.
+    SrtCaller {
+        address: String,
+        stream_id: Option<String>,
+    },
+    /// Wait for an SRT caller on this port. One caller at a time.
+    SrtListener { port: u16 },
+    /// Plain MPEG-TS over UDP, 7 packets per datagram.
+    Udp { address: SocketAddr },
+    /// MPEG-TS over RTP. (RFC 2250)
+    Rtp { address: SocketAddr },
+}
+
+impl FromStr for ContributionTransport {
+    type Err = anyhow::Error;
+
+    /// One of `srt://host:port[?streamid=id]` (caller), `srt://:port`
+    /// (listener), `udp://host:port` or `rtp://host:port`.
+    fn from_str(s: &str) -> Result<Self> {
+        let Some((scheme, rest)) = s.split_once("://") else {
+            bail!("expected <scheme>://<address>, got {s:?}");
+        };
+        let transport = match scheme {
+            "srt" => {
+                let (address, query) = match rest.split_once('?') {
+                    Some((address, query)) => (address, Some(query)),
+                    None => (rest, None),
+                };
+                if let Some(port) = address.strip_prefix(':') {
+                    if query.is_some() {
+                        bail!("SRT listeners don't take a stream id");
+                    }
+                    let port = port
+                        .parse()
+                        .with_context(|| format!("invalid port in {s:?}"))?;
+                    Self::SrtListener { port }
+                } else {
+                    if !address.contains(':') {
+                        bail!("expected srt://<host>:<port>, got {s:?}");
+                    }
+                    let stream_id = match query.map(|query| query.split_once('=')) {
+                        None => None,
+                        Some(Some(("streamid", stream_id))) => Some(stream_id.to_owned()),
+                        Some(_) => bail!("expected ?streamid=<id>, got {s:?}"),
+                    };
+                    Self::SrtCaller {
+                        address: address.to_owned(),
+                        stream_id,
+                    }
+                }
+            }
+            "udp" => Self::Udp {
+                address: rest
+                    .parse()
+                    .with_context(|| format!("invalid address in {s:?}"))?,
+            },
+            "rtp" => Self::Rtp {
+                address: rest
+                    .parse()
+                    .with_context(|| format!("invalid address in {s:?}"))?,
+            },
+            _ => bail!("unknown transport {scheme:?}"),
+        };
+        Ok(transport)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ContributionConfig {
+    pub transport: ContributionTransport,
+    /// SRT receive buffer, i.e. how long lost packets have to be resent.
+    /// Both ends use the larger of theirs. Defaults to the WebRTC playout
+    /// delay bound, so remote sites run as far behind as browsers do.
+    /// Unused by UDP and RTP.
+    pub latency: Duration,
+}
+
+impl ContributionConfig {
+    pub fn new(transport: ContributionTransport) -> Self {
+        Self {
+            transport,
+            latency: PLAYOUT_MAX_DELAY,
+        }
+    }
+}
+
+struct TsDatagram {
+    data: Bytes,
+    /// 90kHz time of the frame the data is from.
+    time: u64,
+}
+
+/// Send the encoded feed as MPEG-TS until it ends. Does nothing without a
+/// config.
+pub async fn main(
+    config: Option<ContributionConfig>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+) -> Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    let mut feed_result_rx = feed_result_tx.subscribe();
+
+    // Pinned, so the remote site gets the primary group's full bitrate
+    feed_control_tx
+        .send(FeedControlMessage::ClientJoined {
+            client_id: CONTRIBUTION_CLIENT_ID.to_owned(),
+            group_tx: None,
+        })
+        .await
+        .context("feed is gone")?;
+
+    let (datagram_tx, datagram_rx) = mpsc::channel::<TsDatagram>(DATAGRAM_QUEUE);
+    // Whether anyone is receiving. Frames are only muxed while connected.
+    let (connected_tx, connected_rx) = watch::channel(false);
+    let sender = tokio::spawn(send_datagrams(
+        config,
+        datagram_rx,
+        connected_tx,
+        feed_control_tx.clone(),
+    ));
+
+    let mut muxer = TsMuxer::new();
+    let mut waiting_for_keyframe = true;
+    loop {
+        let frame = match feed_result_rx.recv().await {
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                waiting_for_keyframe = true;
+                request_keyframe(&feed_control_tx);
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+
+            Ok(FeedResultMessage::EncodedBitstream(frame)) => frame,
+            Ok(_) => continue,
+        };
+        if sender.is_finished() {
+            break;
+        }
+        if !*connected_rx.borrow() {
+            // The sender asks for a keyframe once someone connects
+            waiting_for_keyframe = true;
+            continue;
+        }
+        if frame.keyframe {
+            waiting_for_keyframe = false;
+        }
+        if waiting_for_keyframe {
+            continue;
+        }
+
+        let ts = muxer.mux(&frame);
+        let time = to_90khz(frame.timestamp);
+        let datagrams = ts
+            .chunks(TS_PACKET_SIZE * PACKETS_PER_DATAGRAM)
+            .map(|chunk| TsDatagram {
+                data: Bytes::copy_from_slice(chunk),
+                time,
+            })
+            .collect::<Vec<_>>();
+
+        // Only queue whole frames. If the link can't keep up, start over
+        // from a keyframe rather than sending a broken GOP.
+        if datagram_tx.capacity() < datagrams.len() {
+            eprintln!("Contribution output is falling behind, dropping frames");
+            waiting_for_keyframe = true;
+            request_keyframe(&feed_control_tx);
+            continue;
+        }
+        for datagram in datagrams {
+            datagram_tx.try_send(datagram).ok();
+        }
+    }
+    drop(datagram_tx);
+
+    // A broken output shouldn't take the feed down with it
+    if let Err(e) = sender.await? {
+        eprintln!("Contribution output stopped: {e:#}");
+    }
+    feed_control_tx
+        .send(FeedControlMessage::ClientLeft {
+            client_id: CONTRIBUTION_CLIENT_ID.to_owned(),
+        })
+        .await
+        .ok();
+    Ok(())
+}
+
+fn request_keyframe(feed_control_tx: &mpsc::Sender<FeedControlMessage>) {
+    feed_control_tx
+        .try_send(FeedControlMessage::RequestKeyframe {
+            client_id: CONTRIBUTION_CLIENT_ID.to_owned(),
+        })
+        .ok();
+}
+
+async fn send_datagrams(
+    config: ContributionConfig,
+    mut datagram_rx: mpsc::Receiver<TsDatagram>,
+    connected_tx: watch::Sender<bool>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+) -> Result<()> {
+    match config.transport {
+        ContributionTransport::Udp { address } | ContributionTransport::Rtp { address } => {
+            let rtp = matches!(config.transport, ContributionTransport::Rtp { .. });
+            let bind = match address {
+                SocketAddr::V4(_) => "0.0.0.0:0",
+                SocketAddr::V6(_) => "[::]:0",
+            };
+            let socket = UdpSocket::bind(bind).await?;
+            socket
+                .connect(address)
+                .await
+                .with_context(|| format!("Unable to send to {address}"))?;
+            let scheme = if rtp { "rtp" } else { "udp" };
+            println!("Sending MPEG-TS to {scheme}://{address}");
+
+            let mut rtp_sender = RtpSender::new();
+            connected_tx.send(true).ok();
+            request_keyframe(&feed_control_tx);
+            while let Some(datagram) = datagram_rx.recv().await {
+                let data = match rtp {
+                    true => rtp_sender.packetize(&datagram)?,
+                    false => datagram.data,
+                };
+                // Nobody listening (ICMP unreachable) isn't our problem
+                socket.send(&data).await.ok();
+            }
+        }
+
+        ContributionTransport::SrtCaller { .. } | ContributionTransport::SrtListener { .. } => {
+            loop {
+                let mut srt = match connect_srt(&config).await {
+                    Ok(srt) => srt,
+                    Err(e) => {
+                        eprintln!("SRT connection failed: {e:#}");
+                        tokio::time::sleep(SRT_RECONNECT_INTERVAL).await;
+                        continue;
+                    }
+                };
+
+                // Whatever queued up while disconnected is stale
+                while datagram_rx.try_recv().is_ok() {}
+                connected_tx.send(true).ok();
+                request_keyframe(&feed_control_tx);
+
+                let mut feed_ended = true;
+                while let Some(datagram) = datagram_rx.recv().await {
+                    if let Err(e) = srt.send((Instant::now(), datagram.data)).await {
+                        eprintln!("SRT connection lost: {e}");
+                        feed_ended = false;
+                        break;
+                    }
+                }
+                connected_tx.send(false).ok();
+                srt.close().await.ok();
+                if feed_ended {
+                    break;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn connect_srt(config: &ContributionConfig) -> Result<SrtSocket> {
+    let builder = SrtSocket::builder().latency(config.latency);
+    let latency = config.latency.as_millis();
+    match &config.transport {
+        ContributionTransport::SrtCaller { address, stream_id } => {
+            println!("Calling srt://{address} (latency {latency} ms)");
+            let srt = builder
+                .call(address.as_str(), stream_id.as_deref())
+                .await
+                .with_context(|| format!("Unable to call {address}"))?;
+            println!("Sending MPEG-TS to srt://{address}");
+            Ok(srt)
+        }
+        ContributionTransport::SrtListener { port } => {
+            println!("Waiting for an SRT caller on srt://0.0.0.0:{port} (latency {latency} ms)");
+            let srt = builder
+                .listen_on(format!(":{port}").as_str())
+                .await
+                .with_context(|| format!("Unable to listen on SRT port {port}"))?;
+            println!("SRT caller connected, sending MPEG-TS");
+            Ok(srt)
+        }
+        _ => unreachable!("not an SRT transport"),
+    }
+}
+
+/// Wraps datagrams in RTP, all with the same random SSRC.
+struct RtpSender {
+    ssrc: u32,
+    sequence_number: u16,
+}
+
+impl RtpSender {
+    fn new() -> Self {
+        let random = uuid::Uuid::new_v4().as_u128();
+        Self {
+            ssrc: random as u32,
+            sequence_number: (random >> 32) as u16,
+        }
+    }
+
+    fn packetize(&mut self, datagram: &TsDatagram) -> Result<Bytes> {
+        let packet = Packet {
+            header: Header {
+                version: 2,
+                payload_type: MP2T_PAYLOAD_TYPE,
+                sequence_number: self.sequence_number,
+                timestamp: datagram.time as u32,
+                ssrc: self.ssrc,
+                ..Default::default()
+            },
+            payload: datagram.data.clone(),
+        };
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+        Ok(packet.marshal()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transport_from_str() {
+        let Ok(ContributionTransport::SrtCaller { address, stream_id }) =
+            "srt://203.0.113.10:9000?streamid=venue".parse()
+        else {
+            panic!("expected an SRT caller");
+        };
+        assert_eq!(address, "203.0.113.10:9000");
+        assert_eq!(stream_id.as_deref(), Some("venue"));
+
+        assert!(matches!(
+            "srt://example.com:9000".parse(),
+            Ok(ContributionTransport::SrtCaller {
+                stream_id: None,
+                ..
+            })
+        ));
+        assert!(matches!(
+            "srt://:9000".parse(),
+            Ok(ContributionTransport::SrtListener { port: 9000 })
+        ));
+        assert!(matches!(
+            "udp://239.0.0.1:5000".parse(),
+            Ok(ContributionTransport::Udp { address }) if address.port() == 5000
+        ));
+        assert!(matches!(
+            "rtp://127.0.0.1:5004".parse(),
+            Ok(ContributionTransport::Rtp { .. })
+        ));
+    }
+
+    #[test]
+    fn transport_from_str_rejects_bad_urls() {
+        for s in [
+            "203.0.113.10:9000",
+            "srt://example.com",
+            "srt://:port",
+            "srt://:9000?streamid=venue",
+            "srt://example.com:9000?latency=200",
+            "udp://example.com",
+            "http://example.com:80",
+        ] {
+            assert!(s.parse::<ContributionTransport>().is_err(), "{s}");
+        }
+    }
+}
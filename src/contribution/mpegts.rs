@@ -0,0 +1,366 @@
+use crate::feed::{
+    encoders::{h264, EncodedFrame},
+    frame::VideoTimestamp,
+};
+
+pub const TS_PACKET_SIZE: usize = 188;
+const TS_PAYLOAD_SIZE: usize = TS_PACKET_SIZE - 4;
+const SYNC_BYTE: u8 = 0x47;
+
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const PROGRAM_NUMBER: u16 = 1;
+const TRANSPORT_STREAM_ID: u16 = 1;
+const STREAM_TYPE_H264: u8 = 0x1b;
+const VIDEO_STREAM_ID: u8 = 0xe0;
+
+/// PAT and PMT are repeated at least this often (90kHz), besides before
+/// every keyframe.
+const PSI_INTERVAL: u64 = 90_000 / 10;
+/// How long (90kHz) decoders get between a frame's PCR and presenting it.
+/// Just enough for a large frame to arrive, anything more is latency.
+const PTS_DELAY: u64 = 90 * 50;
+/// PTS and PCR bases are 33 bits.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// An access unit delimiter, which H.264 in MPEG-TS requires.
+const AUD: [u8; 6] = [0, 0, 0, 1, h264::NAL_TYPE_AUD, 0xf0];
+
+/// 90kHz time of `timestamp`, unwrapped.
+pub fn to_90khz(timestamp: VideoTimestamp) -> u64 {
+    timestamp.to_micros() * 9 / 100
+}
+
+/// Muxes the encoded feed into a single program MPEG-TS, with the PCR on the
+/// video PID. Video only, since our Opus audio isn't something broadcast
+/// decoders take.
+pub struct TsMuxer {
+    pat_continuity: u8,
+    pmt_continuity: u8,
+    video_continuity: u8,
+    /// 90kHz time the tables were last sent at.
+    last_psi: Option<u64>,
+}
+
+impl TsMuxer {
+    pub fn new() -> Self {
+        Self {
+            pat_continuity: 0,
+            pmt_continuity: 0,
+            video_continuity: 0,
+            last_psi: None,
+        }
+    }
+
+    /// Mux one frame into whole TS packets.
+    pub fn mux(&mut self, frame: &EncodedFrame) -> Vec<u8> {
+        let time = to_90khz(frame.timestamp);
+        let mut out = Vec::with_capacity(frame.data.len() + frame.data.len() / 8 + 512);
+
+        // Timestamps going backwards (e.g. a source restart) resend them too
+        let psi_due = frame.keyframe
+            || self
+                .last_psi
+                .map_or(true, |last| time < last || time - last >= PSI_INTERVAL);
+        if psi_due {
+            self.last_psi = Some(time);
+            self.write_psi(&mut out);
+        }
+
+        let has_aud = h264::nal_units(&frame.data)
+            .find(|nal| !nal.is_empty())
+            .and_then(h264::nal_type)
+            == Some(h264::NAL_TYPE_AUD);
+
+        let mut pes = Vec::with_capacity(14 + AUD.len() + frame.data.len());
+        pes.extend_from_slice(&[0, 0, 1, VIDEO_STREAM_ID]);
+        // Unbounded, as allowed for video
+        pes.extend_from_slice(&0u16.to_be_bytes());
+        // Marker bits and data alignment, then PTS only
+        pes.extend_from_slice(&[0x84, 0x80, 5]);
+        write_timestamp(&mut pes, 0x2, (time + PTS_DELAY) & TIMESTAMP_MASK);
+        if !has_aud {
+            pes.extend_from_slice(&AUD);
+        }
+        pes.extend_from_slice(&frame.data);
+
+        self.write_packets(
+            &mut out,
+            VIDEO_PID,
+            &pes,
+            Some(time & TIMESTAMP_MASK),
+            frame.keyframe,
+        );
+        out
+    }
+
+    fn write_psi(&mut self, out: &mut Vec<u8>) {
+        let mut pat = Vec::new();
+        pat.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        pat.extend_from_slice(&(0xe000 | PMT_PID).to_be_bytes());
+        let pat = psi_section(0x00, TRANSPORT_STREAM_ID, &pat);
+        let continuity = next_continuity(&mut self.pat_continuity);
+        write_section_packet(out, PAT_PID, continuity, &pat);
+
+        let mut pmt = Vec::new();
+        pmt.extend_from_slice(&(0xe000 | VIDEO_PID).to_be_bytes()); // PCR PID
+        pmt.extend_from_slice(&0xf000u16.to_be_bytes()); // no program info
+        pmt.push(STREAM_TYPE_H264);
+        pmt.extend_from_slice(&(0xe000 | VIDEO_PID).to_be_bytes());
+        pmt.extend_from_slice(&0xf000u16.to_be_bytes()); // no ES info
+        let pmt = psi_section(0x02, PROGRAM_NUMBER, &pmt);
+        let continuity = next_continuity(&mut self.pmt_continuity);
+        write_section_packet(out, PMT_PID, continuity, &pmt);
+    }
+
+    /// Split a PES packet into TS packets. The first one carries `pcr` and
+    /// the random access flag, and the last one is stuffed to fill it.
+    fn write_packets(
+        &mut self,
+        out: &mut Vec<u8>,
+        pid: u16,
+        pes: &[u8],
+        pcr: Option<u64>,
+        random_access: bool,
+    ) {
+        let mut remaining = pes;
+        let mut first = true;
+        while first || !remaining.is_empty() {
+            // Adaptation field contents after its length byte
+            let mut fields = Vec::new();
+            if first && (random_access || pcr.is_some()) {
+                let mut flags = 0;
+                if random_access {
+                    flags |= 0x40;
+                }
+                if pcr.is_some() {
+                    flags |= 0x10;
+                }
+                fields.push(flags);
+                if let Some(pcr) = pcr {
+                    write_pcr(&mut fields, pcr);
+                }
+            }
+            let min_adaptation = match fields.is_empty() {
+                true => 0,
+                false => 1 + fields.len(),
+            };
+            let payload_len = remaining.len().min(TS_PAYLOAD_SIZE - min_adaptation);
+            // Including the length byte. Whatever the payload doesn't fill
+            // is stuffing.
+            let adaptation_len = TS_PAYLOAD_SIZE - payload_len;
+
+            let continuity = next_continuity(&mut self.video_continuity);
+            let adaptation_control = match adaptation_len {
+                0 => 0x10,
+                _ => 0x30,
+            };
+            out.push(SYNC_BYTE);
+            out.push(((first as u8) << 6) | ((pid >> 8) as u8 & 0x1f));
+            out.push(pid as u8);
+            out.push(adaptation_control | continuity);
+            if adaptation_len > 0 {
+                out.push((adaptation_len - 1) as u8);
+                if adaptation_len > 1 {
+                    if fields.is_empty() {
+                        fields.push(0);
+                    }
+                    out.extend_from_slice(&fields);
+                    out.resize(out.len() + adaptation_len - 1 - fields.len(), 0xff);
+                }
+            }
+            out.extend_from_slice(&remaining[..payload_len]);
+
+            remaining = &remaining[payload_len..];
+            first = false;
+        }
+    }
+}
+
+fn next_continuity(counter: &mut u8) -> u8 {
+    let continuity = *counter;
+    *counter = (*counter + 1) & 0x0f;
+    continuity
+}
+
+/// A long-form PSI section, with its CRC.
+fn psi_section(table_id: u8, id: u16, body: &[u8]) -> Vec<u8> {
+    // Everything after the length field, CRC included
+    let length = 5 + body.len() + 4;
+    let mut section = vec![table_id, 0xb0 | (length >> 8) as u8, length as u8];
+    section.extend_from_slice(&id.to_be_bytes());
+    // Version 0, current, a single section
+    section.extend_from_slice(&[0xc1, 0, 0]);
+    section.extend_from_slice(body);
+    let crc = crc32_mpeg2(&section);
+    section.extend_from_slice(&crc.to_be_bytes());
+    section
+}
+
+fn write_section_packet(out: &mut Vec<u8>, pid: u16, continuity: u8, section: &[u8]) {
+    let start = out.len();
+    out.push(SYNC_BYTE);
+    out.push(0x40 | (pid >> 8) as u8 & 0x1f);
+    out.push(pid as u8);
+    out.push(0x10 | continuity);
+    out.push(0); // pointer field
+    out.extend_from_slice(section);
+    out.resize(start + TS_PACKET_SIZE, 0xff);
+}
+
+/// A PTS (`prefix` 0x2) or DTS, with its marker bits.
+fn write_timestamp(out: &mut Vec<u8>, prefix: u8, timestamp: u64) {
+    out.push((prefix << 4) | ((timestamp >> 29) as u8 & 0x0e) | 1);
+    out.push((timestamp >> 22) as u8);
+    out.push(((timestamp >> 14) as u8 & 0xfe) | 1);
+    out.push((timestamp >> 7) as u8);
+    out.push(((timestamp << 1) as u8 & 0xfe) | 1);
+}
+
+/// A PCR with no extension, i.e. in 90kHz units.
+fn write_pcr(out: &mut Vec<u8>, base: u64) {
+    out.push((base >> 25) as u8);
+    out.push((base >> 17) as u8);
+    out.push((base >> 9) as u8);
+    out.push((base >> 1) as u8);
+    out.push(((base as u8 & 1) << 7) | 0x7e);
+    out.push(0);
+}
+
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x04c1_1db7,
+            };
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    /// Undo `write_timestamp`.
+    fn read_timestamp(b: &[u8]) -> u64 {
+        ((b[0] as u64 >> 1) & 0x07) << 30
+            | (b[1] as u64) << 22
+            | (b[2] as u64 >> 1) << 15
+            | (b[3] as u64) << 7
+            | b[4] as u64 >> 1
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_e6e7);
+        assert_eq!(crc32_mpeg2(&[]), 0xffff_ffff);
+    }
+
+    #[test]
+    fn pat_section() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&PROGRAM_NUMBER.to_be_bytes());
+        body.extend_from_slice(&(0xe000 | PMT_PID).to_be_bytes());
+        assert_eq!(
+            psi_section(0x00, TRANSPORT_STREAM_ID, &body),
+            [
+                0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00, 0x2a, 0xb1,
+                0x04, 0xb2
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamps() {
+        let mut out = Vec::new();
+        write_timestamp(&mut out, 0x2, 0);
+        assert_eq!(out, [0x21, 0x00, 0x01, 0x00, 0x01]);
+        for pts in [1, 90_000, TIMESTAMP_MASK] {
+            out.clear();
+            write_timestamp(&mut out, 0x2, pts);
+            assert_eq!(read_timestamp(&out), pts);
+        }
+
+        out.clear();
+        write_pcr(&mut out, 1);
+        assert_eq!(out, [0x00, 0x00, 0x00, 0x00, 0xfe, 0x00]);
+        out.clear();
+        write_pcr(&mut out, TIMESTAMP_MASK);
+        assert_eq!(out, [0xff, 0xff, 0xff, 0xff, 0xfe, 0x00]);
+    }
+
+    #[test]
+    fn mux_keyframe() {
+        let mut data = vec![0, 0, 0, 1, 0x65];
+        data.resize(1000, 0xab);
+        let frame = EncodedFrame::new(
+            Bytes::from(data.clone()),
+            VideoTimestamp::from_micros(1_000_000),
+        );
+        assert!(frame.keyframe);
+
+        let mut muxer = TsMuxer::new();
+        let out = muxer.mux(&frame);
+        assert_eq!(out.len() % TS_PACKET_SIZE, 0);
+        let packets = out.chunks(TS_PACKET_SIZE).collect::<Vec<_>>();
+        assert!(packets.iter().all(|packet| packet[0] == SYNC_BYTE));
+        let pid = |packet: &[u8]| (packet[1] as u16 & 0x1f) << 8 | packet[2] as u16;
+
+        // Tables first, since it's a keyframe
+        assert_eq!(pid(packets[0]), PAT_PID);
+        assert_eq!(
+            &packets[0][5..21],
+            &psi_section(0x00, TRANSPORT_STREAM_ID, &[0x00, 0x01, 0xf0, 0x00])[..]
+        );
+        assert_eq!(pid(packets[1]), PMT_PID);
+
+        // Then the PES, starting with a random access point and the PCR
+        let video = &packets[2..];
+        assert!(video.iter().all(|packet| pid(packet) == VIDEO_PID));
+        let first = video[0];
+        assert_eq!(first[1] & 0x40, 0x40);
+        assert_eq!(first[3] & 0x30, 0x30);
+        assert_eq!(first[5], 0x50);
+        let pcr = (first[6] as u64) << 25
+            | (first[7] as u64) << 17
+            | (first[8] as u64) << 9
+            | (first[9] as u64) << 1
+            | (first[10] as u64) >> 7;
+        assert_eq!(pcr, 90_000);
+
+        let pes = &first[5 + first[4] as usize..];
+        assert_eq!(&pes[..4], &[0, 0, 1, VIDEO_STREAM_ID]);
+        assert_eq!(read_timestamp(&pes[9..14]), 90_000 + PTS_DELAY);
+        assert_eq!(&pes[14..20], &AUD);
+
+        // Continuity counts up per PID, and every byte of the frame is there
+        for (i, packet) in video.iter().enumerate() {
+            assert_eq!(packet[3] & 0x0f, i as u8 & 0x0f);
+        }
+        let mut payload = Vec::new();
+        for packet in video {
+            let start = match packet[3] & 0x20 {
+                0 => 4,
+                _ => 5 + packet[4] as usize,
+            };
+            payload.extend_from_slice(&packet[start..]);
+        }
+        assert_eq!(&payload[14 + AUD.len()..], &data[..]);
+
+        // Tables aren't repeated until they're due
+        let frame = EncodedFrame::new(
+            Bytes::from_static(&[0, 0, 0, 1, 0x41, 0x9a]),
+            VideoTimestamp::from_micros(1_040_000),
+        );
+        let out = muxer.mux(&frame);
+        assert_eq!(pid(&out), VIDEO_PID);
+        assert_eq!(out[3] & 0x0f, video.len() as u8 & 0x0f);
+    }
+}
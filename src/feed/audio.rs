@@ -0,0 +1,176 @@
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use super::frame::{AudioFrameBuffer, VideoTimestamp};
+
+/// WebRTC always runs Opus at 48kHz.
+pub const OPUS_SAMPLE_RATE: u32 = 48000;
+const OPUS_CHANNELS: usize = 2;
+/// 20ms frames, the usual for WebRTC.
+const OPUS_FRAME_SAMPLES: usize = 960;
+const OPUS_FRAME_DURATION: Duration = Duration::from_millis(20);
+const OPUS_BITRATE: i32 = 128_000;
+/// Largest packet we accept from the encoder.
+const MAX_PACKET_SIZE: usize = 4000;
+
+/// If source timestamps and our sample count disagree by more than this,
+/// the source jumped and we start over from its timestamp.
+const MAX_TIMESTAMP_DRIFT: Duration = Duration::from_millis(40);
+
+#[derive(Debug, Clone)]
+pub struct EncodedAudioPacket {
+    pub data: Bytes,
+    /// Source timestamp of the first sample in the packet.
+    pub timestamp: VideoTimestamp,
+    pub duration: Duration,
+}
+
+/// Resamples and encodes source audio to 48kHz stereo Opus.
+pub struct AudioEncoder {
+    encoder: opus::Encoder,
+    resampler: Option<LinearResampler>,
+
+    /// Interleaved stereo samples waiting for a full Opus frame.
+    pending: Vec<f32>,
+    /// Source timestamp of the first pending sample, in micros.
+    pending_timestamp: Option<u64>,
+}
+
+impl AudioEncoder {
+    pub fn new() -> Result<Self> {
+        let mut encoder = opus::Encoder::new(
+            OPUS_SAMPLE_RATE,
+            opus::Channels::Stereo,
+            opus::Application::Audio,
+        )
+        .context("Unable to create Opus encoder")?;
+        encoder.set_bitrate(opus::Bitrate::Bits(OPUS_BITRATE))?;
+
+        Ok(Self {
+            encoder,
+            resampler: None,
+            pending: Vec::with_capacity(OPUS_FRAME_SAMPLES * OPUS_CHANNELS * 2),
+            pending_timestamp: None,
+        })
+    }
+
+    /// Encode as many complete Opus frames as the buffered audio allows.
+    pub fn encode(&mut self, frame: &AudioFrameBuffer) -> Result<Vec<EncodedAudioPacket>> {
+        if frame.channels == 0 || frame.sample_rate == 0 {
+            return Ok(vec![]);
+        }
+
+        // Restart on discontinuities, rather than drifting out of sync
+        let timestamp = frame.timestamp.to_micros();
+        let expected = self.pending_timestamp.map(|start| {
+            let pending = (self.pending.len() / OPUS_CHANNELS) as u64;
+            start + pending * 1_000_000 / OPUS_SAMPLE_RATE as u64
+        });
+        match expected {
+            Some(expected)
+                if expected.abs_diff(timestamp) <= MAX_TIMESTAMP_DRIFT.as_micros() as u64 => {}
+            _ => {
+                self.pending.clear();
+                self.pending_timestamp = Some(timestamp);
+            }
+        }
+
+        let stereo = to_stereo(frame);
+        let resampler = match &mut self.resampler {
+            Some(resampler) if resampler.in_rate == frame.sample_rate => resampler,
+            resampler => {
+                resampler.insert(LinearResampler::new(frame.sample_rate, OPUS_SAMPLE_RATE))
+            }
+        };
+        resampler.process(&stereo, &mut self.pending);
+
+        let mut packets = vec![];
+        let frame_len = OPUS_FRAME_SAMPLES * OPUS_CHANNELS;
+        while self.pending.len() >= frame_len {
+            let mut data = vec![0u8; MAX_PACKET_SIZE];
+            let len = self
+                .encoder
+                .encode_float(&self.pending[..frame_len], &mut data)
+                .context("Unable to encode Opus frame")?;
+            data.truncate(len);
+            self.pending.drain(..frame_len);
+
+            let start = self.pending_timestamp.unwrap_or(timestamp);
+            packets.push(EncodedAudioPacket {
+                data: Bytes::from(data),
+                timestamp: VideoTimestamp::from_micros(start),
+                duration: OPUS_FRAME_DURATION,
+            });
+            self.pending_timestamp = Some(start + OPUS_FRAME_DURATION.as_micros() as u64);
+        }
+
+        Ok(packets)
+    }
+}
+
+/// Interleaved stereo from any channel layout. Mono is duplicated, anything
+/// past the first two channels is dropped.
+fn to_stereo(frame: &AudioFrameBuffer) -> Vec<[f32; 2]> {
+    frame
+        .data
+        .chunks_exact(frame.channels)
+        .map(|sample| match sample {
+            [mono] => [*mono, *mono],
+            [left, right, ..] => [*left, *right],
+            [] => [0., 0.],
+        })
+        .collect()
+}
+
+/// Linear interpolation resampler. Not pretty, but sources are nearly always
+/// 48kHz already, in which case it passes samples through untouched.
+struct LinearResampler {
+    in_rate: u32,
+    step: f64,
+    /// Position of the next output sample, relative to the first sample of
+    /// the next input. -1 is the last sample of the previous input.
+    position: f64,
+    last: [f32; 2],
+}
+
+impl LinearResampler {
+    fn new(in_rate: u32, out_rate: u32) -> Self {
+        Self {
+            in_rate,
+            step: in_rate as f64 / out_rate as f64,
+            position: 0.,
+            last: [0., 0.],
+        }
+    }
+
+    /// Resample `input`, appending interleaved samples to `output`.
+    fn process(&mut self, input: &[[f32; 2]], output: &mut Vec<f32>) {
+        if input.is_empty() {
+            return;
+        }
+
+        let sample = |i: isize| -> [f32; 2] {
+            if i < 0 {
+                self.last
+            } else {
+                input[i as usize]
+            }
+        };
+
+        let end = (input.len() - 1) as f64;
+        let mut position = self.position;
+        while position <= end {
+            let i = position.floor() as isize;
+            let frac = (position - i as f64) as f32;
+            let (a, b) = (sample(i), sample((i + 1).min(end as isize)));
+            output.push(a[0] + (b[0] - a[0]) * frac);
+            output.push(a[1] + (b[1] - a[1]) * frac);
+            position += self.step;
+        }
+
+        self.position = position - input.len() as f64;
+        self.last = input[input.len() - 1];
+    }
+}
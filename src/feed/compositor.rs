@@ -0,0 +1,739 @@
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+
+use crate::annotations::{
+    hub::AnnotationHub,
+    model::{AnnotationItem, ItemKind, Point, StampKind},
+};
+
+use super::{
+    font,
+    frame::{VideoFrameBuffer, VideoFramePixelFormat},
+};
+
+/// Segments used to approximate an ellipse.
+const ELLIPSE_SEGMENTS: usize = 64;
+/// Arrow head length, relative to the line width.
+const ARROW_HEAD_SCALE: f32 = 4.;
+/// Angle between the arrow head and the shaft. (radians)
+const ARROW_HEAD_ANGLE: f32 = 0.5;
+/// Height of capitals relative to the font size, about the same as the
+/// sans-serif our page draws text in.
+const TEXT_CAP_HEIGHT: f32 = 0.7;
+/// Stroke width of text, relative to the font size.
+const TEXT_WEIGHT: f32 = 0.08;
+/// How long annotations take to fade out when leaving freeze-frame.
+const FADE_DURATION: Duration = Duration::from_millis(800);
+/// Used when an annotation's color can't be parsed.
+const FALLBACK_COLOR: [u8; 3] = [255, 0, 0];
+/// Laser pointer dot radius, as a fraction of the frame height.
+const LASER_RADIUS: f32 = 0.006;
+/// Radius of the glow around the dot, relative to the dot.
+const LASER_GLOW: f32 = 3.;
+
+/// Burns the annotations into frames before they're encoded, so viewers
+/// without our page (WHEP players, recordings, ...) still see them.
+///
+/// Annotations are rasterized into a YUV + alpha layer, which is only redrawn
+/// when the annotations or the resolution change. Blending happens in the
+/// frame's own pixel format, so there's no RGB round trip.
+pub struct Compositor {
+    annotations: AnnotationHub,
+    layer: Option<Layer>,
+    /// When the current fade out started.
+    fade_start: Option<Instant>,
+    /// Opacity the last frame was composited with.
+    opacity: f32,
+    /// Whether presenters' pointers are drawn as laser dots.
+    laser_pointer: bool,
+}
+
+impl Compositor {
+    pub fn new(annotations: AnnotationHub, laser_pointer: bool) -> Self {
+        Self {
+            annotations,
+            layer: None,
+            fade_start: None,
+            opacity: 1.,
+            laser_pointer,
+        }
+    }
+
+    /// Fade the annotations out over `FADE_DURATION`, then clear them.
+    pub fn fade_out(&mut self) {
+        self.fade_start = Some(Instant::now());
+    }
+
+    /// The layer the last frame was composited with, as straight alpha BGRA
+    /// at the frame's resolution. Transparent where nothing's drawn.
+    pub fn layer_bgra(&mut self) -> Option<Bytes> {
+        let opacity = self.opacity;
+        self.layer.as_mut().map(|layer| layer.bgra(opacity))
+    }
+
+    /// Clear the annotations right away, cancelling any fade.
+    pub fn clear(&mut self) {
+        self.fade_start = None;
+        self.annotations.clear();
+    }
+
+    /// Opacity of the annotations, clearing them once a fade completes.
+    fn opacity(&mut self) -> f32 {
+        let Some(fade_start) = self.fade_start else {
+            return 1.;
+        };
+        let progress = fade_start.elapsed().as_secs_f32() / FADE_DURATION.as_secs_f32();
+        if progress < 1. {
+            return 1. - progress;
+        }
+        self.fade_start = None;
+        self.annotations.clear();
+        1.
+    }
+
+    pub fn composite(&mut self, frame: VideoFrameBuffer) -> VideoFrameBuffer {
+        let opacity = self.opacity();
+        self.opacity = opacity;
+        let layer = match self.layer.take() {
+            Some(layer) if layer.width == frame.width && layer.height == frame.height => layer,
+            _ => Layer::new(frame.width, frame.height),
+        };
+        let layer = self.layer.insert(layer);
+        if layer.revision != Some(self.annotations.revision()) {
+            let (revision, items) = self.annotations.snapshot();
+            layer.draw(revision, &items);
+        }
+
+        // Pointers move every frame, so they're drawn straight into it
+        let pointers = match self.laser_pointer {
+            true => self.annotations.pointers(),
+            false => Vec::new(),
+        };
+
+        // Nothing drawn, so leave the frame alone
+        if layer.bounds.is_none() && pointers.is_empty() {
+            return frame;
+        }
+
+        let mut data = frame.data.to_vec();
+        if let Some(bounds) = layer.bounds {
+            match frame.pix_fmt {
+                VideoFramePixelFormat::I420 => layer.blend_i420(&mut data, bounds, opacity),
+                VideoFramePixelFormat::UYVY => {
+                    layer.blend_uyvy(&mut data, frame.line_stride, bounds, opacity)
+                }
+            }
+        }
+        for (position, color) in pointers {
+            let color = parse_color(&color).unwrap_or(FALLBACK_COLOR);
+            draw_laser(&mut data, &frame, position, color);
+        }
+        VideoFrameBuffer {
+            data: Bytes::from(data),
+            ..frame
+        }
+    }
+}
+
+/// Pixel rectangle, exclusive of `x1`/`y1`.
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+}
+
+impl Bounds {
+    fn union(self, other: Bounds) -> Bounds {
+        Bounds {
+            x0: self.x0.min(other.x0),
+            y0: self.y0.min(other.y0),
+            x1: self.x1.max(other.x1),
+            y1: self.y1.max(other.y1),
+        }
+    }
+}
+
+/// Rasterized annotations at frame resolution. Colors are stored as YUV per
+/// pixel, alongside their coverage.
+struct Layer {
+    width: usize,
+    height: usize,
+    /// Annotation revision this was drawn from.
+    revision: Option<u64>,
+    /// Area touched by annotations, if any.
+    bounds: Option<Bounds>,
+
+    alpha: Vec<u8>,
+    y: Vec<u8>,
+    u: Vec<u8>,
+    v: Vec<u8>,
+
+    /// Converted for outputs that take RGB, and the opacity it was built for.
+    bgra: Option<(f32, Bytes)>,
+}
+
+impl Layer {
+    fn new(width: usize, height: usize) -> Self {
+        let dim = width * height;
+        Self {
+            width,
+            height,
+            revision: None,
+            bounds: None,
+            alpha: vec![0; dim],
+            y: vec![0; dim],
+            u: vec![0; dim],
+            v: vec![0; dim],
+            bgra: None,
+        }
+    }
+
+    fn draw(&mut self, revision: u64, items: &[AnnotationItem]) {
+        if let Some(bounds) = self.bounds.take() {
+            for row in bounds.y0..bounds.y1 {
+                self.alpha[row * self.width..][bounds.x0..bounds.x1].fill(0);
+            }
+        }
+        self.revision = Some(revision);
+        self.bgra = None;
+
+        for item in items {
+            let polylines = self.outline(item);
+            let yuv = rgb_to_yuv(parse_color(&item.color).unwrap_or(FALLBACK_COLOR));
+            let width = match item.kind {
+                ItemKind::Text { size, .. } => size * TEXT_WEIGHT,
+                _ => item.width,
+            };
+            let radius = (width * self.height as f32 / 2.).max(0.5);
+            if let Some(bounds) = self.draw_polylines(&polylines, radius, yuv) {
+                self.bounds = Some(match self.bounds {
+                    Some(existing) => existing.union(bounds),
+                    None => bounds,
+                });
+            }
+        }
+    }
+
+    /// Everything is drawn as anti-aliased polylines, in pixels.
+    fn outline(&self, item: &AnnotationItem) -> Vec<Vec<(f32, f32)>> {
+        let (width, height) = (self.width as f32, self.height as f32);
+        let to_pixels = |p: &Point| (p.x * width, p.y * height);
+
+        match &item.kind {
+            ItemKind::Stroke { points, .. } => vec![points.iter().map(to_pixels).collect()],
+            ItemKind::Line { from, to } => vec![vec![to_pixels(from), to_pixels(to)]],
+            ItemKind::Arrow { from, to } => {
+                let ((x0, y0), (x1, y1)) = (to_pixels(from), to_pixels(to));
+                let angle = (y1 - y0).atan2(x1 - x0);
+                let head = (item.width * height).max(1.) * ARROW_HEAD_SCALE;
+                let barb = |angle: f32| (x1 - head * angle.cos(), y1 - head * angle.sin());
+                vec![
+                    vec![(x0, y0), (x1, y1)],
+                    vec![
+                        barb(angle - ARROW_HEAD_ANGLE),
+                        (x1, y1),
+                        barb(angle + ARROW_HEAD_ANGLE),
+                    ],
+                ]
+            }
+            ItemKind::Rectangle { from, to } => {
+                let ((x0, y0), (x1, y1)) = (to_pixels(from), to_pixels(to));
+                vec![vec![(x0, y0), (x1, y0), (x1, y1), (x0, y1), (x0, y0)]]
+            }
+            ItemKind::Ellipse { from, to } => {
+                let ((x0, y0), (x1, y1)) = (to_pixels(from), to_pixels(to));
+                let center = ((x0 + x1) / 2., (y0 + y1) / 2.);
+                vec![ellipse(center, (x1 - x0).abs() / 2., (y1 - y0).abs() / 2.)]
+            }
+            ItemKind::Stamp {
+                position,
+                stamp,
+                size,
+            } => {
+                let (x, y) = to_pixels(position);
+                let r = size * height / 2.;
+                match stamp {
+                    StampKind::Cross => vec![
+                        vec![(x - r, y - r), (x + r, y + r)],
+                        vec![(x - r, y + r), (x + r, y - r)],
+                    ],
+                    StampKind::Check => vec![vec![
+                        (x - r, y),
+                        (x - r / 3., y + r * 2. / 3.),
+                        (x + r, y - r * 2. / 3.),
+                    ]],
+                    StampKind::Circle => vec![ellipse((x, y), r, r)],
+                    StampKind::Star => vec![(0..=10)
+                        .map(|i| {
+                            let radius = if i % 2 == 0 { r } else { r * 0.4 };
+                            let t =
+                                i as f32 * std::f32::consts::PI / 5. - std::f32::consts::FRAC_PI_2;
+                            (x + radius * t.cos(), y + radius * t.sin())
+                        })
+                        .collect()],
+                }
+            }
+            // Placed like the page's `fillText`, from the left of the baseline
+            ItemKind::Text {
+                position,
+                text,
+                size,
+            } => {
+                let (x, y) = to_pixels(position);
+                let unit = size * height * TEXT_CAP_HEIGHT / font::CAP_HEIGHT;
+                text.chars()
+                    .enumerate()
+                    .flat_map(|(i, c)| {
+                        let left = x + i as f32 * font::ADVANCE * unit;
+                        let top = y - font::CAP_HEIGHT * unit;
+                        font::glyph(c).iter().map(move |stroke| {
+                            stroke
+                                .iter()
+                                .map(|&(gx, gy)| (left + gx as f32 * unit, top + gy as f32 * unit))
+                                .collect()
+                        })
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    /// Draw one annotation's polylines over the layer. The annotation's
+    /// coverage is built up separately first, so overlapping segments don't
+    /// blend with each other.
+    fn draw_polylines(
+        &mut self,
+        polylines: &[Vec<(f32, f32)>],
+        radius: f32,
+        (y, u, v): (u8, u8, u8),
+    ) -> Option<Bounds> {
+        let points = polylines.iter().flatten();
+        let min_x = points.clone().map(|p| p.0).fold(f32::INFINITY, f32::min);
+        let min_y = points.clone().map(|p| p.1).fold(f32::INFINITY, f32::min);
+        let max_x = points
+            .clone()
+            .map(|p| p.0)
+            .fold(f32::NEG_INFINITY, f32::max);
+        let max_y = points.map(|p| p.1).fold(f32::NEG_INFINITY, f32::max);
+        let bounds = self.clip(min_x, min_y, max_x, max_y, radius + 1.)?;
+
+        let mask_width = bounds.x1 - bounds.x0;
+        let mut mask = vec![0f32; mask_width * (bounds.y1 - bounds.y0)];
+        for polyline in polylines {
+            match polyline.as_slice() {
+                [] => {}
+                [point] => self.draw_segment(&mut mask, bounds, *point, *point, radius),
+                _ => {
+                    for segment in polyline.windows(2) {
+                        self.draw_segment(&mut mask, bounds, segment[0], segment[1], radius);
+                    }
+                }
+            }
+        }
+
+        for row in bounds.y0..bounds.y1 {
+            for col in bounds.x0..bounds.x1 {
+                let coverage = mask[(row - bounds.y0) * mask_width + col - bounds.x0];
+                if coverage <= 0. {
+                    continue;
+                }
+                let i = row * self.width + col;
+                let below = self.alpha[i] as f32 / 255. * (1. - coverage);
+                let alpha = coverage + below;
+                let over = |top: u8, bottom: u8| {
+                    ((top as f32 * coverage + bottom as f32 * below) / alpha).round() as u8
+                };
+                self.y[i] = over(y, self.y[i]);
+                self.u[i] = over(u, self.u[i]);
+                self.v[i] = over(v, self.v[i]);
+                self.alpha[i] = (alpha * 255.).round() as u8;
+            }
+        }
+
+        Some(bounds)
+    }
+
+    /// Accumulate the coverage of a round-capped segment into `mask`.
+    fn draw_segment(
+        &self,
+        mask: &mut [f32],
+        mask_bounds: Bounds,
+        (ax, ay): (f32, f32),
+        (bx, by): (f32, f32),
+        radius: f32,
+    ) {
+        let Some(bounds) = self.clip(ax.min(bx), ay.min(by), ax.max(bx), ay.max(by), radius + 1.)
+        else {
+            return;
+        };
+        let mask_width = mask_bounds.x1 - mask_bounds.x0;
+        let (dx, dy) = (bx - ax, by - ay);
+        let length_sq = dx * dx + dy * dy;
+
+        for row in bounds.y0..bounds.y1 {
+            for col in bounds.x0..bounds.x1 {
+                let (px, py) = (col as f32 + 0.5, row as f32 + 0.5);
+                let t = if length_sq > 0. {
+                    (((px - ax) * dx + (py - ay) * dy) / length_sq).clamp(0., 1.)
+                } else {
+                    0.
+                };
+                let (cx, cy) = (ax + t * dx - px, ay + t * dy - py);
+                let distance = (cx * cx + cy * cy).sqrt();
+
+                // One pixel wide ramp along the edge, for anti-aliasing
+                let coverage = (radius + 0.5 - distance).clamp(0., 1.);
+                let cell = &mut mask[(row - mask_bounds.y0) * mask_width + col - mask_bounds.x0];
+                *cell = cell.max(coverage);
+            }
+        }
+    }
+
+    /// Pixel bounds of a box grown by `margin`, clipped to the layer.
+    fn clip(&self, min_x: f32, min_y: f32, max_x: f32, max_y: f32, margin: f32) -> Option<Bounds> {
+        if !(min_x.is_finite() && min_y.is_finite() && max_x.is_finite() && max_y.is_finite()) {
+            return None;
+        }
+        let clamp = |value: f32, max: usize| value.clamp(0., max as f32) as usize;
+        let bounds = Bounds {
+            x0: clamp((min_x - margin).floor(), self.width),
+            y0: clamp((min_y - margin).floor(), self.height),
+            x1: clamp((max_x + margin).ceil(), self.width),
+            y1: clamp((max_y + margin).ceil(), self.height),
+        };
+        (bounds.x0 < bounds.x1 && bounds.y0 < bounds.y1).then_some(bounds)
+    }
+
+    fn bgra(&mut self, opacity: f32) -> Bytes {
+        if let Some((built_for, bgra)) = &self.bgra {
+            if *built_for == opacity {
+                return bgra.clone();
+            }
+        }
+
+        let mut bgra = vec![0u8; self.width * self.height * 4];
+        if let Some(bounds) = self.bounds {
+            for row in bounds.y0..bounds.y1 {
+                for col in bounds.x0..bounds.x1 {
+                    let i = row * self.width + col;
+                    if self.alpha[i] == 0 {
+                        continue;
+                    }
+                    let [r, g, b] = yuv_to_rgb(self.y[i], self.u[i], self.v[i]);
+                    let alpha = (self.alpha[i] as f32 * opacity).round() as u8;
+                    bgra[i * 4..i * 4 + 4].copy_from_slice(&[b, g, r, alpha]);
+                }
+            }
+        }
+
+        let bgra = Bytes::from(bgra);
+        self.bgra = Some((opacity, bgra.clone()));
+        bgra
+    }
+
+    fn blend_i420(&self, data: &mut [u8], bounds: Bounds, opacity: f32) {
+        let (width, height) = (self.width, self.height);
+        let dim = width * height;
+        let (y_plane, chroma) = data.split_at_mut(dim);
+        let (u_plane, v_plane) = chroma.split_at_mut(dim / 4);
+
+        for row in bounds.y0..bounds.y1 {
+            for col in bounds.x0..bounds.x1 {
+                let i = row * width + col;
+                y_plane[i] = blend(y_plane[i], self.y[i], self.alpha[i] as f32 / 255. * opacity);
+            }
+        }
+
+        // Chroma is shared by 2x2 pixels, so blend in their average
+        let chroma_width = width / 2;
+        for chroma_row in bounds.y0 / 2..(bounds.y1.div_ceil(2)).min(height / 2) {
+            for chroma_col in bounds.x0 / 2..(bounds.x1.div_ceil(2)).min(chroma_width) {
+                let pixels = [(0, 0), (1, 0), (0, 1), (1, 1)]
+                    .map(|(x, y)| (chroma_row * 2 + y) * width + chroma_col * 2 + x);
+                let Some((alpha, u, v)) = self.average(&pixels) else {
+                    continue;
+                };
+                let i = chroma_row * chroma_width + chroma_col;
+                u_plane[i] = blend(u_plane[i], u, alpha * opacity);
+                v_plane[i] = blend(v_plane[i], v, alpha * opacity);
+            }
+        }
+    }
+
+    fn blend_uyvy(&self, data: &mut [u8], line_stride: usize, bounds: Bounds, opacity: f32) {
+        for row in bounds.y0..bounds.y1 {
+            for pair in bounds.x0 / 2..bounds.x1.div_ceil(2).min(self.width / 2) {
+                let i = row * self.width + pair * 2;
+                let offset = row * line_stride + pair * 4;
+                let Some(macropixel) = data.get_mut(offset..offset + 4) else {
+                    continue;
+                };
+
+                // U Y0 V Y1, with chroma shared by both pixels
+                macropixel[1] = blend(
+                    macropixel[1],
+                    self.y[i],
+                    self.alpha[i] as f32 / 255. * opacity,
+                );
+                macropixel[3] = blend(
+                    macropixel[3],
+                    self.y[i + 1],
+                    self.alpha[i + 1] as f32 / 255. * opacity,
+                );
+                if let Some((alpha, u, v)) = self.average(&[i, i + 1]) {
+                    macropixel[0] = blend(macropixel[0], u, alpha * opacity);
+                    macropixel[2] = blend(macropixel[2], v, alpha * opacity);
+                }
+            }
+        }
+    }
+
+    /// Average coverage and chroma of `pixels`, or `None` if none are drawn.
+    fn average(&self, pixels: &[usize]) -> Option<(f32, u8, u8)> {
+        let (mut alpha, mut u, mut v) = (0., 0., 0.);
+        for &i in pixels {
+            let a = self.alpha[i] as f32 / 255.;
+            alpha += a;
+            u += self.u[i] as f32 * a;
+            v += self.v[i] as f32 * a;
+        }
+        if alpha <= 0. {
+            return None;
+        }
+        Some((
+            alpha / pixels.len() as f32,
+            (u / alpha).round() as u8,
+            (v / alpha).round() as u8,
+        ))
+    }
+}
+
+/// Draw a glowing dot at `position`, white hot in the middle.
+fn draw_laser(data: &mut [u8], frame: &VideoFrameBuffer, position: Point, color: [u8; 3]) {
+    let (width, height) = (frame.width, frame.height);
+    let radius = (LASER_RADIUS * height as f32).max(2.);
+    let glow = radius * LASER_GLOW;
+    let (cx, cy) = (position.x * width as f32, position.y * height as f32);
+    let (color_y, color_u, color_v) = rgb_to_yuv(color);
+
+    let x0 = (cx - glow).floor().max(0.) as usize;
+    let x1 = ((cx + glow).ceil().max(0.) as usize).min(width);
+    let y0 = (cy - glow).floor().max(0.) as usize;
+    let y1 = ((cy + glow).ceil().max(0.) as usize).min(height);
+
+    for row in y0..y1 {
+        for col in x0..x1 {
+            let distance = (col as f32 + 0.5 - cx).hypot(row as f32 + 0.5 - cy);
+            let (alpha, luma) = if distance <= radius {
+                (1., blend(235, color_y, distance / radius))
+            } else if distance < glow {
+                let falloff = 1. - (distance - radius) / (glow - radius);
+                (0.6 * falloff * falloff, color_y)
+            } else {
+                continue;
+            };
+
+            // Chroma is shared between pixels, so only blend it once
+            match frame.pix_fmt {
+                VideoFramePixelFormat::I420 => {
+                    let dim = width * height;
+                    let i = row * width + col;
+                    data[i] = blend(data[i], luma, alpha);
+                    if row % 2 == 0 && col % 2 == 0 && col / 2 < width / 2 && row / 2 < height / 2 {
+                        let i = (row / 2) * (width / 2) + col / 2;
+                        data[dim + i] = blend(data[dim + i], color_u, alpha);
+                        data[dim + dim / 4 + i] = blend(data[dim + dim / 4 + i], color_v, alpha);
+                    }
+                }
+                VideoFramePixelFormat::UYVY => {
+                    let offset = row * frame.line_stride + (col / 2) * 4;
+                    let Some(macropixel) = data.get_mut(offset..offset + 4) else {
+                        continue;
+                    };
+                    let y = 1 + (col % 2) * 2;
+                    macropixel[y] = blend(macropixel[y], luma, alpha);
+                    if col % 2 == 0 {
+                        macropixel[0] = blend(macropixel[0], color_u, alpha);
+                        macropixel[2] = blend(macropixel[2], color_v, alpha);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Closed polyline around an ellipse.
+fn ellipse((cx, cy): (f32, f32), rx: f32, ry: f32) -> Vec<(f32, f32)> {
+    (0..=ELLIPSE_SEGMENTS)
+        .map(|i| {
+            let t = i as f32 / ELLIPSE_SEGMENTS as f32 * std::f32::consts::TAU;
+            (cx + rx * t.cos(), cy + ry * t.sin())
+        })
+        .collect()
+}
+
+fn blend(bottom: u8, top: u8, alpha: f32) -> u8 {
+    (bottom as f32 + (top as f32 - bottom as f32) * alpha).round() as u8
+}
+
+/// Parse a CSS hex color, `#rrggbb` or `#rgb`.
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let hex = color.strip_prefix('#').filter(|hex| hex.is_ascii())?;
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).ok();
+    match hex.len() {
+        6 => Some([
+            channel(&hex[0..2])?,
+            channel(&hex[2..4])?,
+            channel(&hex[4..6])?,
+        ]),
+        3 => Some([
+            channel(&hex[0..1])? * 17,
+            channel(&hex[1..2])? * 17,
+            channel(&hex[2..3])? * 17,
+        ]),
+        _ => None,
+    }
+}
+
+/// Inverse of `rgb_to_yuv`.
+fn yuv_to_rgb(y: u8, u: u8, v: u8) -> [u8; 3] {
+    let (y, u, v) = (1.164 * (y as f32 - 16.), u as f32 - 128., v as f32 - 128.);
+    let r = y + 1.793 * v;
+    let g = y - 0.213 * u - 0.533 * v;
+    let b = y + 2.112 * u;
+    [r, g, b].map(|c| c.round().clamp(0., 255.) as u8)
+}
+
+/// BT.709, limited range.
+fn rgb_to_yuv([r, g, b]: [u8; 3]) -> (u8, u8, u8) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    let y = 16. + 0.1826 * r + 0.6142 * g + 0.0620 * b;
+    let u = 128. - 0.1006 * r - 0.3386 * g + 0.4392 * b;
+    let v = 128. + 0.4392 * r - 0.3989 * g - 0.0403 * b;
+    (y.round() as u8, u.round() as u8, v.round() as u8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        annotations::{model::NewItem, protocol::ClientMessage},
+        feed::frame::{VideoFramerate, VideoTimestamp},
+    };
+
+    const SIZE: usize = 20;
+    /// `#ff0000` in limited range BT.709.
+    const RED: (u8, u8, u8) = (63, 102, 240);
+    const BLACK: (u8, u8, u8) = (16, 128, 128);
+
+    /// Red line across the frame, covering exactly pixel rows 4 and 5, so
+    /// chroma row 2 in I420.
+    fn compositor() -> Compositor {
+        let hub = AnnotationHub::new();
+        let item = NewItem {
+            id: "line".to_owned(),
+            color: "#ff0000".to_owned(),
+            width: 0.1,
+            kind: ItemKind::Line {
+                from: Point { x: 0., y: 0.25 },
+                to: Point { x: 1., y: 0.25 },
+            },
+        };
+        hub.apply("presenter", ClientMessage::Add { item }).unwrap();
+        Compositor::new(hub, false)
+    }
+
+    fn frame(pix_fmt: VideoFramePixelFormat) -> VideoFrameBuffer {
+        let (y, u, v) = BLACK;
+        let (data, line_stride) = match pix_fmt {
+            VideoFramePixelFormat::I420 => {
+                let mut data = vec![y; SIZE * SIZE];
+                data.extend([u; SIZE * SIZE / 4]);
+                data.extend([v; SIZE * SIZE / 4]);
+                (data, SIZE)
+            }
+            VideoFramePixelFormat::UYVY => ([u, y, v, y].repeat(SIZE * SIZE / 2), SIZE * 2),
+        };
+        VideoFrameBuffer {
+            pix_fmt,
+            width: SIZE,
+            height: SIZE,
+            timestamp: VideoTimestamp::from_micros(0),
+            framerate: VideoFramerate::new(30, 1),
+            line_stride,
+            data: Bytes::from(data),
+        }
+    }
+
+    fn under_stroke(row: usize) -> bool {
+        (4..6).contains(&row)
+    }
+
+    #[test]
+    fn composites_into_i420() {
+        let frame = compositor().composite(frame(VideoFramePixelFormat::I420));
+        let (y_plane, chroma) = frame.data.split_at(SIZE * SIZE);
+        let (u_plane, v_plane) = chroma.split_at(SIZE * SIZE / 4);
+
+        for (row, line) in y_plane.chunks(SIZE).enumerate() {
+            let expected = if under_stroke(row) { RED.0 } else { BLACK.0 };
+            assert_eq!(line, [expected; SIZE], "luma row {row}");
+        }
+        for (row, (u, v)) in u_plane
+            .chunks(SIZE / 2)
+            .zip(v_plane.chunks(SIZE / 2))
+            .enumerate()
+        {
+            let expected = if under_stroke(row * 2) { RED } else { BLACK };
+            assert_eq!(u, [expected.1; SIZE / 2], "U row {row}");
+            assert_eq!(v, [expected.2; SIZE / 2], "V row {row}");
+        }
+    }
+
+    #[test]
+    fn composites_into_uyvy() {
+        let frame = compositor().composite(frame(VideoFramePixelFormat::UYVY));
+
+        for (row, line) in frame.data.chunks(frame.line_stride).enumerate() {
+            let (y, u, v) = if under_stroke(row) { RED } else { BLACK };
+            assert_eq!(line, [u, y, v, y].repeat(SIZE / 2), "row {row}");
+        }
+    }
+
+    #[test]
+    fn fades_out() {
+        let mut compositor = compositor();
+        compositor.fade_out();
+        // Three quarters of the way through
+        compositor.fade_start = compositor
+            .fade_start
+            .map(|start| start - FADE_DURATION * 3 / 4);
+        let faded = compositor.composite(frame(VideoFramePixelFormat::UYVY));
+        assert!(compositor.opacity > 0.2 && compositor.opacity <= 0.25);
+
+        // Blended at a quarter of the stroke's opacity
+        let near = |actual: u8, expected: f32| (actual as f32 - expected).abs() <= 1.;
+        let quarter = |bottom: u8, top: u8| bottom as f32 + (top as f32 - bottom as f32) / 4.;
+        for (row, line) in faded.data.chunks(faded.line_stride).enumerate() {
+            let [u, y, v, _] = line[..4] else {
+                unreachable!()
+            };
+            if under_stroke(row) {
+                assert!(near(u, quarter(BLACK.1, RED.1)), "U {u} in row {row}");
+                assert!(near(y, quarter(BLACK.0, RED.0)), "Y {y} in row {row}");
+                assert!(near(v, quarter(BLACK.2, RED.2)), "V {v} in row {row}");
+            } else {
+                assert_eq!((y, u, v), BLACK, "row {row}");
+            }
+        }
+
+        // Once the fade is over, the annotations are gone
+        compositor.fade_start = compositor.fade_start.map(|start| start - FADE_DURATION);
+        let untouched = frame(VideoFramePixelFormat::UYVY);
+        assert_eq!(compositor.composite(untouched.clone()).data, untouched.data);
+        assert!(compositor.annotations.snapshot().1.is_empty());
+    }
+}
@@ -0,0 +1,306 @@
+/// NAL unit type of an IDR slice.
+pub const NAL_TYPE_IDR: u8 = 5;
+/// NAL unit type of a sequence parameter set.
+pub const NAL_TYPE_SPS: u8 = 7;
+/// NAL unit type of a picture parameter set.
+pub const NAL_TYPE_PPS: u8 = 8;
+/// NAL unit type of an access unit delimiter.
+pub const NAL_TYPE_AUD: u8 = 9;
+
+/// Split an Annex-B bitstream into NAL units, without their start codes.
+pub fn nal_units(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut starts = vec![];
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i] == 0 && data[i + 1] == 0 && data[i + 2] == 1 {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+
+    let mut ends: Vec<usize> = starts.iter().skip(1).map(|&start| start - 3).collect();
+    ends.push(data.len());
+
+    starts.into_iter().zip(ends).map(move |(start, end)| {
+        // A 4 byte start code leaves a trailing zero on the previous unit
+        let mut end = end;
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        &data[start..end]
+    })
+}
+
+/// The type of a NAL unit, from its header byte.
+pub fn nal_type(nal: &[u8]) -> Option<u8> {
+    nal.first().map(|header| header & 0x1f)
+}
+
+/// Whether the access unit can be decoded on its own.
+pub fn is_keyframe(data: &[u8]) -> bool {
+    nal_units(data).any(|nal| nal_type(nal) == Some(NAL_TYPE_IDR))
+}
+
+/// An access unit as 4 byte length-prefixed NAL units (AVCC, as MP4 stores
+/// them), with the parameter sets split out.
+pub struct AvccAccessUnit {
+    pub data: Vec<u8>,
+    pub sps: Option<Vec<u8>>,
+    pub pps: Option<Vec<u8>>,
+}
+
+/// Convert an Annex-B access unit to AVCC. Parameter sets and delimiters are
+/// dropped from `data`, since MP4 keeps those in the sample description.
+pub fn to_avcc(data: &[u8]) -> AvccAccessUnit {
+    let mut access_unit = AvccAccessUnit {
+        data: Vec::with_capacity(data.len()),
+        sps: None,
+        pps: None,
+    };
+    for nal in nal_units(data).filter(|nal| !nal.is_empty()) {
+        match nal_type(nal) {
+            Some(NAL_TYPE_SPS) => access_unit.sps = Some(nal.to_vec()),
+            Some(NAL_TYPE_PPS) => access_unit.pps = Some(nal.to_vec()),
+            Some(NAL_TYPE_AUD) => {}
+            _ => {
+                access_unit
+                    .data
+                    .extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                access_unit.data.extend_from_slice(nal);
+            }
+        }
+    }
+    access_unit
+}
+
+/// Picture size coded in a sequence parameter set, after cropping.
+pub fn sps_dimensions(sps: &[u8]) -> Option<(u32, u32)> {
+    let mut reader = BitReader::new(sps.get(1..)?);
+    let profile_idc = reader.bits(8)?;
+    reader.bits(16)?; // constraint flags, level_idc
+    reader.ue()?; // seq_parameter_set_id
+
+    let mut chroma_format_idc = 1;
+    if [100, 110, 122, 244, 44, 83, 86, 118, 128, 138, 139, 134, 135].contains(&profile_idc) {
+        chroma_format_idc = reader.ue()?;
+        if chroma_format_idc == 3 {
+            reader.bits(1)?; // separate_colour_plane_flag
+        }
+        reader.ue()?; // bit_depth_luma_minus8
+        reader.ue()?; // bit_depth_chroma_minus8
+        reader.bits(1)?; // qpprime_y_zero_transform_bypass_flag
+        if reader.bits(1)? == 1 {
+            let lists = if chroma_format_idc == 3 { 12 } else { 8 };
+            for i in 0..lists {
+                if reader.bits(1)? == 1 {
+                    reader.skip_scaling_list(if i < 6 { 16 } else { 64 })?;
+                }
+            }
+        }
+    }
+
+    reader.ue()?; // log2_max_frame_num_minus4
+    match reader.ue()? {
+        0 => {
+            reader.ue()?; // log2_max_pic_order_cnt_lsb_minus4
+        }
+        1 => {
+            reader.bits(1)?; // delta_pic_order_always_zero_flag
+            reader.se()?; // offset_for_non_ref_pic
+            reader.se()?; // offset_for_top_to_bottom_field
+            for _ in 0..reader.ue()? {
+                reader.se()?; // offset_for_ref_frame
+            }
+        }
+        _ => {}
+    }
+    reader.ue()?; // max_num_ref_frames
+    reader.bits(1)?; // gaps_in_frame_num_value_allowed_flag
+
+    let width_in_mbs = reader.ue()?.checked_add(1)?;
+    let height_in_map_units = reader.ue()?.checked_add(1)?;
+    let frame_mbs_only = reader.bits(1)?;
+    if frame_mbs_only == 0 {
+        reader.bits(1)?; // mb_adaptive_frame_field_flag
+    }
+    reader.bits(1)?; // direct_8x8_inference_flag
+
+    let mut width = width_in_mbs.checked_mul(16)?;
+    let mut height = height_in_map_units
+        .checked_mul(16)?
+        .checked_mul(2 - frame_mbs_only)?;
+    if reader.bits(1)? == 1 {
+        let (left, right, top, bottom) = (reader.ue()?, reader.ue()?, reader.ue()?, reader.ue()?);
+        let (crop_x, crop_y) = match chroma_format_idc {
+            1 => (2, 2 * (2 - frame_mbs_only)),
+            2 => (2, 2 - frame_mbs_only),
+            _ => (1, 2 - frame_mbs_only),
+        };
+        width = width.checked_sub(left.checked_add(right)?.checked_mul(crop_x)?)?;
+        height = height.checked_sub(top.checked_add(bottom)?.checked_mul(crop_y)?)?;
+    }
+    Some((width, height))
+}
+
+/// Reads the RBSP of a NAL unit, skipping emulation prevention bytes.
+struct BitReader<'a> {
+    data: &'a [u8],
+    byte: usize,
+    bit: u8,
+    zeros: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            byte: 0,
+            bit: 0,
+            zeros: 0,
+        }
+    }
+
+    fn bit(&mut self) -> Option<u32> {
+        if self.bit == 0 {
+            // 00 00 03 is an escaped 00 00
+            if self.zeros >= 2 && self.data.get(self.byte) == Some(&3) {
+                self.byte += 1;
+                self.zeros = 0;
+            }
+            let byte = *self.data.get(self.byte)?;
+            self.zeros = if byte == 0 { self.zeros + 1 } else { 0 };
+        }
+        let value = (self.data[self.byte] >> (7 - self.bit)) & 1;
+        self.bit += 1;
+        if self.bit == 8 {
+            self.bit = 0;
+            self.byte += 1;
+        }
+        Some(value as u32)
+    }
+
+    fn bits(&mut self, count: u32) -> Option<u32> {
+        (0..count).try_fold(0, |value, _| Some(value << 1 | self.bit()?))
+    }
+
+    /// Unsigned Exp-Golomb.
+    fn ue(&mut self) -> Option<u32> {
+        let mut leading_zeros = 0;
+        while self.bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                return None;
+            }
+        }
+        Some((1 << leading_zeros) - 1 + self.bits(leading_zeros)?)
+    }
+
+    /// Signed Exp-Golomb.
+    fn se(&mut self) -> Option<i32> {
+        let value = self.ue()? as i64;
+        Some(match value % 2 {
+            0 => -(value / 2),
+            _ => (value + 1) / 2,
+        } as i32)
+    }
+
+    fn skip_scaling_list(&mut self, size: usize) -> Option<()> {
+        let (mut last, mut next) = (8, 8);
+        for _ in 0..size {
+            if next != 0 {
+                next = (last + self.se()? + 256) % 256;
+            }
+            if next != 0 {
+                last = next;
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes an SPS the way an encoder would, bit by bit.
+    #[derive(Default)]
+    struct BitWriter {
+        bytes: Vec<u8>,
+        len: usize,
+    }
+
+    impl BitWriter {
+        fn bits(&mut self, value: u64, count: u32) {
+            for i in (0..count).rev() {
+                if self.len / 8 == self.bytes.len() {
+                    self.bytes.push(0);
+                }
+                let bit = ((value >> i) & 1) as u8;
+                *self.bytes.last_mut().unwrap() |= bit << (7 - self.len % 8);
+                self.len += 1;
+            }
+        }
+
+        fn ue(&mut self, value: u32) {
+            let code = value as u64 + 1;
+            let len = 64 - code.leading_zeros();
+            self.bits(0, len - 1);
+            self.bits(code, len);
+        }
+    }
+
+    /// Baseline SPS with the given size in macroblocks, cropped by
+    /// `crop_bottom` chroma rows.
+    fn baseline_sps(
+        width_in_mbs_minus1: u32,
+        height_in_mbs_minus1: u32,
+        crop_bottom: u32,
+    ) -> Vec<u8> {
+        let mut sps = BitWriter::default();
+        sps.bits(NAL_TYPE_SPS as u64 | 0x60, 8);
+        sps.bits(66, 8); // profile_idc
+        sps.bits(0x001f, 16); // constraint flags, level_idc
+        sps.ue(0); // seq_parameter_set_id
+        sps.ue(0); // log2_max_frame_num_minus4
+        sps.ue(0); // pic_order_cnt_type
+        sps.ue(0); // log2_max_pic_order_cnt_lsb_minus4
+        sps.ue(1); // max_num_ref_frames
+        sps.bits(0, 1); // gaps_in_frame_num_value_allowed_flag
+        sps.ue(width_in_mbs_minus1);
+        sps.ue(height_in_mbs_minus1);
+        sps.bits(1, 1); // frame_mbs_only_flag
+        sps.bits(1, 1); // direct_8x8_inference_flag
+        sps.bits(1, 1); // frame_cropping_flag
+        sps.ue(0);
+        sps.ue(0);
+        sps.ue(0);
+        sps.ue(crop_bottom);
+        sps.bits(0, 1); // vui_parameters_present_flag
+        sps.bits(1, 1); // rbsp_stop_one_bit
+        sps.bytes
+    }
+
+    #[test]
+    fn cropped_1080p() {
+        assert_eq!(
+            sps_dimensions(&baseline_sps(119, 67, 4)),
+            Some((1920, 1080))
+        );
+    }
+
+    #[test]
+    fn oversized_dimensions_dont_overflow() {
+        assert_eq!(sps_dimensions(&baseline_sps(u32::MAX - 1, 67, 0)), None);
+        assert_eq!(sps_dimensions(&baseline_sps(119, 1 << 30, 0)), None);
+        assert_eq!(sps_dimensions(&baseline_sps(119, 67, u32::MAX / 2)), None);
+    }
+
+    #[test]
+    fn truncated_sps() {
+        let sps = baseline_sps(119, 67, 4);
+        assert_eq!(sps_dimensions(&sps[..6]), None);
+        assert_eq!(sps_dimensions(&[]), None);
+    }
+}
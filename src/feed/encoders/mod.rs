@@ -1,3 +1,4 @@
+pub mod h264;
 pub mod nvenc;
 pub mod openh264;
 
@@ -6,7 +7,7 @@ use bytes::Bytes;
 
 use self::nvenc::{NvencFeedEncoder, NvencFeedEncoderConfig};
 use self::openh264::{OpenH264FeedEncoder, OpenH264FeedEncoderConfig};
-use crate::feed::frame::VideoFrameBuffer;
+use crate::feed::frame::{VideoFrameBuffer, VideoTimestamp};
 
 pub trait FeedEncoderImpl {
     fn encode(&mut self, frame: &VideoFrameBuffer, flags: EncoderFrameFlags) -> Result<Bytes>;
@@ -36,6 +37,7 @@ pub trait FeedEncoderConfigImpl {
     fn build(&self, rate: RateParameters) -> Result<FeedEncoder>;
 }
 
+#[derive(Clone)]
 pub enum FeedEncoderConfig {
     OpenH264(OpenH264FeedEncoderConfig),
     Nvenc(NvencFeedEncoderConfig),
@@ -49,12 +51,35 @@ impl FeedEncoderConfigImpl for FeedEncoderConfig {
     }
 }
 
+/// One encoded access unit (Annex-B), tagged with the source timestamp of the
+/// frame it came from.
+#[derive(Debug, Clone)]
+pub struct EncodedFrame {
+    pub data: Bytes,
+    pub timestamp: VideoTimestamp,
+    pub keyframe: bool,
+    /// Encoded from a replay rather than the source.
+    pub replayed: bool,
+}
+
+impl EncodedFrame {
+    pub fn new(data: Bytes, timestamp: VideoTimestamp) -> Self {
+        let keyframe = h264::is_keyframe(&data);
+        Self {
+            data,
+            timestamp,
+            keyframe,
+            replayed: false,
+        }
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct EncoderFrameFlags {
     pub force_keyframe: bool,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct RateParameters {
     pub target_bitrate: u32,
     pub max_fps: f32,
@@ -0,0 +1,257 @@
+/// Height of the capitals, in grid units.
+pub const CAP_HEIGHT: f32 = 6.;
+/// Distance from one glyph to the next, in grid units.
+pub const ADVANCE: f32 = 5.5;
+
+pub type Glyph = &'static [&'static [(i8, i8)]];
+
+/// Drawn for characters the font doesn't have.
+const MISSING: Glyph = &[&[(0, 0), (4, 0), (4, 6), (0, 6), (0, 0)]];
+
+/// The strokes making up `c`, in a small stroke font so text can be burned in
+/// by the same polyline rasterizer as everything else. Strokes are on a grid
+/// 4 units wide, from the top of the capitals (y = 0) down to the baseline
+/// (y = `CAP_HEIGHT`), with descenders below it. Lowercase letters are drawn
+/// as capitals.
+pub fn glyph(c: char) -> Glyph {
+    match c.to_ascii_uppercase() {
+        'A' => &[&[(0, 6), (0, 2), (2, 0), (4, 2), (4, 6)], &[(0, 4), (4, 4)]],
+        'B' => &[
+            &[(0, 6), (0, 0), (3, 0), (4, 1), (4, 2), (3, 3), (0, 3)],
+            &[(3, 3), (4, 4), (4, 5), (3, 6), (0, 6)],
+        ],
+        'C' => &[&[
+            (4, 1),
+            (3, 0),
+            (1, 0),
+            (0, 1),
+            (0, 5),
+            (1, 6),
+            (3, 6),
+            (4, 5),
+        ]],
+        'D' => &[&[(0, 0), (0, 6), (2, 6), (4, 4), (4, 2), (2, 0), (0, 0)]],
+        'E' => &[&[(4, 0), (0, 0), (0, 6), (4, 6)], &[(0, 3), (3, 3)]],
+        'F' => &[&[(4, 0), (0, 0), (0, 6)], &[(0, 3), (3, 3)]],
+        'G' => &[&[
+            (4, 1),
+            (3, 0),
+            (1, 0),
+            (0, 1),
+            (0, 5),
+            (1, 6),
+            (3, 6),
+            (4, 5),
+            (4, 3),
+            (2, 3),
+        ]],
+        'H' => &[&[(0, 0), (0, 6)], &[(4, 0), (4, 6)], &[(0, 3), (4, 3)]],
+        'I' => &[&[(1, 0), (3, 0)], &[(2, 0), (2, 6)], &[(1, 6), (3, 6)]],
+        'J' => &[&[(4, 0), (4, 5), (3, 6), (1, 6), (0, 5)]],
+        'K' => &[&[(0, 0), (0, 6)], &[(4, 0), (0, 4)], &[(1, 3), (4, 6)]],
+        'L' => &[&[(0, 0), (0, 6), (4, 6)]],
+        'M' => &[&[(0, 6), (0, 0), (2, 3), (4, 0), (4, 6)]],
+        'N' => &[&[(0, 6), (0, 0), (4, 6), (4, 0)]],
+        'O' => &[&[
+            (1, 0),
+            (3, 0),
+            (4, 1),
+            (4, 5),
+            (3, 6),
+            (1, 6),
+            (0, 5),
+            (0, 1),
+            (1, 0),
+        ]],
+        'P' => &[&[(0, 6), (0, 0), (3, 0), (4, 1), (4, 2), (3, 3), (0, 3)]],
+        'Q' => &[
+            &[
+                (1, 0),
+                (3, 0),
+                (4, 1),
+                (4, 5),
+                (3, 6),
+                (1, 6),
+                (0, 5),
+                (0, 1),
+                (1, 0),
+            ],
+            &[(2, 4), (4, 6)],
+        ],
+        'R' => &[
+            &[(0, 6), (0, 0), (3, 0), (4, 1), (4, 2), (3, 3), (0, 3)],
+            &[(2, 3), (4, 6)],
+        ],
+        'S' => &[&[
+            (4, 1),
+            (3, 0),
+            (1, 0),
+            (0, 1),
+            (0, 2),
+            (1, 3),
+            (3, 3),
+            (4, 4),
+            (4, 5),
+            (3, 6),
+            (1, 6),
+            (0, 5),
+        ]],
+        'T' => &[&[(0, 0), (4, 0)], &[(2, 0), (2, 6)]],
+        'U' => &[&[(0, 0), (0, 5), (1, 6), (3, 6), (4, 5), (4, 0)]],
+        'V' => &[&[(0, 0), (2, 6), (4, 0)]],
+        'W' => &[&[(0, 0), (1, 6), (2, 3), (3, 6), (4, 0)]],
+        'X' => &[&[(0, 0), (4, 6)], &[(4, 0), (0, 6)]],
+        'Y' => &[&[(0, 0), (2, 3), (4, 0)], &[(2, 3), (2, 6)]],
+        'Z' => &[&[(0, 0), (4, 0), (0, 6), (4, 6)]],
+
+        '0' => &[
+            &[
+                (1, 0),
+                (3, 0),
+                (4, 1),
+                (4, 5),
+                (3, 6),
+                (1, 6),
+                (0, 5),
+                (0, 1),
+                (1, 0),
+            ],
+            &[(4, 1), (0, 5)],
+        ],
+        '1' => &[&[(1, 1), (2, 0), (2, 6)], &[(1, 6), (3, 6)]],
+        '2' => &[&[(0, 1), (1, 0), (3, 0), (4, 1), (4, 2), (0, 6), (4, 6)]],
+        '3' => &[
+            &[
+                (0, 1),
+                (1, 0),
+                (3, 0),
+                (4, 1),
+                (4, 2),
+                (3, 3),
+                (4, 4),
+                (4, 5),
+                (3, 6),
+                (1, 6),
+                (0, 5),
+            ],
+            &[(1, 3), (3, 3)],
+        ],
+        '4' => &[&[(3, 6), (3, 0), (0, 4), (4, 4)]],
+        '5' => &[&[
+            (4, 0),
+            (0, 0),
+            (0, 3),
+            (3, 3),
+            (4, 4),
+            (4, 5),
+            (3, 6),
+            (0, 6),
+        ]],
+        '6' => &[&[
+            (3, 0),
+            (1, 0),
+            (0, 1),
+            (0, 5),
+            (1, 6),
+            (3, 6),
+            (4, 5),
+            (4, 4),
+            (3, 3),
+            (0, 3),
+        ]],
+        '7' => &[&[(0, 0), (4, 0), (1, 6)]],
+        '8' => &[
+            &[
+                (1, 0),
+                (3, 0),
+                (4, 1),
+                (4, 2),
+                (3, 3),
+                (1, 3),
+                (0, 2),
+                (0, 1),
+                (1, 0),
+            ],
+            &[
+                (1, 3),
+                (0, 4),
+                (0, 5),
+                (1, 6),
+                (3, 6),
+                (4, 5),
+                (4, 4),
+                (3, 3),
+            ],
+        ],
+        '9' => &[&[
+            (4, 3),
+            (1, 3),
+            (0, 2),
+            (0, 1),
+            (1, 0),
+            (3, 0),
+            (4, 1),
+            (4, 5),
+            (3, 6),
+            (1, 6),
+        ]],
+
+        '.' => &[&[(2, 6)]],
+        ',' => &[&[(2, 5), (1, 7)]],
+        ':' => &[&[(2, 2)], &[(2, 5)]],
+        ';' => &[&[(2, 2)], &[(2, 5), (1, 7)]],
+        '!' => &[&[(2, 0), (2, 4)], &[(2, 6)]],
+        '?' => &[
+            &[(0, 1), (1, 0), (3, 0), (4, 1), (4, 2), (2, 3), (2, 4)],
+            &[(2, 6)],
+        ],
+        '\'' => &[&[(2, 0), (2, 2)]],
+        '"' => &[&[(1, 0), (1, 2)], &[(3, 0), (3, 2)]],
+        '-' => &[&[(1, 3), (3, 3)]],
+        '+' => &[&[(0, 3), (4, 3)], &[(2, 1), (2, 5)]],
+        '=' => &[&[(0, 2), (4, 2)], &[(0, 4), (4, 4)]],
+        '*' => &[&[(2, 1), (2, 5)], &[(0, 2), (4, 4)], &[(0, 4), (4, 2)]],
+        '/' => &[&[(4, 0), (0, 6)]],
+        '_' => &[&[(0, 7), (4, 7)]],
+        '(' => &[&[(3, 0), (2, 1), (2, 5), (3, 6)]],
+        ')' => &[&[(1, 0), (2, 1), (2, 5), (1, 6)]],
+        '<' => &[&[(4, 0), (0, 3), (4, 6)]],
+        '>' => &[&[(0, 0), (4, 3), (0, 6)]],
+        '#' => &[
+            &[(1, 0), (1, 6)],
+            &[(3, 0), (3, 6)],
+            &[(0, 2), (4, 2)],
+            &[(0, 4), (4, 4)],
+        ],
+        '%' => &[&[(4, 0), (0, 6)], &[(0, 1)], &[(4, 5)]],
+
+        c if c.is_whitespace() => &[],
+        _ => MISSING,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glyphs_stay_on_the_grid() {
+        for c in (' '..='~').chain(['é', '€']) {
+            for stroke in glyph(c) {
+                assert!(!stroke.is_empty(), "{c:?} has an empty stroke");
+                for &(x, y) in *stroke {
+                    assert!((0..=4).contains(&x), "{c:?} is too wide");
+                    assert!((0..=7).contains(&y), "{c:?} is too tall");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lowercase_is_drawn_as_capitals() {
+        assert_eq!(glyph('a'), glyph('A'));
+        assert_eq!(glyph('z'), glyph('Z'));
+        assert!(glyph(' ').is_empty());
+        assert_eq!(glyph('é'), MISSING);
+    }
+}
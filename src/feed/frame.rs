@@ -1,5 +1,6 @@
 use anyhow::Result;
 use bytes::Bytes;
+use openh264::formats::YUVSource;
 
 pub type Resolution = (u32, u32);
 
@@ -28,7 +29,10 @@ impl VideoFramerate {
 
 /// Represent the timecode of a video frame. Internally stores timecodes as
 /// microseconds (higher resolutions are lost).
-#[derive(Clone)]
+///
+/// Audio frames from the same source share the clock, which is what keeps
+/// them in sync.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct VideoTimestamp(u64);
 impl VideoTimestamp {
     pub fn from_micros(micros: u64) -> Self {
@@ -45,6 +49,23 @@ impl VideoTimestamp {
     }
 }
 
+/// Uncompressed audio, as interleaved f32 samples.
+#[derive(Clone)]
+pub struct AudioFrameBuffer {
+    pub sample_rate: u32,
+    pub channels: usize,
+
+    /// timestamp of the first sample
+    pub timestamp: VideoTimestamp,
+    pub data: Vec<f32>,
+}
+impl AudioFrameBuffer {
+    /// Number of samples per channel.
+    pub fn samples(&self) -> usize {
+        self.data.len() / self.channels.max(1)
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ColorConversionError {
     #[error("ipp color conversion threw an error: {0}")]
@@ -121,6 +142,38 @@ impl VideoFrameBuffer {
         });
     }
 
+    /// Copy a decoded picture into an I420 frame, dropping the decoder's
+    /// row padding.
+    pub fn from_decoded(
+        yuv: &impl YUVSource,
+        timestamp: VideoTimestamp,
+        framerate: VideoFramerate,
+    ) -> VideoFrameBuffer {
+        let (width, height) = yuv.dimensions();
+        let (y_stride, u_stride, v_stride) = yuv.strides();
+
+        let mut data = Vec::with_capacity(3 * (width * height) / 2);
+        for row in 0..height {
+            data.extend_from_slice(&yuv.y()[row * y_stride..][..width]);
+        }
+        for row in 0..height / 2 {
+            data.extend_from_slice(&yuv.u()[row * u_stride..][..width / 2]);
+        }
+        for row in 0..height / 2 {
+            data.extend_from_slice(&yuv.v()[row * v_stride..][..width / 2]);
+        }
+
+        VideoFrameBuffer {
+            pix_fmt: VideoFramePixelFormat::I420,
+            width,
+            height,
+            timestamp,
+            framerate,
+            line_stride: width,
+            data: Bytes::from(data),
+        }
+    }
+
     // TODO: This is ugly. Make separate structs for each color type.
     pub fn yuv_slices(&self) -> (&[u8], &[u8], &[u8]) {
         debug_assert!(self.pix_fmt == VideoFramePixelFormat::I420);
@@ -1,22 +1,32 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     thread,
     time::{Duration, Instant},
 };
 
-use anyhow::{Context, Result};
-use bytes::Bytes;
-use tokio::sync::{broadcast, mpsc};
+use anyhow::{bail, Context, Result};
+use tokio::sync::{broadcast, mpsc, watch};
 
-use crate::timing_stats::TimingStats;
+use crate::{annotations::hub::AnnotationHub, timing_stats::TimingStats};
 
 use super::{
+    audio::{AudioEncoder, EncodedAudioPacket},
+    compositor::Compositor,
     encoders::{
-        self, EncoderFrameFlags, FeedEncoder, FeedEncoderConfig, FeedEncoderConfigImpl,
-        FeedEncoderImpl, RateParameters,
+        self, EncodedFrame, EncoderFrameFlags, FeedEncoderConfig, FeedEncoderImpl, RateParameters,
     },
-    frame::Resolution,
-    sources::{self, FeedSource, FeedSourceConfig, FeedSourceConfigImpl, FeedSourceImpl},
+    frame::{Resolution, VideoFrameBuffer, VideoTimestamp},
+    outputs::{
+        dump::{DebugDump, DumpConfig},
+        ndi::{NDIOutput, NDIOutputConfig},
+    },
+    pool::{self, EncoderGroup, EncoderPool},
+    replay::{ReplayClip, ReplayPlayback},
+    sources::{
+        self, whip::WhipFeedSourceConfig, FeedSource, FeedSourceConfig, FeedSourceConfigImpl,
+        FeedSourceImpl,
+    },
+    zoom::{Zoom, ZoomRegion},
 };
 
 #[derive(Default)]
@@ -24,14 +34,17 @@ pub struct FeedConfigBuilder {
     /// Frame source configuration
     source: Option<FeedSourceConfig>,
 
+    /// WHIP ingest, offered as a source when prompting interactively.
+    whip_source: Option<WhipFeedSourceConfig>,
+
     /// Frame encoder configuration
     encoder: Option<FeedEncoderConfig>,
 
-    /// Minimum bitrate due to bandwidth-related adjustments. (kbps)
+    /// Minimum bitrate due to bandwidth-related adjustments. (bps)
     min_bitrate: Option<u32>,
-    /// Initial bitrate pre bandwidth-related adjustments. (kbps)
+    /// Initial bitrate pre bandwidth-related adjustments. (bps)
     start_bitrate: Option<u32>,
-    /// Maximum bitrate due to bandwidth-related adjustments. (kbps)
+    /// Maximum bitrate due to bandwidth-related adjustments. (bps)
     max_bitrate: Option<u32>,
 
     /// The encoding pipeline will not exceed this FPS limit.
@@ -39,6 +52,20 @@ pub struct FeedConfigBuilder {
 
     /// If specified, frames will be resized to this if they are larger.
     resolution: Option<Resolution>,
+
+    /// Upper bound on concurrently running encoders. Clients with diverging
+    /// bandwidth estimates are split across up to this many encoders.
+    max_encoders: Option<usize>,
+
+    /// If specified, the annotations (and optionally the program) are
+    /// published over NDI.
+    ndi_output: Option<NDIOutputConfig>,
+
+    /// Burn presenters' pointers into the feed as laser dots.
+    laser_pointer: Option<bool>,
+
+    /// If specified, encoder input and output are dumped to disk.
+    debug_dump: Option<DumpConfig>,
 }
 
 impl FeedConfigBuilder {
@@ -53,6 +80,11 @@ impl FeedConfigBuilder {
         self
     }
 
+    pub fn whip_source(mut self, whip_source: WhipFeedSourceConfig) -> Self {
+        self.whip_source = Some(whip_source);
+        self
+    }
+
     pub fn encoder(mut self, encoder: FeedEncoderConfig) -> Self {
         self.encoder = Some(encoder);
         self
@@ -75,10 +107,30 @@ impl FeedConfigBuilder {
         self
     }
 
+    pub fn max_encoders(mut self, max_encoders: usize) -> Self {
+        self.max_encoders = Some(max_encoders);
+        self
+    }
+
+    pub fn ndi_output(mut self, ndi_output: NDIOutputConfig) -> Self {
+        self.ndi_output = Some(ndi_output);
+        self
+    }
+
+    pub fn laser_pointer(mut self, laser_pointer: bool) -> Self {
+        self.laser_pointer = Some(laser_pointer);
+        self
+    }
+
+    pub fn debug_dump(mut self, debug_dump: DumpConfig) -> Self {
+        self.debug_dump = Some(debug_dump);
+        self
+    }
+
     pub fn build_interactive(self) -> Result<FeedConfig> {
         let source = match self.source {
             Some(source) => source,
-            None => sources::ndi::NDIFeedSourceConfig::build_interactive()
+            None => sources::build_interactive(self.whip_source)
                 .context("Failed to build source config")?,
         };
 
@@ -91,10 +143,23 @@ impl FeedConfigBuilder {
         let min_bitrate = self.min_bitrate.unwrap_or(500_000);
         let start_bitrate = self.start_bitrate.unwrap_or(6_000_000);
         let max_bitrate = self.max_bitrate.unwrap_or(20_000_000);
+        if min_bitrate > start_bitrate || start_bitrate > max_bitrate {
+            bail!(
+                "Bitrates must satisfy min <= start <= max, got {min_bitrate} / {start_bitrate} / {max_bitrate} b/s"
+            );
+        }
 
         let max_fps = self.max_fps.unwrap_or(60.);
         let resolution = self.resolution;
 
+        // Every encoder costs a full encode per frame, so only allow extra
+        // encoders on machines with cores to spare.
+        let max_encoders = self.max_encoders.unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|n| (n.get() / 4).clamp(1, 4))
+                .unwrap_or(1)
+        });
+
         Ok(FeedConfig {
             source,
             encoder,
@@ -106,6 +171,14 @@ impl FeedConfigBuilder {
             max_fps,
 
             resolution,
+
+            max_encoders,
+
+            ndi_output: self.ndi_output,
+
+            laser_pointer: self.laser_pointer.unwrap_or(false),
+
+            debug_dump: self.debug_dump,
         })
     }
 }
@@ -121,37 +194,222 @@ pub struct FeedConfig {
     max_fps: f32,
 
     resolution: Option<Resolution>,
+
+    max_encoders: usize,
+
+    ndi_output: Option<NDIOutputConfig>,
+
+    laser_pointer: bool,
+
+    debug_dump: Option<DumpConfig>,
+}
+
+/// Tells a client which encoder group's result channel to listen on.
+pub type FeedGroupSender = watch::Sender<broadcast::Sender<FeedResultMessage>>;
+
+/// A client's end of a `FeedGroupSender`, which follows it from group to
+/// group.
+pub struct FeedGroupReceiver {
+    group_rx: watch::Receiver<broadcast::Sender<FeedResultMessage>>,
+    feed_result_rx: broadcast::Receiver<FeedResultMessage>,
+}
+
+impl FeedGroupReceiver {
+    /// Start out on `feed_result_tx`, i.e. the primary group.
+    pub fn new(feed_result_tx: broadcast::Sender<FeedResultMessage>) -> (FeedGroupSender, Self) {
+        let feed_result_rx = feed_result_tx.subscribe();
+        let (group_tx, group_rx) = watch::channel(feed_result_tx);
+        let receiver = Self {
+            group_rx,
+            feed_result_rx,
+        };
+        (group_tx, receiver)
+    }
+
+    /// The next result from the client's current group. `Closed` once the
+    /// feed is done with the client.
+    pub async fn recv(&mut self) -> Result<FeedResultMessage, broadcast::error::RecvError> {
+        loop {
+            let result = tokio::select! {
+                // Replacing a removed group drops its last sender, so both
+                // are ready at once. The old group closing mustn't be taken
+                // for the feed ending.
+                biased;
+                changed = self.group_rx.changed() => match changed {
+                    Ok(()) => {
+                        self.resubscribe();
+                        continue;
+                    }
+                    Err(_) => return Err(broadcast::error::RecvError::Closed),
+                },
+                result = self.feed_result_rx.recv() => result,
+            };
+            if let Err(broadcast::error::RecvError::Closed) = result {
+                if self.group_rx.has_changed().unwrap_or(false) {
+                    self.resubscribe();
+                    continue;
+                }
+            }
+            return result;
+        }
+    }
+
+    fn resubscribe(&mut self) {
+        self.feed_result_rx = self.group_rx.borrow_and_update().subscribe();
+    }
 }
 
 #[derive(Debug)]
 pub enum FeedControlMessage {
-    ClientJoined { client_id: String },
-    ClientLeft { client_id: String },
-    RequestKeyframe,
-    BandwidthEstimate { client_id: String, bitrate: u32 },
+    /// `group_tx` is used to migrate the client between encoder groups. If
+    /// `None`, the client is pinned to the primary result channel.
+    ClientJoined {
+        client_id: String,
+        group_tx: Option<FeedGroupSender>,
+    },
+    ClientLeft {
+        client_id: String,
+    },
+    RequestKeyframe {
+        client_id: String,
+    },
+    /// Estimated bandwidth available to the client. (bps)
+    BandwidthEstimate {
+        client_id: String,
+        bitrate: u32,
+    },
+    /// NACK handling counters for a client since the previous report.
+    RetransmitStats {
+        client_id: String,
+        nacked: u64,
+        retransmitted: u64,
+        missed: u64,
+    },
+    /// Hold the current frame, so presenters can draw on a still.
+    Freeze,
+    /// Go back to the live source. With `fade`, the annotations fade out
+    /// instead of disappearing at once.
+    Unfreeze {
+        fade: bool,
+    },
+    /// Crop to `region` of the source, scaled up to the full frame.
+    /// `ZoomRegion::FULL` zooms back out.
+    Zoom {
+        region: ZoomRegion,
+    },
+    /// Play `clip` in place of the source, then go back to live.
+    Replay {
+        clip: ReplayClip,
+    },
+    /// Cut a replay short and go back to live.
+    StopReplay,
 }
 
+/// Frame rate and bitrate cap while frozen.
+const FROZEN_FPS: f32 = 10.;
+const FROZEN_MAX_BITRATE: u32 = 1_000_000;
+
+/// Groups are rebalanced at most this often, since every migration and
+/// rate change forces a keyframe.
+const REBALANCE_INTERVAL: Duration = Duration::from_secs(5);
+/// Once migrated, a client stays in its group at least this long.
+const MIN_GROUP_DWELL: Duration = Duration::from_secs(15);
+/// A client's bandwidth estimate has to move by more than this ratio before
+/// it's regrouped, so noisy estimates don't bounce it between groups.
+const ESTIMATE_HYSTERESIS: f32 = 1.25;
+
+/// Frames an encoder group's result channel holds for slow subscribers.
+/// Frames are reference counted, so this is cheap, but a subscriber that
+/// falls further behind has to wait for a keyframe. The primary group alone
+/// has the recorder, contribution, replay, HLS, RTSP and WebSocket outputs
+/// listening.
+pub const FEED_RESULT_CAPACITY: usize = 32;
+
+/// Audio packets held back waiting for video before they're sent anyway.
+const MAX_PENDING_AUDIO: usize = 25;
+
 #[derive(Debug, Clone)]
 pub enum FeedResultMessage {
-    EncodedBitstream(Bytes),
+    EncodedBitstream(EncodedFrame),
+    /// Sent on the audio channel, which is shared by every encoder group.
+    EncodedAudio(EncodedAudioPacket),
+}
+
+/// The frame held in freeze-frame mode.
+struct FrozenFrame {
+    frame: VideoFrameBuffer,
+    since: Instant,
+}
+
+impl FrozenFrame {
+    /// The held frame, with its timestamp moved along so the stream keeps
+    /// advancing.
+    fn next(&self) -> VideoFrameBuffer {
+        let elapsed = self.since.elapsed().as_micros() as u64;
+        VideoFrameBuffer {
+            timestamp: VideoTimestamp::from_micros(self.frame.timestamp.to_micros() + elapsed),
+            ..self.frame.clone()
+        }
+    }
+}
+
+struct FeedClient {
+    /// Bandwidth estimate the client was last grouped by.
+    bitrate: u32,
+    group: usize,
+    group_tx: Option<FeedGroupSender>,
+    /// When the client was last migrated, if ever.
+    migrated: Option<Instant>,
+}
+
+impl FeedClient {
+    /// Whether the client stays put for now: it was migrated within
+    /// `MIN_GROUP_DWELL`, and its group is still there at a bitrate its
+    /// link can take.
+    fn dwelling(&self, groups: &[EncoderGroup]) -> bool {
+        let recent = self
+            .migrated
+            .is_some_and(|migrated| migrated.elapsed() < MIN_GROUP_DWELL);
+        recent
+            && groups
+                .get(self.group)
+                .is_some_and(|group| group.target_bitrate <= self.bitrate)
+    }
 }
 
 pub struct FeedManager {
     config: FeedConfig,
 
     source: FeedSource,
-    encoder: FeedEncoder,
+    compositor: Compositor,
+    zoom: Zoom,
+    pool: EncoderPool,
 
-    feed_control_rx: mpsc::Receiver<FeedControlMessage>,
-    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+    /// Latest frame from the source, before compositing.
+    last_frame: Option<VideoFrameBuffer>,
+    frozen: Option<FrozenFrame>,
+    replay: Option<ReplayPlayback>,
 
-    force_keyframe: bool,
+    ndi_output: Option<NDIOutput>,
+    debug_dump: Option<DebugDump>,
+
+    annotations: AnnotationHub,
+
+    audio_encoder: Option<AudioEncoder>,
+    audio_tx: broadcast::Sender<FeedResultMessage>,
+    /// Encoded audio, released once the video it accompanies is sent.
+    pending_audio: VecDeque<EncodedAudioPacket>,
+
+    feed_control_rx: mpsc::Receiver<FeedControlMessage>,
 
-    client_bitrates: HashMap<String, u32>,
-    target_bitrate: u32,
+    clients: HashMap<String, FeedClient>,
+    /// Clients changed since the groups were last rebalanced.
+    rebalance_pending: bool,
+    last_rebalance: Option<Instant>,
     max_fps: f32,
 
     last_frame_time: Instant,
+    stats: TimingStats,
 }
 
 impl FeedManager {
@@ -159,82 +417,226 @@ impl FeedManager {
         config: FeedConfig,
         feed_control_rx: mpsc::Receiver<FeedControlMessage>,
         feed_result_tx: broadcast::Sender<FeedResultMessage>,
+        audio_tx: broadcast::Sender<FeedResultMessage>,
+        annotations: AnnotationHub,
     ) -> Result<Self> {
         let max_fps = config.max_fps;
         let target_bitrate = config.start_bitrate;
+        let laser_pointer = config.laser_pointer;
 
         let source = config.source.build()?;
-        let encoder = config.encoder.build(RateParameters {
-            max_fps,
-            target_bitrate,
-        })?;
+        let pool = EncoderPool::new(
+            config.encoder.clone(),
+            config.max_encoders,
+            feed_result_tx,
+            RateParameters {
+                max_fps,
+                target_bitrate,
+            },
+        )?;
+
+        let feed_name = config.source.name();
+        let ndi_output = config
+            .ndi_output
+            .as_ref()
+            .map(|ndi_output| NDIOutput::new(ndi_output, &feed_name))
+            .transpose()
+            .context("Unable to start NDI output")?;
+
+        let debug_dump = config
+            .debug_dump
+            .as_ref()
+            .map(DebugDump::new)
+            .transpose()
+            .context("Unable to start debug dump")?;
+
+        let audio_encoder = match AudioEncoder::new() {
+            Ok(audio_encoder) => Some(audio_encoder),
+            Err(e) => {
+                eprintln!("Audio disabled: {e:#}");
+                None
+            }
+        };
 
         Ok(Self {
             config,
 
             source,
-            encoder,
+            compositor: Compositor::new(annotations.clone(), laser_pointer),
+            zoom: Zoom::new(),
+            pool,
 
-            feed_control_rx,
-            feed_result_tx,
+            last_frame: None,
+            frozen: None,
+            replay: None,
+
+            ndi_output,
+            debug_dump,
 
-            force_keyframe: false,
+            annotations,
 
-            client_bitrates: HashMap::new(),
-            target_bitrate,
+            audio_encoder,
+            audio_tx,
+            pending_audio: VecDeque::new(),
+
+            feed_control_rx,
+
+            clients: HashMap::new(),
+            rebalance_pending: false,
+            last_rebalance: None,
             max_fps,
 
             last_frame_time: Instant::now(),
+            stats: TimingStats::new("feed".into()),
         })
     }
 
     pub fn run_forever(&mut self) -> Result<()> {
-        let mut stats = TimingStats::new("feed".into());
-
         loop {
             self.process_queued_control_messages()?;
+            self.rebalance_groups_if_due()?;
 
-            if self.client_bitrates.len() == 0 {
+            if self.clients.len() == 0 && self.ndi_output.is_none() {
                 // There's nothing to do, since we don't have any clients.
                 // Just block until the next message and try again.
                 self.block_until_next_message()?;
                 continue;
             }
 
-            // TODO: get_frame() could block while a keyframe request comes
-            // in. If so, the keyframe gets deferred for a really long time.
-            let Some(frame) = self.source.get_frame()? else {
-                continue;
+            let frozen_frame = self.frozen.as_ref().map(FrozenFrame::next);
+            let replay_frame = match frozen_frame {
+                Some(_) => None,
+                None => self.next_replay_frame()?,
+            };
+            let replaying = replay_frame.is_some();
+            let frame = match frozen_frame.or(replay_frame) {
+                Some(frame) => frame,
+                None => {
+                    // TODO: get_frame() could block while a keyframe request comes
+                    // in. If so, the keyframe gets deferred for a really long time.
+                    let frame = self.source.get_frame()?;
+                    self.encode_audio()?;
+                    let Some(frame) = frame else {
+                        // No video to sync to, so don't hold the audio back
+                        self.send_audio(None);
+                        continue;
+                    };
+                    self.last_frame = Some(frame.clone());
+                    frame
+                }
             };
 
-            stats.tick();
+            self.stats.tick();
+
+            // Annotation changes are recorded against (and replayed in sync
+            // with) the frame they're drawn on
+            self.annotations.set_timestamp(frame.timestamp);
+
+            // Replays are the encoded feed, so their annotations are
+            // already burned in
+            let frame = match replaying {
+                true => frame,
+                false => {
+                    self.stats.start("composite");
+                    let frame = self.compositor.composite(frame);
+                    self.stats.end("composite");
+
+                    if let Some(ndi_output) = &mut self.ndi_output {
+                        ndi_output.send_annotations(&frame, &mut self.compositor);
+                    }
+                    frame
+                }
+            };
 
-            let force_keyframe = std::mem::replace(&mut self.force_keyframe, false);
-            stats.start("encode");
-            let data = self
-                .encoder
-                .encode(&frame, EncoderFrameFlags { force_keyframe })
-                .context("failed to encode frame")?;
-            stats.end("encode");
+            // Annotations are drawn in source space, so zoom after compositing
+            self.stats.start("zoom");
+            let frame = self.zoom.apply(frame).context("failed to zoom frame")?;
+            self.stats.end("zoom");
 
-            stats.track(
-                "bitrate",
-                ((self.max_fps.round() as usize) * 8 * data.len() / 1000) as _,
-                " kb/s",
-            );
+            if let Some(debug_dump) = &self.debug_dump {
+                debug_dump.frame(&frame);
+            }
+            if let Some(ndi_output) = &mut self.ndi_output {
+                ndi_output.send_program(&frame);
+            }
+
+            let mut results = Vec::with_capacity(self.pool.groups().len());
+            for (idx, group) in self.pool.groups_mut().iter_mut().enumerate() {
+                let force_keyframe = std::mem::replace(&mut group.force_keyframe, false);
+                self.stats.start("encode");
+                let data = group
+                    .encoder
+                    .encode(&frame, EncoderFrameFlags { force_keyframe })
+                    .with_context(|| format!("failed to encode frame for group {idx}"))?;
+                self.stats.end("encode");
+
+                if let Some(debug_dump) = &self.debug_dump {
+                    debug_dump.encoded(idx, frame.timestamp, &data);
+                }
+
+                self.stats.track(
+                    &format!("bitrate[{idx}]"),
+                    ((self.max_fps.round() as usize) * 8 * data.len() / 1000) as _,
+                    " kb/s",
+                );
+                results.push(data);
+            }
 
             // TODO: better rate-limit (adjust for processing time.)
             self.rate_limit();
 
-            self.feed_result_tx
-                .send(FeedResultMessage::EncodedBitstream(data))
+            for (group, data) in self.pool.groups().iter().zip(results) {
+                let mut encoded = EncodedFrame::new(data, frame.timestamp);
+                encoded.replayed = replaying;
+                group
+                    .feed_result_tx
+                    .send(FeedResultMessage::EncodedBitstream(encoded))
+                    .ok();
+            }
+            self.send_audio(Some(frame.timestamp));
+        }
+    }
+
+    /// Encode the audio the source captured since the last frame.
+    fn encode_audio(&mut self) -> Result<()> {
+        let audio = self.source.take_audio();
+        let Some(audio_encoder) = &mut self.audio_encoder else {
+            return Ok(());
+        };
+
+        for frame in audio {
+            self.stats.start("audio encode");
+            let packets = audio_encoder
+                .encode(&frame)
+                .context("failed to encode audio")?;
+            self.stats.end("audio encode");
+            self.pending_audio.extend(packets);
+        }
+        Ok(())
+    }
+
+    /// Send the audio up to `until`, so it goes out alongside its video and
+    /// both tracks' RTP clocks line up. Without a timestamp, send it all.
+    fn send_audio(&mut self, until: Option<VideoTimestamp>) {
+        while let Some(packet) = self.pending_audio.front() {
+            let ready = until.map_or(true, |until| packet.timestamp <= until);
+            if !ready && self.pending_audio.len() <= MAX_PENDING_AUDIO {
+                break;
+            }
+            let packet = self.pending_audio.pop_front().unwrap();
+            self.audio_tx
+                .send(FeedResultMessage::EncodedAudio(packet))
                 .ok();
         }
     }
 
     /// Sleep to meet the target FPS.
     fn rate_limit(&mut self) {
-        let frame_length = Duration::from_secs_f32(1. / self.max_fps);
+        let fps = match self.frozen {
+            Some(_) => FROZEN_FPS,
+            None => self.max_fps,
+        };
+        let frame_length = Duration::from_secs_f32(1. / fps);
         let expected_deadline = self.last_frame_time + frame_length;
         thread::sleep(expected_deadline.duration_since(Instant::now()));
         self.last_frame_time = Instant::now();
@@ -258,70 +660,270 @@ impl FeedManager {
 
     fn process_control_message(&mut self, message: FeedControlMessage) -> Result<()> {
         match message {
-            FeedControlMessage::ClientJoined { client_id } => {
-                self.client_bitrates.insert(
+            FeedControlMessage::ClientJoined {
+                client_id,
+                group_tx,
+            } => {
+                self.clients.insert(
                     client_id,
-                    std::cmp::min(self.target_bitrate, self.config.start_bitrate),
+                    FeedClient {
+                        bitrate: self.config.start_bitrate,
+                        group: 0,
+                        group_tx,
+                        migrated: None,
+                    },
                 );
-                self.update_target_bitrate()?;
+                self.rebalance_pending = true;
             }
             FeedControlMessage::BandwidthEstimate { client_id, bitrate } => {
-                self.client_bitrates.insert(client_id, bitrate);
-                self.update_target_bitrate()?;
+                if let Some(client) = self.clients.get_mut(&client_id) {
+                    if pool::split_ratio(client.bitrate.max(bitrate), client.bitrate.min(bitrate))
+                        > ESTIMATE_HYSTERESIS
+                    {
+                        client.bitrate = bitrate;
+                        self.rebalance_pending = true;
+                    }
+                }
             }
             FeedControlMessage::ClientLeft { client_id } => {
-                self.client_bitrates.remove(&client_id);
-                self.update_target_bitrate()?;
+                self.clients.remove(&client_id);
+                self.rebalance_pending = true;
             }
-            FeedControlMessage::RequestKeyframe => {
-                self.force_keyframe = true;
+            FeedControlMessage::RequestKeyframe { client_id } => {
+                match self.clients.get(&client_id) {
+                    Some(client) => self.pool.request_keyframe(client.group),
+                    None => self.pool.request_keyframe_all(),
+                }
             }
+            FeedControlMessage::RetransmitStats {
+                client_id: _,
+                nacked,
+                retransmitted,
+                missed,
+            } => {
+                self.stats.track("nacked", nacked as _, " pkts");
+                self.stats
+                    .track("retransmitted", retransmitted as _, " pkts");
+                self.stats.track("rtx missed", missed as _, " pkts");
+            }
+            FeedControlMessage::Freeze => self.freeze()?,
+            FeedControlMessage::Unfreeze { fade } => self.unfreeze(fade)?,
+            FeedControlMessage::Zoom { region } => {
+                self.zoom.set(region);
+                self.annotations.set_view(self.zoom.target());
+            }
+            FeedControlMessage::Replay { clip } => self.start_replay(clip)?,
+            FeedControlMessage::StopReplay => self.stop_replay(),
         }
 
         Ok(())
     }
 
-    /// Compute the target bitrate as the minimum of all connected client
-    /// bitrates. If there are no clients, the target bitrate is set to
-    /// `config.start_bitrate`.
-    fn update_target_bitrate(&mut self) -> Result<()> {
-        self.target_bitrate = self.config.start_bitrate;
+    /// Hold the latest frame, re-encoding it at a reduced rate until
+    /// unfrozen.
+    fn freeze(&mut self) -> Result<()> {
+        if self.frozen.is_some() {
+            return Ok(());
+        }
+        let Some(frame) = self.last_frame.clone() else {
+            eprintln!("Nothing to freeze yet");
+            return Ok(());
+        };
 
-        let rate = RateParameters {
-            target_bitrate: self.target_bitrate,
-            max_fps: self.max_fps,
+        self.pool
+            .limit_rate(Some(RateParameters {
+                target_bitrate: FROZEN_MAX_BITRATE,
+                max_fps: FROZEN_FPS,
+            }))
+            .context("unable to limit rate for freeze-frame")?;
+        self.pool.request_keyframe_all();
+        self.frozen = Some(FrozenFrame {
+            frame,
+            since: Instant::now(),
+        });
+        println!("Feed frozen");
+        Ok(())
+    }
+
+    fn unfreeze(&mut self, fade: bool) -> Result<()> {
+        if self.frozen.take().is_none() {
+            return Ok(());
+        }
+
+        self.pool
+            .limit_rate(None)
+            .context("unable to restore rate after freeze-frame")?;
+        self.pool.request_keyframe_all();
+        if fade {
+            self.compositor.fade_out();
+        } else {
+            self.compositor.clear();
+        }
+        println!("Feed live");
+        Ok(())
+    }
+
+    /// Play a clip in place of the source. The clip is the encoded feed, so
+    /// whatever was zoomed into and drawn at the time is already in it.
+    fn start_replay(&mut self, clip: ReplayClip) -> Result<()> {
+        let Some(last_frame) = &self.last_frame else {
+            eprintln!("Nothing to replay into yet");
+            return Ok(());
         };
+        let playback =
+            ReplayPlayback::new(clip, last_frame.timestamp).context("unable to start replay")?;
+
+        // A replay takes over from freeze-frame, and isn't zoomed into again
+        self.unfreeze(false)?;
+        self.zoom.set(ZoomRegion::FULL);
+        self.annotations.set_view(self.zoom.target());
+        self.replay = Some(playback);
+        println!("Replaying");
+        Ok(())
+    }
+
+    fn stop_replay(&mut self) {
+        if self.replay.take().is_some() {
+            self.compositor.fade_out();
+            println!("Feed live");
+        }
+    }
+
+    /// The replay's next frame, if one is playing. Goes back to live once
+    /// it's over.
+    fn next_replay_frame(&mut self) -> Result<Option<VideoFrameBuffer>> {
+        let Some(replay) = &mut self.replay else {
+            return Ok(None);
+        };
+        let Some(frame) = replay.next_frame()? else {
+            self.stop_replay();
+            return Ok(None);
+        };
+        // So freeze-frame holds the replay rather than the source
+        self.last_frame = Some(frame.clone());
+        Ok(Some(frame))
+    }
+
+    /// Rebalance the groups if clients changed, at most once per
+    /// `REBALANCE_INTERVAL`.
+    fn rebalance_groups_if_due(&mut self) -> Result<()> {
+        let due = self
+            .last_rebalance
+            .is_none_or(|last| last.elapsed() >= REBALANCE_INTERVAL);
+        if !self.rebalance_pending || !due {
+            return Ok(());
+        }
+        self.last_rebalance = Some(Instant::now());
+        self.rebalance_pending = false;
+        self.rebalance_groups()
+    }
+
+    /// Cluster client bandwidth estimates and assign each cluster its own
+    /// encoder group. Clients whose group changed are migrated to the new
+    /// group's result channel, which is forced to emit a keyframe. Clients
+    /// migrated within `MIN_GROUP_DWELL` are left where they are until the
+    /// next rebalance, unless their group is gone or now sends more than
+    /// they can take.
+    ///
+    /// The highest cluster (and every pinned client) is served by the primary
+    /// group at `config.start_bitrate`. Lower clusters are encoded at the
+    /// lowest estimate in the cluster, clamped to the configured limits.
+    fn rebalance_groups(&mut self) -> Result<()> {
+        let (client_ids, estimates): (Vec<String>, Vec<u32>) = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.group_tx.is_some())
+            .map(|(client_id, client)| (client_id.clone(), client.bitrate))
+            .unzip();
+
+        let clusters = pool::cluster_bitrates(&estimates, self.pool.max_encoders());
+
+        let mut bitrates = vec![self.config.start_bitrate];
+        for cluster in clusters.iter().skip(1) {
+            let min_estimate = cluster.iter().map(|&i| estimates[i]).min().unwrap();
+            bitrates.push(min_estimate.clamp(
+                self.config.min_bitrate,
+                self.config.start_bitrate.min(self.config.max_bitrate),
+            ));
+        }
+
+        self.pool
+            .resize(&bitrates)
+            .context("unable to resize encoder pool")?;
+
+        for (group_idx, cluster) in clusters.iter().enumerate() {
+            for &i in cluster {
+                let Some(client) = self.clients.get_mut(&client_ids[i]) else {
+                    continue;
+                };
+                if client.group == group_idx {
+                    continue;
+                }
+                if client.dwelling(self.pool.groups()) {
+                    self.rebalance_pending = true;
+                    continue;
+                }
+
+                let group = self.pool.group(group_idx);
+                if let Some(group_tx) = &client.group_tx {
+                    group_tx.send_replace(group.feed_result_tx.clone());
+                }
+                println!(
+                    "Migrating client {} from group {} to group {group_idx}",
+                    client_ids[i], client.group
+                );
+                client.group = group_idx;
+                client.migrated = Some(Instant::now());
+                self.pool.request_keyframe(group_idx);
+            }
+        }
 
-        self.encoder
-            .set_rate(rate)
-            .context("unable to update rate parameters")?;
         Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migrated_client(group: usize, bitrate: u32) -> FeedClient {
+        FeedClient {
+            bitrate,
+            group,
+            group_tx: None,
+            migrated: Some(Instant::now()),
+        }
+    }
+
+    #[test]
+    fn dwelling_ends_when_the_group_outgrows_the_client() {
+        let mut pool = EncoderPool::new(
+            FeedEncoderConfig::OpenH264(Default::default()),
+            2,
+            broadcast::Sender::new(FEED_RESULT_CAPACITY),
+            RateParameters {
+                target_bitrate: 6_000_000,
+                max_fps: 30.,
+            },
+        )
+        .unwrap();
+        pool.resize(&[6_000_000, 1_000_000]).unwrap();
+
+        let client = migrated_client(1, 1_200_000);
+        assert!(client.dwelling(pool.groups()));
+
+        let settled = FeedClient {
+            migrated: None,
+            ..migrated_client(1, 1_200_000)
+        };
+        assert!(!settled.dwelling(pool.groups()));
+
+        // Group 1 now serves a faster cluster
+        pool.resize(&[6_000_000, 3_000_000]).unwrap();
+        assert!(!client.dwelling(pool.groups()));
 
-        // TODO: revise this implementation to make it prefer higher bitrates.
-        // Currently, it picks really low bitrates better suited for video calls.
-
-        // let min_client_bitrate = self
-        //     .client_bitrates
-        //     .values()
-        //     .min()
-        //     .unwrap_or(&self.config.start_bitrate)
-        //     .to_owned();
-
-        // self.target_bitrate = match (self.config.min_bitrate, self.config.max_bitrate) {
-        //     (0, 0) => min_client_bitrate,
-        //     (0, max) => min_client_bitrate.min(max),
-        //     (min, 0) => min_client_bitrate.max(min),
-        //     (min, max) => min_client_bitrate.clamp(min, max),
-        // };
-
-        // let rate = RateParameters {
-        //     target_bitrate: self.target_bitrate,
-        //     max_fps: self.max_fps,
-        // };
-
-        // self.encoder
-        //     .set_rate(rate)
-        //     .context("unable to update rate parameters")?;
-        // Ok(())
+        // Or is gone
+        pool.resize(&[6_000_000]).unwrap();
+        assert!(!client.dwelling(pool.groups()));
     }
 }
@@ -1,20 +1,37 @@
+pub mod audio;
+pub mod compositor;
 pub mod encoders;
+pub mod font;
 pub mod frame;
 pub mod manager;
+pub mod outputs;
+pub mod pool;
+pub mod replay;
 pub mod sources;
+pub mod zoom;
 
 use anyhow::Result;
 use tokio::sync::{broadcast, mpsc};
 
+use crate::annotations::hub::AnnotationHub;
+
 use self::manager::{FeedConfig, FeedControlMessage, FeedManager, FeedResultMessage};
 
 pub async fn main(
     config: FeedConfig,
     feed_control_rx: mpsc::Receiver<FeedControlMessage>,
     feed_result_tx: broadcast::Sender<FeedResultMessage>,
+    audio_tx: broadcast::Sender<FeedResultMessage>,
+    annotations: AnnotationHub,
 ) -> Result<()> {
     tokio::task::spawn_blocking(move || {
-        let mut manager = FeedManager::new(config, feed_control_rx, feed_result_tx)?;
+        let mut manager = FeedManager::new(
+            config,
+            feed_control_rx,
+            feed_result_tx,
+            audio_tx,
+            annotations,
+        )?;
         manager.run_forever()
     })
     .await??;
@@ -0,0 +1,245 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, SyncSender},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use crate::feed::{
+    encoders::h264,
+    frame::{Resolution, VideoFrameBuffer, VideoTimestamp},
+};
+
+/// Frames queued for the writer before the feed waits on it.
+const DUMP_QUEUE: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct DumpConfig {
+    /// Each run dumps into a new `dump-<UNIX time>` directory under this.
+    pub directory: PathBuf,
+    /// Write the frames the encoders are given to `frames.y4m`.
+    pub frames: bool,
+    /// Write each encoder group's output to `group-<n>.h264`.
+    pub bitstream: bool,
+}
+
+impl Default for DumpConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("dumps"),
+            frames: true,
+            bitstream: true,
+        }
+    }
+}
+
+enum DumpMessage {
+    Frame(VideoFrameBuffer),
+    Encoded {
+        group: usize,
+        timestamp: VideoTimestamp,
+        data: Bytes,
+    },
+}
+
+/// Tees what goes into and comes out of the encoders to disk, for debugging
+/// encoder artefacts. Each file gets a CSV sidecar with per-frame timestamps.
+///
+/// Nothing is dropped: if the disk can't keep up (raw 1080p60 is ~190 MB/s),
+/// the feed slows down instead.
+pub struct DebugDump {
+    config: DumpConfig,
+    tx: SyncSender<DumpMessage>,
+}
+
+impl DebugDump {
+    pub fn new(config: &DumpConfig) -> Result<Self> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let directory = config.directory.join(format!("dump-{now}"));
+        fs::create_dir_all(&directory)
+            .with_context(|| format!("unable to create {}", directory.display()))?;
+        println!("Dumping encoder input/output to {}", directory.display());
+
+        let (tx, rx) = mpsc::sync_channel(DUMP_QUEUE);
+        thread::spawn(move || {
+            let mut writer = DumpWriter::new(directory);
+            for message in rx {
+                if let Err(e) = writer.write(message) {
+                    eprintln!("Debug dump stopped: {e:#}");
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            config: config.clone(),
+            tx,
+        })
+    }
+
+    /// Dump a frame as it's given to the encoders.
+    pub fn frame(&self, frame: &VideoFrameBuffer) {
+        if self.config.frames {
+            self.tx.send(DumpMessage::Frame(frame.clone())).ok();
+        }
+    }
+
+    /// Dump an encoder group's output for a frame.
+    pub fn encoded(&self, group: usize, timestamp: VideoTimestamp, data: &Bytes) {
+        if self.config.bitstream {
+            self.tx
+                .send(DumpMessage::Encoded {
+                    group,
+                    timestamp,
+                    data: data.clone(),
+                })
+                .ok();
+        }
+    }
+}
+
+/// A dump file and its CSV sidecar.
+struct DumpFile {
+    data: BufWriter<File>,
+    sidecar: BufWriter<File>,
+    frames: u64,
+    /// Bytes written to `data`.
+    offset: u64,
+}
+
+impl DumpFile {
+    fn create(path: &Path, header: &[u8], sidecar_header: &str) -> Result<Self> {
+        let create = |path: &Path| {
+            File::create(path)
+                .map(BufWriter::new)
+                .with_context(|| format!("unable to create {}", path.display()))
+        };
+        let mut data = create(path)?;
+        data.write_all(header)?;
+        let mut sidecar = create(&path.with_extension("csv"))?;
+        writeln!(sidecar, "{sidecar_header}")?;
+        Ok(Self {
+            data,
+            sidecar,
+            frames: 0,
+            offset: header.len() as u64,
+        })
+    }
+
+    /// Flushed after every frame, so a dump survives the crash it's
+    /// capturing.
+    fn flush(&mut self) -> Result<()> {
+        self.data.flush()?;
+        self.sidecar.flush()?;
+        Ok(())
+    }
+}
+
+struct DumpWriter {
+    directory: PathBuf,
+    /// Y4M can't change resolution, so a new file is started when it does.
+    y4m: Option<(Resolution, DumpFile)>,
+    y4m_files: usize,
+    /// By encoder group.
+    h264: HashMap<usize, DumpFile>,
+}
+
+impl DumpWriter {
+    fn new(directory: PathBuf) -> Self {
+        Self {
+            directory,
+            y4m: None,
+            y4m_files: 0,
+            h264: HashMap::new(),
+        }
+    }
+
+    fn write(&mut self, message: DumpMessage) -> Result<()> {
+        match message {
+            DumpMessage::Frame(frame) => self.write_frame(&frame),
+            DumpMessage::Encoded {
+                group,
+                timestamp,
+                data,
+            } => self.write_encoded(group, timestamp, &data),
+        }
+    }
+
+    fn write_frame(&mut self, frame: &VideoFrameBuffer) -> Result<()> {
+        let frame = frame.to_i420()?;
+        let resolution = frame.resolution();
+
+        if self.y4m.as_ref().map(|(r, _)| *r) != Some(resolution) {
+            self.y4m_files += 1;
+            let name = match self.y4m_files {
+                1 => "frames.y4m".to_owned(),
+                n => format!("frames-{n}.y4m"),
+            };
+            let header = format!(
+                "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C420jpeg\n",
+                frame.width, frame.height, frame.framerate.num, frame.framerate.den
+            );
+            let file = DumpFile::create(
+                &self.directory.join(name),
+                header.as_bytes(),
+                "frame,timestamp_us",
+            )?;
+            self.y4m = Some((resolution, file));
+        }
+        let (_, file) = self.y4m.as_mut().unwrap();
+
+        let (y, u, v) = frame.yuv_slices();
+        file.data.write_all(b"FRAME\n")?;
+        for plane in [y, u, v] {
+            file.data.write_all(plane)?;
+        }
+        writeln!(
+            file.sidecar,
+            "{},{}",
+            file.frames,
+            frame.timestamp.to_micros()
+        )?;
+        file.frames += 1;
+        file.flush()
+    }
+
+    fn write_encoded(
+        &mut self,
+        group: usize,
+        timestamp: VideoTimestamp,
+        data: &[u8],
+    ) -> Result<()> {
+        let file = match self.h264.entry(group) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(DumpFile::create(
+                &self.directory.join(format!("group-{group}.h264")),
+                &[],
+                "frame,timestamp_us,keyframe,offset,size",
+            )?),
+        };
+
+        // Encoder output is already Annex-B
+        file.data.write_all(data)?;
+        writeln!(
+            file.sidecar,
+            "{},{},{},{},{}",
+            file.frames,
+            timestamp.to_micros(),
+            h264::is_keyframe(data),
+            file.offset,
+            data.len()
+        )?;
+        file.frames += 1;
+        file.offset += data.len() as u64;
+        file.flush()
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread,
+};
+
+use anyhow::{Context, Result};
+use bytes::Bytes;
+
+use crate::feed::{
+    compositor::Compositor,
+    frame::{VideoFrameBuffer, VideoFramePixelFormat},
+};
+
+#[derive(Debug, Clone)]
+pub struct NDIOutputConfig {
+    /// Sources are published as "<name> - <feed>" for the program and
+    /// "<name> - <feed> Annotations" for the annotation layer, where <feed>
+    /// is the name of the feed's source.
+    pub name: String,
+    /// Also publish the program (the source with the annotations and laser
+    /// pointers burned in, zoomed), not just the annotations.
+    pub program: bool,
+}
+
+impl Default for NDIOutputConfig {
+    fn default() -> Self {
+        Self {
+            name: "Telestrator".to_owned(),
+            program: true,
+        }
+    }
+}
+
+/// Publishes the annotation layer as an NDI source with alpha, so a switcher
+/// can key the drawings over its own feed, and the finished program as a
+/// regular NDI source.
+pub struct NDIOutput {
+    annotations: NDISender,
+    program: Option<NDISender>,
+}
+
+impl NDIOutput {
+    pub fn new(config: &NDIOutputConfig, feed_name: &str) -> Result<Self> {
+        ndi::initialize()?;
+        let name = format!("{} - {feed_name}", config.name);
+        let annotations = NDISender::spawn(format!("{name} Annotations"))?;
+        let program = match config.program {
+            true => Some(NDISender::spawn(name)?),
+            false => None,
+        };
+        Ok(Self {
+            annotations,
+            program,
+        })
+    }
+
+    /// Publish the annotation layer for `frame`, the composited (but not yet
+    /// zoomed) frame. The compositor provides the layer it was composited
+    /// with, in source space.
+    pub fn send_annotations(&mut self, frame: &VideoFrameBuffer, compositor: &mut Compositor) {
+        if let Some(data) = compositor.layer_bgra() {
+            self.annotations.send(NDIFrame {
+                data,
+                fourcc: ndi::FourCCVideoType::BGRA,
+                width: frame.width,
+                height: frame.height,
+                line_stride: frame.width * 4,
+                framerate: (frame.framerate.num, frame.framerate.den),
+                timecode: timecode(frame),
+            });
+        }
+    }
+
+    /// Publish the program, i.e. exactly what's given to the encoders.
+    pub fn send_program(&mut self, frame: &VideoFrameBuffer) {
+        let Some(program) = &mut self.program else {
+            return;
+        };
+        let (fourcc, line_stride) = match frame.pix_fmt {
+            VideoFramePixelFormat::UYVY => (ndi::FourCCVideoType::UYVY, frame.line_stride),
+            VideoFramePixelFormat::I420 => (ndi::FourCCVideoType::I420, frame.width),
+        };
+        program.send(NDIFrame {
+            data: frame.data.clone(),
+            fourcc,
+            width: frame.width,
+            height: frame.height,
+            line_stride,
+            framerate: (frame.framerate.num, frame.framerate.den),
+            timecode: timecode(frame),
+        });
+    }
+}
+
+/// The source's timestamp, in NDI's 100ns units, so receivers can line the
+/// outputs up with the input.
+fn timecode(frame: &VideoFrameBuffer) -> i64 {
+    frame.timestamp.to_micros() as i64 * 10
+}
+
+struct NDIFrame {
+    data: Bytes,
+    fourcc: ndi::FourCCVideoType,
+    width: usize,
+    height: usize,
+    line_stride: usize,
+    framerate: (u32, u32),
+    /// In 100ns units.
+    timecode: i64,
+}
+
+/// An NDI sender running on its own thread, so compressing frames doesn't
+/// hold up the encoders.
+struct NDISender {
+    name: String,
+    frames_tx: SyncSender<NDIFrame>,
+    dropped: usize,
+}
+
+impl NDISender {
+    fn spawn(name: String) -> Result<Self> {
+        // Only one frame in flight. Anything more is dropped, since a live
+        // output shouldn't queue up.
+        let (frames_tx, frames_rx) = mpsc::sync_channel::<NDIFrame>(1);
+        let (ready_tx, ready_rx) = mpsc::channel();
+
+        let thread_name = name.clone();
+        thread::spawn(move || {
+            let send = match ndi::SendBuilder::new()
+                .ndi_name(thread_name)
+                .clock_video(false)
+                .build()
+            {
+                Ok(send) => {
+                    ready_tx.send(Ok(())).ok();
+                    send
+                }
+                Err(e) => {
+                    ready_tx.send(Err(e)).ok();
+                    return;
+                }
+            };
+
+            // NDI wants a mutable buffer, so frames are copied into this one
+            let mut buffer = vec![];
+            while let Ok(frame) = frames_rx.recv() {
+                buffer.clear();
+                buffer.extend_from_slice(&frame.data);
+                let video_data = ndi::VideoData::from_buffer(
+                    frame.width as _,
+                    frame.height as _,
+                    frame.fourcc,
+                    frame.framerate.0 as _,
+                    frame.framerate.1 as _,
+                    ndi::FrameFormatType::Progressive,
+                    frame.timecode,
+                    frame.line_stride as _,
+                    None,
+                    &mut buffer,
+                );
+                send.send_video(&video_data);
+            }
+        });
+
+        ready_rx
+            .recv()
+            .context("NDI sender thread exited")?
+            .with_context(|| format!("Unable to create NDI sender {name}"))?;
+        println!("Publishing NDI source {name}");
+
+        Ok(Self {
+            name,
+            frames_tx,
+            dropped: 0,
+        })
+    }
+
+    fn send(&mut self, frame: NDIFrame) {
+        match self.frames_tx.try_send(frame) {
+            Ok(()) => self.dropped = 0,
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                if self.dropped == 30 {
+                    eprintln!("NDI output {} is falling behind", self.name);
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
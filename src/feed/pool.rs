@@ -0,0 +1,311 @@
+use anyhow::{Context, Result};
+use tokio::sync::broadcast;
+
+use super::{
+    encoders::{
+        FeedEncoder, FeedEncoderConfig, FeedEncoderConfigImpl, FeedEncoderImpl, RateParameters,
+    },
+    manager::{FeedResultMessage, FEED_RESULT_CAPACITY},
+};
+
+/// Adjacent bandwidth estimates further apart than this ratio are considered
+/// to belong to separate groups.
+const GROUP_SPLIT_RATIO: f32 = 2.;
+
+/// One encoder instance, and the broadcast channel its clients listen on.
+pub struct EncoderGroup {
+    pub encoder: FeedEncoder,
+    pub feed_result_tx: broadcast::Sender<FeedResultMessage>,
+
+    pub target_bitrate: u32,
+    pub force_keyframe: bool,
+}
+
+impl EncoderGroup {
+    fn new(
+        config: &FeedEncoderConfig,
+        feed_result_tx: broadcast::Sender<FeedResultMessage>,
+        rate: RateParameters,
+    ) -> Result<Self> {
+        let target_bitrate = rate.target_bitrate;
+        let encoder = config.build(rate)?;
+        Ok(Self {
+            encoder,
+            feed_result_tx,
+            target_bitrate,
+            force_keyframe: true,
+        })
+    }
+}
+
+/// Pool of encoders, one per cluster of client bandwidth estimates.
+///
+/// Group 0 always exists, always publishes on the primary result channel and
+/// always serves the highest bitrate cluster. Additional groups are spun up
+/// on demand, up to `max_encoders`.
+pub struct EncoderPool {
+    encoder_config: FeedEncoderConfig,
+    max_encoders: usize,
+    max_fps: f32,
+    /// Overrides the frame rate and caps the bitrate of every group.
+    limit: Option<RateParameters>,
+
+    groups: Vec<EncoderGroup>,
+}
+
+impl EncoderPool {
+    pub fn new(
+        encoder_config: FeedEncoderConfig,
+        max_encoders: usize,
+        feed_result_tx: broadcast::Sender<FeedResultMessage>,
+        rate: RateParameters,
+    ) -> Result<Self> {
+        let max_fps = rate.max_fps;
+        let primary = EncoderGroup::new(&encoder_config, feed_result_tx, rate)?;
+        Ok(Self {
+            encoder_config,
+            max_encoders: max_encoders.max(1),
+            max_fps,
+            limit: None,
+            groups: vec![primary],
+        })
+    }
+
+    pub fn groups(&self) -> &[EncoderGroup] {
+        &self.groups
+    }
+
+    pub fn groups_mut(&mut self) -> &mut [EncoderGroup] {
+        &mut self.groups
+    }
+
+    pub fn group(&self, idx: usize) -> &EncoderGroup {
+        &self.groups[idx]
+    }
+
+    pub fn max_encoders(&self) -> usize {
+        self.max_encoders
+    }
+
+    /// Force a keyframe on the next frame of the given group.
+    pub fn request_keyframe(&mut self, idx: usize) {
+        if let Some(group) = self.groups.get_mut(idx) {
+            group.force_keyframe = true;
+        }
+    }
+
+    /// Force a keyframe on the next frame of every group.
+    pub fn request_keyframe_all(&mut self) {
+        self.groups
+            .iter_mut()
+            .for_each(|group| group.force_keyframe = true);
+    }
+
+    /// Limit every group to `limit`, or lift the limit. Used while the feed
+    /// is frozen, since a still frame needs neither the rate nor the bits.
+    pub fn limit_rate(&mut self, limit: Option<RateParameters>) -> Result<()> {
+        self.limit = limit;
+        for idx in 0..self.groups.len() {
+            let rate = self.rate(self.groups[idx].target_bitrate);
+            self.groups[idx]
+                .encoder
+                .set_rate(rate)
+                .with_context(|| format!("unable to update rate for group {idx}"))?;
+        }
+        Ok(())
+    }
+
+    /// Rate parameters for a group targeting `target_bitrate`.
+    fn rate(&self, target_bitrate: u32) -> RateParameters {
+        match self.limit {
+            Some(limit) => RateParameters {
+                target_bitrate: target_bitrate.min(limit.target_bitrate),
+                max_fps: limit.max_fps,
+            },
+            None => RateParameters {
+                target_bitrate,
+                max_fps: self.max_fps,
+            },
+        }
+    }
+
+    /// Resize the pool to the provided per-group bitrates, building or
+    /// dropping encoders as required. `bitrates[0]` is the primary group.
+    pub fn resize(&mut self, bitrates: &[u32]) -> Result<()> {
+        let count = bitrates.len().clamp(1, self.max_encoders);
+
+        if self.groups.len() > count {
+            self.groups.truncate(count);
+        }
+
+        while self.groups.len() < count {
+            let idx = self.groups.len();
+            let mut group = EncoderGroup::new(
+                &self.encoder_config,
+                broadcast::Sender::new(FEED_RESULT_CAPACITY),
+                self.rate(bitrates[idx]),
+            )
+            .with_context(|| format!("unable to build encoder for group {idx}"))?;
+            // Remember the target, not the limited rate
+            group.target_bitrate = bitrates[idx];
+            println!("Started encoder group {idx} at {} b/s", bitrates[idx]);
+            self.groups.push(group);
+        }
+
+        for idx in 0..self.groups.len() {
+            if self.groups[idx].target_bitrate == bitrates[idx] {
+                continue;
+            }
+            let rate = self.rate(bitrates[idx]);
+            let group = &mut self.groups[idx];
+            group
+                .encoder
+                .set_rate(rate)
+                .with_context(|| format!("unable to update rate for group {idx}"))?;
+            group.target_bitrate = bitrates[idx];
+        }
+
+        Ok(())
+    }
+}
+
+/// Split bandwidth estimates into clusters, highest bitrate first.
+///
+/// Estimates are sorted and split wherever two neighbours are more than
+/// `GROUP_SPLIT_RATIO` apart. While there are more clusters than
+/// `max_clusters`, the two closest neighbouring clusters are merged. Returns
+/// the indices into `bitrates` that make up each cluster.
+pub fn cluster_bitrates(bitrates: &[u32], max_clusters: usize) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..bitrates.len()).collect();
+    order.sort_by(|&a, &b| bitrates[b].cmp(&bitrates[a]));
+
+    let mut clusters: Vec<Vec<usize>> = vec![];
+    for idx in order {
+        match clusters.last_mut() {
+            Some(cluster)
+                if split_ratio(bitrates[*cluster.last().unwrap()], bitrates[idx])
+                    < GROUP_SPLIT_RATIO =>
+            {
+                cluster.push(idx)
+            }
+            _ => clusters.push(vec![idx]),
+        }
+    }
+
+    while clusters.len() > max_clusters.max(1) {
+        // Merge the pair of neighbouring clusters with the smallest gap.
+        let (merge_at, _) = clusters
+            .windows(2)
+            .map(|pair| {
+                split_ratio(
+                    bitrates[*pair[0].last().unwrap()],
+                    bitrates[*pair[1].first().unwrap()],
+                )
+            })
+            .enumerate()
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap();
+        let next = clusters.remove(merge_at + 1);
+        clusters[merge_at].extend(next);
+    }
+
+    clusters
+}
+
+/// How many times `high` is `low`, treating zero as 1 b/s.
+pub fn split_ratio(high: u32, low: u32) -> f32 {
+    high.max(1) as f32 / low.max(1) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::feed::{encoders::EncodedFrame, frame::VideoTimestamp, manager::FeedGroupReceiver};
+
+    #[test]
+    fn clusters_split_at_ratio() {
+        let clusters = cluster_bitrates(&[1_000_000, 6_000_000, 5_000_000, 900_000], 4);
+        assert_eq!(clusters, vec![vec![1, 2], vec![0, 3]]);
+    }
+
+    #[test]
+    fn close_estimates_share_a_cluster() {
+        let clusters = cluster_bitrates(&[4_000_000, 3_000_000, 2_500_000], 4);
+        assert_eq!(clusters, vec![vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn closest_clusters_merge_down_to_max() {
+        // 8M, 3M, 1M and 100k are all more than 2x apart, and 8M and 3M are
+        // the closest pair
+        let bitrates = [100_000, 8_000_000, 1_000_000, 3_000_000];
+        assert_eq!(cluster_bitrates(&bitrates, 4).len(), 4);
+        assert_eq!(
+            cluster_bitrates(&bitrates, 3),
+            vec![vec![1, 3], vec![2], vec![0]]
+        );
+        assert_eq!(cluster_bitrates(&bitrates, 0), vec![vec![1, 3, 2, 0]]);
+    }
+
+    #[test]
+    fn zero_estimates_dont_divide_by_zero() {
+        assert_eq!(cluster_bitrates(&[0, 0], 2), vec![vec![0, 1]]);
+        assert_eq!(cluster_bitrates(&[], 2), Vec::<Vec<usize>>::new());
+    }
+
+    fn rate(target_bitrate: u32) -> RateParameters {
+        RateParameters {
+            target_bitrate,
+            max_fps: 30.,
+        }
+    }
+
+    fn send_frame(group: &EncoderGroup) {
+        let frame = EncodedFrame::new(Bytes::new(), VideoTimestamp::from_micros(0));
+        group
+            .feed_result_tx
+            .send(FeedResultMessage::EncodedBitstream(frame))
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn subscribers_follow_their_group_when_the_pool_shrinks() {
+        let mut pool = EncoderPool::new(
+            FeedEncoderConfig::OpenH264(Default::default()),
+            2,
+            broadcast::Sender::new(FEED_RESULT_CAPACITY),
+            rate(6_000_000),
+        )
+        .unwrap();
+
+        // Both channels become ready at once, so give a wrong pick plenty of
+        // chances to show
+        for _ in 0..20 {
+            pool.resize(&[6_000_000, 1_000_000]).unwrap();
+            let (group_tx, mut rx) = FeedGroupReceiver::new(pool.group(1).feed_result_tx.clone());
+            send_frame(pool.group(1));
+            assert!(rx.recv().await.is_ok());
+
+            // The manager drops the group, then moves its clients, which
+            // drops the group's last sender
+            pool.resize(&[6_000_000]).unwrap();
+            group_tx.send_replace(pool.group(0).feed_result_tx.clone());
+            let waiting = tokio::time::timeout(Duration::from_millis(5), rx.recv()).await;
+            assert!(waiting.is_err(), "gave up on the feed: {waiting:?}");
+
+            send_frame(pool.group(0));
+            assert!(rx.recv().await.is_ok());
+
+            // Only once the client's gone is it closed
+            drop(group_tx);
+            assert!(matches!(
+                rx.recv().await,
+                Err(broadcast::error::RecvError::Closed)
+            ));
+        }
+    }
+}
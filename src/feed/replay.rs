@@ -0,0 +1,117 @@
+use std::{
+    fmt, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use openh264::{
+    decoder::{Decoder, DecoderConfig},
+    OpenH264API,
+};
+
+use super::{
+    encoders::EncodedFrame,
+    frame::{VideoFrameBuffer, VideoFramerate, VideoTimestamp},
+};
+
+/// Longest the replay waits between two frames. Longer gaps in the clip
+/// (e.g. the source stalled) are skipped over.
+const MAX_FRAME_GAP: Duration = Duration::from_millis(500);
+
+/// A stretch of the encoded feed to play back in place of the source.
+#[derive(Clone)]
+pub struct ReplayClip {
+    /// Starts with a keyframe.
+    pub frames: Vec<EncodedFrame>,
+    /// Frames before this are only decoded, as references for the ones
+    /// after.
+    pub start: VideoTimestamp,
+}
+
+impl fmt::Debug for ReplayClip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplayClip")
+            .field("frames", &self.frames.len())
+            .field("start", &self.start)
+            .finish()
+    }
+}
+
+/// Decodes a clip back into frames, at the pace it was encoded at.
+pub struct ReplayPlayback {
+    clip: ReplayClip,
+    /// Index of the next frame to decode.
+    next: usize,
+    decoder: Decoder,
+    /// Timestamp the replay started at. Frames carry on from there in real
+    /// time, so the stream keeps advancing as if it were live.
+    timestamp: VideoTimestamp,
+    since: Instant,
+    /// When the last frame was shown, and its timestamp in the clip.
+    last_shown: Option<(Instant, VideoTimestamp)>,
+}
+
+impl ReplayPlayback {
+    /// Play `clip` with timestamps carrying on from `timestamp`.
+    pub fn new(clip: ReplayClip, timestamp: VideoTimestamp) -> Result<Self> {
+        let api = OpenH264API::from_source();
+        let decoder = Decoder::with_api_config(api, DecoderConfig::new())
+            .context("Unable to create OpenH264 decoder")?;
+        Ok(Self {
+            clip,
+            next: 0,
+            decoder,
+            timestamp,
+            since: Instant::now(),
+            last_shown: None,
+        })
+    }
+
+    /// The next frame of the clip, blocking until it's due. `None` once the
+    /// clip is over.
+    pub fn next_frame(&mut self) -> Result<Option<VideoFrameBuffer>> {
+        while let Some(encoded) = self.clip.frames.get(self.next) {
+            self.next += 1;
+            let yuv = match self.decoder.decode(&encoded.data) {
+                Ok(Some(yuv)) => yuv,
+                Ok(None) => continue,
+                Err(e) => {
+                    eprintln!("Unable to decode replay frame: {e}");
+                    continue;
+                }
+            };
+            if encoded.timestamp < self.clip.start {
+                continue;
+            }
+
+            let gap = match self.last_shown {
+                Some((_, last)) if encoded.timestamp > last => {
+                    Duration::from_micros(encoded.timestamp.to_micros() - last.to_micros())
+                        .min(MAX_FRAME_GAP)
+                }
+                _ => Duration::ZERO,
+            };
+            let framerate = match gap.as_micros() {
+                0 => VideoFramerate::new(30, 1),
+                micros => VideoFramerate::new(1_000_000, micros as u32),
+            };
+            let mut frame = VideoFrameBuffer::from_decoded(&yuv, encoded.timestamp, framerate);
+
+            // Paced off when the last frame was due rather than when it
+            // went out, so encoding time doesn't add up. After a pause (e.g.
+            // a freeze-frame) the replay picks up from now.
+            let now = Instant::now();
+            let due = match self.last_shown {
+                Some((shown, _)) if shown + gap + MAX_FRAME_GAP >= now => shown + gap,
+                _ => now,
+            };
+            thread::sleep(due.saturating_duration_since(now));
+            self.last_shown = Some((due, encoded.timestamp));
+
+            let elapsed = self.since.elapsed().as_micros() as u64;
+            frame.timestamp = VideoTimestamp::from_micros(self.timestamp.to_micros() + elapsed);
+            return Ok(Some(frame));
+        }
+        Ok(None)
+    }
+}
@@ -1,27 +1,71 @@
 pub mod ndi;
+pub mod whip;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use self::ndi::NDIFeedSource;
+use self::{
+    ndi::{NDIFeedSource, NDIFeedSourceConfig},
+    whip::{WhipFeedSource, WhipFeedSourceConfig},
+};
 
-use super::frame::VideoFrameBuffer;
+use super::frame::{AudioFrameBuffer, VideoFrameBuffer};
 
 #[enum_delegate::register]
 pub trait FeedSourceImpl {
     fn get_frame(&mut self) -> Result<Option<VideoFrameBuffer>>;
+    /// Audio received since the last call, in order.
+    fn take_audio(&mut self) -> Vec<AudioFrameBuffer>;
 }
 
 #[enum_delegate::implement(FeedSourceImpl)]
 pub enum FeedSource {
     NDI(NDIFeedSource),
+    WHIP(WhipFeedSource),
 }
 
 #[enum_delegate::register]
 pub trait FeedSourceConfigImpl {
     fn build(&self) -> Result<FeedSource>;
+    /// Human readable name of the source, e.g. for naming outputs after it.
+    fn name(&self) -> String;
 }
 
 #[enum_delegate::implement(FeedSourceConfigImpl)]
 pub enum FeedSourceConfig {
-    NDI(ndi::NDIFeedSourceConfig),
+    NDI(NDIFeedSourceConfig),
+    WHIP(WhipFeedSourceConfig),
+}
+
+/// Prompt for a source on stdin. Lists every NDI source on the network, and
+/// WHIP ingest if it's available.
+pub fn build_interactive(whip: Option<WhipFeedSourceConfig>) -> Result<FeedSourceConfig> {
+    let mut ndi_sources = NDIFeedSourceConfig::discover().unwrap_or_else(|e| {
+        println!("{e:#}");
+        vec![]
+    });
+    if ndi_sources.is_empty() && whip.is_none() {
+        anyhow::bail!("No sources are available");
+    }
+
+    println!("Available sources:");
+    ndi_sources
+        .iter()
+        .enumerate()
+        .for_each(|(i, s)| println!("{i}) {}", s.name()));
+    if whip.is_some() {
+        println!("{}) WHIP ingest (POST /whip)", ndi_sources.len());
+    }
+
+    let stdin = std::io::stdin();
+    let mut buf = String::new();
+    stdin.read_line(&mut buf)?;
+    let i = buf.trim_end().parse::<usize>()?;
+
+    if i < ndi_sources.len() {
+        return Ok(FeedSourceConfig::NDI(ndi_sources.swap_remove(i)));
+    }
+    match whip {
+        Some(whip) if i == ndi_sources.len() => Ok(FeedSourceConfig::WHIP(whip)),
+        _ => None.context("Invalid source selected"),
+    }
 }
@@ -2,11 +2,13 @@ use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use bytes::Bytes;
-use ndi::VideoData;
+use ndi::{AudioData, VideoData};
 
-use crate::feed::frame::{VideoFrameBuffer, VideoFramePixelFormat, VideoFramerate, VideoTimestamp};
+use crate::feed::frame::{
+    AudioFrameBuffer, VideoFrameBuffer, VideoFramePixelFormat, VideoFramerate, VideoTimestamp,
+};
 
-use super::{FeedSource, FeedSourceConfig, FeedSourceConfigImpl, FeedSourceImpl};
+use super::{FeedSource, FeedSourceConfigImpl, FeedSourceImpl};
 
 pub struct NDIFeedSourceConfig {
     source: ndi::Source,
@@ -18,33 +20,28 @@ impl FeedSourceConfigImpl for NDIFeedSourceConfig {
         let source = NDIFeedSource::new(self)?;
         Ok(FeedSource::NDI(source))
     }
+
+    fn name(&self) -> String {
+        self.source.get_name()
+    }
 }
 
 impl NDIFeedSourceConfig {
-    pub fn build_interactive() -> Result<FeedSourceConfig> {
+    /// Find the NDI sources currently on the network.
+    pub fn discover() -> Result<Vec<NDIFeedSourceConfig>> {
         ndi::initialize()?;
         let find = ndi::Find::new()?;
         let sources = find
             .current_sources(2000)
             .context("No NDI sources were found within the timeout")?;
 
-        println!("Available sources:");
-        sources
-            .iter()
-            .enumerate()
-            .for_each(|(i, s)| println!("{i}) {}", s.get_name()));
-
-        let stdin = std::io::stdin();
-        let mut buf = String::new();
-        stdin.read_line(&mut buf)?;
-        let i = buf.trim_end().parse::<usize>()?;
-
-        let source = sources[i].clone();
-
-        return Ok(FeedSourceConfig::NDI(Self {
-            source,
-            recv_timeout: 1000,
-        }));
+        Ok(sources
+            .into_iter()
+            .map(|source| Self {
+                source,
+                recv_timeout: 1000,
+            })
+            .collect())
     }
 }
 
@@ -52,6 +49,8 @@ pub struct NDIFeedSource {
     recv: ndi::Recv,
     ndi_video_data: Option<VideoData>,
     recv_timeout: Duration,
+    /// Audio captured while waiting for video.
+    audio_frames: Vec<AudioFrameBuffer>,
 }
 
 impl NDIFeedSource {
@@ -68,6 +67,7 @@ impl NDIFeedSource {
                 .context("Unable to build NDI receiver")?,
             ndi_video_data: None,
             recv_timeout: Duration::from_millis(config.recv_timeout),
+            audio_frames: vec![],
         })
     }
 }
@@ -77,10 +77,19 @@ impl FeedSourceImpl for NDIFeedSource {
     fn get_frame(&mut self) -> Result<Option<VideoFrameBuffer>> {
         let start = Instant::now();
         while start.elapsed() < self.recv_timeout {
-            let response = self.recv.capture_video(&mut self.ndi_video_data, 100);
+            let mut audio_data = None;
+            let response =
+                self.recv
+                    .capture_all(&mut self.ndi_video_data, &mut audio_data, &mut None, 100);
 
             match response {
                 ndi::FrameType::Video => {}
+                ndi::FrameType::Audio => {
+                    if let Some(frame) = audio_data.as_ref().and_then(audio_frame) {
+                        self.audio_frames.push(frame);
+                    }
+                    continue;
+                }
                 ndi::FrameType::ErrorFrame => bail!("NDI error frame received."),
                 _ => continue,
             }
@@ -120,6 +129,41 @@ impl FeedSourceImpl for NDIFeedSource {
         }
         return Ok(None);
     }
+
+    fn take_audio(&mut self) -> Vec<AudioFrameBuffer> {
+        std::mem::take(&mut self.audio_frames)
+    }
+}
+
+/// Interleave a planar (FLTP) NDI audio frame, if it has any samples.
+fn audio_frame(audio_data: &AudioData) -> Option<AudioFrameBuffer> {
+    let channels = audio_data.no_channels() as usize;
+    let samples = audio_data.no_samples() as usize;
+    let stride = audio_data.channel_stride_in_bytes() as usize / std::mem::size_of::<f32>();
+    if audio_data.p_data().is_null() || samples == 0 {
+        return None;
+    }
+    // Each channel's samples have to fit in its stride for the reads below
+    assert!(
+        samples <= stride,
+        "{samples} samples overrun a stride of {stride}"
+    );
+
+    let planar =
+        unsafe { std::slice::from_raw_parts(audio_data.p_data() as *const f32, channels * stride) };
+    let mut data = Vec::with_capacity(channels * samples);
+    for sample in 0..samples {
+        for channel in 0..channels {
+            data.push(planar[channel * stride + sample]);
+        }
+    }
+
+    Some(AudioFrameBuffer {
+        sample_rate: audio_data.sample_rate(),
+        channels,
+        timestamp: VideoTimestamp::from_micros((audio_data.timestamp().unwrap_or(0) / 10) as u64),
+        data,
+    })
 }
 
 impl TryFrom<ndi::FourCCVideoType> for VideoFramePixelFormat {
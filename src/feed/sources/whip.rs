@@ -0,0 +1,214 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::Bytes;
+use openh264::{
+    decoder::{Decoder, DecoderConfig},
+    OpenH264API,
+};
+use tokio::sync::Notify;
+
+use crate::feed::frame::{AudioFrameBuffer, VideoFrameBuffer, VideoFramerate, VideoTimestamp};
+
+use super::{FeedSource, FeedSourceConfig, FeedSourceConfigImpl, FeedSourceImpl};
+
+/// Access units buffered between the publisher and the decoder. Anything
+/// beyond this is dropped, since a live source shouldn't queue up.
+const INGEST_QUEUE_SIZE: usize = 8;
+
+/// One H.264 access unit (Annex-B) received from a WHIP publisher.
+pub struct WhipSample {
+    pub data: Bytes,
+    pub timestamp: VideoTimestamp,
+}
+
+/// The remote half of a WHIP source. Publishers push their depacketized
+/// access units through this.
+#[derive(Clone)]
+pub struct WhipIngest {
+    samples_tx: SyncSender<WhipSample>,
+    keyframe_request: Arc<Notify>,
+    consumed: Arc<AtomicBool>,
+    publisher: Arc<Mutex<Option<String>>>,
+}
+
+impl WhipIngest {
+    /// Whether the feed is actually reading from this ingest.
+    pub fn is_consumed(&self) -> bool {
+        self.consumed.load(Ordering::Relaxed)
+    }
+
+    /// Claim the ingest for a publisher. Returns false if another publisher
+    /// is already live.
+    pub fn claim(&self, client_id: &str) -> bool {
+        let mut publisher = self.publisher.lock().unwrap();
+        if publisher.is_some() {
+            return false;
+        }
+        *publisher = Some(client_id.to_owned());
+        true
+    }
+
+    /// Release the ingest, if it's held by `client_id`.
+    pub fn release(&self, client_id: &str) {
+        let mut publisher = self.publisher.lock().unwrap();
+        if publisher.as_deref() == Some(client_id) {
+            *publisher = None;
+        }
+    }
+
+    pub fn publisher(&self) -> Option<String> {
+        self.publisher.lock().unwrap().clone()
+    }
+
+    /// Queue an access unit for decoding, dropping it if the feed is behind.
+    pub fn push(&self, sample: WhipSample) {
+        match self.samples_tx.try_send(sample) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                // The decoder needs a fresh reference after a dropped frame.
+                self.keyframe_request.notify_one();
+            }
+        }
+    }
+
+    /// Resolves when the decoder wants the publisher to send a keyframe.
+    pub async fn keyframe_requested(&self) {
+        self.keyframe_request.notified().await
+    }
+}
+
+pub struct WhipFeedSourceConfig {
+    samples_rx: Mutex<Option<Receiver<WhipSample>>>,
+    keyframe_request: Arc<Notify>,
+    consumed: Arc<AtomicBool>,
+    recv_timeout: u64,
+}
+
+impl WhipFeedSourceConfig {
+    /// Create a WHIP source, and the ingest handle publishers feed it with.
+    pub fn new() -> (Self, WhipIngest) {
+        let (samples_tx, samples_rx) = mpsc::sync_channel(INGEST_QUEUE_SIZE);
+        let keyframe_request = Arc::new(Notify::new());
+        let consumed = Arc::new(AtomicBool::new(false));
+
+        let config = Self {
+            samples_rx: Mutex::new(Some(samples_rx)),
+            keyframe_request: keyframe_request.clone(),
+            consumed: consumed.clone(),
+            recv_timeout: 1000,
+        };
+        let ingest = WhipIngest {
+            samples_tx,
+            keyframe_request,
+            consumed,
+            publisher: Arc::new(Mutex::new(None)),
+        };
+        (config, ingest)
+    }
+}
+
+impl FeedSourceConfigImpl for WhipFeedSourceConfig {
+    fn build(&self) -> Result<FeedSource> {
+        let source = WhipFeedSource::new(self)?;
+        Ok(FeedSource::WHIP(source))
+    }
+
+    fn name(&self) -> String {
+        "WHIP".to_owned()
+    }
+}
+
+pub struct WhipFeedSource {
+    samples_rx: Receiver<WhipSample>,
+    keyframe_request: Arc<Notify>,
+    consumed: Arc<AtomicBool>,
+    recv_timeout: Duration,
+
+    decoder: Decoder,
+    last_timestamp: Option<u64>,
+}
+
+impl WhipFeedSource {
+    /// Construct a WhipFeedSource reading from the config's ingest
+    pub fn new(config: &WhipFeedSourceConfig) -> Result<Self> {
+        let samples_rx = config
+            .samples_rx
+            .lock()
+            .unwrap()
+            .take()
+            .context("WHIP ingest is already in use")?;
+
+        // TODO: Replace with dll/dylib
+        let api = OpenH264API::from_source();
+        let decoder = Decoder::with_api_config(api, DecoderConfig::new())
+            .context("Unable to create OpenH264 decoder")?;
+
+        config.consumed.store(true, Ordering::Relaxed);
+
+        Ok(Self {
+            samples_rx,
+            keyframe_request: config.keyframe_request.clone(),
+            consumed: config.consumed.clone(),
+            recv_timeout: Duration::from_millis(config.recv_timeout),
+
+            decoder,
+            last_timestamp: None,
+        })
+    }
+}
+
+impl Drop for WhipFeedSource {
+    fn drop(&mut self) {
+        self.consumed.store(false, Ordering::Relaxed);
+    }
+}
+
+impl FeedSourceImpl for WhipFeedSource {
+    /// Decode one access unit from the publisher.
+    fn get_frame(&mut self) -> Result<Option<VideoFrameBuffer>> {
+        let sample = match self.samples_rx.recv_timeout(self.recv_timeout) {
+            Ok(sample) => sample,
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("WHIP ingest closed."),
+        };
+
+        let yuv = match self.decoder.decode(&sample.data) {
+            Ok(Some(yuv)) => yuv,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                eprintln!("Unable to decode WHIP access unit: {e}");
+                self.keyframe_request.notify_one();
+                return Ok(None);
+            }
+        };
+
+        // WebRTC doesn't signal a framerate, so derive it from the timestamps.
+        let timestamp = sample.timestamp.to_micros();
+        let framerate = match self.last_timestamp {
+            Some(last) if timestamp > last => {
+                VideoFramerate::new(1_000_000, (timestamp - last) as u32)
+            }
+            _ => VideoFramerate::new(30, 1),
+        };
+        self.last_timestamp = Some(timestamp);
+
+        Ok(Some(VideoFrameBuffer::from_decoded(
+            &yuv,
+            sample.timestamp,
+            framerate,
+        )))
+    }
+
+    /// Only video is ingested.
+    fn take_audio(&mut self) -> Vec<AudioFrameBuffer> {
+        vec![]
+    }
+}
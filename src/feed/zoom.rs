@@ -0,0 +1,154 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use super::frame::{VideoFrameBuffer, VideoFramePixelFormat};
+
+/// How long moving between regions takes.
+const ZOOM_TRANSITION: Duration = Duration::from_millis(500);
+/// Smallest region we zoom into, as a fraction of the frame. (8x)
+const MIN_REGION_SIZE: f32 = 0.125;
+
+/// Part of the source frame to show, normalized to 0..1 like annotation
+/// coordinates.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ZoomRegion {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl ZoomRegion {
+    pub const FULL: ZoomRegion = ZoomRegion {
+        x: 0.,
+        y: 0.,
+        width: 1.,
+        height: 1.,
+    };
+
+    /// Square up the region (in normalized units, which keeps the frame's
+    /// aspect ratio) around its center, and keep it inside the frame.
+    pub fn clamped(self) -> Self {
+        let size = self.width.max(self.height).clamp(MIN_REGION_SIZE, 1.);
+        let center_x = self.x + self.width / 2.;
+        let center_y = self.y + self.height / 2.;
+        Self {
+            x: (center_x - size / 2.).clamp(0., 1. - size),
+            y: (center_y - size / 2.).clamp(0., 1. - size),
+            width: size,
+            height: size,
+        }
+    }
+
+    fn lerp(self, other: Self, t: f32) -> Self {
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        Self {
+            x: lerp(self.x, other.x),
+            y: lerp(self.y, other.y),
+            width: lerp(self.width, other.width),
+            height: lerp(self.height, other.height),
+        }
+    }
+}
+
+/// Crops frames to a region of interest and scales it back up to the
+/// frame's resolution, easing between regions.
+pub struct Zoom {
+    from: ZoomRegion,
+    to: ZoomRegion,
+    transition_start: Instant,
+}
+
+impl Zoom {
+    pub fn new() -> Self {
+        Self {
+            from: ZoomRegion::FULL,
+            to: ZoomRegion::FULL,
+            transition_start: Instant::now(),
+        }
+    }
+
+    /// The region we're at, or moving to.
+    pub fn target(&self) -> ZoomRegion {
+        self.to
+    }
+
+    /// Start moving to `region`, from wherever we are now.
+    pub fn set(&mut self, region: ZoomRegion) {
+        self.from = self.current();
+        self.to = region.clamped();
+        self.transition_start = Instant::now();
+    }
+
+    fn current(&self) -> ZoomRegion {
+        let t = self.transition_start.elapsed().as_secs_f32() / ZOOM_TRANSITION.as_secs_f32();
+        let t = t.clamp(0., 1.);
+        // Smoothstep, so it eases in and out
+        self.from.lerp(self.to, t * t * (3. - 2. * t))
+    }
+
+    /// Crop and scale the frame. Zoomed frames come out as I420.
+    pub fn apply(&self, frame: VideoFrameBuffer) -> Result<VideoFrameBuffer> {
+        let region = self.current();
+        if region == ZoomRegion::FULL {
+            return Ok(frame);
+        }
+
+        let frame = frame.to_i420()?;
+        let (width, height) = (frame.width, frame.height);
+        let (y, u, v) = frame.yuv_slices();
+
+        let dim = width * height;
+        let mut data = vec![0u8; dim + 2 * (dim / 4)];
+        let (y_out, chroma_out) = data.split_at_mut(dim);
+        let (u_out, v_out) = chroma_out.split_at_mut(dim / 4);
+        scale_plane(y, (width, height), region, y_out);
+        scale_plane(u, (width / 2, height / 2), region, u_out);
+        scale_plane(v, (width / 2, height / 2), region, v_out);
+
+        Ok(VideoFrameBuffer {
+            pix_fmt: VideoFramePixelFormat::I420,
+            line_stride: width,
+            data: Bytes::from(data),
+            ..frame
+        })
+    }
+}
+
+/// Bilinear scale of `region` of a plane into `dst`, which has the same
+/// dimensions as the plane.
+fn scale_plane(src: &[u8], (width, height): (usize, usize), region: ZoomRegion, dst: &mut [u8]) {
+    if width == 0 || height == 0 {
+        return;
+    }
+
+    // Source position of each output column, which is the same for every row
+    let scale_x = region.width;
+    let columns: Vec<(usize, usize, f32)> = (0..width)
+        .map(|x| sample_position(region.x * width as f32, scale_x, x, width))
+        .collect();
+
+    let scale_y = region.height;
+    for y in 0..height {
+        let (y0, y1, fy) = sample_position(region.y * height as f32, scale_y, y, height);
+        let (row0, row1) = (&src[y0 * width..][..width], &src[y1 * width..][..width]);
+        let out = &mut dst[y * width..][..width];
+        for (x, &(x0, x1, fx)) in columns.iter().enumerate() {
+            let top = row0[x0] as f32 + (row0[x1] as f32 - row0[x0] as f32) * fx;
+            let bottom = row1[x0] as f32 + (row1[x1] as f32 - row1[x0] as f32) * fx;
+            out[x] = (top + (bottom - top) * fy).round() as u8;
+        }
+    }
+}
+
+/// Neighbouring source samples for output sample `i`, and the weight of the
+/// second one.
+fn sample_position(offset: f32, scale: f32, i: usize, len: usize) -> (usize, usize, f32) {
+    let position = (offset + (i as f32 + 0.5) * scale - 0.5).clamp(0., (len - 1) as f32);
+    let first = position.floor() as usize;
+    let second = (first + 1).min(len - 1);
+    (first, second, position - first as f32)
+}
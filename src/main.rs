@@ -1,10 +1,21 @@
+mod annotations;
+mod contribution;
 mod feed;
+mod recording;
 mod remote;
+mod replay;
+mod settings;
 mod timing_stats;
 
 use anyhow::{Context, Result};
 
-use feed::manager::{FeedConfigBuilder, FeedControlMessage, FeedResultMessage};
+use annotations::hub::AnnotationHub;
+use feed::{
+    manager::{FeedConfigBuilder, FeedControlMessage, FeedResultMessage, FEED_RESULT_CAPACITY},
+    sources::whip::WhipFeedSourceConfig,
+};
+use remote::config::RemoteConfigBuilder;
+use replay::InstantReplay;
 use tokio::{
     sync::{broadcast, mpsc},
     try_join,
@@ -12,15 +23,62 @@ use tokio::{
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = FeedConfigBuilder::new()
+    let (whip_source, whip_ingest) = WhipFeedSourceConfig::new();
+    let config = settings::feed(FeedConfigBuilder::new().whip_source(whip_source))?
         .build_interactive()
         .context("unable to build config")?;
+    let remote_config = settings::remote(RemoteConfigBuilder::new())?
+        .build()
+        .context("unable to build remote config")?;
+    let recorder_config = settings::recorder()?;
+    let contribution_config = settings::contribution()?;
+    let replay_config = settings::replay()?;
     let (feed_control_tx, feed_control_rx) = mpsc::channel::<FeedControlMessage>(64);
-    let feed_result_tx = broadcast::Sender::<FeedResultMessage>::new(1);
+    let feed_result_tx = broadcast::Sender::<FeedResultMessage>::new(FEED_RESULT_CAPACITY);
+    // Audio packets are small and frequent, so allow a few to queue up
+    let audio_tx = broadcast::Sender::<FeedResultMessage>::new(16);
+    let annotations = AnnotationHub::new();
+    if let Some(path) = settings::annotations_record()? {
+        annotations.record_to(&path)?;
+    }
+    if let Some(path) = settings::annotations_replay()? {
+        annotations.replay_from(&path)?;
+    }
+    let instant_replay =
+        replay_config.map(|config| InstantReplay::new(config, annotations.clone()));
 
     try_join!(
-        feed::main(config, feed_control_rx, feed_result_tx.clone()),
-        remote::main(feed_control_tx.clone(), feed_result_tx.clone()),
+        feed::main(
+            config,
+            feed_control_rx,
+            feed_result_tx.clone(),
+            audio_tx.clone(),
+            annotations.clone()
+        ),
+        remote::main(
+            remote_config,
+            feed_control_tx.clone(),
+            feed_result_tx.clone(),
+            audio_tx.clone(),
+            whip_ingest,
+            annotations,
+            instant_replay.clone()
+        ),
+        recording::main(
+            recorder_config,
+            feed_control_tx.clone(),
+            feed_result_tx.clone()
+        ),
+        contribution::main(
+            contribution_config,
+            feed_control_tx.clone(),
+            feed_result_tx.clone()
+        ),
+        replay::main(
+            instant_replay,
+            feed_control_tx.clone(),
+            feed_result_tx.clone()
+        ),
     )?;
 
     Ok(())
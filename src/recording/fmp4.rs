@@ -0,0 +1,323 @@
+use anyhow::{Context, Result};
+
+use crate::feed::encoders::h264;
+
+/// Ticks per second of the video track.
+pub const TIMESCALE: u32 = 90_000;
+const TRACK_ID: u32 = 1;
+
+/// Sample flags for a sync sample, and for one that depends on others.
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+
+/// One access unit of a fragment.
+pub struct Fmp4Sample {
+    /// AVCC, see `h264::to_avcc`.
+    pub data: Vec<u8>,
+    /// In `TIMESCALE` ticks.
+    pub duration: u32,
+    pub keyframe: bool,
+}
+
+/// `ftyp` and `moov` for a single H.264 track, described by its parameter
+/// sets. Fragments follow.
+pub fn init_segment(sps: &[u8], pps: &[u8]) -> Result<Vec<u8>> {
+    let (width, height) = h264::sps_dimensions(sps).context("unable to parse SPS")?;
+    let mut out = Vec::new();
+
+    write_box(&mut out, b"ftyp", |out| {
+        out.extend_from_slice(b"isom");
+        out.extend_from_slice(&0x200u32.to_be_bytes());
+        for brand in [b"isom", b"iso6", b"avc1", b"mp41"] {
+            out.extend_from_slice(brand);
+        }
+    });
+
+    write_box(&mut out, b"moov", |out| {
+        write_full_box(out, b"mvhd", 0, 0, |out| {
+            out.extend_from_slice(&[0; 8]); // creation and modification time
+            out.extend_from_slice(&TIMESCALE.to_be_bytes());
+            out.extend_from_slice(&0u32.to_be_bytes()); // duration, see fragments
+            out.extend_from_slice(&0x0001_0000u32.to_be_bytes()); // rate 1.0
+            out.extend_from_slice(&0x0100u16.to_be_bytes()); // volume 1.0
+            out.extend_from_slice(&[0; 10]);
+            write_matrix(out);
+            out.extend_from_slice(&[0; 24]); // pre_defined
+            out.extend_from_slice(&(TRACK_ID + 1).to_be_bytes()); // next_track_ID
+        });
+
+        write_box(out, b"mvex", |out| {
+            write_full_box(out, b"trex", 0, 0, |out| {
+                out.extend_from_slice(&TRACK_ID.to_be_bytes());
+                out.extend_from_slice(&1u32.to_be_bytes()); // sample description index
+                out.extend_from_slice(&[0; 12]); // duration, size and flags, see trun
+            });
+        });
+
+        write_box(out, b"trak", |out| {
+            // Enabled, in movie and in preview
+            write_full_box(out, b"tkhd", 0, 0x7, |out| {
+                out.extend_from_slice(&[0; 8]);
+                out.extend_from_slice(&TRACK_ID.to_be_bytes());
+                out.extend_from_slice(&[0; 4]);
+                out.extend_from_slice(&0u32.to_be_bytes()); // duration
+                out.extend_from_slice(&[0; 8]);
+                out.extend_from_slice(&[0; 8]); // layer, alternate group, volume
+                write_matrix(out);
+                out.extend_from_slice(&(width << 16).to_be_bytes());
+                out.extend_from_slice(&(height << 16).to_be_bytes());
+            });
+
+            write_box(out, b"mdia", |out| {
+                write_full_box(out, b"mdhd", 0, 0, |out| {
+                    out.extend_from_slice(&[0; 8]);
+                    out.extend_from_slice(&TIMESCALE.to_be_bytes());
+                    out.extend_from_slice(&0u32.to_be_bytes());
+                    out.extend_from_slice(&0x55c4u16.to_be_bytes()); // "und"
+                    out.extend_from_slice(&[0; 2]);
+                });
+                write_full_box(out, b"hdlr", 0, 0, |out| {
+                    out.extend_from_slice(&[0; 4]);
+                    out.extend_from_slice(b"vide");
+                    out.extend_from_slice(&[0; 12]);
+                    out.extend_from_slice(b"Telestrator\0");
+                });
+
+                write_box(out, b"minf", |out| {
+                    write_full_box(out, b"vmhd", 0, 1, |out| {
+                        out.extend_from_slice(&[0; 8]);
+                    });
+                    write_box(out, b"dinf", |out| {
+                        write_full_box(out, b"dref", 0, 0, |out| {
+                            out.extend_from_slice(&1u32.to_be_bytes());
+                            // Media is in this file
+                            write_full_box(out, b"url ", 0, 1, |_| {});
+                        });
+                    });
+                    write_box(out, b"stbl", |out| {
+                        write_full_box(out, b"stsd", 0, 0, |out| {
+                            out.extend_from_slice(&1u32.to_be_bytes());
+                            write_avc1(out, width, height, sps, pps);
+                        });
+                        // Samples are described by the fragments
+                        for kind in [b"stts", b"stsc", b"stco"] {
+                            write_full_box(out, kind, 0, 0, |out| {
+                                out.extend_from_slice(&0u32.to_be_bytes());
+                            });
+                        }
+                        write_full_box(out, b"stsz", 0, 0, |out| {
+                            out.extend_from_slice(&[0; 8]);
+                        });
+                    });
+                });
+            });
+        });
+    });
+
+    Ok(out)
+}
+
+/// `moof` and `mdat` for `samples`, which start at `base_decode_time`.
+pub fn fragment(sequence: u32, base_decode_time: u64, samples: &[Fmp4Sample]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut data_offset_at = 0;
+
+    write_box(&mut out, b"moof", |out| {
+        write_full_box(out, b"mfhd", 0, 0, |out| {
+            out.extend_from_slice(&sequence.to_be_bytes());
+        });
+        write_box(out, b"traf", |out| {
+            // default-base-is-moof
+            write_full_box(out, b"tfhd", 0, 0x02_0000, |out| {
+                out.extend_from_slice(&TRACK_ID.to_be_bytes());
+            });
+            write_full_box(out, b"tfdt", 1, 0, |out| {
+                out.extend_from_slice(&base_decode_time.to_be_bytes());
+            });
+            // data-offset, and per sample duration, size and flags
+            write_full_box(out, b"trun", 0, 0x0701, |out| {
+                out.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+                data_offset_at = out.len();
+                out.extend_from_slice(&0u32.to_be_bytes());
+                for sample in samples {
+                    let flags = match sample.keyframe {
+                        true => SYNC_SAMPLE_FLAGS,
+                        false => NON_SYNC_SAMPLE_FLAGS,
+                    };
+                    out.extend_from_slice(&sample.duration.to_be_bytes());
+                    out.extend_from_slice(&(sample.data.len() as u32).to_be_bytes());
+                    out.extend_from_slice(&flags.to_be_bytes());
+                }
+            });
+        });
+    });
+
+    // Samples start right after the mdat header, relative to the moof
+    let data_offset = (out.len() + 8) as u32;
+    out[data_offset_at..data_offset_at + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+    write_box(&mut out, b"mdat", |out| {
+        for sample in samples {
+            out.extend_from_slice(&sample.data);
+        }
+    });
+    out
+}
+
+fn write_avc1(out: &mut Vec<u8>, width: u32, height: u32, sps: &[u8], pps: &[u8]) {
+    write_box(out, b"avc1", |out| {
+        out.extend_from_slice(&[0; 6]);
+        out.extend_from_slice(&1u16.to_be_bytes()); // data reference index
+        out.extend_from_slice(&[0; 16]);
+        out.extend_from_slice(&(width as u16).to_be_bytes());
+        out.extend_from_slice(&(height as u16).to_be_bytes());
+        out.extend_from_slice(&0x0048_0000u32.to_be_bytes()); // 72 dpi
+        out.extend_from_slice(&0x0048_0000u32.to_be_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&1u16.to_be_bytes()); // frame count
+        out.extend_from_slice(&[0; 32]); // compressor name
+        out.extend_from_slice(&0x0018u16.to_be_bytes()); // depth
+        out.extend_from_slice(&(-1i16).to_be_bytes());
+
+        write_box(out, b"avcC", |out| {
+            out.push(1); // configuration version
+            out.extend_from_slice(&sps[1..4]); // profile, compatibility, level
+            out.push(0xff); // 4 byte NAL lengths
+            out.push(0xe1); // one SPS
+            out.extend_from_slice(&(sps.len() as u16).to_be_bytes());
+            out.extend_from_slice(sps);
+            out.push(1); // one PPS
+            out.extend_from_slice(&(pps.len() as u16).to_be_bytes());
+            out.extend_from_slice(pps);
+        });
+    });
+}
+
+/// Identity transformation.
+fn write_matrix(out: &mut Vec<u8>) {
+    for value in [0x0001_0000u32, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000] {
+        out.extend_from_slice(&value.to_be_bytes());
+    }
+}
+
+fn write_box(out: &mut Vec<u8>, kind: &[u8; 4], contents: impl FnOnce(&mut Vec<u8>)) {
+    let start = out.len();
+    out.extend_from_slice(&[0; 4]);
+    out.extend_from_slice(kind);
+    contents(out);
+    let size = (out.len() - start) as u32;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+}
+
+fn write_full_box(
+    out: &mut Vec<u8>,
+    kind: &[u8; 4],
+    version: u8,
+    flags: u32,
+    contents: impl FnOnce(&mut Vec<u8>),
+) {
+    write_box(out, kind, |out| {
+        out.extend_from_slice(&(((version as u32) << 24) | flags).to_be_bytes());
+        contents(out);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Baseline, level 3.1, 320x240.
+    const SPS: [u8; 8] = [0x67, 0x42, 0x00, 0x1f, 0xf4, 0x0a, 0x0f, 0xc8];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+
+    /// Contents of the first `kind` box, after its header.
+    fn find_box<'a>(data: &'a [u8], kind: &[u8; 4]) -> &'a [u8] {
+        let at = data
+            .windows(4)
+            .position(|window| window == kind)
+            .expect("box is missing");
+        let size = u32::from_be_bytes(data[at - 4..at].try_into().unwrap()) as usize;
+        &data[at + 4..at - 4 + size]
+    }
+
+    fn u32_at(data: &[u8], at: usize) -> u32 {
+        u32::from_be_bytes(data[at..at + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn avcc_from_parameter_sets() {
+        let init = init_segment(&SPS, &PPS).unwrap();
+        assert_eq!(&init[4..8], b"ftyp");
+
+        let mut avcc = vec![
+            0x01, // version
+            0x42, 0x00, 0x1f, // profile, compatibility, level
+            0xff, // 4 byte NAL lengths
+            0xe1, 0x00, 0x08, // one SPS of 8 bytes
+        ];
+        avcc.extend_from_slice(&SPS);
+        avcc.extend_from_slice(&[0x01, 0x00, 0x04]); // one PPS of 4 bytes
+        avcc.extend_from_slice(&PPS);
+        assert_eq!(find_box(&init, b"avcC"), avcc);
+
+        // Dimensions from the SPS, 16.16 fixed point in the track header
+        let tkhd = find_box(&init, b"tkhd");
+        assert_eq!(u32_at(tkhd, tkhd.len() - 8), 320 << 16);
+        assert_eq!(u32_at(tkhd, tkhd.len() - 4), 240 << 16);
+        // The brand in ftyp comes first
+        let avc1 = find_box(find_box(&init, b"stsd"), b"avc1");
+        assert_eq!(avc1[24..28], [0x01, 0x40, 0x00, 0xf0]);
+    }
+
+    #[test]
+    fn fragment_layout() {
+        let samples = [
+            Fmp4Sample {
+                data: vec![0, 0, 0, 2, 0x65, 0x88],
+                duration: 3000,
+                keyframe: true,
+            },
+            Fmp4Sample {
+                data: vec![0, 0, 0, 1, 0x41],
+                duration: 3003,
+                keyframe: false,
+            },
+        ];
+        let fragment = fragment(7, 0x1_0000_0005, &samples);
+
+        let mfhd = find_box(&fragment, b"mfhd");
+        assert_eq!(u32_at(mfhd, 4), 7);
+
+        // Version 1, so the decode time is 64 bits
+        assert_eq!(
+            find_box(&fragment, b"tfdt"),
+            [0x01, 0, 0, 0, 0, 0, 0, 0x01, 0, 0, 0, 0x05]
+        );
+
+        let trun = find_box(&fragment, b"trun");
+        assert_eq!(u32_at(trun, 0), 0x0701);
+        assert_eq!(u32_at(trun, 4), 2);
+        let entries: Vec<_> = trun[12..]
+            .chunks(12)
+            .map(|entry| (u32_at(entry, 0), u32_at(entry, 4), u32_at(entry, 8)))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (3000, 6, SYNC_SAMPLE_FLAGS),
+                (3003, 5, NON_SYNC_SAMPLE_FLAGS)
+            ]
+        );
+
+        // The data offset is relative to the moof, and points right past
+        // the mdat header
+        let moof_size = u32_at(&fragment, 0) as usize;
+        let data_offset = u32_at(trun, 8) as usize;
+        assert_eq!(data_offset, moof_size + 8);
+        assert_eq!(&fragment[moof_size + 4..moof_size + 8], b"mdat");
+        assert_eq!(
+            fragment[data_offset..],
+            [0, 0, 0, 2, 0x65, 0x88, 0, 0, 0, 1, 0x41]
+        );
+    }
+}
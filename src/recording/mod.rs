@@ -0,0 +1,92 @@
+pub mod fmp4;
+pub mod recorder;
+
+use std::sync::mpsc as std_mpsc;
+
+use anyhow::{Context, Result};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::feed::{
+    encoders::EncodedFrame,
+    manager::{FeedControlMessage, FeedResultMessage},
+};
+
+use self::recorder::{Fmp4Recorder, RecorderConfig};
+
+/// Client id the recorder joins the feed as.
+const RECORDER_CLIENT_ID: &str = "recorder";
+
+/// Record the encoded feed until it ends. Does nothing without a config.
+pub async fn main(
+    config: Option<RecorderConfig>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+) -> Result<()> {
+    let Some(config) = config else {
+        return Ok(());
+    };
+    let mut recorder = Fmp4Recorder::new(config)?;
+    let mut feed_result_rx = feed_result_tx.subscribe();
+
+    // Pinned, so the recording gets the primary group's full bitrate and the
+    // feed keeps running without viewers
+    feed_control_tx
+        .send(FeedControlMessage::ClientJoined {
+            client_id: RECORDER_CLIENT_ID.to_owned(),
+            group_tx: None,
+        })
+        .await
+        .context("feed is gone")?;
+
+    // The result channel only holds a frame, so queue them up for the
+    // writer rather than lagging whenever the disk is slow. `None` marks
+    // frames lost to lagging anyway.
+    let (frame_tx, frame_rx) = std_mpsc::channel::<Option<EncodedFrame>>();
+    let writer_feed_control_tx = feed_control_tx.clone();
+    let writer = tokio::task::spawn_blocking(move || {
+        for frame in frame_rx {
+            let request_keyframe = match frame {
+                Some(frame) => recorder.push(&frame)?,
+                None => {
+                    recorder.discontinuity()?;
+                    true
+                }
+            };
+            if request_keyframe {
+                writer_feed_control_tx
+                    .try_send(FeedControlMessage::RequestKeyframe {
+                        client_id: RECORDER_CLIENT_ID.to_owned(),
+                    })
+                    .ok();
+            }
+        }
+        recorder.finish()
+    });
+
+    loop {
+        let frame = match feed_result_rx.recv().await {
+            Err(broadcast::error::RecvError::Lagged(_)) => None,
+            Err(broadcast::error::RecvError::Closed) => break,
+
+            Ok(FeedResultMessage::EncodedBitstream(frame)) => Some(frame),
+            Ok(_) => continue,
+        };
+        // Only fails once the writer gave up
+        if frame_tx.send(frame).is_err() {
+            break;
+        }
+    }
+    drop(frame_tx);
+
+    // A broken recording shouldn't take the feed down with it
+    if let Err(e) = writer.await? {
+        eprintln!("Recording stopped: {e:#}");
+        feed_control_tx
+            .send(FeedControlMessage::ClientLeft {
+                client_id: RECORDER_CLIENT_ID.to_owned(),
+            })
+            .await
+            .ok();
+    }
+    Ok(())
+}
@@ -0,0 +1,417 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+
+use crate::feed::{
+    encoders::{h264, EncodedFrame},
+    frame::VideoTimestamp,
+};
+
+use super::fmp4::{self, Fmp4Sample, TIMESCALE};
+
+/// Used for frames whose duration can't be told from the timestamps.
+const DEFAULT_FRAME_DURATION: u32 = TIMESCALE / 30;
+
+#[derive(Debug, Clone)]
+pub struct RecorderConfig {
+    /// Recordings are written here, as `recording-<UNIX time>.mp4`.
+    pub directory: PathBuf,
+    /// Fragments are cut at the first keyframe after this long. A keyframe
+    /// is requested if none comes.
+    pub fragment_duration: Duration,
+    /// A new file is started at the first keyframe after the current one
+    /// gets this long...
+    pub max_file_duration: Duration,
+    /// ...or this big. (bytes)
+    pub max_file_size: u64,
+}
+
+impl Default for RecorderConfig {
+    fn default() -> Self {
+        Self {
+            directory: PathBuf::from("recordings"),
+            fragment_duration: Duration::from_secs(2),
+            max_file_duration: Duration::from_secs(30 * 60),
+            max_file_size: 2 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+struct RecordingFile {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+    /// Bytes written so far.
+    size: u64,
+    /// Decode time of the next fragment. (ticks)
+    decode_time: u64,
+    sequence: u32,
+}
+
+struct PendingSample {
+    data: Vec<u8>,
+    keyframe: bool,
+    timestamp: VideoTimestamp,
+}
+
+/// Writes the encoded feed into fragmented MP4 files, one fragment per GOP.
+/// Each fragment is written out in one go, so a file stays playable up to
+/// its last fragment if we're killed.
+pub struct Fmp4Recorder {
+    config: RecorderConfig,
+    file: Option<RecordingFile>,
+    /// Samples of the fragment being built. Each duration is only known
+    /// once the next frame arrives.
+    pending: Vec<PendingSample>,
+    /// Latest parameter sets seen in the stream.
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    /// Frames are dropped until the next keyframe, e.g. after lagging.
+    waiting_for_keyframe: bool,
+    last_keyframe_request: Option<Instant>,
+    last_duration: u32,
+}
+
+impl Fmp4Recorder {
+    pub fn new(config: RecorderConfig) -> Result<Self> {
+        fs::create_dir_all(&config.directory).with_context(|| {
+            format!(
+                "unable to create recording directory {}",
+                config.directory.display()
+            )
+        })?;
+        Ok(Self {
+            config,
+            file: None,
+            pending: Vec::new(),
+            sps: None,
+            pps: None,
+            waiting_for_keyframe: true,
+            last_keyframe_request: None,
+            last_duration: DEFAULT_FRAME_DURATION,
+        })
+    }
+
+    /// Add the next encoded frame. Returns whether a keyframe should be
+    /// requested from the encoder.
+    pub fn push(&mut self, frame: &EncodedFrame) -> Result<bool> {
+        let access_unit = h264::to_avcc(&frame.data);
+        if let Some(sps) = access_unit.sps {
+            self.sps = Some(sps);
+        }
+        if let Some(pps) = access_unit.pps {
+            self.pps = Some(pps);
+        }
+
+        if frame.keyframe {
+            self.flush(Some(frame.timestamp))?;
+            self.waiting_for_keyframe = false;
+            if self.should_rotate() {
+                self.rotate()?;
+            }
+        }
+
+        if self.waiting_for_keyframe || self.file.is_none() {
+            return Ok(self.keyframe_due());
+        }
+        self.pending.push(PendingSample {
+            data: access_unit.data,
+            keyframe: frame.keyframe,
+            timestamp: frame.timestamp,
+        });
+
+        let fragment_length = frame
+            .timestamp
+            .to_micros()
+            .saturating_sub(self.pending[0].timestamp.to_micros());
+        if fragment_length < self.config.fragment_duration.as_micros() as u64 {
+            return Ok(false);
+        }
+        Ok(self.keyframe_due())
+    }
+
+    /// Frames went missing, so nothing can be decoded until the next
+    /// keyframe. Writes out what we have and waits for it.
+    pub fn discontinuity(&mut self) -> Result<()> {
+        self.flush(None)?;
+        self.waiting_for_keyframe = true;
+        Ok(())
+    }
+
+    /// Write out everything pending.
+    pub fn finish(&mut self) -> Result<()> {
+        self.flush(None)?;
+        if let Some(mut file) = self.file.take() {
+            file.writer.flush()?;
+            println!("Finished recording {}", file.path.display());
+        }
+        Ok(())
+    }
+
+    /// Rate limit keyframe requests to one per fragment.
+    fn keyframe_due(&mut self) -> bool {
+        let due = self
+            .last_keyframe_request
+            .map_or(true, |last| last.elapsed() >= self.config.fragment_duration);
+        if due {
+            self.last_keyframe_request = Some(Instant::now());
+        }
+        due
+    }
+
+    /// Whether the next GOP should go into a new file: the current one is
+    /// full, or the parameter sets changed (e.g. the resolution).
+    fn should_rotate(&self) -> bool {
+        let Some(file) = &self.file else {
+            return true;
+        };
+        let max_duration = self.config.max_file_duration.as_secs_f64() * TIMESCALE as f64;
+        file.size >= self.config.max_file_size
+            || file.decode_time as f64 >= max_duration
+            || self.sps.as_ref() != Some(&file.sps)
+            || self.pps.as_ref() != Some(&file.pps)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        self.finish()?;
+
+        let (Some(sps), Some(pps)) = (self.sps.clone(), self.pps.clone()) else {
+            eprintln!("Keyframe without parameter sets, not recording yet");
+            return Ok(());
+        };
+        let init = fmp4::init_segment(&sps, &pps)?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let mut path = self.config.directory.join(format!("recording-{now}.mp4"));
+        let mut n = 1;
+        while path.exists() {
+            path = self
+                .config
+                .directory
+                .join(format!("recording-{now}-{n}.mp4"));
+            n += 1;
+        }
+
+        let file = File::create(&path)
+            .with_context(|| format!("unable to create recording {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&init)?;
+        writer.flush()?;
+        println!("Recording to {}", path.display());
+
+        self.file = Some(RecordingFile {
+            path,
+            writer,
+            sps,
+            pps,
+            size: init.len() as u64,
+            decode_time: 0,
+            sequence: 1,
+        });
+        Ok(())
+    }
+
+    /// Write the pending samples as a fragment. `next` is the timestamp of
+    /// the frame after them, if known.
+    fn flush(&mut self, next: Option<VideoTimestamp>) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = std::mem::take(&mut self.pending);
+        let Some(file) = &mut self.file else {
+            return Ok(());
+        };
+
+        let timestamps = pending
+            .iter()
+            .map(|sample| sample.timestamp)
+            .collect::<Vec<_>>();
+        let mut samples = Vec::with_capacity(pending.len());
+        for (i, sample) in pending.into_iter().enumerate() {
+            let next = timestamps.get(i + 1).copied().or(next);
+            let duration = match next {
+                // Timestamps going backwards (e.g. a source restart) reuse
+                // the last good duration
+                Some(next) if next > sample.timestamp => {
+                    let micros = next.to_micros() - sample.timestamp.to_micros();
+                    (micros * TIMESCALE as u64 / 1_000_000) as u32
+                }
+                _ => self.last_duration,
+            };
+            self.last_duration = duration;
+            samples.push(Fmp4Sample {
+                data: sample.data,
+                duration,
+                keyframe: sample.keyframe,
+            });
+        }
+
+        let fragment = fmp4::fragment(file.sequence, file.decode_time, &samples);
+        file.writer.write_all(&fragment)?;
+        file.writer.flush()?;
+        file.sequence += 1;
+        file.size += fragment.len() as u64;
+        file.decode_time += samples.iter().map(|s| s.duration as u64).sum::<u64>();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    const SPS: [u8; 8] = [0x67, 0x42, 0x00, 0x1f, 0xf4, 0x0a, 0x0f, 0xc8];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+    /// 25 fps, a whole number of ticks.
+    const FRAME_MICROS: u64 = 40_000;
+
+    /// Scratch directory, removed when dropped.
+    struct Directory(PathBuf);
+
+    impl Directory {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "telestrator-recorder-{}-{name}",
+                std::process::id()
+            ));
+            fs::remove_dir_all(&path).ok();
+            Self(path)
+        }
+
+        /// Fragments of each recording, as the sample count and whether each
+        /// fragment starts with a sync sample.
+        fn recordings(&self) -> Vec<Vec<(u32, bool)>> {
+            let mut recordings: Vec<_> = fs::read_dir(&self.0)
+                .unwrap()
+                .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+                .map(|data| fragments(&data))
+                .collect();
+            recordings.sort();
+            recordings
+        }
+    }
+
+    impl Drop for Directory {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn fragments(mut data: &[u8]) -> Vec<(u32, bool)> {
+        let mut fragments = Vec::new();
+        while !data.is_empty() {
+            let size = u32::from_be_bytes(data[0..4].try_into().unwrap()) as usize;
+            if &data[4..8] == b"moof" {
+                let moof = &data[..size];
+                let trun = moof.windows(4).position(|w| w == b"trun").unwrap() + 4;
+                let count = u32::from_be_bytes(moof[trun + 4..trun + 8].try_into().unwrap());
+                // Flags of the first sample, after its duration and size
+                let flags = &moof[trun + 20..trun + 24];
+                fragments.push((count, flags[0] == 0x02));
+            }
+            data = &data[size..];
+        }
+        fragments
+    }
+
+    fn frame(index: u64, keyframe: bool) -> EncodedFrame {
+        let mut data = Vec::new();
+        if keyframe {
+            for nal in [&SPS[..], &PPS, &[0x65, 0x88, 0x84]] {
+                data.extend_from_slice(&[0, 0, 0, 1]);
+                data.extend_from_slice(nal);
+            }
+        } else {
+            data.extend_from_slice(&[0, 0, 0, 1, 0x41, 0x9a, 0x02]);
+        }
+        EncodedFrame::new(
+            Bytes::from(data),
+            VideoTimestamp::from_micros(index * FRAME_MICROS),
+        )
+    }
+
+    /// Push GOPs of the given lengths, returning the frames after which a
+    /// keyframe was requested.
+    fn record(recorder: &mut Fmp4Recorder, gops: &[u64]) -> Vec<u64> {
+        let mut requests = Vec::new();
+        let mut index = 0;
+        for &length in gops {
+            for i in 0..length {
+                if recorder.push(&frame(index, i == 0)).unwrap() {
+                    requests.push(index);
+                }
+                index += 1;
+            }
+        }
+        recorder.finish().unwrap();
+        requests
+    }
+
+    #[test]
+    fn fragments_only_start_at_keyframes() {
+        let directory = Directory::new("fragments");
+        let mut recorder = Fmp4Recorder::new(RecorderConfig {
+            directory: directory.0.clone(),
+            fragment_duration: Duration::from_secs(1),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // A keyframe is asked for a second in, but the fragment goes on
+        // until one comes
+        let requests = record(&mut recorder, &[60, 20]);
+        assert_eq!(requests, [25]);
+        assert_eq!(directory.recordings(), [vec![(60, true), (20, true)]]);
+    }
+
+    #[test]
+    fn rotates_on_duration() {
+        let directory = Directory::new("duration");
+        let mut recorder = Fmp4Recorder::new(RecorderConfig {
+            directory: directory.0.clone(),
+            max_file_duration: Duration::from_secs(2),
+            ..Default::default()
+        })
+        .unwrap();
+
+        // The file reaches two seconds with the second GOP
+        record(&mut recorder, &[25, 25, 25, 25, 10]);
+        assert_eq!(
+            directory.recordings(),
+            [
+                vec![(10, true)],
+                vec![(25, true), (25, true)],
+                vec![(25, true), (25, true)],
+            ]
+        );
+    }
+
+    #[test]
+    fn rotates_on_size() {
+        let directory = Directory::new("size");
+        let mut recorder = Fmp4Recorder::new(RecorderConfig {
+            directory: directory.0.clone(),
+            max_file_size: 1,
+            ..Default::default()
+        })
+        .unwrap();
+
+        // Any fragment fills the file, so every GOP gets its own
+        record(&mut recorder, &[10, 20, 30]);
+        assert_eq!(
+            directory.recordings(),
+            [vec![(10, true)], vec![(20, true)], vec![(30, true)]]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use webrtc::data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel};
+
+use crate::annotations::{hub::AnnotationHub, protocol::Envelope};
+
+/// Label of the data channel clients open for annotations.
+pub const LABEL: &str = "annotations";
+
+/// Wire a client's annotation data channel up to the hub. The client opens
+/// the channel, so players that don't draw never join the hub.
+pub fn attach(data_channel: Arc<RTCDataChannel>, client_id: String, hub: AnnotationHub) {
+    let open_channel = data_channel.clone();
+    let open_client_id = client_id.clone();
+    let open_hub = hub.clone();
+    data_channel.on_open(Box::new(move || {
+        let mut messages_rx = open_hub.join(&open_client_id);
+        let data_channel = open_channel.clone();
+        Box::pin(async move {
+            tokio::spawn(async move {
+                // Ends when the hub drops us on leave
+                while let Some(message) = messages_rx.recv().await {
+                    let Ok(text) = serde_json::to_string(&Envelope::new(message)) else {
+                        continue;
+                    };
+                    if data_channel.send_text(text).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        })
+    }));
+
+    let message_client_id = client_id.clone();
+    let message_hub = hub.clone();
+    data_channel.on_message(Box::new(move |message: DataChannelMessage| {
+        if message.is_string {
+            match std::str::from_utf8(&message.data) {
+                Ok(text) => message_hub.handle_text(&message_client_id, text),
+                Err(_) => eprintln!("Dropping non-UTF-8 annotation message"),
+            }
+        }
+        Box::pin(async {})
+    }));
+
+    data_channel.on_close(Box::new(move || {
+        hub.leave(&client_id);
+        Box::pin(async {})
+    }));
+}
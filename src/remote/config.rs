@@ -0,0 +1,181 @@
+use std::{net::SocketAddr, str::FromStr};
+
+use anyhow::{Context, Result};
+use serde_json::json;
+use tokio::net::UdpSocket;
+use webrtc::{
+    api::setting_engine::SettingEngine,
+    ice::{
+        udp_mux::{UDPMuxDefault, UDPMuxParams},
+        udp_network::{EphemeralUDP, UDPNetwork},
+    },
+    ice_transport::{ice_candidate_type::RTCIceCandidateType, ice_server::RTCIceServer},
+    peer_connection::configuration::RTCConfiguration,
+};
+
+#[derive(Default)]
+pub struct RemoteConfigBuilder {
+    /// Port of the signalling (HTTP) server.
+    http_port: Option<u16>,
+
+    /// STUN/TURN servers, shared by us and the web page.
+    ice_servers: Vec<RTCIceServer>,
+
+    /// Public IPs to advertise as host candidates, for hosts behind a 1:1 NAT
+    /// (e.g. cloud VMs).
+    nat_1to1_ips: Vec<String>,
+
+    /// Ephemeral UDP ports are picked from this range. (inclusive)
+    udp_port_range: Option<(u16, u16)>,
+    /// If specified, every peer connection is muxed over this one UDP port.
+    /// Takes precedence over `udp_port_range`.
+    udp_mux_port: Option<u16>,
+
+    /// If specified, the feed is also served over RTSP on this port.
+    rtsp_port: Option<u16>,
+
+    /// Offer RED+ULPFEC protection for the video track. Useful when NACK
+    /// round trips exceed the playout delay, e.g. on satellite links.
+    fec: Option<bool>,
+}
+
+impl RemoteConfigBuilder {
+    pub fn new() -> Self {
+        Self {
+            ..Default::default()
+        }
+    }
+
+    pub fn http_port(mut self, http_port: u16) -> Self {
+        self.http_port = Some(http_port);
+        self
+    }
+
+    /// Add a STUN server, e.g. `stun:stun.l.google.com:19302`.
+    pub fn stun_server(mut self, url: &str) -> Self {
+        self.ice_servers.push(RTCIceServer {
+            urls: vec![url.to_owned()],
+            ..Default::default()
+        });
+        self
+    }
+
+    /// Add a TURN server, e.g. `turn:turn.example.com:3478?transport=udp`.
+    pub fn turn_server(mut self, url: &str, username: &str, credential: &str) -> Self {
+        self.ice_servers.push(RTCIceServer {
+            urls: vec![url.to_owned()],
+            username: username.to_owned(),
+            credential: credential.to_owned(),
+            ..Default::default()
+        });
+        self
+    }
+
+    pub fn nat_1to1_ips(mut self, ips: Vec<String>) -> Self {
+        self.nat_1to1_ips = ips;
+        self
+    }
+
+    pub fn udp_port_range(mut self, min: u16, max: u16) -> Self {
+        self.udp_port_range = Some((min, max));
+        self
+    }
+
+    pub fn udp_mux_port(mut self, port: u16) -> Self {
+        self.udp_mux_port = Some(port);
+        self
+    }
+
+    pub fn rtsp_port(mut self, port: u16) -> Self {
+        self.rtsp_port = Some(port);
+        self
+    }
+
+    pub fn fec(mut self, fec: bool) -> Self {
+        self.fec = Some(fec);
+        self
+    }
+
+    pub fn build(self) -> Result<RemoteConfig> {
+        let http_port = self.http_port.unwrap_or(8888);
+
+        if let Some((min, max)) = self.udp_port_range {
+            // Validate early rather than on the first connection
+            EphemeralUDP::new(min, max).context("Invalid UDP port range")?;
+        }
+
+        Ok(RemoteConfig {
+            http_port,
+            ice_servers: self.ice_servers,
+            nat_1to1_ips: self.nat_1to1_ips,
+            udp_port_range: self.udp_port_range,
+            udp_mux_port: self.udp_mux_port,
+            rtsp_port: self.rtsp_port,
+            fec: self.fec.unwrap_or(true),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RemoteConfig {
+    pub http_port: u16,
+    pub ice_servers: Vec<RTCIceServer>,
+    nat_1to1_ips: Vec<String>,
+    udp_port_range: Option<(u16, u16)>,
+    udp_mux_port: Option<u16>,
+    pub rtsp_port: Option<u16>,
+    pub fec: bool,
+}
+
+impl RemoteConfig {
+    pub fn rtc_configuration(&self) -> RTCConfiguration {
+        RTCConfiguration {
+            ice_servers: self.ice_servers.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Build the setting engine every peer connection is created with. In
+    /// mux mode this binds the shared UDP socket, so only call it once.
+    pub async fn setting_engine(&self) -> Result<SettingEngine> {
+        let mut setting_engine = SettingEngine::default();
+
+        if !self.nat_1to1_ips.is_empty() {
+            setting_engine.set_nat_1to1_ips(self.nat_1to1_ips.clone(), RTCIceCandidateType::Host);
+        }
+
+        if let Some(port) = self.udp_mux_port {
+            let addr = SocketAddr::from_str(&format!("0.0.0.0:{port}")).unwrap();
+            let socket = UdpSocket::bind(addr)
+                .await
+                .with_context(|| format!("Unable to bind UDP mux port {port}"))?;
+            let udp_mux = UDPMuxDefault::new(UDPMuxParams::new(socket));
+            setting_engine.set_udp_network(UDPNetwork::Muxed(udp_mux));
+            println!("WebRTC media muxed on udp://0.0.0.0:{port}");
+        } else if let Some((min, max)) = self.udp_port_range {
+            setting_engine.set_udp_network(UDPNetwork::Ephemeral(EphemeralUDP::new(min, max)?));
+        }
+
+        Ok(setting_engine)
+    }
+
+    /// ICE servers in the shape `RTCPeerConnection` takes, for the web page.
+    pub fn ice_servers_json(&self) -> serde_json::Value {
+        let ice_servers = self
+            .ice_servers
+            .iter()
+            .map(|server| {
+                if server.username.is_empty() {
+                    json!({ "urls": server.urls })
+                } else {
+                    json!({
+                        "urls": server.urls,
+                        "username": server.username,
+                        "credential": server.credential,
+                    })
+                }
+            })
+            .collect::<Vec<_>>();
+        json!({ "iceServers": ice_servers })
+    }
+}
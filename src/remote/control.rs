@@ -0,0 +1,80 @@
+use std::convert::Infallible;
+
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use crate::feed::{manager::FeedControlMessage, zoom::ZoomRegion};
+
+#[derive(Deserialize)]
+struct UnfreezeQuery {
+    #[serde(default)]
+    fade: bool,
+}
+
+/// Presenter controls for the feed.
+///
+/// `POST /feed/freeze` holds the current frame, `POST /feed/unfreeze` goes
+/// back to live. Pass `?fade=true` to fade the annotations out rather than
+/// clearing them.
+///
+/// `POST /feed/zoom` with a JSON `{x, y, width, height}` body (normalized to
+/// the source) zooms into that region, `DELETE /feed/zoom` zooms back out.
+pub fn routes(
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let freeze_tx = feed_control_tx.clone();
+    let freeze_handler = warp::post()
+        .and(warp::path!("feed" / "freeze"))
+        .and(warp::any().map(move || freeze_tx.clone()))
+        .and_then(|feed_control_tx| send_control(feed_control_tx, FeedControlMessage::Freeze));
+
+    let unfreeze_tx = feed_control_tx.clone();
+    let unfreeze_handler = warp::post()
+        .and(warp::path!("feed" / "unfreeze"))
+        .and(warp::query::<UnfreezeQuery>())
+        .and(warp::any().map(move || unfreeze_tx.clone()))
+        .and_then(|query: UnfreezeQuery, feed_control_tx| {
+            send_control(
+                feed_control_tx,
+                FeedControlMessage::Unfreeze { fade: query.fade },
+            )
+        });
+
+    let zoom_tx = feed_control_tx.clone();
+    let zoom_handler = warp::post()
+        .and(warp::path!("feed" / "zoom"))
+        .and(warp::body::content_length_limit(1024))
+        .and(warp::body::json::<ZoomRegion>())
+        .and(warp::any().map(move || zoom_tx.clone()))
+        .and_then(|region, feed_control_tx| {
+            send_control(feed_control_tx, FeedControlMessage::Zoom { region })
+        });
+
+    let unzoom_handler = warp::delete()
+        .and(warp::path!("feed" / "zoom"))
+        .and(warp::any().map(move || feed_control_tx.clone()))
+        .and_then(|feed_control_tx| {
+            send_control(
+                feed_control_tx,
+                FeedControlMessage::Zoom {
+                    region: ZoomRegion::FULL,
+                },
+            )
+        });
+
+    freeze_handler
+        .or(unfreeze_handler)
+        .or(zoom_handler)
+        .or(unzoom_handler)
+}
+
+async fn send_control(
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    message: FeedControlMessage,
+) -> std::result::Result<StatusCode, Infallible> {
+    match feed_control_tx.send(message).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT),
+        Err(_) => Ok(StatusCode::SERVICE_UNAVAILABLE),
+    }
+}
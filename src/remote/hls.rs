@@ -0,0 +1,919 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    sync::{broadcast, mpsc, watch, Notify},
+    time::timeout_at,
+};
+use warp::{
+    http::{header, Response, StatusCode},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    feed::{
+        encoders::{h264, EncodedFrame},
+        frame::VideoTimestamp,
+        manager::{FeedControlMessage, FeedResultMessage},
+    },
+    recording::fmp4::{self, Fmp4Sample, TIMESCALE},
+};
+
+/// Client id the packager joins the feed as.
+const HLS_CLIENT_ID: &str = "hls";
+
+/// Segments are cut at the first keyframe after this long. A keyframe is
+/// requested if none comes.
+const SEGMENT_TARGET: Duration = Duration::from_secs(2);
+/// Advertised as EXT-X-TARGETDURATION, which can't change. Leaves room for
+/// keyframes that are a little late. Segments are cut before outgrowing it
+/// even without one, and the next starts mid-GOP.
+const TARGET_DURATION: u64 = 4;
+const PART_TARGET: Duration = Duration::from_millis(500);
+/// How far behind the live edge players start. Three parts, as recommended.
+const PART_HOLD_BACK: Duration = Duration::from_millis(1500);
+/// Segments in the playlist. Older ones are forgotten.
+const PLAYLIST_SEGMENTS: usize = 6;
+/// Segments at the end of the playlist whose parts are listed.
+const PARTS_SEGMENTS: usize = 3;
+/// Blocking requests give up after this long, i.e. three target durations.
+const BLOCKING_TIMEOUT: Duration = Duration::from_secs(3 * TARGET_DURATION);
+/// The packager leaves the feed once nobody has asked for anything this
+/// long, so the encoders can idle.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Used for frames whose duration can't be told from the timestamps.
+const DEFAULT_FRAME_DURATION: u32 = TIMESCALE / 30;
+
+struct Part {
+    /// A `moof` and `mdat`.
+    data: Bytes,
+    /// In `TIMESCALE` ticks.
+    duration: u64,
+    /// Starts with a keyframe.
+    independent: bool,
+}
+
+struct Segment {
+    /// Media sequence number.
+    msn: u64,
+    /// Version of the init segment the parts need.
+    init_version: u64,
+    /// Follows a gap in the feed.
+    discontinuity: bool,
+    parts: Vec<Part>,
+    /// No more parts are coming.
+    complete: bool,
+}
+
+impl Segment {
+    fn duration(&self) -> u64 {
+        self.parts.iter().map(|part| part.duration).sum()
+    }
+}
+
+#[derive(Default)]
+struct HlsState {
+    /// Current version and contents of the init segment, which changes with
+    /// the parameter sets (e.g. the resolution).
+    init: Option<(u64, Bytes)>,
+    /// Oldest first. The last one is in progress.
+    segments: VecDeque<Segment>,
+    /// EXT-X-DISCONTINUITY-SEQUENCE, i.e. discontinuities that have left
+    /// the playlist.
+    discontinuity_sequence: u64,
+}
+
+impl HlsState {
+    fn segment(&self, msn: u64) -> Option<&Segment> {
+        let first = self.segments.front()?.msn;
+        self.segments.get(msn.checked_sub(first)? as usize)
+    }
+
+    /// The next part to be published, as (msn, part).
+    fn next_part(&self) -> Option<(u64, usize)> {
+        self.segments
+            .back()
+            .filter(|segment| !segment.complete)
+            .map(|segment| (segment.msn, segment.parts.len()))
+    }
+
+    /// Whether the playlist can be served: it has a finished segment to
+    /// start playback from.
+    fn ready(&self) -> bool {
+        self.segments.iter().any(|segment| segment.complete)
+    }
+
+    fn playlist(&self) -> String {
+        let mut playlist = String::new();
+        let first = self.segments.front().map_or(0, |segment| segment.msn);
+        writeln!(playlist, "#EXTM3U").ok();
+        writeln!(playlist, "#EXT-X-VERSION:6").ok();
+        writeln!(playlist, "#EXT-X-TARGETDURATION:{TARGET_DURATION}").ok();
+        writeln!(
+            playlist,
+            "#EXT-X-PART-INF:PART-TARGET={:.3}",
+            PART_TARGET.as_secs_f64()
+        )
+        .ok();
+        writeln!(
+            playlist,
+            "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={:.3}",
+            PART_HOLD_BACK.as_secs_f64()
+        )
+        .ok();
+        writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{first}").ok();
+        writeln!(
+            playlist,
+            "#EXT-X-DISCONTINUITY-SEQUENCE:{}",
+            self.discontinuity_sequence
+        )
+        .ok();
+
+        let parts_from = self.segments.len().saturating_sub(PARTS_SEGMENTS);
+        let mut init_version = None;
+        for (i, segment) in self.segments.iter().enumerate() {
+            if segment.discontinuity {
+                writeln!(playlist, "#EXT-X-DISCONTINUITY").ok();
+            }
+            if init_version != Some(segment.init_version) {
+                init_version = Some(segment.init_version);
+                writeln!(
+                    playlist,
+                    "#EXT-X-MAP:URI=\"init-{}.mp4\"",
+                    segment.init_version
+                )
+                .ok();
+            }
+            if i >= parts_from {
+                for (j, part) in segment.parts.iter().enumerate() {
+                    write!(
+                        playlist,
+                        "#EXT-X-PART:DURATION={:.5},URI=\"part-{}-{j}.m4s\"",
+                        part.duration as f64 / TIMESCALE as f64,
+                        segment.msn
+                    )
+                    .ok();
+                    if part.independent {
+                        write!(playlist, ",INDEPENDENT=YES").ok();
+                    }
+                    writeln!(playlist).ok();
+                }
+            }
+            if segment.complete {
+                writeln!(
+                    playlist,
+                    "#EXTINF:{:.5},",
+                    segment.duration() as f64 / TIMESCALE as f64
+                )
+                .ok();
+                writeln!(playlist, "segment-{}.m4s", segment.msn).ok();
+            }
+        }
+
+        if let Some((msn, part)) = self.next_part() {
+            writeln!(
+                playlist,
+                "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"part-{msn}-{part}.m4s\""
+            )
+            .ok();
+        }
+        playlist
+    }
+}
+
+struct HlsShared {
+    state: Mutex<HlsState>,
+    /// Bumped whenever a part is published, for blocking requests.
+    updated_tx: watch::Sender<()>,
+    last_request: Mutex<Instant>,
+    /// Wakes the packager when it's idle.
+    wake: Notify,
+}
+
+/// LL-HLS (fMP4, with partial segments and blocking playlist reload) for
+/// viewers who can live with a couple of seconds of latency, at the cost
+/// of plain HTTP requests rather than a peer connection each.
+///
+/// The packager only joins the feed while someone is requesting.
+#[derive(Clone)]
+pub struct Hls {
+    shared: Arc<HlsShared>,
+}
+
+impl Hls {
+    pub fn new(
+        feed_control_tx: mpsc::Sender<FeedControlMessage>,
+        feed_result_tx: broadcast::Sender<FeedResultMessage>,
+    ) -> Self {
+        let shared = Arc::new(HlsShared {
+            state: Mutex::new(HlsState::default()),
+            updated_tx: watch::Sender::new(()),
+            last_request: Mutex::new(Instant::now()),
+            wake: Notify::new(),
+        });
+        tokio::spawn(run_packager(
+            shared.clone(),
+            feed_control_tx,
+            feed_result_tx,
+        ));
+        Self { shared }
+    }
+
+    fn touch(&self) {
+        *self.shared.last_request.lock().unwrap() = Instant::now();
+        self.shared.wake.notify_one();
+    }
+
+    /// Wait (up to `BLOCKING_TIMEOUT`) for the state to satisfy `ready`.
+    /// Returns whether it did.
+    async fn wait_for(&self, ready: impl Fn(&HlsState) -> bool) -> bool {
+        let deadline = tokio::time::Instant::now() + BLOCKING_TIMEOUT;
+        let mut updated_rx = self.shared.updated_tx.subscribe();
+        loop {
+            if ready(&self.shared.state.lock().unwrap()) {
+                return true;
+            }
+            match timeout_at(deadline, updated_rx.changed()).await {
+                Ok(Ok(())) => continue,
+                _ => return false,
+            }
+        }
+    }
+}
+
+/// `GET /hls/stream.m3u8` is the media playlist. It takes `_HLS_msn` and
+/// `_HLS_part` to block until that segment or part is available. Init
+/// segments, segments and parts are served alongside it.
+pub fn routes(hls: Hls) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get()
+        .and(warp::path!("hls" / String))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::any().map(move || hls.clone()))
+        .and_then(handle_request)
+}
+
+fn empty_response(status: StatusCode) -> Response<Bytes> {
+    let mut response = Response::new(Bytes::new());
+    *response.status_mut() = status;
+    response
+}
+
+fn media_response(data: Bytes) -> Response<Bytes> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, "video/mp4")
+        // Never changes once published
+        .header(header::CACHE_CONTROL, "max-age=60")
+        .body(data)
+        .unwrap_or_else(|_| empty_response(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+async fn handle_request(
+    name: String,
+    query: HashMap<String, String>,
+    hls: Hls,
+) -> std::result::Result<Response<Bytes>, Infallible> {
+    hls.touch();
+
+    if name == "stream.m3u8" {
+        return Ok(handle_playlist(&query, &hls).await);
+    }
+    let Some((kind, numbers)) = name.split_once('-') else {
+        return Ok(empty_response(StatusCode::NOT_FOUND));
+    };
+    let numbers = numbers
+        .trim_end_matches(".m4s")
+        .trim_end_matches(".mp4")
+        .split('-')
+        .map(str::parse::<u64>)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap_or_default();
+
+    let response = match (kind, numbers.as_slice()) {
+        ("init", &[version]) => {
+            let state = hls.shared.state.lock().unwrap();
+            match &state.init {
+                Some((v, data)) if *v == version => media_response(data.clone()),
+                _ => empty_response(StatusCode::NOT_FOUND),
+            }
+        }
+        ("segment", &[msn]) => {
+            let state = hls.shared.state.lock().unwrap();
+            match state.segment(msn).filter(|segment| segment.complete) {
+                Some(segment) => {
+                    let mut data = BytesMut::new();
+                    for part in &segment.parts {
+                        data.extend_from_slice(&part.data);
+                    }
+                    media_response(data.freeze())
+                }
+                None => empty_response(StatusCode::NOT_FOUND),
+            }
+        }
+        ("part", &[msn, part]) => {
+            let part = part as usize;
+            let available = |state: &HlsState| {
+                state
+                    .segment(msn)
+                    .map_or(false, |segment| segment.parts.len() > part)
+            };
+            // The preload hint is requested before it exists, and answered
+            // as soon as it does
+            let hinted = hls.shared.state.lock().unwrap().next_part() == Some((msn, part));
+            if hinted && !hls.wait_for(available).await {
+                return Ok(empty_response(StatusCode::SERVICE_UNAVAILABLE));
+            }
+            let state = hls.shared.state.lock().unwrap();
+            match state
+                .segment(msn)
+                .and_then(|segment| segment.parts.get(part))
+            {
+                Some(part) => media_response(part.data.clone()),
+                None => empty_response(StatusCode::NOT_FOUND),
+            }
+        }
+        _ => empty_response(StatusCode::NOT_FOUND),
+    };
+    Ok(response)
+}
+
+async fn handle_playlist(query: &HashMap<String, String>, hls: &Hls) -> Response<Bytes> {
+    let msn = query
+        .get("_HLS_msn")
+        .and_then(|msn| msn.parse::<u64>().ok());
+    let part = query
+        .get("_HLS_part")
+        .and_then(|part| part.parse::<usize>().ok());
+    if part.is_some() && msn.is_none() {
+        return empty_response(StatusCode::BAD_REQUEST);
+    }
+
+    if let Some(msn) = msn {
+        // Too far in the future to ever be answered in time
+        let last = hls
+            .shared
+            .state
+            .lock()
+            .unwrap()
+            .segments
+            .back()
+            .map(|segment| segment.msn);
+        if last.map_or(false, |last| msn > last + 2) {
+            return empty_response(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    let ready = hls
+        .wait_for(|state| {
+            if !state.ready() {
+                return false;
+            }
+            let Some(msn) = msn else {
+                return true;
+            };
+            let first = state.segments.front().map_or(0, |segment| segment.msn);
+            // Gone already, so any playlist will do
+            if msn < first {
+                return true;
+            }
+            match (state.segment(msn), part) {
+                (Some(segment), Some(part)) => segment.parts.len() > part || segment.complete,
+                (Some(segment), None) => segment.complete,
+                (None, _) => false,
+            }
+        })
+        .await;
+    if !ready {
+        return empty_response(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let playlist = hls.shared.state.lock().unwrap().playlist();
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")
+        .header(header::CACHE_CONTROL, "no-cache")
+        .body(Bytes::from(playlist))
+        .unwrap_or_else(|_| empty_response(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+/// Join the feed whenever there are viewers, and package it until they're
+/// gone.
+async fn run_packager(
+    shared: Arc<HlsShared>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+) {
+    let idle = || shared.last_request.lock().unwrap().elapsed() >= IDLE_TIMEOUT;
+    let mut packager = HlsPackager::new(shared.clone(), feed_control_tx.clone());
+
+    loop {
+        shared.wake.notified().await;
+        if idle() {
+            continue;
+        }
+
+        let mut feed_result_rx = feed_result_tx.subscribe();
+        // Pinned, since HTTP gives us no bandwidth feedback
+        let joined = feed_control_tx
+            .send(FeedControlMessage::ClientJoined {
+                client_id: HLS_CLIENT_ID.to_owned(),
+                group_tx: None,
+            })
+            .await;
+        if joined.is_err() {
+            return;
+        }
+        println!("HLS packager joined the feed");
+
+        loop {
+            let result = tokio::time::timeout(Duration::from_secs(1), feed_result_rx.recv()).await;
+            match result {
+                // Frames can stop (e.g. the source went away), so viewers
+                // are checked for either way
+                Err(_) => {}
+                Ok(Err(broadcast::error::RecvError::Lagged(_))) => packager.discontinuity(),
+                Ok(Err(broadcast::error::RecvError::Closed)) => return,
+
+                Ok(Ok(FeedResultMessage::EncodedBitstream(frame))) => packager.push(&frame),
+                Ok(Ok(_)) => {}
+            }
+            if idle() {
+                break;
+            }
+        }
+
+        feed_control_tx
+            .send(FeedControlMessage::ClientLeft {
+                client_id: HLS_CLIENT_ID.to_owned(),
+            })
+            .await
+            .ok();
+        packager.reset();
+        println!("HLS packager left the feed");
+    }
+}
+
+struct PendingSample {
+    data: Vec<u8>,
+    keyframe: bool,
+    timestamp: VideoTimestamp,
+}
+
+/// Cuts the feed into keyframe-aligned segments of partial segments, much
+/// like `Fmp4Recorder` cuts fragments.
+struct HlsPackager {
+    shared: Arc<HlsShared>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    /// Samples of the part being built. Each duration is only known once
+    /// the next frame arrives.
+    pending: Vec<PendingSample>,
+    sps: Option<Vec<u8>>,
+    pps: Option<Vec<u8>>,
+    /// Parameter sets the current init segment was made from.
+    init_parameters: Option<(Vec<u8>, Vec<u8>)>,
+    init_version: u64,
+    next_msn: u64,
+    /// `mfhd` sequence number of the next part.
+    sequence: u32,
+    /// Decode time of the next part. (ticks)
+    decode_time: u64,
+    /// Frames are dropped until the next keyframe, e.g. after lagging.
+    waiting_for_keyframe: bool,
+    /// The next segment follows a gap.
+    discontinuity: bool,
+    last_keyframe_request: Option<Instant>,
+    last_timestamp: Option<VideoTimestamp>,
+    last_duration: u32,
+}
+
+impl HlsPackager {
+    fn new(shared: Arc<HlsShared>, feed_control_tx: mpsc::Sender<FeedControlMessage>) -> Self {
+        Self {
+            shared,
+            feed_control_tx,
+            pending: Vec::new(),
+            sps: None,
+            pps: None,
+            init_parameters: None,
+            init_version: 0,
+            next_msn: 0,
+            sequence: 1,
+            decode_time: 0,
+            waiting_for_keyframe: true,
+            discontinuity: false,
+            last_keyframe_request: None,
+            last_timestamp: None,
+            last_duration: DEFAULT_FRAME_DURATION,
+        }
+    }
+
+    fn push(&mut self, frame: &EncodedFrame) {
+        if self
+            .last_timestamp
+            .map_or(false, |last| frame.timestamp <= last)
+        {
+            // The source restarted, so the timeline starts over
+            self.discontinuity();
+        }
+        self.last_timestamp = Some(frame.timestamp);
+
+        let access_unit = h264::to_avcc(&frame.data);
+        if let Some(sps) = access_unit.sps {
+            self.sps = Some(sps);
+        }
+        if let Some(pps) = access_unit.pps {
+            self.pps = Some(pps);
+        }
+
+        let segment_length = self.segment_length(frame.timestamp);
+        let new_segment =
+            frame.keyframe && (self.waiting_for_keyframe || segment_length >= SEGMENT_TARGET);
+        // Assuming the frame lasts as long as the last one
+        let overlong = !self.waiting_for_keyframe
+            && segment_length + self.frame_duration() > Duration::from_secs(TARGET_DURATION);
+        if new_segment || overlong {
+            self.publish_part(Some(frame.timestamp), true);
+            if !self.start_segment() {
+                return;
+            }
+        } else if self.waiting_for_keyframe {
+            self.request_keyframe();
+            return;
+        } else if let Some(first) = self.pending.first() {
+            // Keep parts within the target, assuming the frame rate holds
+            let part_length =
+                Duration::from_micros(frame.timestamp.to_micros() - first.timestamp.to_micros())
+                    + self.frame_duration();
+            if part_length > PART_TARGET {
+                self.publish_part(Some(frame.timestamp), false);
+            }
+        }
+
+        self.pending.push(PendingSample {
+            data: access_unit.data,
+            keyframe: frame.keyframe,
+            timestamp: frame.timestamp,
+        });
+        if self.segment_length(frame.timestamp) >= SEGMENT_TARGET {
+            self.request_keyframe();
+        }
+    }
+
+    /// Frames went missing. Publishes what we have and waits for a keyframe
+    /// to start a new segment after a discontinuity.
+    fn discontinuity(&mut self) {
+        self.publish_part(None, true);
+        self.waiting_for_keyframe = true;
+        self.discontinuity = true;
+        self.last_timestamp = None;
+    }
+
+    /// Forget everything, e.g. once nobody's watching. Sequence numbers
+    /// carry on, and the next segment is a discontinuity, so players that
+    /// were around before see the gap.
+    fn reset(&mut self) {
+        self.pending.clear();
+        self.waiting_for_keyframe = true;
+        self.discontinuity = true;
+        self.last_timestamp = None;
+        self.init_parameters = None;
+        let mut state = self.shared.state.lock().unwrap();
+        // The forgotten segments' discontinuities have left the playlist
+        let discontinuity_sequence = state.discontinuity_sequence
+            + state
+                .segments
+                .iter()
+                .filter(|segment| segment.discontinuity)
+                .count() as u64;
+        *state = HlsState {
+            discontinuity_sequence,
+            ..Default::default()
+        };
+    }
+
+    /// Duration of the last frame, which the next is assumed to match.
+    fn frame_duration(&self) -> Duration {
+        Duration::from_micros(self.last_duration as u64 * 1_000_000 / TIMESCALE as u64)
+    }
+
+    /// How long the current segment will be once the pending samples are
+    /// published, if `next` follows them.
+    fn segment_length(&self, next: VideoTimestamp) -> Duration {
+        let state = self.shared.state.lock().unwrap();
+        let published = state
+            .segments
+            .back()
+            .filter(|segment| !segment.complete)
+            .map_or(0, Segment::duration);
+        let pending = self.pending.first().map_or(0, |first| {
+            next.to_micros().saturating_sub(first.timestamp.to_micros())
+        });
+        Duration::from_micros(published * 1_000_000 / TIMESCALE as u64 + pending)
+    }
+
+    fn request_keyframe(&mut self) {
+        let due = self
+            .last_keyframe_request
+            .map_or(true, |last| last.elapsed() >= PART_TARGET);
+        if due {
+            self.last_keyframe_request = Some(Instant::now());
+            self.feed_control_tx
+                .try_send(FeedControlMessage::RequestKeyframe {
+                    client_id: HLS_CLIENT_ID.to_owned(),
+                })
+                .ok();
+        }
+    }
+
+    /// Start a new segment at a keyframe, with a new init segment if the
+    /// parameter sets changed. Returns false if there are no parameter sets
+    /// to describe it with yet.
+    fn start_segment(&mut self) -> bool {
+        let (Some(sps), Some(pps)) = (self.sps.clone(), self.pps.clone()) else {
+            eprintln!("Keyframe without parameter sets, not packaging HLS yet");
+            return false;
+        };
+
+        let mut state = self.shared.state.lock().unwrap();
+        if self.init_parameters.as_ref() != Some(&(sps.clone(), pps.clone())) {
+            let init = match fmp4::init_segment(&sps, &pps) {
+                Ok(init) => init,
+                Err(e) => {
+                    eprintln!("Unable to make HLS init segment: {e:#}");
+                    return false;
+                }
+            };
+            self.init_version += 1;
+            state.init = Some((self.init_version, init.into()));
+            self.init_parameters = Some((sps, pps));
+        }
+
+        state.segments.push_back(Segment {
+            msn: self.next_msn,
+            init_version: self.init_version,
+            discontinuity: std::mem::take(&mut self.discontinuity),
+            parts: Vec::new(),
+            complete: false,
+        });
+        self.next_msn += 1;
+        while state.segments.len() > PLAYLIST_SEGMENTS {
+            if let Some(removed) = state.segments.pop_front() {
+                if removed.discontinuity {
+                    state.discontinuity_sequence += 1;
+                }
+            }
+        }
+        self.waiting_for_keyframe = false;
+        true
+    }
+
+    /// Publish the pending samples as a part of the current segment, and
+    /// maybe finish the segment. `next` is the timestamp of the frame after
+    /// them, if known.
+    fn publish_part(&mut self, next: Option<VideoTimestamp>, end_segment: bool) {
+        let pending = std::mem::take(&mut self.pending);
+        let mut state = self.shared.state.lock().unwrap();
+        let Some(segment) = state.segments.back_mut().filter(|s| !s.complete) else {
+            return;
+        };
+
+        if !pending.is_empty() {
+            let timestamps = pending
+                .iter()
+                .map(|sample| sample.timestamp)
+                .collect::<Vec<_>>();
+            let independent = pending[0].keyframe;
+            let mut samples = Vec::with_capacity(pending.len());
+            for (i, sample) in pending.into_iter().enumerate() {
+                let next = timestamps.get(i + 1).copied().or(next);
+                let duration = match next {
+                    Some(next) if next > sample.timestamp => {
+                        let micros = next.to_micros() - sample.timestamp.to_micros();
+                        (micros * TIMESCALE as u64 / 1_000_000) as u32
+                    }
+                    _ => self.last_duration,
+                };
+                self.last_duration = duration;
+                samples.push(Fmp4Sample {
+                    data: sample.data,
+                    duration,
+                    keyframe: sample.keyframe,
+                });
+            }
+
+            let duration = samples.iter().map(|s| s.duration as u64).sum::<u64>();
+            let data = fmp4::fragment(self.sequence, self.decode_time, &samples);
+            self.sequence = self.sequence.wrapping_add(1);
+            self.decode_time += duration;
+            segment.parts.push(Part {
+                data: data.into(),
+                duration,
+                independent,
+            });
+        }
+
+        if end_segment {
+            segment.complete = true;
+            // Empty segments (e.g. a discontinuity right after a new one
+            // started) aren't worth listing. Their number is reused, since
+            // the playlist can't skip any.
+            if segment.parts.is_empty() {
+                if let Some(removed) = state.segments.pop_back() {
+                    self.next_msn = removed.msn;
+                    self.discontinuity |= removed.discontinuity;
+                }
+            }
+        }
+        drop(state);
+        self.shared.updated_tx.send_replace(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 320x240 baseline.
+    const SPS: [u8; 8] = [0x67, 0x42, 0x00, 0x1f, 0xf4, 0x0a, 0x0f, 0xc8];
+    const PPS: [u8; 4] = [0x68, 0xce, 0x3c, 0x80];
+    const FRAME_INTERVAL: u64 = 1_000_000 / 30;
+
+    fn packager() -> (HlsPackager, mpsc::Receiver<FeedControlMessage>) {
+        let shared = Arc::new(HlsShared {
+            state: Mutex::new(HlsState::default()),
+            updated_tx: watch::Sender::new(()),
+            last_request: Mutex::new(Instant::now()),
+            wake: Notify::new(),
+        });
+        let (feed_control_tx, feed_control_rx) = mpsc::channel(64);
+        (HlsPackager::new(shared, feed_control_tx), feed_control_rx)
+    }
+
+    fn frame(micros: u64, keyframe: bool) -> EncodedFrame {
+        let mut data = Vec::new();
+        if keyframe {
+            for nal in [&SPS[..], &PPS, &[0x65, 0x88]] {
+                data.extend_from_slice(&[0, 0, 0, 1]);
+                data.extend_from_slice(nal);
+            }
+        } else {
+            data.extend_from_slice(&[0, 0, 0, 1, 0x41, 0x9a]);
+        }
+        EncodedFrame::new(data.into(), VideoTimestamp::from_micros(micros))
+    }
+
+    fn part(duration: u64, independent: bool) -> Part {
+        Part {
+            data: Bytes::new(),
+            duration,
+            independent,
+        }
+    }
+
+    #[test]
+    fn playlist() {
+        let second = TIMESCALE as u64;
+        let state = HlsState {
+            init: Some((2, Bytes::new())),
+            segments: VecDeque::from([
+                Segment {
+                    msn: 5,
+                    init_version: 1,
+                    discontinuity: false,
+                    parts: vec![part(second, true), part(second, false)],
+                    complete: true,
+                },
+                Segment {
+                    msn: 6,
+                    init_version: 2,
+                    discontinuity: true,
+                    parts: vec![part(second, true), part(second / 2, false)],
+                    complete: true,
+                },
+                Segment {
+                    msn: 7,
+                    init_version: 2,
+                    discontinuity: false,
+                    parts: vec![part(second / 2, true)],
+                    complete: false,
+                },
+            ]),
+            discontinuity_sequence: 3,
+        };
+        assert!(state.ready());
+        assert_eq!(state.next_part(), Some((7, 1)));
+        assert_eq!(
+            state.playlist(),
+            "#EXTM3U
+#EXT-X-VERSION:6
+#EXT-X-TARGETDURATION:4
+#EXT-X-PART-INF:PART-TARGET=0.500
+#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK=1.500
+#EXT-X-MEDIA-SEQUENCE:5
+#EXT-X-DISCONTINUITY-SEQUENCE:3
+#EXT-X-MAP:URI=\"init-1.mp4\"
+#EXT-X-PART:DURATION=1.00000,URI=\"part-5-0.m4s\",INDEPENDENT=YES
+#EXT-X-PART:DURATION=1.00000,URI=\"part-5-1.m4s\"
+#EXTINF:2.00000,
+segment-5.m4s
+#EXT-X-DISCONTINUITY
+#EXT-X-MAP:URI=\"init-2.mp4\"
+#EXT-X-PART:DURATION=1.00000,URI=\"part-6-0.m4s\",INDEPENDENT=YES
+#EXT-X-PART:DURATION=0.50000,URI=\"part-6-1.m4s\"
+#EXTINF:1.50000,
+segment-6.m4s
+#EXT-X-PART:DURATION=0.50000,URI=\"part-7-0.m4s\",INDEPENDENT=YES
+#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"part-7-1.m4s\"
+"
+        );
+    }
+
+    #[test]
+    fn playlist_lists_parts_of_the_last_segments_only() {
+        let state = HlsState {
+            init: None,
+            segments: (0..PARTS_SEGMENTS as u64 + 1)
+                .map(|msn| Segment {
+                    msn,
+                    init_version: 1,
+                    discontinuity: false,
+                    parts: vec![part(TIMESCALE as u64, true)],
+                    complete: true,
+                })
+                .collect(),
+            discontinuity_sequence: 0,
+        };
+        let playlist = state.playlist();
+        assert!(!playlist.contains("part-0-0.m4s"));
+        assert!(playlist.contains("part-1-0.m4s"));
+        assert!(playlist.contains("segment-0.m4s"));
+        assert!(!playlist.contains("PRELOAD-HINT"));
+    }
+
+    #[test]
+    fn long_gop_segments_stay_within_target_duration() {
+        let (mut packager, _feed_control_rx) = packager();
+        // A single keyframe, then 20s of predicted frames
+        for i in 0..600 {
+            packager.push(&frame(i * FRAME_INTERVAL, i == 0));
+        }
+
+        let state = packager.shared.state.lock().unwrap();
+        let complete = state
+            .segments
+            .iter()
+            .filter(|segment| segment.complete)
+            .collect::<Vec<_>>();
+        assert!(complete.len() >= 4);
+        for segment in &complete {
+            assert!(segment.duration() <= TARGET_DURATION * TIMESCALE as u64);
+            assert!(segment.duration() > SEGMENT_TARGET.as_secs() * TIMESCALE as u64);
+            // Only the first segment starts on the keyframe
+            assert_eq!(segment.parts[0].independent, segment.msn == 0);
+        }
+        let msns = state.segments.iter().map(|s| s.msn).collect::<Vec<_>>();
+        assert!(msns.windows(2).all(|w| w[1] == w[0] + 1));
+    }
+
+    #[test]
+    fn reset_keeps_sequences_consistent() {
+        let (mut packager, _feed_control_rx) = packager();
+        let mut micros = 0;
+        let mut push_seconds = |packager: &mut HlsPackager, seconds: u64| {
+            for i in 0..seconds * 30 {
+                packager.push(&frame(micros, i % 60 == 0));
+                micros += FRAME_INTERVAL;
+            }
+        };
+        push_seconds(&mut packager, 5);
+        packager.discontinuity();
+        push_seconds(&mut packager, 5);
+
+        let (next_msn, discontinuities) = {
+            let state = packager.shared.state.lock().unwrap();
+            let discontinuities = state.segments.iter().filter(|s| s.discontinuity).count();
+            (
+                state.segments.back().unwrap().msn + 1,
+                discontinuities as u64,
+            )
+        };
+        assert_eq!(discontinuities, 1);
+
+        packager.reset();
+        assert!(!packager.shared.state.lock().unwrap().ready());
+        push_seconds(&mut packager, 5);
+
+        let state = packager.shared.state.lock().unwrap();
+        let first = state.segments.front().unwrap();
+        assert_eq!(first.msn, next_msn);
+        assert!(first.discontinuity);
+        assert_eq!(state.discontinuity_sequence, discontinuities);
+        assert!(state.playlist().contains(&format!(
+            "#EXT-X-MEDIA-SEQUENCE:{next_msn}\n#EXT-X-DISCONTINUITY-SEQUENCE:{discontinuities}\n#EXT-X-DISCONTINUITY\n"
+        )));
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use webrtc::{
+    interceptor::{
+        stream_info::StreamInfo, Attributes, Interceptor, InterceptorBuilder, RTCPReader,
+        RTCPWriter, RTPReader, RTPWriter,
+    },
+    rtcp::{packet::Packet as RtcpPacket, receiver_report::ReceiverReport},
+    rtp::{header::Header, packet::Packet},
+    util::Marshal,
+};
+
+use super::NegotiatedStreams;
+
+type Result<T> = std::result::Result<T, webrtc::interceptor::Error>;
+
+/// Fixed RTP header size. ULPFEC protects everything after it.
+const RTP_HEADER_SIZE: usize = 12;
+/// Most media packets one ULPFEC packet can cover with the short mask.
+const MAX_GROUP_SIZE: usize = 16;
+/// Fewest media packets per ULPFEC packet, i.e. at most 50% overhead.
+const MIN_GROUP_SIZE: usize = 2;
+/// Weight of a new receiver report in the smoothed loss estimate.
+const LOSS_SMOOTHING: f32 = 0.3;
+/// Below this much loss, no protection packets are sent at all.
+const MIN_PROTECTED_LOSS: f32 = 0.005;
+
+pub struct FecGeneratorBuilder {
+    negotiated: NegotiatedStreams,
+}
+
+impl FecGeneratorBuilder {
+    pub fn new(negotiated: NegotiatedStreams) -> Self {
+        Self { negotiated }
+    }
+}
+
+impl InterceptorBuilder for FecGeneratorBuilder {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>> {
+        Ok(Arc::new(FecGenerator {
+            negotiated: self.negotiated.clone(),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }))
+    }
+}
+
+/// Wraps outgoing media in RED (RFC 2198) and interleaves ULPFEC (RFC 5109)
+/// packets, at a rate following the loss reported by the receiver.
+///
+/// FlexFEC would be more efficient, but browsers only decode RED+ULPFEC
+/// without field trials.
+pub struct FecGenerator {
+    negotiated: NegotiatedStreams,
+    streams: Arc<Mutex<HashMap<u32, Arc<FecStream>>>>,
+}
+
+struct FecStream {
+    writer: Arc<dyn RTPWriter + Send + Sync>,
+    red_payload_type: u8,
+    ulpfec_payload_type: u8,
+    inner: Mutex<FecStreamState>,
+}
+
+#[derive(Default)]
+struct FecStreamState {
+    /// Sequence numbers are rewritten to make room for the FEC packets.
+    sequence_number: Option<u16>,
+    /// Smoothed fraction of lost packets, from receiver reports.
+    loss: f32,
+    /// Media packets, as they'd be sent without RED, awaiting protection.
+    group: Vec<Bytes>,
+    group_base: u16,
+}
+
+impl FecStreamState {
+    fn next_sequence_number(&mut self, initial: u16) -> u16 {
+        let sequence_number = self.sequence_number.unwrap_or(initial);
+        self.sequence_number = Some(sequence_number.wrapping_add(1));
+        sequence_number
+    }
+
+    /// Media packets per ULPFEC packet for the current loss, if protecting.
+    fn group_size(&self) -> Option<usize> {
+        if self.loss < MIN_PROTECTED_LOSS {
+            return None;
+        }
+        // Aim for twice the measured loss in overhead.
+        let overhead = (2. * self.loss).min(0.5);
+        Some(((1. / overhead).round() as usize).clamp(MIN_GROUP_SIZE, MAX_GROUP_SIZE))
+    }
+}
+
+impl FecStream {
+    fn red_packet(&self, header: &Header, block_payload_type: u8, block: &[u8]) -> Packet {
+        let mut payload = BytesMut::with_capacity(1 + block.len());
+        // Single primary block; F bit is clear
+        payload.put_u8(block_payload_type & 0x7f);
+        payload.put_slice(block);
+
+        let mut header = header.clone();
+        header.payload_type = self.red_payload_type;
+        header.padding = false;
+        Packet {
+            header,
+            payload: payload.freeze(),
+        }
+    }
+
+    /// Wrap the media packet in RED and, if a protection group is complete,
+    /// build the ULPFEC packet protecting it.
+    fn protect(&self, pkt: &Packet) -> (Packet, Option<Packet>) {
+        let mut state = self.inner.lock().unwrap();
+
+        let mut media = pkt.clone();
+        media.header.sequence_number = state.next_sequence_number(pkt.header.sequence_number);
+        let red = self.red_packet(&media.header, media.header.payload_type, &media.payload);
+
+        let Some(group_size) = state.group_size() else {
+            state.group.clear();
+            return (red, None);
+        };
+        let Ok(raw) = media.marshal() else {
+            state.group.clear();
+            return (red, None);
+        };
+        if state.group.is_empty() {
+            state.group_base = media.header.sequence_number;
+        }
+        state.group.push(raw);
+
+        // Close the group at the end of each frame, so protection never
+        // waits on the next frame.
+        if state.group.len() < group_size && !media.header.marker {
+            return (red, None);
+        }
+
+        let group = std::mem::take(&mut state.group);
+        let fec = ulpfec_payload(&group, state.group_base);
+        let mut header = media.header.clone();
+        header.sequence_number = state.next_sequence_number(0);
+        header.marker = false;
+        let fec = self.red_packet(&header, self.ulpfec_payload_type, &fec);
+
+        (red, Some(fec))
+    }
+}
+
+/// Build a level 0 ULPFEC payload protecting every packet in the group.
+fn ulpfec_payload(group: &[Bytes], base: u16) -> Bytes {
+    let protection_length = group
+        .iter()
+        .map(|pkt| pkt.len() - RTP_HEADER_SIZE)
+        .max()
+        .unwrap_or(0);
+
+    let mut first_byte = 0u8;
+    let mut second_byte = 0u8;
+    let mut timestamp = 0u32;
+    let mut length = 0u16;
+    let mut mask = 0u16;
+    let mut protected = vec![0u8; protection_length];
+
+    for pkt in group {
+        first_byte ^= pkt[0];
+        second_byte ^= pkt[1];
+        timestamp ^= u32::from_be_bytes([pkt[4], pkt[5], pkt[6], pkt[7]]);
+        length ^= (pkt.len() - RTP_HEADER_SIZE) as u16;
+
+        let sequence_number = u16::from_be_bytes([pkt[2], pkt[3]]);
+        mask |= 0x8000 >> sequence_number.wrapping_sub(base);
+
+        protected
+            .iter_mut()
+            .zip(&pkt[RTP_HEADER_SIZE..])
+            .for_each(|(dst, src)| *dst ^= src);
+    }
+
+    let mut payload = BytesMut::with_capacity(14 + protection_length);
+    // FEC header. E and L are clear: no extension, 16 bit mask.
+    payload.put_u8(first_byte & 0x3f);
+    payload.put_u8(second_byte);
+    payload.put_u16(base);
+    payload.put_u32(timestamp);
+    payload.put_u16(length);
+    // Level 0 header
+    payload.put_u16(protection_length as u16);
+    payload.put_u16(mask);
+    payload.put_slice(&protected);
+    payload.freeze()
+}
+
+#[async_trait]
+impl RTPWriter for FecStream {
+    async fn write(&self, pkt: &Packet, attributes: &Attributes) -> Result<usize> {
+        let (red, fec) = self.protect(pkt);
+        let n = self.writer.write(&red, attributes).await?;
+        if let Some(fec) = fec {
+            self.writer.write(&fec, &Attributes::new()).await?;
+        }
+        Ok(n)
+    }
+}
+
+struct FecLossReader {
+    parent: Arc<dyn RTCPReader + Send + Sync>,
+    streams: Arc<Mutex<HashMap<u32, Arc<FecStream>>>>,
+}
+
+#[async_trait]
+impl RTCPReader for FecLossReader {
+    async fn read(
+        &self,
+        buf: &mut [u8],
+        attributes: &Attributes,
+    ) -> Result<(Vec<Box<dyn RtcpPacket + Send + Sync>>, Attributes)> {
+        let (packets, attributes) = self.parent.read(buf, attributes).await?;
+
+        let streams = self.streams.lock().unwrap();
+        for pkt in &packets {
+            let Some(rr) = pkt.as_any().downcast_ref::<ReceiverReport>() else {
+                continue;
+            };
+            for report in &rr.reports {
+                let Some(stream) = streams.get(&report.ssrc) else {
+                    continue;
+                };
+                let mut state = stream.inner.lock().unwrap();
+                let loss = report.fraction_lost as f32 / 256.;
+                state.loss += LOSS_SMOOTHING * (loss - state.loss);
+            }
+        }
+        drop(streams);
+
+        Ok((packets, attributes))
+    }
+}
+
+#[async_trait]
+impl Interceptor for FecGenerator {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        Arc::new(FecLossReader {
+            parent: reader,
+            streams: self.streams.clone(),
+        })
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        let Some(negotiated) = self.negotiated.lock().unwrap().get(&info.ssrc).cloned() else {
+            return writer;
+        };
+        let (Some(red_payload_type), Some(ulpfec_payload_type)) =
+            (negotiated.red_payload_type, negotiated.ulpfec_payload_type)
+        else {
+            return writer;
+        };
+
+        let stream = Arc::new(FecStream {
+            writer,
+            red_payload_type,
+            ulpfec_payload_type,
+            inner: Mutex::new(Default::default()),
+        });
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(info.ssrc, stream.clone());
+        stream
+    }
+
+    async fn unbind_local_stream(&self, info: &StreamInfo) {
+        self.streams.lock().unwrap().remove(&info.ssrc);
+    }
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<()> {
+        self.streams.lock().unwrap().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SSRC: u32 = 0x1122_3344;
+
+    struct NullWriter;
+
+    #[async_trait]
+    impl RTPWriter for NullWriter {
+        async fn write(&self, _pkt: &Packet, _attributes: &Attributes) -> Result<usize> {
+            Ok(0)
+        }
+    }
+
+    fn media(sequence_number: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Packet {
+        Packet {
+            header: Header {
+                version: 2,
+                marker,
+                payload_type: 96,
+                sequence_number,
+                timestamp,
+                ssrc: SSRC,
+                ..Default::default()
+            },
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    /// Recover the one packet of the group missing from `received`, as a
+    /// receiver would (RFC 5109 section 8).
+    fn recover(fec: &[u8], received: &[Bytes]) -> Bytes {
+        let mut first_byte = fec[0];
+        let mut second_byte = fec[1];
+        let base = u16::from_be_bytes([fec[2], fec[3]]);
+        let mut timestamp = u32::from_be_bytes([fec[4], fec[5], fec[6], fec[7]]);
+        let mut length = u16::from_be_bytes([fec[8], fec[9]]);
+        let protection_length = u16::from_be_bytes([fec[10], fec[11]]) as usize;
+        let mut mask = u16::from_be_bytes([fec[12], fec[13]]);
+        let mut protected = fec[14..14 + protection_length].to_vec();
+
+        for pkt in received {
+            first_byte ^= pkt[0];
+            second_byte ^= pkt[1];
+            timestamp ^= u32::from_be_bytes([pkt[4], pkt[5], pkt[6], pkt[7]]);
+            length ^= (pkt.len() - RTP_HEADER_SIZE) as u16;
+            let sequence_number = u16::from_be_bytes([pkt[2], pkt[3]]);
+            mask &= !(0x8000 >> sequence_number.wrapping_sub(base));
+            protected
+                .iter_mut()
+                .zip(&pkt[RTP_HEADER_SIZE..])
+                .for_each(|(dst, src)| *dst ^= src);
+        }
+        assert_eq!(mask.count_ones(), 1, "exactly one packet is missing");
+
+        let mut pkt = BytesMut::new();
+        pkt.put_u8(0x80 | (first_byte & 0x3f));
+        pkt.put_u8(second_byte);
+        pkt.put_u16(base.wrapping_add(mask.leading_zeros() as u16));
+        pkt.put_u32(timestamp);
+        pkt.put_u32(SSRC);
+        pkt.put_slice(&protected[..length as usize]);
+        pkt.freeze()
+    }
+
+    #[test]
+    fn ulpfec_header_and_payload() {
+        let group = [
+            media(10, 0x10, false, &[0x01, 0x02, 0x03])
+                .marshal()
+                .unwrap(),
+            media(11, 0x20, true, &[0xf0, 0x0f]).marshal().unwrap(),
+        ];
+        assert_eq!(
+            &ulpfec_payload(&group, 10)[..],
+            [
+                0x00, 0x80, // P/X/CC, M/PT recovery
+                0x00, 0x0a, // SN base
+                0x00, 0x00, 0x00, 0x30, // TS recovery
+                0x00, 0x01, // Length recovery
+                0x00, 0x03, // Protection length
+                0xc0, 0x00, // Mask
+                0xf1, 0x0d, 0x03, // Payload
+            ]
+        );
+    }
+
+    #[test]
+    fn any_single_loss_is_recoverable() {
+        // Wraps the sequence number, and varies lengths, timestamps and markers
+        let group: Vec<_> = [
+            media(65534, 9000, false, &[1, 2, 3, 4, 5]),
+            media(65535, 9000, false, &[6; 9]),
+            media(0, 9000, true, &[7]),
+            media(1, 12000, false, &[0xaa, 0x55, 0xaa]),
+            media(2, 12000, true, &[]),
+        ]
+        .iter()
+        .map(|pkt| pkt.marshal().unwrap())
+        .collect();
+        let fec = ulpfec_payload(&group, 65534);
+        assert_eq!(u16::from_be_bytes([fec[12], fec[13]]), 0xf800);
+
+        for lost in 0..group.len() {
+            let received: Vec<_> = group
+                .iter()
+                .enumerate()
+                .filter(|&(i, _)| i != lost)
+                .map(|(_, pkt)| pkt.clone())
+                .collect();
+            assert_eq!(recover(&fec, &received), group[lost], "lost packet {lost}");
+        }
+    }
+
+    #[test]
+    fn group_size_follows_loss() {
+        let group_size = |loss| {
+            FecStreamState {
+                loss,
+                ..Default::default()
+            }
+            .group_size()
+        };
+        assert_eq!(group_size(0.), None);
+        assert_eq!(group_size(0.004), None);
+        assert_eq!(group_size(0.01), Some(MAX_GROUP_SIZE));
+        assert_eq!(group_size(0.05), Some(10));
+        assert_eq!(group_size(0.5), Some(MIN_GROUP_SIZE));
+    }
+
+    #[test]
+    fn media_and_fec_are_wrapped_in_red() {
+        let stream = FecStream {
+            writer: Arc::new(NullWriter),
+            red_payload_type: 120,
+            ulpfec_payload_type: 121,
+            inner: Mutex::new(FecStreamState {
+                loss: 0.25,
+                ..Default::default()
+            }),
+        };
+
+        let first = media(1000, 3000, false, &[1, 2, 3]);
+        let (red, fec) = stream.protect(&first);
+        assert!(fec.is_none());
+        assert_eq!(red.header.payload_type, 120);
+        assert_eq!(red.header.sequence_number, 1000);
+        // One byte RED header: F clear, then the media payload type
+        assert_eq!(&red.payload[..], [96, 1, 2, 3]);
+
+        let second = media(1001, 3000, true, &[4, 5]);
+        let (red, fec) = stream.protect(&second);
+        assert_eq!(&red.payload[..], [96, 4, 5]);
+        assert!(red.header.marker);
+
+        let fec = fec.expect("group of two is complete");
+        assert_eq!(fec.header.payload_type, 120);
+        assert_eq!(fec.header.sequence_number, 1002);
+        assert_eq!(fec.header.timestamp, 3000);
+        assert!(!fec.header.marker);
+        assert_eq!(fec.payload[0], 121);
+        let group = [first.marshal().unwrap(), second.marshal().unwrap()];
+        assert_eq!(fec.payload[1..], ulpfec_payload(&group, 1000));
+
+        // Later media makes room for the FEC packet
+        let (red, _) = stream.protect(&media(1002, 6000, false, &[6]));
+        assert_eq!(red.header.sequence_number, 1003);
+    }
+}
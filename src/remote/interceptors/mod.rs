@@ -0,0 +1,96 @@
+pub mod fec;
+pub mod rtx;
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use self::rtx::RtxStats;
+
+/// Payload types and SSRCs negotiated for one outgoing media stream.
+///
+/// Answers reuse the payload types of the offer, so interceptors can't rely on
+/// the ones registered in the media engine and look them up here instead.
+#[derive(Debug, Default, Clone)]
+pub struct NegotiatedStream {
+    pub rtx_ssrc: Option<u32>,
+    /// Map of media payload type to its RTX payload type.
+    pub rtx_payload_types: HashMap<u8, u8>,
+    pub red_payload_type: Option<u8>,
+    pub ulpfec_payload_type: Option<u8>,
+    /// NACK counters of the peer connection the stream belongs to.
+    pub rtx_stats: Arc<RtxStats>,
+}
+
+/// Negotiated streams keyed by media SSRC. Shared by every peer connection,
+/// since SSRCs are unique per track.
+pub type NegotiatedStreams = Arc<Mutex<HashMap<u32, NegotiatedStream>>>;
+
+impl NegotiatedStream {
+    /// Parse every media section of a local description that sends media.
+    pub fn from_sdp(sdp: &str, rtx_stats: &Arc<RtxStats>) -> HashMap<u32, NegotiatedStream> {
+        let mut streams = HashMap::new();
+        let mut lines = sdp.split("\r\n").peekable();
+
+        // Skip the session section
+        while lines.next_if(|l| !l.starts_with("m=")).is_some() {}
+
+        while lines.peek().is_some() {
+            let mut section = vec![lines.next().unwrap()];
+            while let Some(line) = lines.next_if(|l| !l.starts_with("m=")) {
+                section.push(line);
+            }
+            if let Some((ssrc, mut stream)) = Self::from_media_section(&section) {
+                stream.rtx_stats = rtx_stats.clone();
+                streams.insert(ssrc, stream);
+            }
+        }
+
+        streams
+    }
+
+    fn from_media_section(section: &[&str]) -> Option<(u32, NegotiatedStream)> {
+        let ssrc = section.iter().find_map(|l| {
+            let (ssrc, _) = l.strip_prefix("a=ssrc:")?.split_once(' ')?;
+            ssrc.parse::<u32>().ok()
+        })?;
+
+        let mut stream = NegotiatedStream::default();
+        for line in section {
+            if let Some(group) = line.strip_prefix("a=ssrc-group:FID ") {
+                let mut ssrcs = group.split(' ').filter_map(|s| s.parse::<u32>().ok());
+                if ssrcs.next() == Some(ssrc) {
+                    stream.rtx_ssrc = ssrcs.next();
+                }
+            } else if let Some(rtpmap) = line.strip_prefix("a=rtpmap:") {
+                let Some((pt, codec)) = rtpmap.split_once(' ') else {
+                    continue;
+                };
+                let Ok(pt) = pt.parse::<u8>() else {
+                    continue;
+                };
+                match codec.split('/').next().unwrap_or_default() {
+                    name if name.eq_ignore_ascii_case("red") => stream.red_payload_type = Some(pt),
+                    name if name.eq_ignore_ascii_case("ulpfec") => {
+                        stream.ulpfec_payload_type = Some(pt)
+                    }
+                    _ => {}
+                }
+            } else if let Some(fmtp) = line.strip_prefix("a=fmtp:") {
+                let Some((pt, params)) = fmtp.split_once(' ') else {
+                    continue;
+                };
+                let apt = params
+                    .split(';')
+                    .find_map(|p| p.trim().strip_prefix("apt="))
+                    .and_then(|apt| apt.parse::<u8>().ok());
+                if let (Ok(pt), Some(apt)) = (pt.parse::<u8>(), apt) {
+                    stream.rtx_payload_types.insert(apt, pt);
+                }
+            }
+        }
+
+        Some((ssrc, stream))
+    }
+}
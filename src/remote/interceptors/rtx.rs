@@ -0,0 +1,277 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::{BufMut, BytesMut};
+use webrtc::{
+    interceptor::{
+        stream_info::StreamInfo, Attributes, Interceptor, InterceptorBuilder, RTCPReader,
+        RTCPWriter, RTPReader, RTPWriter,
+    },
+    rtcp::{
+        packet::Packet as RtcpPacket, transport_feedbacks::transport_layer_nack::TransportLayerNack,
+    },
+    rtp::packet::Packet,
+};
+
+use super::NegotiatedStreams;
+
+type Result<T> = std::result::Result<T, webrtc::interceptor::Error>;
+
+/// Hard cap on stored packets per stream, regardless of the history duration.
+const MAX_HISTORY_PACKETS: usize = 4096;
+
+/// RTX streams use a separate SSRC. Unless the SDP already announced one, it
+/// is derived from the media SSRC.
+pub fn rtx_ssrc(media_ssrc: u32) -> u32 {
+    media_ssrc ^ 0x5254_5800
+}
+
+/// Counters for NACK handling, shared with whoever reports on them.
+#[derive(Debug, Default)]
+pub struct RtxStats {
+    /// Packets requested through NACKs.
+    pub nacked: AtomicU64,
+    /// Packets answered with an RTX packet.
+    pub retransmitted: AtomicU64,
+    /// Packets that had already left the history.
+    pub missed: AtomicU64,
+}
+
+impl RtxStats {
+    /// Read and reset the counters. Returns (nacked, retransmitted, missed).
+    pub fn take(&self) -> (u64, u64, u64) {
+        (
+            self.nacked.swap(0, Ordering::Relaxed),
+            self.retransmitted.swap(0, Ordering::Relaxed),
+            self.missed.swap(0, Ordering::Relaxed),
+        )
+    }
+}
+
+pub struct RtxResponderBuilder {
+    /// How long sent packets are kept around for retransmission.
+    history: Duration,
+    negotiated: NegotiatedStreams,
+}
+
+impl RtxResponderBuilder {
+    pub fn new(history: Duration, negotiated: NegotiatedStreams) -> Self {
+        Self {
+            history,
+            negotiated,
+        }
+    }
+}
+
+impl InterceptorBuilder for RtxResponderBuilder {
+    fn build(&self, _id: &str) -> Result<Arc<dyn Interceptor + Send + Sync>> {
+        Ok(Arc::new(RtxResponder {
+            history: self.history,
+            negotiated: self.negotiated.clone(),
+            streams: Arc::new(Mutex::new(HashMap::new())),
+        }))
+    }
+}
+
+/// Keeps a short history of outgoing packets and answers NACKs with RTX
+/// (RFC 4588) packets on a separate SSRC.
+pub struct RtxResponder {
+    history: Duration,
+    negotiated: NegotiatedStreams,
+    streams: Arc<Mutex<HashMap<u32, Arc<RtxStream>>>>,
+}
+
+struct RtxStream {
+    writer: Arc<dyn RTPWriter + Send + Sync>,
+    history: Duration,
+    rtx_ssrc: u32,
+    /// Map of media payload type to its RTX payload type.
+    rtx_payload_types: HashMap<u8, u8>,
+    stats: Arc<RtxStats>,
+    inner: Mutex<RtxStreamState>,
+}
+
+#[derive(Default)]
+struct RtxStreamState {
+    packets: VecDeque<(Instant, Packet)>,
+    rtx_sequence_number: u16,
+}
+
+impl RtxStream {
+    fn remember(&self, pkt: &Packet) {
+        let mut state = self.inner.lock().unwrap();
+        let now = Instant::now();
+
+        // The history is indexed by sequence number offset, so a gap means
+        // the stream was reset and the old packets are useless.
+        if let Some((_, last)) = state.packets.back() {
+            if last.header.sequence_number.wrapping_add(1) != pkt.header.sequence_number {
+                state.packets.clear();
+            }
+        }
+        state.packets.push_back((now, pkt.clone()));
+
+        while let Some((sent_at, _)) = state.packets.front() {
+            if now.duration_since(*sent_at) <= self.history
+                && state.packets.len() <= MAX_HISTORY_PACKETS
+            {
+                break;
+            }
+            state.packets.pop_front();
+        }
+    }
+
+    /// Build the RTX packet for `sequence_number`, if it's still in history.
+    fn make_rtx(&self, sequence_number: u16) -> Option<Packet> {
+        let mut state = self.inner.lock().unwrap();
+        let (_, first) = state.packets.front()?;
+        let offset = sequence_number.wrapping_sub(first.header.sequence_number) as usize;
+        let (_, original) = state.packets.get(offset)?;
+        if original.header.sequence_number != sequence_number {
+            return None;
+        }
+        let rtx_payload_type = *self.rtx_payload_types.get(&original.header.payload_type)?;
+
+        // RTX payload is the original sequence number followed by the
+        // original payload.
+        let mut payload = BytesMut::with_capacity(2 + original.payload.len());
+        payload.put_u16(sequence_number);
+        payload.put_slice(&original.payload);
+
+        let mut header = original.header.clone();
+        header.ssrc = self.rtx_ssrc;
+        header.payload_type = rtx_payload_type;
+        header.sequence_number = state.rtx_sequence_number;
+        header.padding = false;
+        state.rtx_sequence_number = state.rtx_sequence_number.wrapping_add(1);
+
+        Some(Packet {
+            header,
+            payload: payload.freeze(),
+        })
+    }
+}
+
+#[async_trait]
+impl RTPWriter for RtxStream {
+    async fn write(&self, pkt: &Packet, attributes: &Attributes) -> Result<usize> {
+        self.remember(pkt);
+        self.writer.write(pkt, attributes).await
+    }
+}
+
+struct RtxNackReader {
+    parent: Arc<dyn RTCPReader + Send + Sync>,
+    streams: Arc<Mutex<HashMap<u32, Arc<RtxStream>>>>,
+}
+
+#[async_trait]
+impl RTCPReader for RtxNackReader {
+    async fn read(
+        &self,
+        buf: &mut [u8],
+        attributes: &Attributes,
+    ) -> Result<(Vec<Box<dyn RtcpPacket + Send + Sync>>, Attributes)> {
+        let (packets, attributes) = self.parent.read(buf, attributes).await?;
+
+        for pkt in &packets {
+            let Some(nack) = pkt.as_any().downcast_ref::<TransportLayerNack>() else {
+                continue;
+            };
+            let Some(stream) = self.streams.lock().unwrap().get(&nack.media_ssrc).cloned() else {
+                continue;
+            };
+
+            for sequence_number in nack.nacks.iter().flat_map(|pair| pair.packet_list()) {
+                stream.stats.nacked.fetch_add(1, Ordering::Relaxed);
+                let Some(rtx) = stream.make_rtx(sequence_number) else {
+                    stream.stats.missed.fetch_add(1, Ordering::Relaxed);
+                    continue;
+                };
+                if stream.writer.write(&rtx, &Attributes::new()).await.is_ok() {
+                    stream.stats.retransmitted.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+
+        Ok((packets, attributes))
+    }
+}
+
+#[async_trait]
+impl Interceptor for RtxResponder {
+    async fn bind_rtcp_reader(
+        &self,
+        reader: Arc<dyn RTCPReader + Send + Sync>,
+    ) -> Arc<dyn RTCPReader + Send + Sync> {
+        Arc::new(RtxNackReader {
+            parent: reader,
+            streams: self.streams.clone(),
+        })
+    }
+
+    async fn bind_rtcp_writer(
+        &self,
+        writer: Arc<dyn RTCPWriter + Send + Sync>,
+    ) -> Arc<dyn RTCPWriter + Send + Sync> {
+        writer
+    }
+
+    async fn bind_local_stream(
+        &self,
+        info: &StreamInfo,
+        writer: Arc<dyn RTPWriter + Send + Sync>,
+    ) -> Arc<dyn RTPWriter + Send + Sync> {
+        let Some(negotiated) = self.negotiated.lock().unwrap().get(&info.ssrc).cloned() else {
+            return writer;
+        };
+        if negotiated.rtx_payload_types.is_empty()
+            || !info
+                .rtcp_feedback
+                .iter()
+                .any(|fb| fb.typ == "nack" && fb.parameter.is_empty())
+        {
+            return writer;
+        }
+
+        let stream = Arc::new(RtxStream {
+            writer,
+            history: self.history,
+            rtx_ssrc: negotiated.rtx_ssrc.unwrap_or_else(|| rtx_ssrc(info.ssrc)),
+            rtx_payload_types: negotiated.rtx_payload_types,
+            stats: negotiated.rtx_stats,
+            inner: Mutex::new(Default::default()),
+        });
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(info.ssrc, stream.clone());
+        stream
+    }
+
+    async fn unbind_local_stream(&self, info: &StreamInfo) {
+        self.streams.lock().unwrap().remove(&info.ssrc);
+    }
+
+    async fn bind_remote_stream(
+        &self,
+        _info: &StreamInfo,
+        reader: Arc<dyn RTPReader + Send + Sync>,
+    ) -> Arc<dyn RTPReader + Send + Sync> {
+        reader
+    }
+
+    async fn unbind_remote_stream(&self, _info: &StreamInfo) {}
+
+    async fn close(&self) -> Result<()> {
+        self.streams.lock().unwrap().clear();
+        Ok(())
+    }
+}
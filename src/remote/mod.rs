@@ -1,17 +1,70 @@
+mod annotation_channel;
+pub mod config;
+mod control;
 mod extensions;
+mod hls;
+mod interceptors;
+mod pointer_channel;
+mod replay;
+mod rtsp;
+mod websocket;
+mod whep;
+mod whip;
 mod wrtc;
 
+use std::time::Duration;
+
 use anyhow::Result;
-use tokio::sync::{broadcast, mpsc};
+use tokio::{
+    sync::{broadcast, mpsc},
+    try_join,
+};
 use uuid::Uuid;
 
-use crate::feed::manager::{FeedControlMessage, FeedResultMessage};
+use crate::{
+    annotations::hub::AnnotationHub,
+    feed::{
+        manager::{FeedControlMessage, FeedResultMessage},
+        sources::whip::WhipIngest,
+    },
+    replay::InstantReplay,
+};
+
+/// Upper bound on receiver-side buffering, advertised through the playout
+/// delay extension. Retransmissions arriving later than this are useless, so
+/// it also sizes the RTX packet history.
+pub const PLAYOUT_MAX_DELAY: Duration = Duration::from_millis(200);
+
+/// Whether a `Content-Type` header is `media_type`, ignoring case and any
+/// parameters (e.g. `application/sdp; charset=utf-8`).
+fn is_media_type(content_type: Option<&str>, media_type: &str) -> bool {
+    content_type
+        .and_then(|content_type| content_type.split(';').next())
+        .is_some_and(|essence| essence.trim().eq_ignore_ascii_case(media_type))
+}
 
 pub async fn main(
+    config: config::RemoteConfig,
     feed_control_tx: mpsc::Sender<FeedControlMessage>,
     feed_result_tx: broadcast::Sender<FeedResultMessage>,
+    audio_tx: broadcast::Sender<FeedResultMessage>,
+    whip_ingest: WhipIngest,
+    annotations: AnnotationHub,
+    replay: Option<InstantReplay>,
 ) -> Result<()> {
-    wrtc::run_webrtc_tasks(feed_control_tx, feed_result_tx).await?;
+    let rtsp_port = config.rtsp_port;
+    try_join!(
+        wrtc::run_webrtc_tasks(
+            config,
+            feed_control_tx.clone(),
+            feed_result_tx.clone(),
+            audio_tx,
+            whip_ingest,
+            annotations,
+            replay,
+        ),
+        rtsp::run_rtsp_server(rtsp_port, feed_control_tx, feed_result_tx),
+    )?;
     // let client_id = Uuid::new_v4().to_string();
     // feed_control_tx
     //     .send(FeedControlMessage::ClientJoined {
@@ -20,3 +73,21 @@ pub async fn main(
     //     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_type_ignores_parameters_and_case() {
+        assert!(is_media_type(Some("application/sdp"), "application/sdp"));
+        assert!(is_media_type(
+            Some("Application/SDP; charset=utf-8"),
+            "application/sdp"
+        ));
+        assert!(is_media_type(Some(" application/sdp ;"), "application/sdp"));
+        assert!(!is_media_type(Some("application/sdpx"), "application/sdp"));
+        assert!(!is_media_type(Some("text/plain"), "application/sdp"));
+        assert!(!is_media_type(None, "application/sdp"));
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use webrtc::data_channel::{data_channel_message::DataChannelMessage, RTCDataChannel};
+
+use crate::annotations::{hub::AnnotationHub, protocol::Envelope};
+
+/// Label of the data channel clients open for pointer positions. Clients
+/// open it unordered without retransmits, so a stale position never holds
+/// up a newer one.
+pub const LABEL: &str = "pointer";
+
+/// Wire a client's pointer data channel up to the hub.
+pub fn attach(data_channel: Arc<RTCDataChannel>, client_id: String, hub: AnnotationHub) {
+    let open_channel = data_channel.clone();
+    let open_client_id = client_id.clone();
+    let open_hub = hub.clone();
+    data_channel.on_open(Box::new(move || {
+        let mut updates_rx = open_hub.join_pointer(&open_client_id);
+        let data_channel = open_channel.clone();
+        Box::pin(async move {
+            tokio::spawn(async move {
+                // Ends when the hub drops us on leave
+                while let Some(update) = updates_rx.recv().await {
+                    let Ok(text) = serde_json::to_string(&Envelope::new(update)) else {
+                        continue;
+                    };
+                    if data_channel.send_text(text).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        })
+    }));
+
+    let message_client_id = client_id.clone();
+    let message_hub = hub.clone();
+    data_channel.on_message(Box::new(move |message: DataChannelMessage| {
+        if message.is_string {
+            if let Ok(text) = std::str::from_utf8(&message.data) {
+                message_hub.handle_pointer_text(&message_client_id, text);
+            }
+        }
+        Box::pin(async {})
+    }));
+
+    data_channel.on_close(Box::new(move || {
+        hub.leave_pointer(&client_id);
+        Box::pin(async {})
+    }));
+}
@@ -0,0 +1,176 @@
+use std::{convert::Infallible, time::Duration};
+
+use bytes::Bytes;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+use warp::{
+    http::{header, Response, StatusCode},
+    Filter, Rejection, Reply,
+};
+
+use crate::{
+    feed::manager::FeedControlMessage,
+    replay::{InstantReplay, ReplayError},
+};
+
+#[derive(Deserialize)]
+struct MarkQuery {
+    /// How long before the latest frame to mark. (seconds)
+    #[serde(default)]
+    ago: f32,
+}
+
+#[derive(Clone, Copy)]
+enum Mark {
+    In,
+    Out,
+}
+
+/// Instant replay controls. All of them 404 unless the replay buffer is
+/// enabled.
+///
+/// `POST /replay/in` and `POST /replay/out` mark where the clip starts and
+/// ends, at the latest frame or `?ago=<seconds>` before it. Without an out
+/// point, the clip runs up to the latest frame.
+///
+/// `GET /replay/clip.mp4` exports the clip, and `GET /replay/clip.jsonl`
+/// what was drawn over it as an annotation recording lined up with the MP4.
+///
+/// `POST /replay/play` plays the clip on the feed, which goes back to live
+/// once it's over or on `DELETE /replay/play`.
+pub fn routes(
+    replay: Option<InstantReplay>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let enabled = warp::path("replay").and(warp::any().map(move || replay.clone()).and_then(
+        |replay: Option<InstantReplay>| async move { replay.ok_or_else(warp::reject::not_found) },
+    ));
+
+    let mark_in_handler = warp::post()
+        .and(enabled.clone())
+        .and(warp::path!("in"))
+        .and(warp::query::<MarkQuery>())
+        .and_then(|replay, query| handle_mark(replay, query, Mark::In));
+
+    let mark_out_handler = warp::post()
+        .and(enabled.clone())
+        .and(warp::path!("out"))
+        .and(warp::query::<MarkQuery>())
+        .and_then(|replay, query| handle_mark(replay, query, Mark::Out));
+
+    let mp4_handler = warp::get()
+        .and(enabled.clone())
+        .and(warp::path!("clip.mp4"))
+        .and_then(|replay: InstantReplay| async move {
+            Ok::<_, Infallible>(match replay.export_mp4() {
+                Ok(data) => attachment(data.into(), "video/mp4", "replay.mp4"),
+                Err(e) => error_response(e),
+            })
+        });
+
+    let annotations_handler = warp::get()
+        .and(enabled.clone())
+        .and(warp::path!("clip.jsonl"))
+        .and_then(|replay: InstantReplay| async move {
+            Ok::<_, Infallible>(match replay.export_annotations() {
+                Ok(lines) => attachment(lines.into(), "application/jsonl", "replay.jsonl"),
+                Err(e) => error_response(e),
+            })
+        });
+
+    let play_tx = feed_control_tx.clone();
+    let play_handler = warp::post()
+        .and(enabled.clone())
+        .and(warp::path!("play"))
+        .and(warp::any().map(move || play_tx.clone()))
+        .and_then(handle_play);
+
+    let stop_handler = warp::delete()
+        .and(enabled)
+        .and(warp::path!("play"))
+        .and(warp::any().map(move || feed_control_tx.clone()))
+        .and_then(
+            |_, feed_control_tx: mpsc::Sender<FeedControlMessage>| async move {
+                let status = match feed_control_tx.send(FeedControlMessage::StopReplay).await {
+                    Ok(()) => StatusCode::NO_CONTENT,
+                    Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+                };
+                Ok::<_, Infallible>(empty_response(status))
+            },
+        );
+
+    mark_in_handler
+        .or(mark_out_handler)
+        .or(mp4_handler)
+        .or(annotations_handler)
+        .or(play_handler)
+        .or(stop_handler)
+}
+
+fn empty_response(status: StatusCode) -> Response<Bytes> {
+    let mut response = Response::new(Bytes::new());
+    *response.status_mut() = status;
+    response
+}
+
+/// Bad requests for the replay's current state are conflicts, e.g. asking
+/// for a clip before marking one.
+fn error_response(error: ReplayError) -> Response<Bytes> {
+    let status = match error {
+        ReplayError::Export(ref e) => {
+            eprintln!("Unable to export replay: {e:#}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        _ => StatusCode::CONFLICT,
+    };
+    let mut response = Response::new(Bytes::from(error.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+fn attachment(data: Bytes, content_type: &str, filename: &str) -> Response<Bytes> {
+    Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{filename}\""),
+        )
+        .body(data)
+        .unwrap_or_else(|_| empty_response(StatusCode::INTERNAL_SERVER_ERROR))
+}
+
+async fn handle_mark(
+    replay: InstantReplay,
+    query: MarkQuery,
+    mark: Mark,
+) -> std::result::Result<Response<Bytes>, Infallible> {
+    let Ok(ago) = Duration::try_from_secs_f32(query.ago) else {
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+    let result = match mark {
+        Mark::In => replay.mark_in(ago),
+        Mark::Out => replay.mark_out(ago),
+    };
+    Ok(match result {
+        Ok(_) => empty_response(StatusCode::NO_CONTENT),
+        Err(e) => error_response(e),
+    })
+}
+
+async fn handle_play(
+    replay: InstantReplay,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+) -> std::result::Result<Response<Bytes>, Infallible> {
+    let clip = match replay.clip() {
+        Ok(clip) => clip,
+        Err(e) => return Ok(error_response(e)),
+    };
+    let status = match feed_control_tx
+        .send(FeedControlMessage::Replay { clip })
+        .await
+    {
+        Ok(()) => StatusCode::NO_CONTENT,
+        Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok(empty_response(status))
+}
@@ -0,0 +1,776 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{broadcast, mpsc},
+    task::AbortHandle,
+};
+use uuid::Uuid;
+use webrtc::{
+    rtcp::sender_report::SenderReport,
+    rtp::{codecs::h264::H264Payloader, header::Header, packet::Packet, packetizer::Payloader},
+    util::Marshal,
+};
+
+use crate::feed::{
+    encoders::{h264, EncodedFrame},
+    frame::VideoTimestamp,
+    manager::{FeedControlMessage, FeedResultMessage},
+};
+
+/// Dynamic payload type the H.264 stream is described with.
+const PAYLOAD_TYPE: u8 = 96;
+/// Keeps packets under a typical Ethernet MTU, with room for IP/UDP.
+const MTU: usize = 1200;
+/// Requests (headers and body) bigger than this are rejected.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+/// Interleaved packets queued for a TCP client before frames are dropped.
+const INTERLEAVED_QUEUE: usize = 512;
+const SENDER_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// Advertised to clients, which keep the session alive with requests.
+const SESSION_TIMEOUT: u32 = 60;
+/// Seconds between the NTP epoch (1900) and the UNIX epoch.
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Clone)]
+struct ParameterSets {
+    sps: Vec<u8>,
+    pps: Vec<u8>,
+}
+
+#[derive(Clone)]
+struct RtspContext {
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+    /// Latest parameter sets seen by any session, described in the SDP for
+    /// decoders that won't wait for them in-band.
+    parameter_sets: Arc<Mutex<Option<ParameterSets>>>,
+}
+
+/// Serve the feed's H.264 stream over RTSP, as RTP over UDP or interleaved
+/// in the RTSP connection. Clients are pinned to the primary encoder group,
+/// since there's no feedback to adapt their bitrate with. Does nothing
+/// without a port.
+pub async fn run_rtsp_server(
+    port: Option<u16>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+) -> Result<()> {
+    let Some(port) = port else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(("0.0.0.0", port))
+        .await
+        .with_context(|| format!("Unable to bind RTSP port {port}"))?;
+    println!("RTSP server on rtsp://0.0.0.0:{port}/feed");
+
+    let context = RtspContext {
+        feed_control_tx,
+        feed_result_tx,
+        parameter_sets: Arc::new(Mutex::new(None)),
+    };
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let context = context.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peer, context).await {
+                eprintln!("RTSP connection from {peer} failed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    peer: SocketAddr,
+    context: RtspContext,
+) -> Result<()> {
+    stream.set_nodelay(true).ok();
+    let local_ip = stream.local_addr()?.ip();
+    let (mut reader, mut writer) = stream.into_split();
+
+    // Responses and interleaved media share the connection, so both go
+    // through here
+    let (out_tx, mut out_rx) = mpsc::channel::<Bytes>(INTERLEAVED_QUEUE);
+    let write_task = tokio::spawn(async move {
+        while let Some(data) = out_rx.recv().await {
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut session = RtspSession::new(peer, local_ip, out_tx.clone(), context);
+    let mut buf = BytesMut::with_capacity(4096);
+    'connection: loop {
+        while let Some(message) = parse_message(&mut buf)? {
+            // Interleaved data from the client is its RTCP, which we don't use
+            let Incoming::Request(request) = message else {
+                continue;
+            };
+            let response = session.handle(&request).await;
+            if out_tx.send(response.into()).await.is_err() {
+                break 'connection;
+            }
+        }
+        if buf.len() > MAX_REQUEST_SIZE {
+            bail!("request too large");
+        }
+        if reader.read_buf(&mut buf).await? == 0 {
+            break;
+        }
+    }
+
+    // The session's stream holds a sender too, so stop it before waiting on
+    // the writer
+    drop(session);
+    drop(out_tx);
+    write_task.await.ok();
+    Ok(())
+}
+
+enum Incoming {
+    Request(RtspRequest),
+    Interleaved,
+}
+
+struct RtspRequest {
+    method: String,
+    uri: String,
+    /// Names are lowercase.
+    headers: Vec<(String, String)>,
+}
+
+impl RtspRequest {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Take the next complete message off the front of `buf`, if there is one.
+fn parse_message(buf: &mut BytesMut) -> Result<Option<Incoming>> {
+    if buf.first() == Some(&b'$') {
+        if buf.len() < 4 {
+            return Ok(None);
+        }
+        let length = 4 + u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if buf.len() < length {
+            return Ok(None);
+        }
+        buf.advance(length);
+        return Ok(Some(Incoming::Interleaved));
+    }
+
+    let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let head = std::str::from_utf8(&buf[..end]).context("request isn't UTF-8")?;
+    let mut lines = head.split("\r\n");
+
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(uri), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        bail!("malformed request line {request_line:?}");
+    };
+    if !version.starts_with("RTSP/1.") {
+        bail!("unsupported version {version:?}");
+    }
+
+    let headers = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_owned()))
+        .collect::<Vec<_>>();
+    let request = RtspRequest {
+        method: method.to_owned(),
+        uri: uri.to_owned(),
+        headers,
+    };
+
+    // Bodies (e.g. SET_PARAMETER) are skipped
+    let content_length = request
+        .header("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    let length = end + 4 + content_length;
+    if buf.len() < length {
+        return Ok(None);
+    }
+    buf.advance(length);
+    Ok(Some(Incoming::Request(request)))
+}
+
+/// Where a session's RTP and RTCP go.
+#[derive(Clone)]
+enum RtpTransport {
+    Udp {
+        rtp: Arc<UdpSocket>,
+        rtcp: Arc<UdpSocket>,
+        rtp_dest: SocketAddr,
+        rtcp_dest: SocketAddr,
+    },
+    Interleaved {
+        rtp_channel: u8,
+        rtcp_channel: u8,
+    },
+}
+
+/// One RTSP connection. A connection only ever has one session, which ends
+/// with it.
+struct RtspSession {
+    id: String,
+    peer: SocketAddr,
+    local_ip: IpAddr,
+    ssrc: u32,
+    out_tx: mpsc::Sender<Bytes>,
+    context: RtspContext,
+    transport: Option<RtpTransport>,
+    /// Set while playing.
+    stream: Option<AbortHandle>,
+}
+
+impl Drop for RtspSession {
+    fn drop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            stream.abort();
+        }
+    }
+}
+
+impl RtspSession {
+    fn new(
+        peer: SocketAddr,
+        local_ip: IpAddr,
+        out_tx: mpsc::Sender<Bytes>,
+        context: RtspContext,
+    ) -> Self {
+        let id = Uuid::new_v4().simple().to_string()[..16].to_owned();
+        Self {
+            id,
+            peer,
+            local_ip,
+            ssrc: Uuid::new_v4().as_u128() as u32,
+            out_tx,
+            context,
+            transport: None,
+            stream: None,
+        }
+    }
+
+    async fn handle(&mut self, request: &RtspRequest) -> String {
+        let cseq = request.header("cseq").unwrap_or("0");
+
+        // Everything after SETUP must be for our session
+        let needs_session = matches!(request.method.as_str(), "PLAY" | "PAUSE" | "TEARDOWN");
+        let session = request
+            .header("session")
+            .map(|session| session.split(';').next().unwrap_or_default().trim());
+        if needs_session && session != Some(self.id.as_str()) {
+            return response(cseq, "454 Session Not Found", &[], "");
+        }
+
+        let result = match request.method.as_str() {
+            "OPTIONS" => Ok(response(
+                cseq,
+                "200 OK",
+                &[(
+                    "Public",
+                    "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER".to_owned(),
+                )],
+                "",
+            )),
+            "DESCRIBE" => Ok(self.describe(cseq, request)),
+            "SETUP" => self.setup(cseq, request).await,
+            "PLAY" => self.play(cseq, request),
+            "PAUSE" => {
+                self.stop();
+                Ok(self.session_response(cseq, &[]))
+            }
+            "TEARDOWN" => {
+                self.stop();
+                self.transport = None;
+                Ok(self.session_response(cseq, &[]))
+            }
+            // Used by clients as a keepalive
+            "GET_PARAMETER" | "SET_PARAMETER" => Ok(self.session_response(cseq, &[])),
+            _ => Ok(response(cseq, "501 Not Implemented", &[], "")),
+        };
+        result.unwrap_or_else(|status| response(cseq, status, &[], ""))
+    }
+
+    fn describe(&self, cseq: &str, request: &RtspRequest) -> String {
+        let parameter_sets = self.context.parameter_sets.lock().unwrap().clone();
+        let mut fmtp = "packetization-mode=1".to_owned();
+        if let Some(ParameterSets { sps, pps }) = parameter_sets {
+            if sps.len() >= 4 {
+                fmtp += &format!(
+                    ";profile-level-id={:02x}{:02x}{:02x}",
+                    sps[1], sps[2], sps[3]
+                );
+            }
+            fmtp += &format!(
+                ";sprop-parameter-sets={},{}",
+                base64_encode(&sps),
+                base64_encode(&pps)
+            );
+        }
+
+        let address_type = match self.local_ip {
+            IpAddr::V4(_) => "IP4",
+            IpAddr::V6(_) => "IP6",
+        };
+        let sdp = [
+            "v=0".to_owned(),
+            format!("o=- {} 1 IN {address_type} {}", self.ssrc, self.local_ip),
+            "s=Telestrator".to_owned(),
+            format!("c=IN {address_type} {}", self.local_ip),
+            "t=0 0".to_owned(),
+            "a=control:*".to_owned(),
+            format!("m=video 0 RTP/AVP {PAYLOAD_TYPE}"),
+            format!("a=rtpmap:{PAYLOAD_TYPE} H264/90000"),
+            format!("a=fmtp:{PAYLOAD_TYPE} {fmtp}"),
+            "a=control:trackID=0".to_owned(),
+        ]
+        .join("\r\n")
+            + "\r\n";
+
+        // Relative controls (trackID=0) are resolved against this
+        let content_base = format!("{}/", request.uri.trim_end_matches('/'));
+        response(
+            cseq,
+            "200 OK",
+            &[
+                ("Content-Base", content_base),
+                ("Content-Type", "application/sdp".to_owned()),
+            ],
+            &sdp,
+        )
+    }
+
+    async fn setup(&mut self, cseq: &str, request: &RtspRequest) -> Result<String, &'static str> {
+        if self.stream.is_some() {
+            return Err("455 Method Not Valid in This State");
+        }
+        let transport = request.header("transport").ok_or("400 Bad Request")?;
+
+        // Clients can offer several transports, in order of preference
+        for offer in transport.split(',') {
+            let params = offer.split(';').map(str::trim).collect::<Vec<_>>();
+            if params.contains(&"multicast") {
+                continue;
+            }
+            let param = |name: &str| {
+                params
+                    .iter()
+                    .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+                    .and_then(parse_port_pair)
+            };
+
+            match params.first().copied() {
+                Some("RTP/AVP/TCP") => {
+                    let (rtp_channel, rtcp_channel) = param("interleaved").unwrap_or((0, 1));
+                    let (Ok(rtp_channel), Ok(rtcp_channel)) =
+                        (u8::try_from(rtp_channel), u8::try_from(rtcp_channel))
+                    else {
+                        continue;
+                    };
+                    self.transport = Some(RtpTransport::Interleaved {
+                        rtp_channel,
+                        rtcp_channel,
+                    });
+                    let transport = format!(
+                        "RTP/AVP/TCP;unicast;interleaved={rtp_channel}-{rtcp_channel};ssrc={:08X}",
+                        self.ssrc
+                    );
+                    return Ok(self.session_response(cseq, &[("Transport", transport)]));
+                }
+                Some("RTP/AVP" | "RTP/AVP/UDP") => {
+                    let Some((rtp_port, rtcp_port)) = param("client_port") else {
+                        continue;
+                    };
+                    let (rtp, rtcp) = bind_port_pair(self.local_ip)
+                        .await
+                        .map_err(|_| "500 Internal Server Error")?;
+                    let server_ports = (
+                        rtp.local_addr().map_or(0, |a| a.port()),
+                        rtcp.local_addr().map_or(0, |a| a.port()),
+                    );
+                    self.transport = Some(RtpTransport::Udp {
+                        rtp: Arc::new(rtp),
+                        rtcp: Arc::new(rtcp),
+                        rtp_dest: SocketAddr::new(self.peer.ip(), rtp_port),
+                        rtcp_dest: SocketAddr::new(self.peer.ip(), rtcp_port),
+                    });
+                    let transport = format!(
+                        "RTP/AVP;unicast;client_port={rtp_port}-{rtcp_port};server_port={}-{};ssrc={:08X}",
+                        server_ports.0, server_ports.1, self.ssrc
+                    );
+                    return Ok(self.session_response(cseq, &[("Transport", transport)]));
+                }
+                _ => continue,
+            }
+        }
+        Err("461 Unsupported Transport")
+    }
+
+    fn play(&mut self, cseq: &str, request: &RtspRequest) -> Result<String, &'static str> {
+        let transport = self
+            .transport
+            .clone()
+            .ok_or("455 Method Not Valid in This State")?;
+
+        if self.stream.is_none() {
+            let sender = RtpSender::new(transport, self.ssrc, self.out_tx.clone());
+            let client_id = format!("rtsp-{}", self.id);
+            let context = self.context.clone();
+            let peer = self.peer;
+            println!("RTSP client {peer} playing");
+            let stream = tokio::spawn(async move {
+                if let Err(e) = stream_feed(client_id, sender, context).await {
+                    eprintln!("RTSP stream to {peer} stopped: {e:#}");
+                }
+            });
+            self.stream = Some(stream.abort_handle());
+        }
+
+        // The stream is live, so there's nothing to seek; echo the range back
+        let range = request.header("range").unwrap_or("npt=0.000-").to_owned();
+        Ok(self.session_response(cseq, &[("Range", range)]))
+    }
+
+    fn stop(&mut self) {
+        if let Some(stream) = self.stream.take() {
+            stream.abort();
+        }
+    }
+
+    fn session_response(&self, cseq: &str, headers: &[(&str, String)]) -> String {
+        let mut headers = headers.to_vec();
+        headers.push(("Session", format!("{};timeout={SESSION_TIMEOUT}", self.id)));
+        response(cseq, "200 OK", &headers, "")
+    }
+}
+
+fn response(cseq: &str, status: &str, headers: &[(&str, String)], body: &str) -> String {
+    let mut response = format!("RTSP/1.0 {status}\r\nCSeq: {cseq}\r\nServer: Telestrator\r\n");
+    for (name, value) in headers {
+        response += &format!("{name}: {value}\r\n");
+    }
+    if !body.is_empty() {
+        response += &format!("Content-Length: {}\r\n", body.len());
+    }
+    response + "\r\n" + body
+}
+
+/// Parse `a-b`, or just `a` meaning `a-(a+1)`.
+fn parse_port_pair(value: &str) -> Option<(u16, u16)> {
+    match value.split_once('-') {
+        Some((a, b)) => Some((a.parse().ok()?, b.parse().ok()?)),
+        None => {
+            let a = value.parse::<u16>().ok()?;
+            Some((a, a.checked_add(1)?))
+        }
+    }
+}
+
+/// Bind RTP and RTCP sockets, on consecutive ports if we can.
+async fn bind_port_pair(ip: IpAddr) -> Result<(UdpSocket, UdpSocket)> {
+    let rtp = UdpSocket::bind((ip, 0)).await?;
+    let rtp_port = rtp.local_addr()?.port();
+    let rtcp = match rtp_port.checked_add(1) {
+        Some(port) => match UdpSocket::bind((ip, port)).await {
+            Ok(rtcp) => rtcp,
+            Err(_) => UdpSocket::bind((ip, 0)).await?,
+        },
+        None => UdpSocket::bind((ip, 0)).await?,
+    };
+    Ok((rtp, rtcp))
+}
+
+/// Tells the feed the client is gone however the stream ends.
+struct ClientGuard {
+    client_id: String,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let client_id = std::mem::take(&mut self.client_id);
+        let feed_control_tx = self.feed_control_tx.clone();
+        tokio::spawn(async move {
+            feed_control_tx
+                .send(FeedControlMessage::ClientLeft { client_id })
+                .await
+                .ok();
+        });
+    }
+}
+
+/// Send the feed to a playing client until it stops.
+async fn stream_feed(client_id: String, mut sender: RtpSender, context: RtspContext) -> Result<()> {
+    let _guard = ClientGuard {
+        client_id: client_id.clone(),
+        feed_control_tx: context.feed_control_tx.clone(),
+    };
+    let mut feed_result_rx = context.feed_result_tx.subscribe();
+    context
+        .feed_control_tx
+        .send(FeedControlMessage::ClientJoined {
+            client_id: client_id.clone(),
+            group_tx: None,
+        })
+        .await
+        .context("feed is gone")?;
+
+    let request_keyframe = || {
+        context
+            .feed_control_tx
+            .try_send(FeedControlMessage::RequestKeyframe {
+                client_id: client_id.clone(),
+            })
+            .ok();
+    };
+    request_keyframe();
+
+    // Nothing decodes until a keyframe, so frames before the first one (or
+    // after any we had to drop) are skipped
+    let mut waiting_for_keyframe = true;
+    loop {
+        let frame = match feed_result_rx.recv().await {
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                if !waiting_for_keyframe {
+                    waiting_for_keyframe = true;
+                    request_keyframe();
+                }
+                continue;
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+
+            Ok(FeedResultMessage::EncodedBitstream(frame)) => frame,
+            Ok(_) => continue,
+        };
+
+        if frame.keyframe {
+            waiting_for_keyframe = false;
+            let access_unit = h264::to_avcc(&frame.data);
+            if let (Some(sps), Some(pps)) = (access_unit.sps, access_unit.pps) {
+                *context.parameter_sets.lock().unwrap() = Some(ParameterSets { sps, pps });
+            }
+        }
+        if waiting_for_keyframe {
+            continue;
+        }
+
+        if !sender.send_frame(&frame).await? {
+            waiting_for_keyframe = true;
+            request_keyframe();
+        }
+    }
+    Ok(())
+}
+
+/// Packetizes frames into RTP for one client.
+struct RtpSender {
+    transport: RtpTransport,
+    out_tx: mpsc::Sender<Bytes>,
+    payloader: H264Payloader,
+    ssrc: u32,
+    sequence_number: u16,
+    /// Random offset of the RTP timestamps, as RFC 3550 wants.
+    rtp_time_offset: u32,
+    packet_count: u32,
+    octet_count: u32,
+    last_report: Option<Instant>,
+}
+
+impl RtpSender {
+    fn new(transport: RtpTransport, ssrc: u32, out_tx: mpsc::Sender<Bytes>) -> Self {
+        let random = Uuid::new_v4().as_u128();
+        Self {
+            transport,
+            out_tx,
+            payloader: H264Payloader::default(),
+            ssrc,
+            sequence_number: random as u16,
+            rtp_time_offset: (random >> 32) as u32,
+            packet_count: 0,
+            octet_count: 0,
+            last_report: None,
+        }
+    }
+
+    fn rtp_time(&self, timestamp: VideoTimestamp) -> u32 {
+        let ticks = timestamp.to_micros() * 90 / 1000;
+        self.rtp_time_offset.wrapping_add(ticks as u32)
+    }
+
+    /// Send a frame. Returns false if it had to be dropped because the
+    /// client isn't keeping up.
+    async fn send_frame(&mut self, frame: &EncodedFrame) -> Result<bool> {
+        let rtp_time = self.rtp_time(frame.timestamp);
+        let payloads = self.payloader.payload(MTU, &frame.data)?;
+
+        let count = payloads.len();
+        let mut packets = Vec::with_capacity(count);
+        for (i, payload) in payloads.into_iter().enumerate() {
+            self.octet_count = self.octet_count.wrapping_add(payload.len() as u32);
+            let packet = Packet {
+                header: Header {
+                    version: 2,
+                    marker: i == count - 1,
+                    payload_type: PAYLOAD_TYPE,
+                    sequence_number: self.sequence_number,
+                    timestamp: rtp_time,
+                    ssrc: self.ssrc,
+                    ..Default::default()
+                },
+                payload,
+            };
+            self.sequence_number = self.sequence_number.wrapping_add(1);
+            packets.push(packet.marshal()?);
+        }
+
+        // Only queue whole frames, so a slow TCP client never gets half of one
+        if let RtpTransport::Interleaved { .. } = self.transport {
+            if self.out_tx.capacity() < count {
+                // The sequence numbers are skipped, so the client sees the loss
+                return Ok(false);
+            }
+        }
+        for packet in packets {
+            self.send(&packet, false).await?;
+        }
+        self.packet_count = self.packet_count.wrapping_add(count as u32);
+
+        let report_due = self
+            .last_report
+            .map_or(true, |last| last.elapsed() >= SENDER_REPORT_INTERVAL);
+        if report_due {
+            self.last_report = Some(Instant::now());
+            self.send_report(rtp_time).await?;
+        }
+        Ok(true)
+    }
+
+    /// Send an RTCP sender report, so clients can map RTP time to wall
+    /// clock time. `rtp_time` is that of the frame just sent.
+    async fn send_report(&mut self, rtp_time: u32) -> Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let seconds = now.as_secs() + NTP_UNIX_OFFSET;
+        let fraction = ((now.subsec_nanos() as u64) << 32) / 1_000_000_000;
+        let report = SenderReport {
+            ssrc: self.ssrc,
+            ntp_time: (seconds << 32) | fraction,
+            rtp_time,
+            packet_count: self.packet_count,
+            octet_count: self.octet_count,
+            ..Default::default()
+        };
+        self.send(&report.marshal()?, true).await
+    }
+
+    async fn send(&self, data: &Bytes, rtcp: bool) -> Result<()> {
+        match &self.transport {
+            RtpTransport::Udp {
+                rtp,
+                rtcp: rtcp_socket,
+                rtp_dest,
+                rtcp_dest,
+            } => {
+                let (socket, dest) = match rtcp {
+                    true => (rtcp_socket, rtcp_dest),
+                    false => (rtp, rtp_dest),
+                };
+                // Errors are usually ICMP from a client that went away, which
+                // the RTSP connection closing will deal with
+                socket.send_to(data, dest).await.ok();
+            }
+            RtpTransport::Interleaved {
+                rtp_channel,
+                rtcp_channel,
+            } => {
+                let channel = match rtcp {
+                    true => *rtcp_channel,
+                    false => *rtp_channel,
+                };
+                let mut interleaved = BytesMut::with_capacity(4 + data.len());
+                interleaved.put_u8(b'$');
+                interleaved.put_u8(channel);
+                interleaved.put_u16(data.len() as u16);
+                interleaved.put_slice(data);
+                // Room for the frame was checked up front. A report that
+                // doesn't fit is just skipped.
+                if let Err(mpsc::error::TrySendError::Closed(_)) =
+                    self.out_tx.try_send(interleaved.freeze())
+                {
+                    bail!("RTSP connection closed");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Standard base64 with padding, for `sprop-parameter-sets`.
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_padding() {
+        // RFC 4648
+        for (data, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64_encode(data.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn base64_parameter_sets() {
+        let sps = [
+            0x67, 0x42, 0xc0, 0x1f, 0xda, 0x01, 0xe0, 0x08, 0x9f, 0x96, 0x10, 0x00, 0x00, 0x03,
+            0x00, 0x10, 0x00, 0x00, 0x03, 0x03, 0x2f, 0x18, 0x32, 0xa0,
+        ];
+        assert_eq!(base64_encode(&sps), "Z0LAH9oB4AiflhAAAAMAEAAAAwMvGDKg");
+        assert_eq!(base64_encode(&[0x68, 0xce, 0x3c, 0x80]), "aM48gA==");
+        assert_eq!(base64_encode(&[0xff, 0xfe]), "//4=");
+    }
+}
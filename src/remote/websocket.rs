@@ -0,0 +1,253 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+use warp::{
+    ws::{Message, WebSocket, Ws},
+    Filter, Rejection, Reply,
+};
+
+use crate::feed::{
+    encoders::{h264, EncodedFrame},
+    manager::{FeedControlMessage, FeedResultMessage},
+};
+
+/// Frames queued for a client's socket. Beyond this, the client isn't
+/// keeping up and frames are dropped until the next keyframe.
+const SOCKET_QUEUE: usize = 6;
+/// Struggling clients ask for at most one keyframe this often, since every
+/// viewer in the group pays for it.
+const KEYFRAME_REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+/// Flags byte of a frame message.
+const FLAG_KEYFRAME: u8 = 0x01;
+
+/// Streams the feed's encoded frames over a WebSocket, for browsers that
+/// can't get UDP through (and have no TURN over TLS) to decode with
+/// WebCodecs.
+///
+/// `GET /ws/feed` upgrades to a socket on which every frame is a binary
+/// message: a flags byte (0x01 is keyframe), the timestamp in microseconds
+/// as a big-endian u64, then the Annex-B access unit. Before the first
+/// keyframe, and whenever the stream's parameters change, a text message
+/// `{"type": "config", "codec", "codedWidth", "codedHeight"}` carries the
+/// `VideoDecoder` config. Clients can send the text message `keyframe` to
+/// recover from a decoder error.
+pub fn routes(
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::path!("ws" / "feed")
+        .and(warp::ws())
+        .and(warp::any().map(move || feed_control_tx.clone()))
+        .and(warp::any().map(move || feed_result_tx.clone()))
+        .map(
+            |ws: Ws,
+             feed_control_tx: mpsc::Sender<FeedControlMessage>,
+             feed_result_tx: broadcast::Sender<FeedResultMessage>| {
+                ws.on_upgrade(move |socket| async move {
+                    if let Err(e) = handle_socket(socket, feed_control_tx, feed_result_tx).await {
+                        eprintln!("WebSocket viewer failed: {e:#}");
+                    }
+                })
+            },
+        )
+}
+
+/// Tells the feed the client is gone however the socket ends.
+struct ClientGuard {
+    client_id: String,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let client_id = std::mem::take(&mut self.client_id);
+        let feed_control_tx = self.feed_control_tx.clone();
+        tokio::spawn(async move {
+            feed_control_tx
+                .send(FeedControlMessage::ClientLeft { client_id })
+                .await
+                .ok();
+        });
+    }
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+) -> Result<()> {
+    let client_id = Uuid::new_v4().to_string();
+    let (mut sink, mut stream) = socket.split();
+    let mut feed_result_rx = feed_result_tx.subscribe();
+
+    let _guard = ClientGuard {
+        client_id: client_id.clone(),
+        feed_control_tx: feed_control_tx.clone(),
+    };
+    // No bandwidth estimates to move it between groups with, so the client
+    // is pinned and dropping frames is how it copes
+    feed_control_tx
+        .send(FeedControlMessage::ClientJoined {
+            client_id: client_id.clone(),
+            group_tx: None,
+        })
+        .await
+        .context("feed is gone")?;
+    println!("WebSocket viewer {client_id} joined");
+
+    // The socket is written from its own task, so a slow client backs up
+    // this queue rather than the feed
+    let (message_tx, mut message_rx) = mpsc::channel::<Message>(SOCKET_QUEUE);
+    let writer = tokio::spawn(async move {
+        while let Some(message) = message_rx.recv().await {
+            if sink.send(message).await.is_err() {
+                break;
+            }
+        }
+        sink.close().await.ok();
+    });
+
+    let mut viewer = WebSocketViewer {
+        client_id: client_id.clone(),
+        feed_control_tx,
+        message_tx,
+        codec: None,
+        waiting_for_keyframe: true,
+        last_keyframe_request: None,
+    };
+    viewer.request_keyframe();
+
+    loop {
+        tokio::select! {
+            message = stream.next() => {
+                let message = match message {
+                    Some(Ok(message)) => message,
+                    _ => break,
+                };
+                if message.is_close() {
+                    break;
+                }
+                if message.to_str() == Ok("keyframe") {
+                    viewer.waiting_for_keyframe = true;
+                    viewer.request_keyframe();
+                }
+            }
+            result = feed_result_rx.recv() => {
+                let frame = match result {
+                    Err(broadcast::error::RecvError::Lagged(_)) => {
+                        viewer.waiting_for_keyframe = true;
+                        viewer.request_keyframe();
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+
+                    Ok(FeedResultMessage::EncodedBitstream(frame)) => frame,
+                    Ok(_) => continue,
+                };
+                if !viewer.send_frame(&frame) {
+                    break;
+                }
+            }
+        }
+    }
+
+    writer.abort();
+    println!("WebSocket viewer {client_id} left");
+    Ok(())
+}
+
+struct WebSocketViewer {
+    client_id: String,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    message_tx: mpsc::Sender<Message>,
+    /// Decoder config last sent, as (codec, width, height).
+    codec: Option<(String, u32, u32)>,
+    /// Frames are dropped until the next keyframe, e.g. after the socket
+    /// backed up.
+    waiting_for_keyframe: bool,
+    last_keyframe_request: Option<Instant>,
+}
+
+impl WebSocketViewer {
+    /// Queue a frame for the client. Returns false once the socket is gone.
+    fn send_frame(&mut self, frame: &EncodedFrame) -> bool {
+        if frame.keyframe {
+            self.waiting_for_keyframe = false;
+            if let Some(config) = self.config_message(frame) {
+                // No room for the config means no room for the keyframe
+                // either. Both are sent again with the next one.
+                if self.message_tx.try_send(config).is_err() {
+                    self.codec = None;
+                    self.waiting_for_keyframe = true;
+                    self.request_keyframe();
+                    return !self.message_tx.is_closed();
+                }
+            }
+        }
+        if self.waiting_for_keyframe {
+            // Asked again in case an earlier request was rate limited
+            self.request_keyframe();
+            return true;
+        }
+
+        let mut data = Vec::with_capacity(9 + frame.data.len());
+        data.push(if frame.keyframe { FLAG_KEYFRAME } else { 0 });
+        data.extend_from_slice(&frame.timestamp.to_micros().to_be_bytes());
+        data.extend_from_slice(&frame.data);
+
+        match self.message_tx.try_send(Message::binary(data)) {
+            Ok(()) => true,
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                // Anything after a dropped frame is undecodable until the
+                // next keyframe
+                self.waiting_for_keyframe = true;
+                self.request_keyframe();
+                true
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => false,
+        }
+    }
+
+    /// The decoder config for a keyframe, if it differs from what the
+    /// client has.
+    fn config_message(&mut self, frame: &EncodedFrame) -> Option<Message> {
+        let sps = h264::nal_units(&frame.data)
+            .find(|nal| h264::nal_type(nal) == Some(h264::NAL_TYPE_SPS))?;
+        if sps.len() < 4 {
+            return None;
+        }
+        let (width, height) = h264::sps_dimensions(sps)?;
+        let codec = format!("avc1.{:02x}{:02x}{:02x}", sps[1], sps[2], sps[3]);
+
+        let config = (codec, width, height);
+        if self.codec.as_ref() == Some(&config) {
+            return None;
+        }
+        let message = json!({
+            "type": "config",
+            "codec": config.0,
+            "codedWidth": width,
+            "codedHeight": height,
+        });
+        self.codec = Some(config);
+        Some(Message::text(message.to_string()))
+    }
+
+    fn request_keyframe(&mut self) {
+        let due = self
+            .last_keyframe_request
+            .map_or(true, |last| last.elapsed() >= KEYFRAME_REQUEST_INTERVAL);
+        if due {
+            self.last_keyframe_request = Some(Instant::now());
+            self.feed_control_tx
+                .try_send(FeedControlMessage::RequestKeyframe {
+                    client_id: self.client_id.clone(),
+                })
+                .ok();
+        }
+    }
+}
@@ -0,0 +1,303 @@
+use std::convert::Infallible;
+
+use bytes::Bytes;
+use tokio::sync::{mpsc, oneshot};
+use warp::{
+    http::{header, Response, StatusCode},
+    Filter, Rejection, Reply,
+};
+use webrtc::{
+    ice_transport::{ice_candidate::RTCIceCandidateInit, ice_server::RTCIceServer},
+    peer_connection::sdp::session_description::RTCSessionDescription,
+};
+
+use super::{
+    is_media_type,
+    wrtc::{OfferError, WrtcOffer, WrtcSessions},
+};
+
+/// WHEP (WebRTC-HTTP Egress Protocol) endpoint, so stock players (OBS,
+/// GStreamer `whepsrc`, ...) can pull the feed without our page.
+/// https://datatracker.ietf.org/doc/draft-ietf-wish-whep/
+///
+/// Sessions are created with `POST /whep` and ended with
+/// `DELETE /whep/<client_id>`. Candidates are trickled, and ICE restarts
+/// requested, with `PATCH /whep/<client_id>`. PATCHes with an `If-Match`
+/// that isn't the current ICE session's `ETag` (or `*`) fail with 412.
+pub fn routes(
+    offer_tx: mpsc::Sender<WrtcOffer>,
+    sessions: WrtcSessions,
+    ice_servers: Vec<RTCIceServer>,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let offer_handler = warp::post()
+        .and(warp::path!("whep"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || offer_tx.clone()))
+        .and(warp::any().map(move || ice_servers.clone()))
+        .and_then(handle_offer);
+
+    let patch_sessions = sessions.clone();
+    let patch_handler = warp::patch()
+        .and(warp::path!("whep" / String))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("if-match"))
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || patch_sessions.clone()))
+        .and_then(handle_patch);
+
+    let delete_handler = warp::delete()
+        .and(warp::path!("whep" / String))
+        .and(warp::any().map(move || sessions.clone()))
+        .and_then(handle_delete);
+
+    offer_handler.or(patch_handler).or(delete_handler)
+}
+
+fn empty_response(status: StatusCode) -> Response<String> {
+    let mut response = Response::new(String::new());
+    *response.status_mut() = status;
+    response
+}
+
+async fn handle_offer(
+    content_type: Option<String>,
+    body: Bytes,
+    offer_tx: mpsc::Sender<WrtcOffer>,
+    ice_servers: Vec<RTCIceServer>,
+) -> std::result::Result<Response<String>, Infallible> {
+    if !is_media_type(content_type.as_deref(), "application/sdp") {
+        return Ok(empty_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    let Ok(sdp) = String::from_utf8(body.to_vec()) else {
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+    let Ok(sdp) = RTCSessionDescription::offer(sdp) else {
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    if offer_tx
+        .send(WrtcOffer { sdp, resp: resp_tx })
+        .await
+        .is_err()
+    {
+        return Ok(empty_response(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    let answer = match resp_rx.await {
+        Ok(Ok(answer)) => answer,
+        Ok(Err(e @ OfferError::Rejected(_))) => {
+            let mut response = Response::new(e.to_string());
+            *response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+            return Ok(response);
+        }
+        Err(_) => return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR)),
+    };
+
+    let mut response = Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::CONTENT_TYPE, "application/sdp")
+        .header(header::LOCATION, format!("/whep/{}", answer.client_id))
+        .header(header::ETAG, answer.etag);
+    for link in ice_server_links(&ice_servers) {
+        response = response.header(header::LINK, link);
+    }
+
+    Ok(response
+        .body(answer.sdp.sdp)
+        .unwrap_or_else(|_| empty_response(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+async fn handle_delete(
+    client_id: String,
+    sessions: WrtcSessions,
+) -> std::result::Result<Response<String>, Infallible> {
+    let session = sessions.lock().unwrap().get(&client_id).cloned();
+    let Some(session) = session else {
+        return Ok(empty_response(StatusCode::NOT_FOUND));
+    };
+    session.done_tx.try_send(()).ok();
+    Ok(empty_response(StatusCode::OK))
+}
+
+/// Trickle the client's candidates, or restart ICE if the fragment carries
+/// new credentials (RFC 8840).
+///
+/// Our own late candidates are returned in the response body. Strictly, WHEP
+/// only returns a body for restarts, but clients ignore it otherwise.
+async fn handle_patch(
+    client_id: String,
+    content_type: Option<String>,
+    if_match: Option<String>,
+    body: Bytes,
+    sessions: WrtcSessions,
+) -> std::result::Result<Response<String>, Infallible> {
+    if !is_media_type(content_type.as_deref(), "application/trickle-ice-sdpfrag") {
+        return Ok(empty_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    let session = sessions.lock().unwrap().get(&client_id).cloned();
+    let Some(session) = session else {
+        return Ok(empty_response(StatusCode::NOT_FOUND));
+    };
+    if let Some(if_match) = if_match {
+        if !etag_matches(&if_match, &session.etag()) {
+            return Ok(empty_response(StatusCode::PRECONDITION_FAILED));
+        }
+    }
+    let Ok(fragment) = String::from_utf8(body.to_vec()) else {
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+    let fragment = SdpFragment::parse(&fragment);
+
+    let mut restarted = None;
+    if let (Some(ufrag), Some(pwd)) = (&fragment.ufrag, &fragment.pwd) {
+        if session.remote_ufrag().await.as_ref() != Some(ufrag) {
+            println!("ICE restart for {client_id}");
+            match session.restart_ice(ufrag, pwd).await {
+                Ok(local_description) => restarted = Some(local_description),
+                Err(e) => {
+                    eprintln!("ICE restart failed: {e:#}");
+                    return Ok(empty_response(StatusCode::INTERNAL_SERVER_ERROR));
+                }
+            }
+        }
+    }
+
+    for candidate in fragment.candidates {
+        if let Err(e) = session.peer_connection.add_ice_candidate(candidate).await {
+            eprintln!("Unable to add remote candidate: {e}");
+        }
+    }
+
+    let mut candidates = session.take_pending_candidates();
+    let local_sdp = match restarted {
+        Some(local_description) => {
+            // The restarted description has the new generation's candidates
+            let mut restart_candidates: Vec<String> = local_description
+                .sdp
+                .split("\r\n")
+                .filter(|l| l.starts_with("a=candidate:"))
+                .map(str::to_owned)
+                .collect();
+            restart_candidates.append(&mut candidates);
+            candidates = restart_candidates;
+            local_description.sdp
+        }
+        None if candidates.is_empty() => return Ok(empty_response(StatusCode::NO_CONTENT)),
+        None => match session.peer_connection.local_description().await {
+            Some(local_description) => local_description.sdp,
+            None => return Ok(empty_response(StatusCode::NO_CONTENT)),
+        },
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/trickle-ice-sdpfrag")
+        .header(header::ETAG, session.etag())
+        .body(sdp_fragment(&local_sdp, &candidates))
+        .unwrap_or_else(|_| empty_response(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+/// Whether an `If-Match` header matches `etag`. Restarting clients send `*`,
+/// since they can't know the new ICE session's tag yet.
+fn etag_matches(if_match: &str, etag: &str) -> bool {
+    if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// The parts of a trickle-ice-sdpfrag body we care about.
+#[derive(Debug, Default)]
+struct SdpFragment {
+    ufrag: Option<String>,
+    pwd: Option<String>,
+    candidates: Vec<RTCIceCandidateInit>,
+}
+
+impl SdpFragment {
+    fn parse(fragment: &str) -> Self {
+        let mut parsed = Self::default();
+        let mut mid = None;
+        for line in fragment.lines() {
+            if let Some(ufrag) = line.strip_prefix("a=ice-ufrag:") {
+                parsed.ufrag = Some(ufrag.to_owned());
+            } else if let Some(pwd) = line.strip_prefix("a=ice-pwd:") {
+                parsed.pwd = Some(pwd.to_owned());
+            } else if let Some(m) = line.strip_prefix("a=mid:") {
+                mid = Some(m.to_owned());
+            } else if let Some(candidate) = line.strip_prefix("a=") {
+                if candidate.starts_with("candidate:") {
+                    parsed.candidates.push(RTCIceCandidateInit {
+                        candidate: candidate.to_owned(),
+                        sdp_mid: mid.clone(),
+                        sdp_mline_index: None,
+                        username_fragment: parsed.ufrag.clone(),
+                    });
+                }
+            }
+        }
+        parsed
+    }
+}
+
+/// Build a trickle-ice-sdpfrag from the credentials and media sections of
+/// `sdp`, carrying `candidates`. Everything is bundled, so the candidates all
+/// go in the first media section.
+fn sdp_fragment(sdp: &str, candidates: &[String]) -> String {
+    let mut lines: Vec<&str> = sdp
+        .split("\r\n")
+        .filter(|l| {
+            l.starts_with("a=ice-ufrag:")
+                || l.starts_with("a=ice-pwd:")
+                || l.starts_with("m=")
+                || l.starts_with("a=mid:")
+        })
+        .collect();
+
+    let first_media = lines.iter().position(|l| l.starts_with("m="));
+    let insert_at = first_media
+        .and_then(|first| {
+            lines[first + 1..]
+                .iter()
+                .position(|l| l.starts_with("m="))
+                .map(|next| first + 1 + next)
+        })
+        .unwrap_or(lines.len());
+    lines.splice(insert_at..insert_at, candidates.iter().map(String::as_str));
+
+    lines.join("\r\n") + "\r\n"
+}
+
+/// Format ICE servers as `Link` headers (RFC 8288), as described by WHEP.
+fn ice_server_links(ice_servers: &[RTCIceServer]) -> Vec<String> {
+    ice_servers
+        .iter()
+        .flat_map(|server| {
+            server.urls.iter().map(move |url| {
+                let mut link = format!("<{url}>; rel=\"ice-server\"");
+                if !server.username.is_empty() {
+                    link += &format!(
+                        "; username=\"{}\"; credential=\"{}\"; credential-type=\"password\"",
+                        server.username, server.credential
+                    );
+                }
+                link
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_match_compares_etags() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(etag_matches("\"xyz\", \"abc\"", "\"abc\""));
+        assert!(!etag_matches("\"xyz\"", "\"abc\""));
+        assert!(!etag_matches("W/\"abc\"", "\"abc\""));
+    }
+}
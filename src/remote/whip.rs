@@ -0,0 +1,383 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use tokio::sync::{mpsc, Notify};
+use uuid::Uuid;
+use warp::{
+    http::{header, Response, StatusCode},
+    Filter, Rejection, Reply,
+};
+use webrtc::{
+    api::{
+        interceptor_registry::register_default_interceptors,
+        media_engine::{MediaEngine, MIME_TYPE_H264},
+        setting_engine::SettingEngine,
+        APIBuilder,
+    },
+    interceptor::registry::Registry,
+    media::io::sample_builder::SampleBuilder,
+    peer_connection::{
+        peer_connection_state::RTCPeerConnectionState,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
+    },
+    rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
+    rtp::codecs::h264::H264Packet,
+    rtp_transceiver::{
+        rtp_codec::{RTCRtpCodecCapability, RTCRtpCodecParameters, RTPCodecType},
+        rtp_transceiver_direction::RTCRtpTransceiverDirection,
+        RTCPFeedback, RTCRtpTransceiverInit,
+    },
+    track::track_remote::TrackRemote,
+};
+
+use crate::{
+    feed::{
+        frame::VideoTimestamp,
+        sources::whip::{WhipIngest, WhipSample},
+    },
+    remote::{config::RemoteConfig, is_media_type, wrtc::gather_candidates},
+};
+
+/// Packets the sample builder holds on to while waiting for reordered ones.
+const MAX_LATE_PACKETS: u16 = 128;
+
+/// How long a publisher has to get its video flowing after being answered.
+/// Until then it holds the ingest, so nobody else can publish.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(15);
+/// A publisher whose video stops for this long is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Running publish sessions, keyed by publisher id. Sending on the channel
+/// ends the session.
+type WhipSessions = Arc<Mutex<HashMap<String, mpsc::Sender<()>>>>;
+
+/// WHIP (WebRTC-HTTP Ingestion Protocol) endpoint, so OBS or a browser can
+/// publish video into the feed instead of an NDI source.
+/// https://datatracker.ietf.org/doc/draft-ietf-wish-whip/
+///
+/// Only H.264 is accepted, since that's the only decoder we have. Only one
+/// publisher is live at a time.
+pub fn routes(
+    ingest: WhipIngest,
+    config: RemoteConfig,
+    setting_engine: SettingEngine,
+) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let sessions: WhipSessions = Arc::new(Mutex::new(HashMap::new()));
+    let publish_sessions = sessions.clone();
+
+    let publish_handler = warp::post()
+        .and(warp::path!("whip"))
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || ingest.clone()))
+        .and(warp::any().map(move || publish_sessions.clone()))
+        .and(warp::any().map(move || (config.clone(), setting_engine.clone())))
+        .and_then(handle_publish);
+
+    let delete_handler = warp::delete()
+        .and(warp::path!("whip" / String))
+        .and(warp::any().map(move || sessions.clone()))
+        .and_then(handle_delete);
+
+    publish_handler.or(delete_handler)
+}
+
+fn empty_response(status: StatusCode) -> Response<String> {
+    let mut response = Response::new(String::new());
+    *response.status_mut() = status;
+    response
+}
+
+async fn handle_publish(
+    content_type: Option<String>,
+    body: Bytes,
+    ingest: WhipIngest,
+    sessions: WhipSessions,
+    (config, setting_engine): (RemoteConfig, SettingEngine),
+) -> std::result::Result<Response<String>, Infallible> {
+    if !is_media_type(content_type.as_deref(), "application/sdp") {
+        return Ok(empty_response(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+    }
+    if !ingest.is_consumed() {
+        // The feed was started with another source
+        return Ok(empty_response(StatusCode::SERVICE_UNAVAILABLE));
+    }
+    let Ok(sdp) = String::from_utf8(body.to_vec()) else {
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+    let Ok(sdp) = RTCSessionDescription::offer(sdp) else {
+        return Ok(empty_response(StatusCode::BAD_REQUEST));
+    };
+    if !offers_h264(&sdp.sdp) {
+        // Otherwise the publisher would be answered, and its video ignored
+        eprintln!("Rejecting WHIP offer without H.264, e.g. VP8 only");
+        let mut response = empty_response(StatusCode::NOT_ACCEPTABLE);
+        *response.body_mut() = "Only H.264 video is supported".to_owned();
+        return Ok(response);
+    }
+
+    let publisher_id = Uuid::new_v4().to_string();
+    if !ingest.claim(&publisher_id) {
+        return Ok(empty_response(StatusCode::CONFLICT));
+    }
+
+    let session = start_session(
+        sdp,
+        publisher_id.clone(),
+        ingest.clone(),
+        sessions,
+        config,
+        setting_engine,
+    );
+    let answer = match session.await {
+        Ok(answer) => answer,
+        Err(e) => {
+            eprintln!("Unable to start WHIP session: {e:#}");
+            ingest.release(&publisher_id);
+            return Ok(empty_response(StatusCode::BAD_REQUEST));
+        }
+    };
+
+    Ok(Response::builder()
+        .status(StatusCode::CREATED)
+        .header(header::CONTENT_TYPE, "application/sdp")
+        .header(header::LOCATION, format!("/whip/{publisher_id}"))
+        .body(answer.sdp)
+        .unwrap_or_else(|_| empty_response(StatusCode::INTERNAL_SERVER_ERROR)))
+}
+
+/// Whether any `rtpmap` in the offer is H.264.
+fn offers_h264(sdp: &str) -> bool {
+    sdp.lines()
+        .filter_map(|line| line.trim().strip_prefix("a=rtpmap:"))
+        .filter_map(|rtpmap| rtpmap.split_whitespace().nth(1))
+        .filter_map(|encoding| encoding.split('/').next())
+        .any(|name| name.eq_ignore_ascii_case("H264"))
+}
+
+async fn handle_delete(
+    publisher_id: String,
+    sessions: WhipSessions,
+) -> std::result::Result<Response<String>, Infallible> {
+    let done_tx = sessions.lock().unwrap().get(&publisher_id).cloned();
+    let Some(done_tx) = done_tx else {
+        return Ok(empty_response(StatusCode::NOT_FOUND));
+    };
+    done_tx.try_send(()).ok();
+    Ok(empty_response(StatusCode::OK))
+}
+
+/// Answer the publisher's offer, and spawn the task feeding its video into
+/// the ingest until the session ends. Publishers that don't start sending
+/// within `CONNECT_TIMEOUT`, or go quiet for `IDLE_TIMEOUT`, are dropped so
+/// they don't hold on to the ingest.
+async fn start_session(
+    offer: RTCSessionDescription,
+    publisher_id: String,
+    ingest: WhipIngest,
+    sessions: WhipSessions,
+    config: RemoteConfig,
+    setting_engine: SettingEngine,
+) -> Result<RTCSessionDescription> {
+    let mut m = MediaEngine::default();
+    m.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: MIME_TYPE_H264.to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line:
+                    "level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f"
+                        .to_owned(),
+                rtcp_feedback: vec![
+                    RTCPFeedback {
+                        typ: "nack".to_owned(),
+                        parameter: "".to_owned(),
+                    },
+                    RTCPFeedback {
+                        typ: "nack".to_owned(),
+                        parameter: "pli".to_owned(),
+                    },
+                ],
+            },
+            payload_type: 102,
+            ..Default::default()
+        },
+        RTPCodecType::Video,
+    )?;
+    let mut registry = Registry::new();
+    registry = register_default_interceptors(registry, &mut m)?;
+
+    let api = APIBuilder::new()
+        .with_media_engine(m)
+        .with_interceptor_registry(registry)
+        .with_setting_engine(setting_engine)
+        .build();
+    let peer_connection = Arc::new(api.new_peer_connection(config.rtc_configuration()).await?);
+    peer_connection
+        .add_transceiver_from_kind(
+            RTPCodecType::Video,
+            Some(RTCRtpTransceiverInit {
+                direction: RTCRtpTransceiverDirection::Recvonly,
+                send_encodings: vec![],
+            }),
+        )
+        .await?;
+
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+
+    let started = Arc::new(Notify::new());
+
+    let track_ingest = ingest.clone();
+    let track_pc = Arc::downgrade(&peer_connection);
+    let track_done_tx = done_tx.clone();
+    let track_started = started.clone();
+    peer_connection.on_track(Box::new(move |track, _, _| {
+        let ingest = track_ingest.clone();
+        let peer_connection = track_pc.clone();
+        let done_tx = track_done_tx.clone();
+        let started = track_started.clone();
+        Box::pin(async move {
+            if !track
+                .codec()
+                .capability
+                .mime_type
+                .eq_ignore_ascii_case(MIME_TYPE_H264)
+            {
+                return;
+            }
+            started.notify_one();
+            tokio::spawn(async move {
+                tokio::spawn(keyframe_requests(
+                    peer_connection,
+                    track.ssrc(),
+                    ingest.clone(),
+                ));
+                if let Err(e) = read_track(track, ingest).await {
+                    eprintln!("WHIP track ended: {e}");
+                }
+                done_tx.try_send(()).ok();
+            });
+        })
+    }));
+
+    let failed_tx = done_tx.clone();
+    peer_connection.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
+        if s == RTCPeerConnectionState::Failed || s == RTCPeerConnectionState::Closed {
+            failed_tx.try_send(()).ok();
+        }
+        Box::pin(async {})
+    }));
+
+    peer_connection.set_remote_description(offer).await?;
+    let answer = peer_connection.create_answer(None).await?;
+    peer_connection.set_local_description(answer).await?;
+    gather_candidates(&peer_connection).await;
+    let local_description = peer_connection
+        .local_description()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("No local description after gathering"))?;
+
+    sessions
+        .lock()
+        .unwrap()
+        .insert(publisher_id.clone(), done_tx);
+    println!("WHIP publisher {publisher_id} connected");
+
+    tokio::spawn(async move {
+        let started = tokio::time::timeout(CONNECT_TIMEOUT, started.notified());
+        tokio::select! {
+            _ = done_rx.recv() => {}
+            Err(_) = started => {
+                println!("WHIP publisher {publisher_id} never sent any video");
+            }
+        }
+        sessions.lock().unwrap().remove(&publisher_id);
+        ingest.release(&publisher_id);
+        peer_connection.close().await.ok();
+        println!("WHIP publisher {publisher_id} left");
+    });
+
+    Ok(local_description)
+}
+
+/// Depacketize the publisher's H.264 and hand complete access units to the
+/// feed source.
+async fn read_track(track: Arc<TrackRemote>, ingest: WhipIngest) -> Result<()> {
+    let clock_rate = track.codec().capability.clock_rate.max(1) as u64;
+    let mut sample_builder = SampleBuilder::new(MAX_LATE_PACKETS, H264Packet::default(), 90000);
+
+    // Timestamps start at zero from the first sample, and are unwrapped so
+    // they keep increasing across RTP timestamp rollover.
+    let mut first_timestamp: Option<u32> = None;
+    let mut elapsed_ticks = 0u64;
+    let mut last_timestamp = 0u32;
+
+    loop {
+        let (packet, _) = tokio::time::timeout(IDLE_TIMEOUT, track.read_rtp())
+            .await
+            .map_err(|_| anyhow!("no video for {IDLE_TIMEOUT:?}"))??;
+        sample_builder.push(packet);
+
+        while let Some(sample) = sample_builder.pop() {
+            let rtp_timestamp = sample.packet_timestamp;
+            match first_timestamp {
+                None => first_timestamp = Some(rtp_timestamp),
+                Some(_) => {
+                    elapsed_ticks += rtp_timestamp.wrapping_sub(last_timestamp) as u64;
+                }
+            }
+            last_timestamp = rtp_timestamp;
+
+            ingest.push(WhipSample {
+                data: sample.data,
+                timestamp: VideoTimestamp::from_micros(elapsed_ticks * 1_000_000 / clock_rate),
+            });
+        }
+    }
+}
+
+/// Forward the decoder's keyframe requests to the publisher as PLIs.
+async fn keyframe_requests(
+    peer_connection: Weak<RTCPeerConnection>,
+    media_ssrc: u32,
+    ingest: WhipIngest,
+) {
+    while let Some(peer_connection) = peer_connection.upgrade() {
+        let pli = PictureLossIndication {
+            sender_ssrc: 0,
+            media_ssrc,
+        };
+        if peer_connection.write_rtcp(&[Box::new(pli)]).await.is_err() {
+            break;
+        }
+        drop(peer_connection);
+        ingest.keyframe_requested().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offers_h264_looks_at_every_rtpmap() {
+        let offer = |rtpmaps: &[&str]| {
+            let mut sdp = "v=0\r\nm=video 9 UDP/TLS/RTP/SAVPF 96 97\r\n".to_owned();
+            for rtpmap in rtpmaps {
+                sdp += &format!("a=rtpmap:{rtpmap}\r\n");
+            }
+            sdp
+        };
+        assert!(offers_h264(&offer(&["96 H264/90000"])));
+        assert!(offers_h264(&offer(&["96 VP8/90000", "97 h264/90000"])));
+        assert!(!offers_h264(&offer(&["96 VP8/90000", "97 rtx/90000"])));
+        assert!(!offers_h264(&offer(&[])));
+    }
+}
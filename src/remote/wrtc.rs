@@ -1,34 +1,43 @@
 /// Differences:
-/// -[ ] retransmission (rtx)
+/// -[x] retransmission (rtx)
 /// -[ ] reduce keyframe count (use fir and pli)
 /// -[ ] bonus points: use bwe to pick bitrate
-/// -[ ] set duration accurately
+/// -[x] set duration accurately
 ///
 /// alternative
 /// -[ ] disable frameskip on encoder (not recommended, blows up max bitrate )
-use std::{convert::Infallible, net::SocketAddr, str::FromStr, sync::Arc, time::Instant};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use static_dir::static_dir;
 use tokio::{
     sync::{broadcast, mpsc, oneshot, Notify},
-    task::JoinHandle,
+    task::{AbortHandle, JoinHandle},
     try_join,
 };
 use uuid::Uuid;
 use warp::Filter;
 use webrtc::{
     api::{
-        interceptor_registry::register_default_interceptors,
-        media_engine::{MediaEngine, MIME_TYPE_H264},
-        APIBuilder,
+        interceptor_registry::{configure_rtcp_reports, configure_twcc_receiver_only},
+        media_engine::{MediaEngine, MIME_TYPE_H264, MIME_TYPE_OPUS},
+        setting_engine::SettingEngine,
+        APIBuilder, API,
     },
-    ice_transport::ice_connection_state::RTCIceConnectionState,
+    data_channel::RTCDataChannel,
+    ice_transport::{ice_candidate::RTCIceCandidate, ice_connection_state::RTCIceConnectionState},
     interceptor::registry::Registry,
     media::Sample,
     peer_connection::{
         configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
-        sdp::session_description::RTCSessionDescription,
+        sdp::session_description::RTCSessionDescription, RTCPeerConnection,
     },
     rtcp::payload_feedbacks::{
         full_intra_request::FullIntraRequest, picture_loss_indication::PictureLossIndication,
@@ -42,14 +51,197 @@ use webrtc::{
 };
 
 use crate::{
-    feed::manager::{FeedControlMessage, FeedResultMessage},
-    remote::extensions::playout_delay::PlayoutDelayExtension,
+    annotations::hub::AnnotationHub,
+    feed::{
+        audio::OPUS_SAMPLE_RATE,
+        frame::VideoTimestamp,
+        manager::{FeedControlMessage, FeedGroupReceiver, FeedResultMessage},
+        sources::whip::WhipIngest,
+    },
+    remote::{
+        annotation_channel,
+        config::RemoteConfig,
+        control,
+        extensions::playout_delay::PlayoutDelayExtension,
+        hls::{self, Hls},
+        interceptors::{
+            fec::FecGeneratorBuilder,
+            rtx::{self, RtxResponderBuilder, RtxStats},
+            NegotiatedStream, NegotiatedStreams,
+        },
+        pointer_channel, replay, websocket, whep, whip, PLAYOUT_MAX_DELAY,
+    },
+    replay::InstantReplay,
 };
 
+const H264_PAYLOAD_TYPE: u8 = 102;
+const H264_RTX_PAYLOAD_TYPE: u8 = 103;
+const RED_PAYLOAD_TYPE: u8 = 119;
+const RED_RTX_PAYLOAD_TYPE: u8 = 120;
+const ULPFEC_PAYLOAD_TYPE: u8 = 121;
+
 #[derive(Debug)]
 pub struct WrtcOffer {
+    pub sdp: RTCSessionDescription,
+    /// Dropped without an answer if something went wrong on our side.
+    pub resp: oneshot::Sender<std::result::Result<WrtcAnswer, OfferError>>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum OfferError {
+    /// The offer can't be negotiated, e.g. it has no codec in common with
+    /// ours.
+    #[error("unable to negotiate offer: {0}")]
+    Rejected(webrtc::Error),
+}
+
+#[derive(Debug)]
+pub struct WrtcAnswer {
+    pub client_id: String,
+    pub sdp: RTCSessionDescription,
+    /// Entity tag of the session's ICE session, see `WrtcSession::etag`.
+    pub etag: String,
+}
+
+/// How long to wait for local candidates before answering. Candidates
+/// gathered later are trickled to the client through WHEP PATCH responses.
+const ICE_GATHER_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How long a failed session is kept around, so the client can ICE restart
+/// (e.g. moving from Wi-Fi to LTE) instead of rejoining.
+const ICE_RESTART_GRACE: Duration = Duration::from_secs(15);
+
+/// Peers that haven't connected by then never will.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// ICE normally moves disconnected peers to failed on its own. This is the
+/// backstop for peers that never do.
+const DISCONNECTED_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// Connected peers that stop sending RTCP are gone, whatever ICE thinks.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct WrtcSession {
+    pub peer_connection: Arc<RTCPeerConnection>,
+    /// Sending on this ends the session.
+    pub done_tx: mpsc::Sender<()>,
+    /// Local candidates (`a=candidate:` lines) the client hasn't seen yet.
+    pub pending_candidates: Arc<Mutex<Vec<String>>>,
+    /// Changes on every ICE restart, so WHEP clients can tell (with
+    /// `If-Match`) whether they're still trickling into the same ICE
+    /// session.
+    etag: Arc<Mutex<String>>,
+}
+
+/// Running sessions, keyed by client id.
+pub type WrtcSessions = Arc<Mutex<HashMap<String, WrtcSession>>>;
+
+impl WrtcSession {
+    fn new(peer_connection: Arc<RTCPeerConnection>, done_tx: mpsc::Sender<()>) -> Self {
+        Self {
+            peer_connection,
+            done_tx,
+            pending_candidates: Arc::new(Mutex::new(vec![])),
+            etag: Arc::new(Mutex::new(new_etag())),
+        }
+    }
+
+    /// The current ICE session's entity tag, quoted as in an `ETag` header.
+    pub fn etag(&self) -> String {
+        self.etag.lock().unwrap().clone()
+    }
+
+    pub fn take_pending_candidates(&self) -> Vec<String> {
+        std::mem::take(&mut *self.pending_candidates.lock().unwrap())
+    }
+
+    /// Forget pending candidates that are already part of `sdp`.
+    fn sent_candidates(&self, sdp: &str) {
+        self.pending_candidates
+            .lock()
+            .unwrap()
+            .retain(|candidate| !sdp.contains(candidate.as_str()));
+    }
+
+    /// The ICE username fragment the client is currently using.
+    pub async fn remote_ufrag(&self) -> Option<String> {
+        let remote = self.peer_connection.remote_description().await?;
+        remote
+            .sdp
+            .split("\r\n")
+            .find_map(|l| l.strip_prefix("a=ice-ufrag:"))
+            .map(str::to_owned)
+    }
+
+    /// Restart ICE with the client's new credentials. This is the same as the
+    /// client sending its previous offer again with new credentials, so the
+    /// session (and client id) survives a network change.
+    ///
+    /// Returns the new local description.
+    pub async fn restart_ice(&self, ufrag: &str, pwd: &str) -> Result<RTCSessionDescription> {
+        let remote = self
+            .peer_connection
+            .remote_description()
+            .await
+            .context("No remote description to restart")?;
+        let sdp = remote
+            .sdp
+            .split("\r\n")
+            .filter(|l| !l.starts_with("a=candidate:") && !l.starts_with("a=end-of-candidates"))
+            .map(|line| {
+                if line.starts_with("a=ice-ufrag:") {
+                    format!("a=ice-ufrag:{ufrag}")
+                } else if line.starts_with("a=ice-pwd:") {
+                    format!("a=ice-pwd:{pwd}")
+                } else {
+                    line.to_owned()
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\r\n");
+
+        // Candidates of the old generation are useless to the client
+        self.pending_candidates.lock().unwrap().clear();
+        *self.etag.lock().unwrap() = new_etag();
+        self.peer_connection
+            .set_remote_description(RTCSessionDescription::offer(sdp)?)
+            .await?;
+        let answer = self.peer_connection.create_answer(None).await?;
+        self.peer_connection.set_local_description(answer).await?;
+        gather_candidates(&self.peer_connection).await;
+
+        let local_description = self
+            .peer_connection
+            .local_description()
+            .await
+            .context("No local description after restart")?;
+        self.sent_candidates(&local_description.sdp);
+        Ok(local_description)
+    }
+}
+
+fn new_etag() -> String {
+    format!("\"{}\"", Uuid::new_v4().simple())
+}
+
+/// Wait for candidate gathering to complete, but no longer than
+/// `ICE_GATHER_TIMEOUT`.
+pub(super) async fn gather_candidates(peer_connection: &RTCPeerConnection) {
+    let mut gathering_complete = peer_connection.gathering_complete_promise().await;
+    tokio::time::timeout(ICE_GATHER_TIMEOUT, gathering_complete.recv())
+        .await
+        .ok();
+}
+
+/// Apply the client's offer and create our answer. Errors here are down to
+/// the offer rather than to us.
+async fn answer_offer(
+    peer_connection: &RTCPeerConnection,
     sdp: RTCSessionDescription,
-    resp: oneshot::Sender<Option<RTCSessionDescription>>,
+) -> std::result::Result<RTCSessionDescription, webrtc::Error> {
+    peer_connection.set_remote_description(sdp).await?;
+    peer_connection.create_answer(None).await
 }
 
 async fn handle_new_offer(
@@ -61,13 +253,40 @@ async fn handle_new_offer(
         .send(WrtcOffer { sdp, resp: resp_tx })
         .await
         .unwrap();
-    let reply = resp_rx.await.unwrap_or(None);
+    let reply = resp_rx
+        .await
+        .ok()
+        .and_then(|answer| answer.ok())
+        .map(|answer| answer.sdp);
     Ok(warp::reply::json(&reply))
 }
 
-fn signalling_server(port: u16) -> (mpsc::Receiver<WrtcOffer>, JoinHandle<()>) {
+#[allow(clippy::too_many_arguments)]
+fn signalling_server(
+    config: &RemoteConfig,
+    setting_engine: SettingEngine,
+    sessions: WrtcSessions,
+    whip_ingest: WhipIngest,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+    hls: Hls,
+    replay: Option<InstantReplay>,
+) -> (mpsc::Receiver<WrtcOffer>, JoinHandle<()>) {
     let (offer_tx, offer_rx) = mpsc::channel::<WrtcOffer>(1);
 
+    let whep_handler = whep::routes(offer_tx.clone(), sessions, config.ice_servers.clone());
+    let whip_handler = whip::routes(whip_ingest, config.clone(), setting_engine);
+    let control_handler = control::routes(feed_control_tx.clone());
+    let hls_handler = hls::routes(hls);
+    let replay_handler = replay::routes(replay, feed_control_tx.clone());
+    let websocket_handler = websocket::routes(feed_control_tx.clone(), feed_result_tx);
+
+    // The page needs the same ICE servers as us
+    let ice_servers = config.ice_servers_json();
+    let config_handler = warp::get()
+        .and(warp::path!("wrtc" / "config"))
+        .map(move || warp::reply::json(&ice_servers));
+
     let offer_handler = warp::post()
         .and(warp::path!("wrtc" / "offer"))
         .and(warp::body::json())
@@ -86,32 +305,47 @@ fn signalling_server(port: u16) -> (mpsc::Receiver<WrtcOffer>, JoinHandle<()>) {
             "Access-Control-Request-Method",
             "Access-Control-Request-Headers",
             "Content-Type",
+            "If-Match",
         ])
+        .expose_headers(vec!["Location", "Link"])
         .allow_methods(&[
             warp::hyper::Method::PUT,
+            warp::hyper::Method::PATCH,
             warp::hyper::Method::DELETE,
             warp::hyper::Method::POST,
             warp::hyper::Method::GET,
         ])
         .build();
 
-    let server = warp::serve(offer_handler.or(static_handler).with(cors));
+    let server = warp::serve(
+        whep_handler
+            .or(whip_handler)
+            .or(control_handler)
+            .or(hls_handler)
+            .or(replay_handler)
+            .or(websocket_handler)
+            .or(config_handler)
+            .or(offer_handler)
+            .or(static_handler)
+            .with(cors),
+    );
+    let port = config.http_port;
     let addr = SocketAddr::from_str(&format!("0.0.0.0:{port}")).unwrap();
     let task = tokio::task::spawn(server.run(addr));
     println!("Remote listening on http://0.0.0.0:{port}");
     (offer_rx, task)
 }
 
-async fn webrtc_worker(
-    offer: WrtcOffer,
-    mut feed_result_rx: broadcast::Receiver<FeedResultMessage>,
-    feed_control_tx: mpsc::Sender<FeedControlMessage>,
-) -> Result<()> {
-    let client_id = Uuid::new_v4().to_string();
-    // Setup webrtc internals
-    // TODO: see what can be moved out.
+/// Build the API every viewer's peer connection is created from. Each peer
+/// connection gets its own copy of the media engine and interceptors. With
+/// `fec`, RED+ULPFEC is offered for the video track.
+fn build_api(
+    setting_engine: SettingEngine,
+    negotiated: NegotiatedStreams,
+    fec: bool,
+) -> Result<API> {
     let mut m = MediaEngine::default();
-    m.register_default_codecs().unwrap();
+    m.register_default_codecs()?;
     m.register_codec(
         RTCRtpCodecParameters {
             capability: RTCRtpCodecCapability {
@@ -140,24 +374,200 @@ async fn webrtc_worker(
                     },
                 ],
             },
-            payload_type: 102,
+            payload_type: H264_PAYLOAD_TYPE,
             ..Default::default()
         },
         RTPCodecType::Video,
     )?;
+    m.register_codec(
+        RTCRtpCodecParameters {
+            capability: RTCRtpCodecCapability {
+                mime_type: "video/rtx".to_owned(),
+                clock_rate: 90000,
+                channels: 0,
+                sdp_fmtp_line: format!("apt={H264_PAYLOAD_TYPE}"),
+                rtcp_feedback: vec![],
+            },
+            payload_type: H264_RTX_PAYLOAD_TYPE,
+            ..Default::default()
+        },
+        RTPCodecType::Video,
+    )?;
+
+    if fec {
+        for (mime_type, payload_type, sdp_fmtp_line) in [
+            ("video/red", RED_PAYLOAD_TYPE, "".to_owned()),
+            (
+                "video/rtx",
+                RED_RTX_PAYLOAD_TYPE,
+                format!("apt={RED_PAYLOAD_TYPE}"),
+            ),
+            ("video/ulpfec", ULPFEC_PAYLOAD_TYPE, "".to_owned()),
+        ] {
+            m.register_codec(
+                RTCRtpCodecParameters {
+                    capability: RTCRtpCodecCapability {
+                        mime_type: mime_type.to_owned(),
+                        clock_rate: 90000,
+                        channels: 0,
+                        sdp_fmtp_line,
+                        rtcp_feedback: vec![],
+                    },
+                    payload_type,
+                    ..Default::default()
+                },
+                RTPCodecType::Video,
+            )?;
+        }
+    }
 
+    // Same as the default interceptors, except NACKs are answered with RTX
+    // instead of plain retransmissions. FEC is registered last so that RTX
+    // sees the final sequence numbers.
     let mut registry = Registry::new();
-    registry = register_default_interceptors(registry, &mut m)?;
+    registry = configure_rtcp_reports(registry);
+    registry = configure_twcc_receiver_only(registry, &mut m)?;
+    registry.add(Box::new(RtxResponderBuilder::new(
+        PLAYOUT_MAX_DELAY,
+        negotiated.clone(),
+    )));
+    if fec {
+        registry.add(Box::new(FecGeneratorBuilder::new(negotiated.clone())));
+    }
 
-    let api = APIBuilder::new()
+    Ok(APIBuilder::new()
         .with_media_engine(m)
         .with_interceptor_registry(registry)
-        .build();
+        .with_setting_engine(setting_engine)
+        .build())
+}
 
-    let config = RTCConfiguration {
-        ..Default::default()
+/// Cleans up after a session however the worker exits, so the feed always
+/// sees `ClientLeft`.
+struct SessionGuard {
+    client_id: String,
+    peer_connection: Arc<RTCPeerConnection>,
+    sessions: WrtcSessions,
+    negotiated: NegotiatedStreams,
+    /// Media SSRCs registered in `negotiated`.
+    ssrcs: Vec<u32>,
+    tasks: Vec<AbortHandle>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    annotations: AnnotationHub,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.lock().unwrap().remove(&self.client_id);
+        self.annotations.leave(&self.client_id);
+        let mut negotiated = self.negotiated.lock().unwrap();
+        self.ssrcs.iter().for_each(|ssrc| {
+            negotiated.remove(ssrc);
+        });
+        self.tasks.iter().for_each(AbortHandle::abort);
+
+        let client_id = std::mem::take(&mut self.client_id);
+        let peer_connection = self.peer_connection.clone();
+        let feed_control_tx = self.feed_control_tx.clone();
+        tokio::spawn(async move {
+            peer_connection.close().await.ok();
+            feed_control_tx
+                .send(FeedControlMessage::ClientLeft { client_id })
+                .await
+                .ok();
+            println!("goodbye thread");
+        });
+    }
+}
+
+/// Ends sessions whose peer went away without telling us.
+struct PeerWatchdog {
+    state: RTCPeerConnectionState,
+    state_since: Instant,
+    /// Receivers report at least every few seconds while they're alive.
+    last_rtcp: Arc<Mutex<Instant>>,
+}
+
+impl PeerWatchdog {
+    fn new() -> Self {
+        Self {
+            state: RTCPeerConnectionState::New,
+            state_since: Instant::now(),
+            last_rtcp: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    /// Returns why the session should end, if it should.
+    fn check(&mut self, state: RTCPeerConnectionState) -> Option<&'static str> {
+        let now = Instant::now();
+        if state != self.state {
+            self.state = state;
+            self.state_since = now;
+        }
+        let elapsed = now - self.state_since;
+
+        match state {
+            RTCPeerConnectionState::New | RTCPeerConnectionState::Connecting
+                if elapsed > CONNECT_TIMEOUT =>
+            {
+                Some("never connected")
+            }
+            RTCPeerConnectionState::Disconnected if elapsed > DISCONNECTED_TIMEOUT => {
+                Some("disconnected")
+            }
+            RTCPeerConnectionState::Failed if elapsed > ICE_RESTART_GRACE => {
+                Some("failed without an ICE restart")
+            }
+            RTCPeerConnectionState::Closed => Some("closed"),
+            RTCPeerConnectionState::Connected
+                if now - *self.last_rtcp.lock().unwrap() > IDLE_TIMEOUT =>
+            {
+                Some("no RTCP from the peer")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Everything a worker shares with the other workers.
+#[derive(Clone)]
+struct WorkerContext {
+    api: Arc<API>,
+    rtc_configuration: RTCConfiguration,
+    negotiated: NegotiatedStreams,
+    sessions: WrtcSessions,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+    audio_tx: broadcast::Sender<FeedResultMessage>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    annotations: AnnotationHub,
+}
+
+async fn webrtc_worker(offer: WrtcOffer, context: WorkerContext) -> Result<()> {
+    let WorkerContext {
+        api,
+        rtc_configuration,
+        negotiated,
+        sessions,
+        feed_result_tx,
+        audio_tx,
+        feed_control_tx,
+        annotations,
+    } = context;
+    let client_id = Uuid::new_v4().to_string();
+    let peer_connection = Arc::new(api.new_peer_connection(rtc_configuration).await?);
+    let rtx_stats = Arc::new(RtxStats::default());
+    let mut watchdog = PeerWatchdog::new();
+
+    let mut guard = SessionGuard {
+        client_id: client_id.clone(),
+        peer_connection: peer_connection.clone(),
+        sessions: sessions.clone(),
+        negotiated: negotiated.clone(),
+        ssrcs: vec![],
+        tasks: vec![],
+        feed_control_tx: feed_control_tx.clone(),
+        annotations: annotations.clone(),
     };
-    let peer_connection = Arc::new(api.new_peer_connection(config).await?);
 
     let notify_tx = Arc::new(Notify::new());
     let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
@@ -175,28 +585,50 @@ async fn webrtc_worker(
         .add_track(Arc::clone(&video_track) as Arc<dyn TrackLocal + Send + Sync>)
         .await?;
 
+    // Same stream id as the video, so the browser syncs the two
+    let audio_track = Arc::new(TrackLocalStaticSample::new(
+        RTCRtpCodecCapability {
+            mime_type: MIME_TYPE_OPUS.to_owned(),
+            clock_rate: OPUS_SAMPLE_RATE,
+            channels: 2,
+            ..Default::default()
+        },
+        "audio".to_owned(),
+        "telestrator".to_owned(),
+    ));
+    let audio_rtp_sender = peer_connection
+        .add_track(Arc::clone(&audio_track) as Arc<dyn TrackLocal + Send + Sync>)
+        .await?;
+
     // Read incoming RTCP
     let rtcp_feed_control_tx = feed_control_tx.clone();
     let rtcp_client_id = client_id.clone();
-    tokio::spawn(async move {
+    let last_rtcp = watchdog.last_rtcp.clone();
+    let rtcp_task = tokio::spawn(async move {
         let mut rtcp_buf = vec![0u8; 1500];
         while let Ok((packets, _)) = rtp_sender.read(&mut rtcp_buf).await {
+            *last_rtcp.lock().unwrap() = Instant::now();
             packets.iter().for_each(|pkt| {
                 let any_pkt = pkt.as_any();
                 if let Some(_) = any_pkt.downcast_ref::<PictureLossIndication>() {
                     rtcp_feed_control_tx
-                        .try_send(FeedControlMessage::RequestKeyframe)
+                        .try_send(FeedControlMessage::RequestKeyframe {
+                            client_id: rtcp_client_id.clone(),
+                        })
                         .ok();
                 } else if let Some(_) = any_pkt.downcast_ref::<FullIntraRequest>() {
                     rtcp_feed_control_tx
-                        .try_send(FeedControlMessage::RequestKeyframe)
+                        .try_send(FeedControlMessage::RequestKeyframe {
+                            client_id: rtcp_client_id.clone(),
+                        })
                         .ok();
                 } else if let Some(pkt) = any_pkt.downcast_ref::<ReceiverEstimatedMaximumBitrate>()
                 {
+                    // REMB is already expressed in bps.
                     rtcp_feed_control_tx
                         .try_send(FeedControlMessage::BandwidthEstimate {
                             client_id: rtcp_client_id.clone(),
-                            bitrate: 8 * (pkt.bitrate as u32) / 1000,
+                            bitrate: pkt.bitrate as u32,
                         })
                         .ok();
                 }
@@ -204,6 +636,66 @@ async fn webrtc_worker(
         }
         Result::<()>::Ok(())
     });
+    guard.tasks.push(rtcp_task.abort_handle());
+
+    // Report NACK handling to the feed stats
+    let stats_feed_control_tx = feed_control_tx.clone();
+    let stats_client_id = client_id.clone();
+    let stats_rtx_stats = rtx_stats.clone();
+    let stats_task = tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            let (nacked, retransmitted, missed) = stats_rtx_stats.take();
+            if nacked == 0 {
+                continue;
+            }
+            let report = FeedControlMessage::RetransmitStats {
+                client_id: stats_client_id.clone(),
+                nacked,
+                retransmitted,
+                missed,
+            };
+            if stats_feed_control_tx.send(report).await.is_err() {
+                break;
+            }
+        }
+    });
+    guard.tasks.push(stats_task.abort_handle());
+
+    // Drain audio RTCP, so the interceptors see it
+    let audio_rtcp_task = tokio::spawn(async move {
+        let mut rtcp_buf = vec![0u8; 1500];
+        while audio_rtp_sender.read(&mut rtcp_buf).await.is_ok() {}
+    });
+    guard.tasks.push(audio_rtcp_task.abort_handle());
+
+    let notify_audio = notify_tx.clone();
+    let mut audio_rx = audio_tx.subscribe();
+    let audio_task = tokio::spawn(async move {
+        notify_audio.notified().await;
+
+        loop {
+            let packet = match audio_rx.recv().await {
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => break,
+
+                Ok(FeedResultMessage::EncodedAudio(packet)) => packet,
+                Ok(_) => continue,
+            };
+
+            audio_track
+                .write_sample(&Sample {
+                    data: packet.data,
+                    duration: packet.duration,
+                    ..Default::default()
+                })
+                .await?;
+        }
+
+        Result::<()>::Ok(())
+    });
+    guard.tasks.push(audio_task.abort_handle());
 
     let notify_video = notify_tx.clone();
     let video_done_tx = done_tx.clone();
@@ -213,40 +705,59 @@ async fn webrtc_worker(
     let video_task = tokio::spawn(async move {
         notify_video.notified().await;
         println!("ready to send video");
+
+        // The feed manager moves us between encoder groups through this.
+        let (group_tx, mut feed_result_rx) = FeedGroupReceiver::new(feed_result_tx);
         video_feed_ctrl_tx
             .send(FeedControlMessage::ClientJoined {
                 client_id: video_client_id,
+                group_tx: Some(group_tx),
             })
             .await?;
 
+        // Durations come from the source timestamps, which the audio shares.
+        // Wall clock time is only used when those go backwards.
         let mut last_write = Instant::now();
+        let mut last_timestamp: Option<VideoTimestamp> = None;
 
         loop {
-            let data = match feed_result_rx.recv().await {
+            let frame = match feed_result_rx.recv().await {
                 Err(broadcast::error::RecvError::Lagged(_)) => continue,
                 Err(broadcast::error::RecvError::Closed) => break,
 
-                Ok(FeedResultMessage::EncodedBitstream(data)) => data,
+                Ok(FeedResultMessage::EncodedBitstream(frame)) => frame,
+                Ok(_) => continue,
             };
 
             let now = Instant::now();
+            let duration = match last_timestamp {
+                Some(last) if frame.timestamp > last => {
+                    Duration::from_micros(frame.timestamp.to_micros() - last.to_micros())
+                }
+                _ => now - last_write,
+            };
             video_track
                 .sample_writer()
-                .with_extension(PlayoutDelayExtension::new(0, 0).to_extension())
+                .with_extension(
+                    PlayoutDelayExtension::new(0, (PLAYOUT_MAX_DELAY.as_millis() / 10) as _)
+                        .to_extension(),
+                )
                 .write_sample(&Sample {
-                    data,
-                    duration: now - last_write,
+                    data: frame.data,
+                    duration,
                     ..Default::default()
                 })
                 .await?;
 
             last_write = now;
+            last_timestamp = Some(frame.timestamp);
         }
 
         video_done_tx.try_send(()).ok();
 
         Result::<()>::Ok(())
     });
+    guard.tasks.push(video_task.abort_handle());
 
     peer_connection.on_ice_connection_state_change(Box::new(
         move |connection_state: RTCIceConnectionState| {
@@ -258,27 +769,54 @@ async fn webrtc_worker(
         },
     ));
 
-    let peer_connection_failed_tx = done_tx.clone();
-    peer_connection.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
-        if s == RTCPeerConnectionState::Failed {
-            println!("Peer Connection has gone to failed exiting");
-            peer_connection_failed_tx.try_send(()).ok();
+    let session = WrtcSession::new(peer_connection.clone(), done_tx.clone());
+
+    let candidates = session.pending_candidates.clone();
+    peer_connection.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+        if let Some(candidate) = candidate.and_then(|c| c.to_json().ok()) {
+            candidates
+                .lock()
+                .unwrap()
+                .push(format!("a={}", candidate.candidate));
         }
         Box::pin(async {})
     }));
 
+    let annotation_client_id = client_id.clone();
+    peer_connection.on_data_channel(Box::new(move |data_channel: Arc<RTCDataChannel>| {
+        match data_channel.label() {
+            annotation_channel::LABEL => annotation_channel::attach(
+                data_channel,
+                annotation_client_id.clone(),
+                annotations.clone(),
+            ),
+            pointer_channel::LABEL => pointer_channel::attach(
+                data_channel,
+                annotation_client_id.clone(),
+                annotations.clone(),
+            ),
+            _ => {}
+        }
+        Box::pin(async {})
+    }));
+
+    // Failed peers get ICE_RESTART_GRACE to restart, see PeerWatchdog
+    peer_connection.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
+        println!("Peer Connection State has changed {s}");
+        Box::pin(async {})
+    }));
+
     // Present connection
-    let sdp = offer.sdp;
-    peer_connection.set_remote_description(sdp).await?;
-    let answer = peer_connection.create_answer(None).await?;
+    let answer = match answer_offer(&peer_connection, offer.sdp).await {
+        Ok(answer) => answer,
+        Err(e) => {
+            offer.resp.send(Err(OfferError::Rejected(e))).ok();
+            return Ok(());
+        }
+    };
 
     peer_connection.set_local_description(answer).await?;
-
-    peer_connection
-        .gathering_complete_promise()
-        .await
-        .recv()
-        .await;
+    gather_candidates(&peer_connection).await;
 
     let Some(mut local_description) = peer_connection.local_description().await else {
         return Ok(());
@@ -298,35 +836,134 @@ async fn webrtc_worker(
         })
         .collect::<Vec<String>>()
         .join("\r\n");
-    offer.resp.send(Some(local_description)).unwrap();
+    local_description.sdp = add_rtx_ssrc_groups(&local_description.sdp);
+    let streams = NegotiatedStream::from_sdp(&local_description.sdp, &rtx_stats);
+    guard.ssrcs.extend(streams.keys());
+    negotiated.lock().unwrap().extend(streams);
+    session.sent_candidates(&local_description.sdp);
+    let etag = session.etag();
+    sessions.lock().unwrap().insert(client_id.clone(), session);
+    let answer = WrtcAnswer {
+        client_id: client_id.clone(),
+        sdp: local_description,
+        etag,
+    };
+    if offer.resp.send(Ok(answer)).is_err() {
+        // Nobody is waiting for the answer anymore
+        return Ok(());
+    }
 
-    tokio::select! {
-        _ = done_rx.recv() => {}
-        _ = tokio::signal::ctrl_c() => {}
+    let mut watchdog_interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        tokio::select! {
+            _ = done_rx.recv() => break,
+            _ = watchdog_interval.tick() => {
+                if let Some(reason) = watchdog.check(peer_connection.connection_state()) {
+                    println!("Ending session {client_id}: {reason}");
+                    break;
+                }
+            }
+        }
     }
 
-    video_task.abort();
-    feed_control_tx
-        .send(FeedControlMessage::ClientLeft { client_id })
-        .await?;
-    println!("goodbye thread");
+    // Dropping the guard ends the session
+    drop(guard);
 
     Ok(())
 }
 
+/// Announce the RTX SSRC of every sending media section, so the receiver can
+/// associate retransmissions with the original stream.
+fn add_rtx_ssrc_groups(sdp: &str) -> String {
+    let mut sections: Vec<Vec<&str>> = vec![vec![]];
+    for line in sdp.split("\r\n") {
+        if line.starts_with("m=") {
+            sections.push(vec![]);
+        }
+        sections.last_mut().unwrap().push(line);
+    }
+
+    sections
+        .iter()
+        .flat_map(|section| add_rtx_ssrc_group(section))
+        .collect::<Vec<String>>()
+        .join("\r\n")
+}
+
+fn add_rtx_ssrc_group(section: &[&str]) -> Vec<String> {
+    let mut lines: Vec<String> = section.iter().map(|l| l.to_string()).collect();
+
+    let has_rtx = section
+        .iter()
+        .any(|l| l.starts_with("a=fmtp:") && l.contains("apt="));
+    let has_group = section.iter().any(|l| l.starts_with("a=ssrc-group:FID"));
+    if !has_rtx || has_group {
+        return lines;
+    }
+
+    let media = section.iter().find_map(|l| {
+        let (ssrc, cname) = l.strip_prefix("a=ssrc:")?.split_once(" cname:")?;
+        Some((ssrc.parse::<u32>().ok()?, cname))
+    });
+    let last_ssrc = section.iter().rposition(|l| l.starts_with("a=ssrc:"));
+    let (Some((ssrc, cname)), Some(last_ssrc)) = (media, last_ssrc) else {
+        return lines;
+    };
+
+    let rtx_ssrc = rtx::rtx_ssrc(ssrc);
+    lines.splice(
+        last_ssrc + 1..last_ssrc + 1,
+        [
+            format!("a=ssrc-group:FID {ssrc} {rtx_ssrc}"),
+            format!("a=ssrc:{rtx_ssrc} cname:{cname}"),
+        ],
+    );
+    lines
+}
+
 pub async fn run_webrtc_tasks(
+    config: RemoteConfig,
     feed_control_tx: mpsc::Sender<FeedControlMessage>,
     frame_ready_tx: broadcast::Sender<FeedResultMessage>,
+    audio_tx: broadcast::Sender<FeedResultMessage>,
+    whip_ingest: WhipIngest,
+    annotations: AnnotationHub,
+    replay: Option<InstantReplay>,
 ) -> Result<()> {
-    let (mut sdp_rx, http_task) = signalling_server(8888);
-
+    let setting_engine = config.setting_engine().await?;
+    let negotiated: NegotiatedStreams = Arc::new(Mutex::new(HashMap::new()));
+    let api = Arc::new(build_api(
+        setting_engine.clone(),
+        negotiated.clone(),
+        config.fec,
+    )?);
+
+    let sessions: WrtcSessions = Arc::new(Mutex::new(HashMap::new()));
+    let hls = Hls::new(feed_control_tx.clone(), frame_ready_tx.clone());
+    let (mut sdp_rx, http_task) = signalling_server(
+        &config,
+        setting_engine,
+        sessions.clone(),
+        whip_ingest,
+        feed_control_tx.clone(),
+        frame_ready_tx.clone(),
+        hls,
+        replay,
+    );
+
+    let context = WorkerContext {
+        api,
+        rtc_configuration: config.rtc_configuration(),
+        negotiated,
+        sessions,
+        feed_result_tx: frame_ready_tx,
+        audio_tx,
+        feed_control_tx,
+        annotations,
+    };
     let wrtc_manager = tokio::task::spawn(async move {
         while let Some(offer) = sdp_rx.recv().await {
-            tokio::task::spawn(webrtc_worker(
-                offer,
-                frame_ready_tx.subscribe(),
-                feed_control_tx.clone(),
-            ));
+            tokio::task::spawn(webrtc_worker(offer, context.clone()));
         }
     });
 
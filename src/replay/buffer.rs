@@ -0,0 +1,257 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::feed::{
+    encoders::{h264, EncodedFrame},
+    frame::VideoTimestamp,
+    replay::ReplayClip,
+};
+
+const START_CODE: [u8; 4] = [0, 0, 0, 1];
+
+/// The last stretch of the encoded feed, as whole GOPs so any of them can
+/// be decoded on its own.
+pub struct ReplayBuffer {
+    /// Each starts with a keyframe.
+    gops: VecDeque<Vec<EncodedFrame>>,
+    /// How far back the buffer reaches, at least.
+    length: Duration,
+    keyframe_interval: Duration,
+    /// SPS and PPS of the latest keyframe that carried them (Annex-B).
+    parameter_sets: Option<Bytes>,
+    /// Frames are dropped until the next keyframe, e.g. after lagging.
+    waiting_for_keyframe: bool,
+    last_keyframe_request: Option<Instant>,
+}
+
+impl ReplayBuffer {
+    pub fn new(length: Duration, keyframe_interval: Duration) -> Self {
+        Self {
+            gops: VecDeque::new(),
+            length,
+            keyframe_interval,
+            parameter_sets: None,
+            waiting_for_keyframe: true,
+            last_keyframe_request: None,
+        }
+    }
+
+    /// Add the next encoded frame. Returns whether a keyframe should be
+    /// requested from the encoder.
+    pub fn push(&mut self, mut frame: EncodedFrame) -> bool {
+        if frame.replayed {
+            // Replays aren't buffered again, and what follows one can't be
+            // decoded without it
+            self.waiting_for_keyframe = true;
+            return false;
+        }
+        if self.latest().is_some_and(|latest| frame.timestamp < latest) {
+            // The source restarted, so nothing buffered lines up anymore
+            self.gops.clear();
+            self.waiting_for_keyframe = true;
+        }
+
+        if frame.keyframe {
+            self.waiting_for_keyframe = false;
+            self.with_parameter_sets(&mut frame);
+            self.prune(frame.timestamp);
+            self.gops.push_back(Vec::new());
+        }
+        if self.waiting_for_keyframe {
+            return self.keyframe_due();
+        }
+        let Some(gop) = self.gops.back_mut() else {
+            return self.keyframe_due();
+        };
+        gop.push(frame);
+
+        let gop_length = gop[gop.len() - 1].timestamp.to_micros() - gop[0].timestamp.to_micros();
+        gop_length >= self.keyframe_interval.as_micros() as u64 && self.keyframe_due()
+    }
+
+    /// Frames went missing, so nothing can be decoded until the next
+    /// keyframe.
+    pub fn discontinuity(&mut self) {
+        self.waiting_for_keyframe = true;
+    }
+
+    pub fn earliest(&self) -> Option<VideoTimestamp> {
+        Some(self.gops.front()?.first()?.timestamp)
+    }
+
+    pub fn latest(&self) -> Option<VideoTimestamp> {
+        Some(self.gops.back()?.last()?.timestamp)
+    }
+
+    /// The frames from `start` through `end`, led in by the rest of the GOP
+    /// `start` falls in.
+    pub fn clip(&self, start: VideoTimestamp, end: VideoTimestamp) -> Option<ReplayClip> {
+        let first = self
+            .gops
+            .iter()
+            .rposition(|gop| gop[0].timestamp <= start)
+            .unwrap_or(0);
+        let frames = self
+            .gops
+            .iter()
+            .skip(first)
+            .flatten()
+            .take_while(|frame| frame.timestamp <= end)
+            .cloned()
+            .collect::<Vec<_>>();
+        if frames.last()?.timestamp < start {
+            return None;
+        }
+        Some(ReplayClip { frames, start })
+    }
+
+    /// Encoders needn't repeat the parameter sets on every keyframe, but
+    /// every GOP here has to stand on its own.
+    fn with_parameter_sets(&mut self, frame: &mut EncodedFrame) {
+        let parameter_sets = h264::nal_units(&frame.data)
+            .filter(|nal| {
+                matches!(
+                    h264::nal_type(nal),
+                    Some(h264::NAL_TYPE_SPS | h264::NAL_TYPE_PPS)
+                )
+            })
+            .flat_map(|nal| [&START_CODE[..], nal].concat())
+            .collect::<Vec<_>>();
+        if !parameter_sets.is_empty() {
+            self.parameter_sets = Some(parameter_sets.into());
+        } else if let Some(parameter_sets) = &self.parameter_sets {
+            frame.data = [&parameter_sets[..], &frame.data[..]].concat().into();
+        }
+    }
+
+    /// Drop the GOPs that are entirely older than `length` before `now`.
+    fn prune(&mut self, now: VideoTimestamp) {
+        let horizon = now
+            .to_micros()
+            .saturating_sub(self.length.as_micros() as u64);
+        while self
+            .gops
+            .get(1)
+            .is_some_and(|gop| gop[0].timestamp.to_micros() <= horizon)
+        {
+            self.gops.pop_front();
+        }
+    }
+
+    /// Rate limit keyframe requests to one per interval.
+    fn keyframe_due(&mut self) -> bool {
+        let due = self
+            .last_keyframe_request
+            .map_or(true, |last| last.elapsed() >= self.keyframe_interval);
+        if due {
+            self.last_keyframe_request = Some(Instant::now());
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_INTERVAL: u64 = 100_000;
+    const PARAMETER_SETS: [u8; 16] = [
+        0, 0, 0, 1, 0x67, 0x42, 0x00, 0x1f, 0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80,
+    ];
+
+    fn frame(index: u64, keyframe: bool) -> EncodedFrame {
+        let data = match keyframe {
+            true => [&PARAMETER_SETS[..], &[0, 0, 0, 1, 0x65, 0x88]].concat(),
+            false => vec![0, 0, 0, 1, 0x41, 0x9a],
+        };
+        EncodedFrame::new(
+            data.into(),
+            VideoTimestamp::from_micros(index * FRAME_INTERVAL),
+        )
+    }
+
+    fn timestamp(index: u64) -> VideoTimestamp {
+        VideoTimestamp::from_micros(index * FRAME_INTERVAL)
+    }
+
+    /// 10s of frames, with a keyframe every second.
+    fn buffer() -> ReplayBuffer {
+        let mut buffer = ReplayBuffer::new(Duration::from_secs(60), Duration::from_secs(1));
+        for i in 0..100 {
+            buffer.push(frame(i, i % 10 == 0));
+        }
+        buffer
+    }
+
+    #[test]
+    fn clip_leads_in_from_the_keyframe() {
+        let clip = buffer().clip(timestamp(25), timestamp(42)).unwrap();
+        assert_eq!(clip.start, timestamp(25));
+        assert!(clip.frames[0].keyframe);
+        assert_eq!(clip.frames[0].timestamp, timestamp(20));
+        assert_eq!(clip.frames.last().unwrap().timestamp, timestamp(42));
+        assert_eq!(clip.frames.len(), 23);
+    }
+
+    #[test]
+    fn clip_on_a_keyframe() {
+        let clip = buffer().clip(timestamp(30), timestamp(30)).unwrap();
+        assert_eq!(clip.frames.len(), 1);
+        assert!(clip.frames[0].keyframe);
+    }
+
+    #[test]
+    fn clip_past_the_latest_frame() {
+        let buffer = buffer();
+        let clip = buffer.clip(timestamp(95), timestamp(200)).unwrap();
+        assert_eq!(clip.frames[0].timestamp, timestamp(90));
+        assert_eq!(clip.frames.last().unwrap().timestamp, timestamp(99));
+        assert!(buffer.clip(timestamp(100), timestamp(200)).is_none());
+    }
+
+    #[test]
+    fn clips_are_pruned_to_whole_gops() {
+        let mut buffer = ReplayBuffer::new(Duration::from_secs(2), Duration::from_secs(1));
+        for i in 0..100 {
+            buffer.push(frame(i, i % 10 == 0));
+        }
+        // The GOP the horizon falls in is kept
+        assert_eq!(buffer.earliest(), Some(timestamp(70)));
+        let clip = buffer.clip(timestamp(0), timestamp(75)).unwrap();
+        assert_eq!(clip.frames[0].timestamp, timestamp(70));
+    }
+
+    #[test]
+    fn keyframes_get_parameter_sets() {
+        let mut buffer = ReplayBuffer::new(Duration::from_secs(60), Duration::from_secs(1));
+        buffer.push(frame(0, true));
+        buffer.push(EncodedFrame::new(
+            vec![0, 0, 0, 1, 0x65, 0x88].into(),
+            timestamp(1),
+        ));
+        let clip = buffer.clip(timestamp(1), timestamp(1)).unwrap();
+        assert_eq!(clip.frames.len(), 1);
+        assert!(clip.frames[0].data.starts_with(&PARAMETER_SETS));
+    }
+
+    #[test]
+    fn replayed_frames_are_skipped() {
+        let mut buffer = buffer();
+        for i in 100..120 {
+            let mut frame = frame(i, i == 100);
+            frame.replayed = true;
+            buffer.push(frame);
+        }
+        assert_eq!(buffer.latest(), Some(timestamp(99)));
+
+        // Live frames after the replay wait for a keyframe
+        assert!(buffer.push(frame(120, false)));
+        assert_eq!(buffer.latest(), Some(timestamp(99)));
+        buffer.push(frame(130, true));
+        assert_eq!(buffer.latest(), Some(timestamp(130)));
+    }
+}
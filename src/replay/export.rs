@@ -0,0 +1,89 @@
+use anyhow::{bail, Result};
+
+use crate::{
+    annotations::{protocol::Envelope, recording::RecordEntry},
+    feed::{encoders::h264, replay::ReplayClip},
+    recording::fmp4::{self, Fmp4Sample, TIMESCALE},
+};
+
+/// Used for frames whose duration can't be told from the timestamps.
+const DEFAULT_FRAME_DURATION: u32 = TIMESCALE / 30;
+
+/// The clip as a fragmented MP4, one fragment per GOP. It starts on the
+/// clip's first keyframe, so may lead in a little before the in point. If
+/// the parameter sets change partway (e.g. the resolution), it ends there,
+/// since one file can only have one.
+pub fn mp4(clip: &ReplayClip) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    // Parameter sets the file was started with
+    let mut parameter_sets = None;
+    let mut samples = Vec::new();
+    let mut sequence = 1;
+    let mut decode_time = 0;
+    let mut last_duration = DEFAULT_FRAME_DURATION;
+
+    for (i, frame) in clip.frames.iter().enumerate() {
+        let access_unit = h264::to_avcc(&frame.data);
+        if frame.keyframe {
+            flush(&mut out, &mut samples, &mut sequence, &mut decode_time);
+            let (Some(sps), Some(pps)) = (access_unit.sps, access_unit.pps) else {
+                bail!("keyframe without parameter sets");
+            };
+            match &parameter_sets {
+                None => {
+                    out.extend_from_slice(&fmp4::init_segment(&sps, &pps)?);
+                    parameter_sets = Some((sps, pps));
+                }
+                Some((started_sps, started_pps)) if *started_sps != sps || *started_pps != pps => {
+                    eprintln!("Stream parameters changed, cutting the exported clip short");
+                    return Ok(out);
+                }
+                Some(_) => {}
+            }
+        }
+
+        // Same as the recorder, durations come from the next frame
+        let duration = match clip.frames.get(i + 1) {
+            Some(next) if next.timestamp > frame.timestamp => {
+                let micros = next.timestamp.to_micros() - frame.timestamp.to_micros();
+                (micros * TIMESCALE as u64 / 1_000_000) as u32
+            }
+            _ => last_duration,
+        };
+        last_duration = duration;
+        samples.push(Fmp4Sample {
+            data: access_unit.data,
+            duration,
+            keyframe: frame.keyframe,
+        });
+    }
+    flush(&mut out, &mut samples, &mut sequence, &mut decode_time);
+    Ok(out)
+}
+
+/// Write out a GOP's samples as a fragment.
+fn flush(
+    out: &mut Vec<u8>,
+    samples: &mut Vec<Fmp4Sample>,
+    sequence: &mut u32,
+    decode_time: &mut u64,
+) {
+    if samples.is_empty() {
+        return;
+    }
+    out.extend_from_slice(&fmp4::fragment(*sequence, *decode_time, samples));
+    *sequence += 1;
+    *decode_time += samples.iter().map(|s| s.duration as u64).sum::<u64>();
+    samples.clear();
+}
+
+/// Annotation entries as JSON lines, the same as `AnnotationHub::record_to`
+/// writes them.
+pub fn json_lines(entries: &[RecordEntry]) -> Result<String> {
+    let mut out = String::new();
+    for entry in entries {
+        out.push_str(&serde_json::to_string(&Envelope::new(entry))?);
+        out.push('\n');
+    }
+    Ok(out)
+}
@@ -0,0 +1,205 @@
+pub mod buffer;
+pub mod export;
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::sync::{broadcast, mpsc};
+
+use crate::{
+    annotations::hub::AnnotationHub,
+    feed::{
+        frame::VideoTimestamp,
+        manager::{FeedControlMessage, FeedResultMessage},
+        replay::ReplayClip,
+    },
+};
+
+use self::buffer::ReplayBuffer;
+
+/// Client id the replay buffer joins the feed as.
+const REPLAY_CLIENT_ID: &str = "replay";
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// How far back clips can reach. The encoded feed is held in memory,
+    /// so at 6 Mbps a minute is about 45 MB.
+    pub duration: Duration,
+    /// Clips can only start on a keyframe, so one is requested this often.
+    /// Shorter intervals mean less lead-in before the in point, at the cost
+    /// of bitrate for every viewer of the primary group.
+    pub keyframe_interval: Duration,
+}
+
+impl Default for ReplayConfig {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(60),
+            keyframe_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ReplayError {
+    #[error("nothing has been buffered yet")]
+    Empty,
+    #[error("no in point has been marked")]
+    NoInPoint,
+    #[error("the out point is before the in point")]
+    OutBeforeIn,
+    #[error("the in point is no longer buffered")]
+    Expired,
+    #[error(transparent)]
+    Export(#[from] anyhow::Error),
+}
+
+struct ReplayState {
+    buffer: ReplayBuffer,
+    mark_in: Option<VideoTimestamp>,
+    /// Without one, clips run up to the latest frame.
+    mark_out: Option<VideoTimestamp>,
+}
+
+/// Keeps the last stretch of the encoded feed and its annotations, so the
+/// presenter can mark a moment and show it again, or take it away as an
+/// MP4.
+#[derive(Clone)]
+pub struct InstantReplay {
+    state: Arc<Mutex<ReplayState>>,
+    annotations: AnnotationHub,
+}
+
+impl InstantReplay {
+    pub fn new(config: ReplayConfig, annotations: AnnotationHub) -> Self {
+        // The buffer reaches back to the keyframe before its horizon
+        annotations.keep_timeline(config.duration + config.keyframe_interval);
+        let buffer = ReplayBuffer::new(config.duration, config.keyframe_interval);
+        Self {
+            state: Arc::new(Mutex::new(ReplayState {
+                buffer,
+                mark_in: None,
+                mark_out: None,
+            })),
+            annotations,
+        }
+    }
+
+    /// Mark the clip to start `ago` before the latest frame, or as far back
+    /// as the buffer goes. Starting a new clip forgets its out point.
+    pub fn mark_in(&self, ago: Duration) -> Result<VideoTimestamp, ReplayError> {
+        let mut state = self.state.lock().unwrap();
+        let timestamp = state.mark(ago)?;
+        state.mark_in = Some(timestamp);
+        state.mark_out = None;
+        Ok(timestamp)
+    }
+
+    /// Mark the clip to end `ago` before the latest frame.
+    pub fn mark_out(&self, ago: Duration) -> Result<VideoTimestamp, ReplayError> {
+        let mut state = self.state.lock().unwrap();
+        let timestamp = state.mark(ago)?;
+        state.mark_out = Some(timestamp);
+        Ok(timestamp)
+    }
+
+    /// The marked clip, led in from the keyframe before its in point.
+    pub fn clip(&self) -> Result<ReplayClip, ReplayError> {
+        let state = self.state.lock().unwrap();
+        let mark_in = state.mark_in.ok_or(ReplayError::NoInPoint)?;
+        let latest = state.buffer.latest().ok_or(ReplayError::Empty)?;
+        let mark_out = state.mark_out.unwrap_or(latest);
+        if mark_out < mark_in {
+            return Err(ReplayError::OutBeforeIn);
+        }
+        if state
+            .buffer
+            .earliest()
+            .map_or(true, |earliest| mark_in < earliest)
+        {
+            return Err(ReplayError::Expired);
+        }
+        state
+            .buffer
+            .clip(mark_in, mark_out)
+            .ok_or(ReplayError::Expired)
+    }
+
+    /// The marked clip as an MP4, see `export::mp4`.
+    pub fn export_mp4(&self) -> Result<Vec<u8>, ReplayError> {
+        let clip = self.clip()?;
+        Ok(export::mp4(&clip).context("unable to export clip")?)
+    }
+
+    /// What was drawn over the marked clip, as an annotation recording
+    /// lined up with the start of `export_mp4`. Any drawings already up
+    /// when it starts are in there too.
+    pub fn export_annotations(&self) -> Result<String, ReplayError> {
+        let clip = self.clip()?;
+        let start = clip.frames[0].timestamp;
+        let end = clip.frames[clip.frames.len() - 1].timestamp;
+        let entries = self.annotations.timeline(start, end);
+        Ok(export::json_lines(&entries).context("unable to export annotations")?)
+    }
+}
+
+impl ReplayState {
+    fn mark(&self, ago: Duration) -> Result<VideoTimestamp, ReplayError> {
+        let latest = self.buffer.latest().ok_or(ReplayError::Empty)?;
+        let earliest = self.buffer.earliest().ok_or(ReplayError::Empty)?;
+        let timestamp = latest
+            .to_micros()
+            .saturating_sub(ago.as_micros() as u64)
+            .max(earliest.to_micros());
+        Ok(VideoTimestamp::from_micros(timestamp))
+    }
+}
+
+/// Fill the replay buffer from the encoded feed until it ends. Does nothing
+/// without a replay.
+pub async fn main(
+    replay: Option<InstantReplay>,
+    feed_control_tx: mpsc::Sender<FeedControlMessage>,
+    feed_result_tx: broadcast::Sender<FeedResultMessage>,
+) -> Result<()> {
+    let Some(replay) = replay else {
+        return Ok(());
+    };
+    let mut feed_result_rx = feed_result_tx.subscribe();
+
+    // Pinned, so the buffer keeps filling without viewers and holds what
+    // the primary group saw
+    feed_control_tx
+        .send(FeedControlMessage::ClientJoined {
+            client_id: REPLAY_CLIENT_ID.to_owned(),
+            group_tx: None,
+        })
+        .await
+        .context("feed is gone")?;
+
+    loop {
+        let request_keyframe = match feed_result_rx.recv().await {
+            Err(broadcast::error::RecvError::Lagged(_)) => {
+                replay.state.lock().unwrap().buffer.discontinuity();
+                true
+            }
+            Err(broadcast::error::RecvError::Closed) => break,
+
+            Ok(FeedResultMessage::EncodedBitstream(frame)) => {
+                replay.state.lock().unwrap().buffer.push(frame)
+            }
+            Ok(_) => continue,
+        };
+        if request_keyframe {
+            feed_control_tx
+                .try_send(FeedControlMessage::RequestKeyframe {
+                    client_id: REPLAY_CLIENT_ID.to_owned(),
+                })
+                .ok();
+        }
+    }
+    Ok(())
+}
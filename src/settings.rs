@@ -0,0 +1,204 @@
+use std::{fmt::Display, path::PathBuf, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::{
+    contribution::{ContributionConfig, ContributionTransport},
+    feed::{
+        encoders::FeedEncoderConfig,
+        manager::FeedConfigBuilder,
+        outputs::{dump::DumpConfig, ndi::NDIOutputConfig},
+    },
+    recording::recorder::RecorderConfig,
+    remote::config::RemoteConfigBuilder,
+    replay::ReplayConfig,
+};
+
+/// Environment variables are named `TELESTRATOR_<name>`.
+const PREFIX: &str = "TELESTRATOR_";
+
+/// Settings for the feed, on top of `builder`:
+///
+/// - `ENCODER`: `nvenc` (default) or `openh264`
+/// - `BITRATE`: `<min>,<start>,<max>` in b/s
+/// - `FPS`: frame rate limit
+/// - `MAX_ENCODERS`: encoder groups to split viewers across
+/// - `NDI_OUTPUT`: publish the annotations (and program) over NDI under this
+///   name. `NDI_PROGRAM=0` publishes the annotations only.
+/// - `LASER_POINTER=1`: burn presenters' pointers in as laser dots
+/// - `DEBUG_DUMP`: dump encoder input and output under this directory
+pub fn feed(mut builder: FeedConfigBuilder) -> Result<FeedConfigBuilder> {
+    if let Some(encoder) = var::<String>("ENCODER")? {
+        builder = builder.encoder(match encoder.as_str() {
+            "nvenc" => FeedEncoderConfig::Nvenc(Default::default()),
+            "openh264" => FeedEncoderConfig::OpenH264(Default::default()),
+            _ => bail!("{PREFIX}ENCODER: unknown encoder {encoder:?}"),
+        });
+    }
+    if let Some(bitrate) = var::<String>("BITRATE")? {
+        let bitrates = bitrate
+            .split(',')
+            .map(|b| b.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("{PREFIX}BITRATE: invalid bitrate {bitrate:?}"))?;
+        let [min, start, max] = bitrates[..] else {
+            bail!("{PREFIX}BITRATE: expected <min>,<start>,<max>");
+        };
+        builder = builder.bitrate(min, start, max);
+    }
+    if let Some(fps) = var("FPS")? {
+        builder = builder.fps(fps);
+    }
+    if let Some(max_encoders) = var("MAX_ENCODERS")? {
+        builder = builder.max_encoders(max_encoders);
+    }
+    if let Some(name) = var("NDI_OUTPUT")? {
+        builder = builder.ndi_output(NDIOutputConfig {
+            name,
+            program: flag("NDI_PROGRAM")?.unwrap_or(true),
+        });
+    }
+    if let Some(laser_pointer) = flag("LASER_POINTER")? {
+        builder = builder.laser_pointer(laser_pointer);
+    }
+    if let Some(directory) = var("DEBUG_DUMP")? {
+        builder = builder.debug_dump(DumpConfig {
+            directory,
+            ..Default::default()
+        });
+    }
+    Ok(builder)
+}
+
+/// Settings for the remote (WebRTC, WHEP, WHIP, HLS and RTSP) side, on top of
+/// `builder`:
+///
+/// - `HTTP_PORT`: signalling server port
+/// - `STUN_SERVERS`: comma separated, e.g. `stun:stun.l.google.com:19302`
+/// - `TURN_SERVER`, `TURN_USERNAME`, `TURN_CREDENTIAL`
+/// - `NAT_1TO1_IPS`: comma separated public IPs to advertise
+/// - `UDP_PORT_RANGE`: `<min>-<max>`
+/// - `UDP_MUX_PORT`: mux every peer connection over this UDP port
+/// - `RTSP_PORT`: also serve the feed over RTSP on this port
+/// - `FEC=0`: don't offer RED+ULPFEC
+pub fn remote(mut builder: RemoteConfigBuilder) -> Result<RemoteConfigBuilder> {
+    if let Some(port) = var("HTTP_PORT")? {
+        builder = builder.http_port(port);
+    }
+    for url in list("STUN_SERVERS")? {
+        builder = builder.stun_server(&url);
+    }
+    if let Some(url) = var::<String>("TURN_SERVER")? {
+        let username = var::<String>("TURN_USERNAME")?.unwrap_or_default();
+        let credential = var::<String>("TURN_CREDENTIAL")?.unwrap_or_default();
+        builder = builder.turn_server(&url, &username, &credential);
+    }
+    let nat_1to1_ips = list("NAT_1TO1_IPS")?;
+    if !nat_1to1_ips.is_empty() {
+        builder = builder.nat_1to1_ips(nat_1to1_ips);
+    }
+    if let Some(range) = var::<String>("UDP_PORT_RANGE")? {
+        let ports = range
+            .split_once('-')
+            .and_then(|(min, max)| Some((min.trim().parse().ok()?, max.trim().parse().ok()?)));
+        let Some((min, max)) = ports else {
+            bail!("{PREFIX}UDP_PORT_RANGE: expected <min>-<max>, got {range:?}");
+        };
+        builder = builder.udp_port_range(min, max);
+    }
+    if let Some(port) = var("UDP_MUX_PORT")? {
+        builder = builder.udp_mux_port(port);
+    }
+    if let Some(port) = var("RTSP_PORT")? {
+        builder = builder.rtsp_port(port);
+    }
+    if let Some(fec) = flag("FEC")? {
+        builder = builder.fec(fec);
+    }
+    Ok(builder)
+}
+
+/// `RECORD`: record the feed to fragmented MP4 under this directory.
+pub fn recorder() -> Result<Option<RecorderConfig>> {
+    Ok(var("RECORD")?.map(|directory| RecorderConfig {
+        directory,
+        ..Default::default()
+    }))
+}
+
+/// `CONTRIBUTION`: send the feed as MPEG-TS to a remote production site, see
+/// `ContributionTransport::from_str`.
+pub fn contribution() -> Result<Option<ContributionConfig>> {
+    Ok(var::<ContributionTransport>("CONTRIBUTION")?.map(ContributionConfig::new))
+}
+
+/// `REPLAY`: keep this many seconds of the feed in memory for instant
+/// replays.
+pub fn replay() -> Result<Option<ReplayConfig>> {
+    let Some(seconds) = var::<f32>("REPLAY")? else {
+        return Ok(None);
+    };
+    let duration = Duration::try_from_secs_f32(seconds)
+        .ok()
+        .filter(|duration| !duration.is_zero())
+        .with_context(|| format!("{PREFIX}REPLAY: invalid duration {seconds}"))?;
+    Ok(Some(ReplayConfig {
+        duration,
+        ..Default::default()
+    }))
+}
+
+/// `ANNOTATIONS_RECORD`: record annotation changes to this file.
+pub fn annotations_record() -> Result<Option<PathBuf>> {
+    var("ANNOTATIONS_RECORD")
+}
+
+/// `ANNOTATIONS_REPLAY`: replay annotations from this recording instead of
+/// taking them from peers.
+pub fn annotations_replay() -> Result<Option<PathBuf>> {
+    var("ANNOTATIONS_REPLAY")
+}
+
+/// The variable parsed as `T`. Unset or empty is `None`.
+fn var<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: Display,
+{
+    let name = format!("{PREFIX}{name}");
+    let value = match std::env::var(&name) {
+        Ok(value) if !value.trim().is_empty() => value,
+        Ok(_) | Err(std::env::VarError::NotPresent) => return Ok(None),
+        Err(e) => return Err(e).context(name),
+    };
+    value
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|e| anyhow!("{name}: {e}"))
+}
+
+/// A boolean variable: `1`, `true`, `yes` or `on`, or their opposites.
+fn flag(name: &str) -> Result<Option<bool>> {
+    let Some(value) = var::<String>(name)? else {
+        return Ok(None);
+    };
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Ok(Some(true)),
+        "0" | "false" | "no" | "off" => Ok(Some(false)),
+        _ => bail!("{PREFIX}{name}: expected 1 or 0, got {value:?}"),
+    }
+}
+
+/// A comma separated variable.
+fn list(name: &str) -> Result<Vec<String>> {
+    Ok(var::<String>(name)?
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(str::to_owned)
+                .collect()
+        })
+        .unwrap_or_default())
+}